@@ -64,6 +64,7 @@ class ResourceDetailsBottomSheet(private val resource: ViewResource, private val
                     StatusEnum.ONLINE -> "Gateway connected"
                     StatusEnum.OFFLINE -> "All Gateways offline"
                     StatusEnum.UNKNOWN -> "No activity"
+                    StatusEnum.DEGRADED -> "Gateway connected, Resource degraded"
                 }
             siteStatusTextView.text = statusText
             siteStatusLayout.visibility = View.VISIBLE
@@ -75,6 +76,7 @@ class ResourceDetailsBottomSheet(private val resource: ViewResource, private val
                     StatusEnum.ONLINE -> Color.GREEN
                     StatusEnum.OFFLINE -> Color.RED
                     StatusEnum.UNKNOWN -> Color.GRAY
+                    StatusEnum.DEGRADED -> Color.YELLOW
                 }
             val dotDrawable = GradientDrawable()
             dotDrawable.shape = GradientDrawable.OVAL
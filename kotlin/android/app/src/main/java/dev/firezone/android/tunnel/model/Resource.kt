@@ -42,4 +42,7 @@ enum class StatusEnum {
 
     @Json(name = "Online")
     ONLINE,
+
+    @Json(name = "Degraded")
+    DEGRADED,
 }
@@ -0,0 +1,99 @@
+//! Declarative TOML config files for the headless Client and the Gateway
+//!
+//! Each binary defines its own config struct. Settings from the CLI or from env vars
+//! take precedence over the config file, which takes precedence over the built-in defaults.
+
+use anyhow::{Context as _, Result};
+use clap::parser::{ArgMatches, ValueSource};
+use serde::de::DeserializeOwned;
+use std::{io, path::Path};
+
+/// Reads and parses a TOML config file
+///
+/// If `must_exist` is false, a missing file is the same as an empty one,
+/// so that the default config path doesn't have to exist.
+pub fn read<T: DeserializeOwned + Default>(path: &Path, must_exist: bool) -> Result<T> {
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(error) if error.kind() == io::ErrorKind::NotFound && !must_exist => {
+            tracing::debug!(?path, "No config file, using defaults");
+            return Ok(T::default());
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("Couldn't read config file `{}`", path.display()))
+        }
+    };
+    parse(&s).with_context(|| format!("Couldn't parse config file `{}`", path.display()))
+}
+
+/// Parses the contents of a TOML config file
+pub fn parse<T: DeserializeOwned>(s: &str) -> Result<T> {
+    Ok(toml::from_str(s)?)
+}
+
+/// Returns true if the user set the CLI arg `id` on the command line or through its env var
+pub fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Replaces a CLI arg with its value from the config file, unless the user set the arg explicitly
+///
+/// Only needed for args that have a default value. For optional args, `Option::or` does the same.
+pub fn merge<T>(matches: &ArgMatches, id: &str, arg: &mut T, from_file: Option<T>) {
+    let Some(from_file) = from_file else {
+        return;
+    };
+    if !is_explicit(matches, id) {
+        *arg = from_file;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory as _, FromArgMatches as _};
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[arg(long, default_value_t = 1280)]
+        mtu: usize,
+    }
+
+    #[derive(Debug, Default, PartialEq, serde::Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct Config {
+        mtu: Option<usize>,
+    }
+
+    #[test]
+    fn file_overrides_default_but_not_cli() {
+        let config = parse::<Config>("mtu = 1500").unwrap();
+
+        let matches = Cli::command().get_matches_from(["exe"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        merge(&matches, "mtu", &mut cli.mtu, config.mtu);
+        assert_eq!(cli.mtu, 1500);
+
+        let matches = Cli::command().get_matches_from(["exe", "--mtu", "1400"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        merge(&matches, "mtu", &mut cli.mtu, config.mtu);
+        assert_eq!(cli.mtu, 1400);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(parse::<Config>("mut = 1500").is_err());
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = Path::new("/this/config/does/not/exist.toml");
+
+        assert_eq!(read::<Config>(path, false).unwrap(), Config::default());
+        assert!(read::<Config>(path, true).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+//! Blocks all traffic outside the tunnel while a session is up or reconnecting
+//!
+//! Full-tunnel users need us to fail closed: If connlib loses the portal or a Gateway,
+//! the Internet Resource's traffic must not quietly leave through the physical interface.
+//!
+//! This doesn't depend on the Internet Resource: Without access to it, only Resources stay reachable.
+
+#[cfg(target_os = "linux")]
+#[path = "kill_switch/linux.rs"]
+mod imp;
+
+#[cfg(target_os = "windows")]
+#[path = "kill_switch/windows.rs"]
+mod imp;
+
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+pub use imp::KillSwitch;
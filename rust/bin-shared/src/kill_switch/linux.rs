@@ -0,0 +1,175 @@
+//! An nftables table that drops everything except our own traffic
+//!
+//! We go through the `nft` CLI because it applies a whole ruleset file in one transaction.
+
+use crate::{TunDeviceManager, FIREZONE_MARK};
+use anyhow::{bail, Context as _, Result};
+use std::{
+    io::Write as _,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+const TABLE: &str = "firezone-kill-switch";
+
+/// Owns our nftables table and deletes it when dropped
+///
+/// If we crash, the table stays and keeps blocking traffic, which is the point.
+#[derive(Default)]
+pub struct KillSwitch {
+    enabled: bool,
+    /// The system's resolvers, which we still allow DNS to.
+    resolvers: Vec<IpAddr>,
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        if let Err(error) = self.disable() {
+            tracing::error!(?error, "Failed to disable kill switch");
+        }
+    }
+}
+
+impl KillSwitch {
+    /// Installs or replaces our table
+    ///
+    /// DNS queries to `resolvers` may leave outside the tunnel,
+    /// because the portal's and relays' hostnames are resolved by the system, not through our marked sockets.
+    /// Idempotent, so it's fine to call this after a crash left the table behind.
+    pub fn enable(&mut self, resolvers: &[IpAddr]) -> Result<()> {
+        let mut child = Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to execute `nft`, is nftables installed?")?;
+        let mut stdin = child.stdin.take().context("`nft` should have a stdin")?;
+        stdin.write_all(ruleset(resolvers).as_bytes())?;
+        drop(stdin); // `nft` reads until EOF.
+
+        if !child.wait()?.success() {
+            bail!("`nft -f` returned non-zero");
+        }
+
+        self.enabled = true;
+        self.resolvers = resolvers.to_vec();
+        tracing::info!(?resolvers, "Enabled kill switch");
+        Ok(())
+    }
+
+    /// Re-installs our table if it's enabled and the system's resolvers changed
+    pub fn set_resolvers(&mut self, resolvers: &[IpAddr]) -> Result<()> {
+        if !self.enabled || self.resolvers == resolvers {
+            return Ok(());
+        }
+        self.enable(resolvers)
+    }
+
+    /// Deletes our table if we installed it
+    pub fn disable(&mut self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        Self::remove_leftover()?;
+        self.enabled = false;
+        tracing::info!("Disabled kill switch");
+        Ok(())
+    }
+
+    /// Deletes our table, even if an earlier run installed it and crashed
+    pub fn remove_leftover() -> Result<()> {
+        // Creating the table first makes deleting it succeed even if it doesn't exist.
+        let status = Command::new("nft")
+            .arg(format!("add table inet {TABLE}; delete table inet {TABLE}"))
+            .status()
+            .context("Failed to execute `nft`")?;
+        if !status.success() {
+            bail!("`nft delete table` returned non-zero");
+        }
+        Ok(())
+    }
+}
+
+/// DHCP and IPv6 neighbor discovery keep the physical interface configured while everything else is blocked.
+fn ruleset(resolvers: &[IpAddr]) -> String {
+    let tun = TunDeviceManager::IFACE_NAME;
+    let dns = dns_rules(resolvers);
+
+    format!(
+        r#"add table inet {TABLE}
+delete table inet {TABLE}
+table inet {TABLE} {{
+    chain output {{
+        type filter hook output priority filter; policy drop;
+        oifname "lo" accept
+        oifname "{tun}" accept
+        meta mark {FIREZONE_MARK:#x} accept
+{dns}        udp sport 68 udp dport 67 accept
+        udp sport 546 udp dport 547 accept
+        icmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept
+    }}
+    chain input {{
+        type filter hook input priority filter; policy drop;
+        iifname "lo" accept
+        iifname "{tun}" accept
+        ct state established,related accept
+        udp sport 67 udp dport 68 accept
+        udp sport 547 udp dport 546 accept
+        icmpv6 type {{ nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert }} accept
+    }}
+}}
+"#
+    )
+}
+
+/// nftables rejects empty sets, so we leave out the rule for a family without resolvers.
+fn dns_rules(resolvers: &[IpAddr]) -> String {
+    let (ipv4, ipv6) = resolvers
+        .iter()
+        .partition::<Vec<&IpAddr>, _>(|resolver| resolver.is_ipv4());
+
+    [("ip", ipv4), ("ip6", ipv6)]
+        .into_iter()
+        .filter(|(_, resolvers)| !resolvers.is_empty())
+        .map(|(family, resolvers)| {
+            let resolvers = resolvers
+                .iter()
+                .map(|resolver| resolver.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            format!("        {family} daddr {{ {resolvers} }} meta l4proto {{ tcp, udp }} th dport 53 accept\n")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ruleset_allows_our_traffic() {
+        let ruleset = ruleset(&[]);
+
+        assert!(ruleset.starts_with("add table inet firezone-kill-switch\ndelete table"));
+        assert!(ruleset.contains("meta mark 0xfd002021 accept"));
+        assert!(ruleset.contains(r#"oifname "tun-firezone" accept"#));
+        assert_eq!(ruleset.matches("policy drop;").count(), 2);
+        assert!(!ruleset.contains("dport 53"));
+    }
+
+    #[test]
+    fn ruleset_allows_dns_to_system_resolvers() {
+        let ruleset = ruleset(&[
+            "192.168.1.1".parse().unwrap(),
+            "fe80::1".parse().unwrap(),
+            "1.1.1.1".parse().unwrap(),
+        ]);
+
+        assert!(ruleset.contains(
+            "ip daddr { 192.168.1.1, 1.1.1.1 } meta l4proto { tcp, udp } th dport 53 accept"
+        ));
+        assert!(
+            ruleset.contains("ip6 daddr { fe80::1 } meta l4proto { tcp, udp } th dport 53 accept")
+        );
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+
+/// Not implemented on Windows yet, enabling it fails so we never silently fail open.
+#[derive(Default)]
+pub struct KillSwitch {}
+
+impl KillSwitch {
+    pub fn enable(&mut self, _resolvers: &[IpAddr]) -> Result<()> {
+        bail!("The kill switch is not implemented on Windows yet")
+    }
+
+    #[allow(clippy::unnecessary_wraps)] // Signature must match Linux
+    pub fn set_resolvers(&mut self, _resolvers: &[IpAddr]) -> Result<()> {
+        Ok(())
+    }
+
+    #[allow(clippy::unnecessary_wraps)] // Signature must match Linux
+    pub fn disable(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
+pub mod config_file;
 pub mod http_health_check;
+pub mod otlp;
 
+mod kill_switch;
 mod network_changes;
 mod tun_device_manager;
 
@@ -38,6 +41,9 @@ pub const BUNDLE_ID: &str = "dev.firezone.client";
 /// Mark for Firezone sockets to prevent routing loops on Linux.
 pub const FIREZONE_MARK: u32 = 0xfd002021;
 
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+pub use kill_switch::KillSwitch;
+
 #[cfg(any(target_os = "linux", target_os = "windows"))]
 pub use network_changes::{new_dns_notifier, new_network_notifier};
 
@@ -4,7 +4,8 @@ use crate::FIREZONE_MARK;
 use nix::sys::socket::{setsockopt, sockopt};
 use socket_factory::{TcpSocket, UdpSocket};
 
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum DnsControlMethod {
     /// Explicitly disable DNS control.
     ///
@@ -16,10 +17,22 @@ pub enum DnsControlMethod {
     /// Only suitable for the Alpine CI containers and maybe something like an
     /// embedded system
     EtcResolvConf,
-    /// Cooperate with `systemd-resolved`
+    /// Cooperate with `systemd-resolved` over D-Bus
+    ///
+    /// Only the domains of DNS Resources are routed to Firezone, other queries keep using the local resolvers.
     ///
     /// Suitable for most Ubuntu systems, probably
     SystemdResolved,
+    /// Register our resolvers for the tunnel interface with `resolvconf`
+    ///
+    /// Works with both Debian's `resolvconf` and openresolv. They regenerate `/etc/resolv.conf` themselves,
+    /// so a crash can't leave it replaced.
+    Resolvconf,
+    /// Configure DNS on the tunnel interface through NetworkManager's D-Bus API
+    ///
+    /// For systems where NetworkManager owns DNS, e.g. with its `dnsmasq` plugin.
+    /// NetworkManager must already manage `tun-firezone`, we don't change that ourselves.
+    NetworkManager,
 }
 
 impl Default for DnsControlMethod {
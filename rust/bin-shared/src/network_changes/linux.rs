@@ -1,10 +1,34 @@
-//! Not implemented for Linux yet
+//! Listens for network and DNS changes on Linux
 
-use crate::platform::DnsControlMethod;
-use anyhow::Result;
-use futures::StreamExt as _;
-use std::time::Duration;
-use tokio::time::{Interval, MissedTickBehavior};
+use crate::{platform::DnsControlMethod, TunDeviceManager};
+use anyhow::{Context as _, Result};
+use futures::{channel::mpsc, future::Either, StreamExt as _, TryStreamExt as _};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
+use netlink_packet_route::{
+    address::AddressScope,
+    link::LinkAttribute,
+    route::{RouteAttribute, RouteMessage},
+    RouteNetlinkMessage,
+};
+use rtnetlink::{
+    constants::{
+        RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK,
+    },
+    sys::{AsyncSocket as _, SocketAddr},
+    IpVersion,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::CString,
+    pin::Pin,
+    time::Duration,
+};
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+
+/// DHCP renewals and roaming usually change links, addresses and routes in one burst, we only want to notify once per burst.
+///
+/// We notify once there was no change for this long.
+const NETLINK_DEBOUNCE: Duration = Duration::from_millis(500);
 
 /// Parameters to tell `zbus` how to listen for a signal.
 struct SignalParams {
@@ -33,9 +57,9 @@ pub async fn new_dns_notifier(
     method: DnsControlMethod,
 ) -> Result<Worker> {
     match method {
-        DnsControlMethod::Disabled | DnsControlMethod::EtcResolvConf => {
-            Ok(Worker::new_dns_poller())
-        }
+        DnsControlMethod::Disabled
+        | DnsControlMethod::EtcResolvConf
+        | DnsControlMethod::Resolvconf => Ok(Worker::new_dns_poller()),
         DnsControlMethod::SystemdResolved => {
             Worker::new_dbus(SignalParams {
                 dest: "org.freedesktop.resolve1",
@@ -45,34 +69,224 @@ pub async fn new_dns_notifier(
             })
             .await
         }
+        DnsControlMethod::NetworkManager => {
+            Worker::new_dbus(SignalParams {
+                dest: "org.freedesktop.NetworkManager",
+                path: "/org/freedesktop/NetworkManager/DnsManager",
+                interface: "org.freedesktop.DBus.Properties",
+                member: "PropertiesChanged",
+            })
+            .await
+        }
     }
 }
 
-/// Listens for changes between Wi-Fi networks
+/// Listens for changes of our uplinks, e.g. switching Wi-Fi networks or a DHCP renewal
+///
+/// Uses netlink directly, so it works without NetworkManager, e.g. on headless servers and in containers.
+/// Only default routes and the global addresses of interfaces that carry one count as changes.
+/// Everything on our own tunnel interface is ignored.
 ///
-/// Should be similar to `dbus-monitor --system "type='signal',interface='org.freedesktop.NetworkManager',member='StateChanged'"`
+/// Should be similar to `ip monitor address route`
 pub async fn new_network_notifier(
     _tokio_handle: tokio::runtime::Handle,
-    method: DnsControlMethod,
+    _method: DnsControlMethod,
 ) -> Result<Worker> {
-    match method {
-        DnsControlMethod::Disabled | DnsControlMethod::EtcResolvConf => Ok(Worker::Null),
-        DnsControlMethod::SystemdResolved => {
-            Worker::new_dbus(SignalParams {
-                dest: "org.freedesktop.NetworkManager",
-                path: "/org/freedesktop/NetworkManager",
-                interface: "org.freedesktop.NetworkManager",
-                member: "StateChanged",
-            })
-            .await
-        }
-    }
+    Ok(Worker::Netlink(Netlink::new().await?))
 }
 
 pub enum Worker {
     DBus(zbus::proxy::SignalStream<'static>),
     DnsPoller(Interval),
-    Null,
+    Netlink(Netlink),
+}
+
+pub struct Netlink {
+    messages: mpsc::UnboundedReceiver<(NetlinkMessage<RouteNetlinkMessage>, SocketAddr)>,
+    task: tokio::task::JoinHandle<()>,
+    /// When to notify about the changes we've seen so far, pushed back by every further change.
+    debounce: Option<Pin<Box<Sleep>>>,
+    uplinks: Uplinks,
+    /// Updated from link events, so we don't have to look it up for every message.
+    ///
+    /// Remembered after the tunnel is gone, so we still recognize the routes that the kernel deletes along with it.
+    tun_index: Option<u32>,
+}
+
+/// The interfaces that carry a default route, and how many of them each one carries.
+#[derive(Debug, Default)]
+struct Uplinks(HashMap<u32, usize>);
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Netlink {
+    async fn new() -> Result<Self> {
+        let (mut cxn, handle, messages) = rtnetlink::new_connection()?;
+
+        // Links going down take their routes with them, we only need link events to learn our tunnel's index.
+        let groups = RTMGRP_LINK
+            | RTMGRP_IPV4_IFADDR
+            | RTMGRP_IPV6_IFADDR
+            | RTMGRP_IPV4_ROUTE
+            | RTMGRP_IPV6_ROUTE;
+        cxn.socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, groups))
+            .context("Failed to subscribe to netlink multicast groups")?;
+        let task = tokio::spawn(cxn);
+
+        let tun_index = tun_index();
+        let mut uplinks = Uplinks::default();
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let routes = handle
+                .route()
+                .get(ip_version)
+                .execute()
+                .try_collect::<Vec<_>>()
+                .await
+                .context("Failed to list routes")?;
+            for route in routes {
+                uplinks.apply(&RouteNetlinkMessage::NewRoute(route), tun_index);
+            }
+        }
+        tracing::debug!(?uplinks, "Listening for changes of our uplinks");
+
+        Ok(Self {
+            messages,
+            task,
+            debounce: None,
+            uplinks,
+            tun_index,
+        })
+    }
+
+    /// Cancel-safe, the callers re-create this future in every iteration of their `select!` loop.
+    async fn notified(&mut self) -> Result<()> {
+        loop {
+            let message = match self.debounce.as_mut() {
+                Some(deadline) => {
+                    match futures::future::select(deadline, self.messages.next()).await {
+                        Either::Left(((), _)) => None,
+                        Either::Right((message, _)) => Some(message),
+                    }
+                }
+                None => Some(self.messages.next().await),
+            };
+
+            let Some(message) = message else {
+                self.debounce = None;
+                tracing::debug!("Netlink notified us");
+                return Ok(());
+            };
+
+            let Some((message, _)) = message else {
+                anyhow::bail!("Netlink connection closed");
+            };
+
+            let NetlinkPayload::InnerMessage(message) = message.payload else {
+                continue;
+            };
+
+            if let Some(index) = announced_tun_index(&message) {
+                self.tun_index = Some(index);
+                continue;
+            }
+
+            if !self.uplinks.apply(&message, self.tun_index) {
+                continue;
+            }
+
+            let deadline = Instant::now() + NETLINK_DEBOUNCE;
+            match self.debounce.as_mut() {
+                Some(debounce) => debounce.as_mut().reset(deadline),
+                None => self.debounce = Some(Box::pin(tokio::time::sleep_until(deadline))),
+            }
+        }
+    }
+}
+
+impl Uplinks {
+    /// Tracks the default routes in `message` and returns whether it changed one of our uplinks.
+    ///
+    /// Link updates only matter for [`announced_tun_index`], neighbour updates aren't in the groups we subscribe to.
+    fn apply(&mut self, message: &RouteNetlinkMessage, tun_index: Option<u32>) -> bool {
+        match message {
+            RouteNetlinkMessage::NewRoute(route) => {
+                let interfaces = default_route_interfaces(route, tun_index);
+                for index in &interfaces {
+                    *self.0.entry(*index).or_default() += 1;
+                }
+
+                !interfaces.is_empty()
+            }
+            RouteNetlinkMessage::DelRoute(route) => {
+                let interfaces = default_route_interfaces(route, tun_index);
+                for index in &interfaces {
+                    if let Entry::Occupied(mut entry) = self.0.entry(*index) {
+                        *entry.get_mut() -= 1;
+                        if *entry.get() == 0 {
+                            entry.remove();
+                        }
+                    }
+                }
+
+                !interfaces.is_empty()
+            }
+            RouteNetlinkMessage::NewAddress(address) | RouteNetlinkMessage::DelAddress(address) => {
+                // Link-local addresses come and go with the link itself, they don't change how we reach the Internet.
+                address.header.scope == AddressScope::Universe
+                    && Some(address.header.index) != tun_index
+                    && self.0.contains_key(&address.header.index)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The interfaces that `route` goes out of, if it is a default route that doesn't go through our tunnel.
+fn default_route_interfaces(route: &RouteMessage, tun_index: Option<u32>) -> Vec<u32> {
+    if route.header.destination_prefix_length != 0 {
+        return Vec::new();
+    }
+
+    route
+        .attributes
+        .iter()
+        .flat_map(|attribute| match attribute {
+            RouteAttribute::Oif(index) => vec![*index],
+            RouteAttribute::MultiPath(hops) => hops.iter().map(|hop| hop.interface_index).collect(),
+            _ => Vec::new(),
+        })
+        .filter(|index| Some(*index) != tun_index)
+        .collect()
+}
+
+/// The index of our tunnel interface if `message` announces it, e.g. because it was (re-)created.
+fn announced_tun_index(message: &RouteNetlinkMessage) -> Option<u32> {
+    let RouteNetlinkMessage::NewLink(link) = message else {
+        return None;
+    };
+
+    link.attributes
+        .iter()
+        .any(|attribute| {
+            matches!(attribute, LinkAttribute::IfName(name) if name == TunDeviceManager::IFACE_NAME)
+        })
+        .then_some(link.header.index)
+}
+
+/// Only looked up on start, [`announced_tun_index`] tells us about later changes.
+fn tun_index() -> Option<u32> {
+    let name = CString::new(TunDeviceManager::IFACE_NAME).ok()?;
+
+    // Safety: `name` is a valid, NUL-terminated C string that outlives the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+
+    (index != 0).then_some(index)
 }
 
 impl Worker {
@@ -101,8 +315,6 @@ impl Worker {
         Ok(())
     }
 
-    // `Result` needed to match Windows
-    #[allow(clippy::unnecessary_wraps)]
     pub async fn notified(&mut self) -> Result<()> {
         match self {
             Self::DnsPoller(interval) => {
@@ -114,8 +326,104 @@ impl Worker {
                 }
                 tracing::debug!("DBus notified us");
             }
-            Self::Null => futures::future::pending::<()>().await,
+            Self::Netlink(netlink) => netlink.notified().await?,
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netlink_packet_route::{address::AddressMessage, link::LinkMessage};
+
+    fn route(prefix_len: u8, oif: u32) -> RouteMessage {
+        let mut route = RouteMessage::default();
+        route.header.destination_prefix_length = prefix_len;
+        route.attributes.push(RouteAttribute::Oif(oif));
+        route
+    }
+
+    fn address(index: u32, scope: AddressScope) -> AddressMessage {
+        let mut address = AddressMessage::default();
+        address.header.index = index;
+        address.header.scope = scope;
+        address
+    }
+
+    #[test]
+    fn only_default_routes_are_changes() {
+        let mut uplinks = Uplinks::default();
+
+        assert!(!uplinks.apply(&RouteNetlinkMessage::NewRoute(route(24, 2)), None));
+        assert!(uplinks.apply(&RouteNetlinkMessage::NewRoute(route(0, 2)), None));
+        assert!(uplinks.apply(&RouteNetlinkMessage::DelRoute(route(0, 2)), None));
+    }
+
+    #[test]
+    fn ignores_changes_on_tun_interface() {
+        let mut uplinks = Uplinks::default();
+
+        assert!(!uplinks.apply(&RouteNetlinkMessage::NewRoute(route(0, 42)), Some(42)));
+        assert!(!uplinks.apply(
+            &RouteNetlinkMessage::NewAddress(address(42, AddressScope::Universe)),
+            Some(42)
+        ));
+    }
+
+    #[test]
+    fn only_global_addresses_of_uplinks_are_changes() {
+        let mut uplinks = Uplinks::default();
+        uplinks.apply(&RouteNetlinkMessage::NewRoute(route(0, 2)), None);
+
+        assert!(uplinks.apply(
+            &RouteNetlinkMessage::NewAddress(address(2, AddressScope::Universe)),
+            None
+        ));
+        assert!(!uplinks.apply(
+            &RouteNetlinkMessage::NewAddress(address(2, AddressScope::Link)),
+            None
+        ));
+        assert!(!uplinks.apply(
+            &RouteNetlinkMessage::DelAddress(address(3, AddressScope::Universe)),
+            None
+        ));
+    }
+
+    #[test]
+    fn learns_tun_index_from_link_events() {
+        let link = |index, name: &str| {
+            let mut link = LinkMessage::default();
+            link.header.index = index;
+            link.attributes.push(LinkAttribute::IfName(name.to_owned()));
+            RouteNetlinkMessage::NewLink(link)
+        };
+
+        assert_eq!(
+            announced_tun_index(&link(42, TunDeviceManager::IFACE_NAME)),
+            Some(42)
+        );
+        assert_eq!(announced_tun_index(&link(2, "eth0")), None);
+        assert_eq!(
+            announced_tun_index(&RouteNetlinkMessage::NewRoute(route(0, 42))),
+            None
+        );
+    }
+
+    #[test]
+    fn interface_stays_uplink_until_its_last_default_route_is_gone() {
+        let mut uplinks = Uplinks::default();
+        let global =
+            |index| RouteNetlinkMessage::NewAddress(address(index, AddressScope::Universe));
+
+        // E.g. an IPv4 and an IPv6 default route on the same interface
+        uplinks.apply(&RouteNetlinkMessage::NewRoute(route(0, 2)), None);
+        uplinks.apply(&RouteNetlinkMessage::NewRoute(route(0, 2)), None);
+
+        uplinks.apply(&RouteNetlinkMessage::DelRoute(route(0, 2)), None);
+        assert!(uplinks.apply(&global(2), None));
+
+        uplinks.apply(&RouteNetlinkMessage::DelRoute(route(0, 2)), None);
+        assert!(!uplinks.apply(&global(2), None));
+    }
+}
@@ -0,0 +1,83 @@
+//! Exports traces and metrics to an OTLP collector, like the Relay does.
+//!
+//! This is opt-in, without it, connlib's metrics stay no-ops and spans only end up in our logs.
+
+use anyhow::{Context as _, Result};
+use opentelemetry::{global, trace::TracerProvider as _, Key, KeyValue};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::{
+    resource::{EnvResourceDetector, TelemetryResourceDetector},
+    runtime::Tokio,
+    trace::Config,
+    Resource,
+};
+use std::time::Duration;
+use tracing::Subscriber;
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+
+/// Only spans with this target are exported, i.e. the connection setup spans in connlib.
+///
+/// All other spans wrap single packets or function calls, which would drown the collector.
+const SPAN_TARGET: &str = "telemetry";
+
+/// Installs the global tracer and meter providers, reporting to the collector at `grpc_endpoint` (`host:port`).
+///
+/// Must be called within a Tokio runtime, the exporters run on it in the background.
+/// The returned layer feeds our spans into the tracer provider.
+pub fn layer<S>(
+    grpc_endpoint: &str,
+    service_name: &'static str,
+) -> Result<Box<dyn Layer<S> + Send + Sync + 'static>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let metadata = make_otel_metadata(service_name);
+    let grpc_endpoint = format!("http://{grpc_endpoint}");
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(grpc_endpoint.clone());
+    let tracer_provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(Config::default().with_resource(metadata.clone()))
+        .install_batch(Tokio)
+        .context("Failed to create OTLP trace pipeline")?;
+    global::set_tracer_provider(tracer_provider.clone());
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(grpc_endpoint);
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(Tokio)
+        .with_resource(metadata)
+        .with_exporter(exporter)
+        .build()
+        .context("Failed to create OTLP metrics pipeline")?;
+    global::set_meter_provider(meter_provider);
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer_provider.tracer(service_name))
+        .with_filter(filter_fn(|metadata| metadata.target() == SPAN_TARGET))
+        .boxed())
+}
+
+/// The `service.name` and `service.namespace` of our binaries, which `OTEL_RESOURCE_ATTRIBUTES` can override
+pub fn make_otel_metadata(service_name: &'static str) -> Resource {
+    const SERVICE_NAME: Key = Key::from_static_str("service.name");
+    const SERVICE_NAMESPACE: Key = Key::from_static_str("service.namespace");
+
+    let default_metadata = Resource::new([
+        KeyValue::new(SERVICE_NAMESPACE, "firezone"),
+        KeyValue::new(SERVICE_NAME, service_name),
+    ]);
+    let detected_metadata = Resource::from_detectors(
+        Duration::ZERO,
+        vec![
+            Box::new(TelemetryResourceDetector),
+            Box::new(EnvResourceDetector::new()), // Allow overriding metadata using `OTEL_RESOURCE_ATTRIBUTES` env var.
+        ],
+    );
+
+    default_metadata.merge(&detected_metadata)
+}
@@ -3,32 +3,40 @@
 use crate::FIREZONE_MARK;
 use anyhow::{anyhow, Context as _, Result};
 use futures::TryStreamExt;
+use gso::SuperPacket;
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use libc::{
     close, fcntl, makedev, mknod, open, EEXIST, ENOENT, F_GETFL, F_SETFL, O_NONBLOCK, O_RDWR,
     S_IFCHR,
 };
+use netlink_packet_route::route::RouteAttribute;
 use netlink_packet_route::route::{RouteProtocol, RouteScope};
-use netlink_packet_route::rule::RuleAction;
-use rtnetlink::{new_connection, Error::NetlinkError, Handle, RouteAddRequest, RuleAddRequest};
+use netlink_packet_route::rule::{RuleAction, RuleAttribute};
+use rtnetlink::{
+    new_connection, Error::NetlinkError, Handle, IpVersion, RouteAddRequest, RuleAddRequest,
+};
+use std::num::NonZeroUsize;
 use std::path::Path;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
 use std::{
     collections::HashSet,
     net::{Ipv4Addr, Ipv6Addr},
 };
 use std::{
-    ffi::CStr,
-    fs, io,
+    ffi::{c_int, CStr},
+    fmt, fs, io,
     os::{
         fd::{AsRawFd, RawFd},
         unix::fs::PermissionsExt,
     },
 };
-use tokio::io::unix::AsyncFd;
+use tokio::io::{unix::AsyncFd, Ready};
 use tun::ioctl;
 
+mod gso;
+
 const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
 const TUN_DEV_MAJOR: u32 = 10;
 const TUN_DEV_MINOR: u32 = 200;
 
@@ -77,6 +85,54 @@ impl TunDeviceManager {
         Ok(Tun::new()?)
     }
 
+    /// Opens `num_queues` queues of a multi-queue device, so several tasks can read and write in parallel.
+    ///
+    /// The kernel spreads flows across the queues by their hash.
+    /// With `offload`, the kernel hands us TCP super-packets of up to 64 KiB, see [`gso`].
+    pub fn make_tun_queues(&mut self, num_queues: NonZeroUsize, offload: bool) -> Result<Vec<Tun>> {
+        let mut flags = libc::IFF_MULTI_QUEUE;
+        if offload {
+            flags |= libc::IFF_VNET_HDR;
+        }
+
+        let queues = (0..num_queues.get())
+            .map(|_| Tun::open(flags))
+            .collect::<io::Result<Vec<_>>>()
+            .context("Failed to open TUN queues")?;
+        tracing::debug!(%num_queues, %offload, "Opened TUN queues");
+
+        Ok(queues)
+    }
+
+    /// Changes the MTU of our interface, without dropping the packets or connections on it
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        let name = Self::IFACE_NAME;
+
+        let handle = &self.connection.handle;
+        let index = handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute()
+            .try_next()
+            .await?
+            .ok_or_else(|| anyhow!("Interface '{name}' does not exist"))?
+            .header
+            .index;
+
+        handle
+            .link()
+            .set(index)
+            .mtu(mtu as u32)
+            .execute()
+            .await
+            .context("Failed to set MTU")?;
+        self.mtu = mtu as u32;
+
+        Ok(())
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_ips(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<()> {
         let name = Self::IFACE_NAME;
@@ -194,6 +250,67 @@ impl TunDeviceManager {
         self.routes = new_routes;
         Ok(())
     }
+
+    /// Deletes our `ip rule`s and any routes left in our routing table
+    ///
+    /// The kernel deletes our routes with the tunnel interface, but the rules outlive it.
+    pub async fn remove_routing(&mut self) -> Result<()> {
+        let handle = &self.connection.handle;
+
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let rules = handle
+                .rule()
+                .get(ip_version.clone())
+                .execute()
+                .try_filter(|rule| {
+                    futures::future::ready(
+                        rule.attributes
+                            .iter()
+                            .any(|attr| matches!(attr, RuleAttribute::Table(FIREZONE_TABLE))),
+                    )
+                })
+                .try_collect::<Vec<_>>()
+                .await
+                .context("Failed to list ip rules")?;
+            for rule in rules {
+                handle
+                    .rule()
+                    .del(rule)
+                    .execute()
+                    .await
+                    .context("Failed to delete ip rule")?;
+            }
+
+            let routes = handle
+                .route()
+                .get(ip_version)
+                .execute()
+                .try_filter(|route| {
+                    futures::future::ready(
+                        route
+                            .attributes
+                            .iter()
+                            .any(|attr| matches!(attr, RouteAttribute::Table(FIREZONE_TABLE))),
+                    )
+                })
+                .try_collect::<Vec<_>>()
+                .await
+                .context("Failed to list routes")?;
+            for route in routes {
+                handle
+                    .route()
+                    .del(route)
+                    .execute()
+                    .await
+                    .context("Failed to delete route")?;
+            }
+        }
+
+        self.routes.clear();
+        tracing::debug!("Removed leftover ip rules and routes");
+
+        Ok(())
+    }
 }
 
 fn make_rule(handle: &Handle) -> RuleAddRequest {
@@ -278,13 +395,28 @@ async fn remove_route(route: &IpNetwork, idx: u32, handle: &Handle) {
     tracing::warn!(%route, "Failed to remove route: {err}");
 }
 
-#[derive(Debug)]
 pub struct Tun {
     fd: AsyncFd<RawFd>,
+    /// Set if packets are prefixed with a `virtio_net_hdr`, i.e. the device was opened with `IFF_VNET_HDR`
+    offload: Option<Box<SuperPacket>>,
+}
+
+impl fmt::Debug for Tun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tun")
+            .field("fd", &self.fd)
+            .field("offload", &self.offload.is_some())
+            .finish()
+    }
 }
 
 impl Tun {
     pub fn new() -> io::Result<Self> {
+        Self::open(0)
+    }
+
+    /// Opens our device, or another queue of it, with `flags` on top of `IFF_TUN | IFF_NO_PI`
+    fn open(flags: c_int) -> io::Result<Self> {
         create_tun_device()?;
 
         let fd = match unsafe { open(TUN_FILE.as_ptr() as _, O_RDWR) } {
@@ -297,14 +429,30 @@ impl Tun {
             ioctl::exec(
                 fd,
                 TUNSETIFF,
-                &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME),
+                &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME)
+                    .with_flags(flags),
             )?;
         }
 
+        let offload = flags & libc::IFF_VNET_HDR != 0;
+        if offload {
+            let offloads =
+                libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6 | libc::TUN_F_TSO_ECN;
+            // Safety: We just opened the file descriptor, `TUNSETOFFLOAD` takes its argument by value.
+            if unsafe { libc::ioctl(fd, TUNSETOFFLOAD as _, offloads as libc::c_ulong) } < 0 {
+                return Err(get_last_error());
+            }
+        }
+
         set_non_blocking(fd)?;
 
         // Safety: We just opened the fd.
-        unsafe { Self::from_fd(fd) }
+        let mut tun = unsafe { Self::from_fd(fd) }?;
+        if offload {
+            tun.offload = Some(Box::default());
+        }
+
+        Ok(tun)
     }
 
     /// Create a new [`Tun`] from a raw file descriptor.
@@ -315,8 +463,16 @@ impl Tun {
     unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
         Ok(Tun {
             fd: AsyncFd::new(fd)?,
+            offload: None,
         })
     }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.offload {
+            Some(_) => write_with_vnet_hdr(self.fd.as_raw_fd(), buf),
+            None => write(self.fd.as_raw_fd(), buf),
+        }
+    }
 }
 
 impl Drop for Tun {
@@ -327,15 +483,95 @@ impl Drop for Tun {
 
 impl tun::Tun for Tun {
     fn write4(&self, buf: &[u8]) -> io::Result<usize> {
-        write(self.fd.as_raw_fd(), buf)
+        self.write(buf)
     }
 
     fn write6(&self, buf: &[u8]) -> io::Result<usize> {
-        write(self.fd.as_raw_fd(), buf)
+        self.write(buf)
     }
 
     fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        tun::unix::poll_raw_fd(&self.fd, |fd| read(fd, buf), cx)
+        let offload = &mut self.offload;
+
+        tun::unix::poll_raw_fd(
+            &self.fd,
+            |fd| read_packet(fd, offload.as_deref_mut(), buf),
+            cx,
+        )
+    }
+
+    /// Reads until the device has no more packets for us, without waiting for readiness in between.
+    fn poll_read_many(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+        lens: &mut [usize],
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let fd = *guard.get_inner();
+            let mut num_packets = 0;
+            let mut would_block = false;
+
+            for (buf, len) in bufs.iter_mut().zip(lens.iter_mut()) {
+                match read_packet(fd, self.offload.as_deref_mut(), buf) {
+                    Ok(0) => break, // Closed, the next call returns 0 right away.
+                    Ok(n) => {
+                        *len = n;
+                        num_packets += 1;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready_matching(Ready::READABLE);
+                        would_block = true;
+                        break;
+                    }
+                    Err(e) if num_packets == 0 => return Poll::Ready(Err(e)),
+                    // Hand out what we have first. If the error persists, the next call returns it.
+                    Err(_) => break,
+                }
+            }
+
+            if would_block && num_packets == 0 {
+                continue; // Wait for readiness again.
+            }
+
+            return Poll::Ready(Ok(num_packets));
+        }
+    }
+
+    /// With offload, coalesces consecutive segments of the same TCP stream into super-packets.
+    ///
+    /// Otherwise, writes one packet after the other.
+    fn write_many(&self, packets: &[&[u8]]) -> io::Result<usize> {
+        let fd = self.fd.as_raw_fd();
+        let mut headers = [0u8; gso::MAX_HEADERS_LEN];
+        let mut num_written = 0;
+
+        while let Some(remaining) = packets.get(num_written..).filter(|p| !p.is_empty()) {
+            let coalesced = self
+                .offload
+                .as_ref()
+                .and_then(|_| gso::coalesce(remaining, &mut headers));
+
+            let res = match coalesced {
+                Some((num_packets, hdr_len)) => write_coalesced(
+                    fd,
+                    &headers[..gso::VNET_HDR_LEN + hdr_len],
+                    &remaining[..num_packets],
+                    hdr_len,
+                )
+                .map(|_| num_packets),
+                None => self.write(remaining[0]).map(|_| 1),
+            };
+
+            match res {
+                Ok(n) => num_written += n,
+                Err(e) if num_written == 0 => return Err(e),
+                Err(_) => break, // The caller learns about the error when it retries the rest.
+            }
+        }
+
+        Ok(num_written)
     }
 
     fn name(&self) -> &str {
@@ -399,3 +635,213 @@ fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
         n => Ok(n as usize),
     }
 }
+
+/// Reads the next packet, or with offload the next segment of the last super-packet we read.
+fn read_packet(fd: RawFd, offload: Option<&mut SuperPacket>, dst: &mut [u8]) -> io::Result<usize> {
+    let Some(super_packet) = offload else {
+        return read(fd, dst);
+    };
+
+    // Hand out the segments of the last super-packet before reading the next one.
+    loop {
+        if let Some(n) = super_packet.next_segment(dst) {
+            return Ok(n);
+        }
+
+        let n = read(fd, super_packet.buf_mut())?;
+        if n == 0 {
+            return Ok(0);
+        }
+        if let Err(e) = super_packet.set_len(n) {
+            tracing::debug!("Dropping packet from TUN device: {e}");
+        }
+    }
+}
+
+/// Writes the super-packet that [`gso::coalesce`] made out of `packets`, without copying their payloads.
+///
+/// `headers` are the `virtio_net_hdr` and the super-packet's IP and TCP headers, which replace the `hdr_len` bytes of headers of every packet.
+fn write_coalesced(
+    fd: RawFd,
+    headers: &[u8],
+    packets: &[&[u8]],
+    hdr_len: usize,
+) -> io::Result<usize> {
+    let iov = std::iter::once(headers)
+        .chain(packets.iter().map(|packet| &packet[hdr_len..]))
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as _,
+            iov_len: buf.len(),
+        })
+        .collect::<Vec<_>>();
+
+    // Safety: Within this module, the file descriptor is always valid, and all buffers outlive the call.
+    match unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as _) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok((n as usize).saturating_sub(gso::VNET_HDR_LEN)),
+    }
+}
+
+/// Write the buffer to the given file descriptor, after an empty `virtio_net_hdr`.
+///
+/// For packets that [`gso::coalesce`] can't coalesce, so the header never asks for segmentation.
+fn write_with_vnet_hdr(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let iov = [
+        libc::iovec {
+            iov_base: gso::EMPTY_VNET_HDR.as_ptr() as _,
+            iov_len: gso::VNET_HDR_LEN,
+        },
+        libc::iovec {
+            iov_base: buf.as_ptr() as _,
+            iov_len: buf.len(),
+        },
+    ];
+
+    // Safety: Within this module, the file descriptor is always valid, and both buffers outlive the call.
+    match unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as _) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok((n as usize).saturating_sub(gso::VNET_HDR_LEN)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read as _,
+        net::{SocketAddr, TcpStream},
+    };
+    use tun::Tun as _;
+
+    const LOCAL: [u8; 4] = [100, 90, 215, 97];
+    const REMOTE: [u8; 4] = [100, 90, 215, 98];
+
+    #[tokio::test]
+    #[ignore = "Needs admin / sudo"]
+    async fn coalesced_segments_reach_socket() {
+        let mut device_manager = TunDeviceManager::new(1280).unwrap();
+        let mut tun = device_manager
+            .make_tun_queues(NonZeroUsize::MIN, true)
+            .unwrap()
+            .remove(0);
+        device_manager
+            .set_ips(
+                LOCAL.into(),
+                Ipv6Addr::from([0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 1]),
+            )
+            .await
+            .unwrap();
+        device_manager
+            .set_routes(vec![Ipv4Network::new(REMOTE.into(), 32).unwrap()], vec![])
+            .await
+            .unwrap();
+
+        let client = std::thread::spawn(|| {
+            let mut stream = TcpStream::connect(SocketAddr::from((REMOTE, 80))).unwrap();
+            let mut received = vec![0u8; 3000];
+            stream.read_exact(&mut received).unwrap();
+            received
+        });
+
+        let syn = read_tcp(&mut tun, |flags| flags & TCP_SYN != 0).await;
+        let ack = u32::from_be_bytes([syn[24], syn[25], syn[26], syn[27]]) + 1;
+        let syn_ack = reply(&syn, 1000, ack, TCP_SYN | TCP_ACK, 1, &[]);
+        assert_eq!(tun.write_many(&[syn_ack.as_slice()]).unwrap(), 1);
+        read_tcp(&mut tun, |flags| flags == TCP_ACK).await;
+
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let segments = data
+            .chunks(1000)
+            .enumerate()
+            .map(|(i, payload)| {
+                let seq = 1001 + (i * 1000) as u32;
+                reply(&syn, seq, ack, TCP_ACK, 2 + i as u16, payload)
+            })
+            .collect::<Vec<_>>();
+        let segments = segments.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let mut headers = [0u8; gso::MAX_HEADERS_LEN];
+        assert_eq!(
+            gso::coalesce(&segments, &mut headers).map(|(n, _)| n),
+            Some(3)
+        );
+
+        assert_eq!(tun.write_many(&segments).unwrap(), 3);
+
+        let received = tokio::task::spawn_blocking(move || client.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+
+        device_manager.remove_routing().await.unwrap();
+    }
+
+    const TCP_SYN: u8 = 0x02;
+    const TCP_ACK: u8 = 0x10;
+
+    /// Reads from `tun` until we get a TCP segment with `flags`, using [`tun::Tun::poll_read_many`]
+    async fn read_tcp(tun: &mut Tun, flags: impl Fn(u8) -> bool) -> Vec<u8> {
+        let mut bufs = vec![vec![0u8; 1500]; 4];
+        let mut lens = [0; 4];
+
+        loop {
+            let mut slices = bufs.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>();
+            let num_packets =
+                std::future::poll_fn(|cx| tun.poll_read_many(&mut slices, &mut lens, cx))
+                    .await
+                    .unwrap();
+
+            for (buf, len) in bufs.iter().zip(&lens[..num_packets]) {
+                let packet = &buf[..*len];
+                if packet[0] >> 4 == 4 && packet[9] == 6 && flags(packet[33]) {
+                    return packet.to_vec();
+                }
+            }
+        }
+    }
+
+    /// A TCP segment in response to `syn`, with valid checksums
+    fn reply(syn: &[u8], seq: u32, ack: u32, flags: u8, id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(40 + payload.len() as u16).to_be_bytes());
+        packet[4..6].copy_from_slice(&id.to_be_bytes());
+        packet[6] = 0x40; // Don't fragment
+        packet[8] = 64;
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&syn[16..20]);
+        packet[16..20].copy_from_slice(&syn[12..16]);
+        packet[20..22].copy_from_slice(&syn[22..24]);
+        packet[22..24].copy_from_slice(&syn[20..22]);
+        packet[24..28].copy_from_slice(&seq.to_be_bytes());
+        packet[28..32].copy_from_slice(&ack.to_be_bytes());
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        packet[34..36].copy_from_slice(&u16::MAX.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        let ip_checksum = !checksum(&packet[..20], 0);
+        packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+        let pseudo_header = [
+            &packet[12..20],
+            &[0, 6],
+            &(packet.len() as u16 - 20).to_be_bytes(),
+        ]
+        .concat();
+        let tcp_checksum = !checksum(&packet[20..], checksum(&pseudo_header, 0));
+        packet[36..38].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+        packet
+    }
+
+    fn checksum(buf: &[u8], initial: u16) -> u16 {
+        let mut sum = u32::from(initial);
+        for word in buf.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+
+        sum as u16
+    }
+}
@@ -63,6 +63,28 @@ impl TunDeviceManager {
         Ok(tun)
     }
 
+    /// Changes the MTU of our interface, without dropping the packets or connections on it
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        for family in ["ipv4", "ipv6"] {
+            let status = Command::new("netsh")
+                .creation_flags(CREATE_NO_WINDOW)
+                .arg("interface")
+                .arg(family)
+                .arg("set")
+                .arg("subinterface")
+                .arg(format!("\"{TUNNEL_NAME}\""))
+                .arg(format!("mtu={mtu}"))
+                .arg("store=active")
+                .stdout(Stdio::null())
+                .status()?;
+            anyhow::ensure!(status.success(), "`netsh` failed to set the {family} MTU");
+        }
+        self.mtu = mtu as u32;
+
+        Ok(())
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_ips(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<()> {
         tracing::debug!("Setting our IPv4 = {}", ipv4);
@@ -26,7 +26,8 @@ use crate::TUNNEL_NAME;
 /// Also used for self-elevation
 pub const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum DnsControlMethod {
     /// Explicitly disable DNS control.
     ///
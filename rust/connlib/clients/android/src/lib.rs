@@ -9,7 +9,9 @@ use connlib_client_shared::{
     keypair, Callbacks, ConnectArgs, DisconnectError, LoginUrl, LoginUrlError, Session,
     V4RouteList, V6RouteList,
 };
-use connlib_shared::{callbacks::ResourceDescription, get_user_agent, messages::ResourceId};
+use connlib_shared::{
+    callbacks::ResourceDescription, get_user_agent, messages::ResourceId, DEFAULT_MTU,
+};
 use ip_network::{Ipv4Network, Ipv6Network};
 use jni::{
     objects::{GlobalRef, JClass, JObject, JString, JValue},
@@ -373,6 +375,7 @@ fn connect(
         udp_socket_factory: Arc::new(protected_udp_socket_factory(callbacks.clone())),
         private_key,
         callbacks,
+        mtu: DEFAULT_MTU,
     };
     let portal = PhoenixChannel::connect(
         Secret::new(url),
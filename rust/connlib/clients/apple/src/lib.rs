@@ -9,7 +9,7 @@ use backoff::ExponentialBackoffBuilder;
 use connlib_client_shared::{
     keypair, Callbacks, ConnectArgs, DisconnectError, LoginUrl, Session, V4RouteList, V6RouteList,
 };
-use connlib_shared::{callbacks::ResourceDescription, get_user_agent};
+use connlib_shared::{callbacks::ResourceDescription, get_user_agent, DEFAULT_MTU};
 use ip_network::{Ipv4Network, Ipv6Network};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
@@ -210,6 +210,7 @@ impl WrappedSession {
             },
             tcp_socket_factory: Arc::new(socket_factory::tcp),
             udp_socket_factory: Arc::new(socket_factory::udp),
+            mtu: DEFAULT_MTU,
         };
         let portal = PhoenixChannel::connect(
             Secret::new(url),
@@ -0,0 +1,406 @@
+//! Reach Firezone resources from within a Rust program
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), firezone::Error> {
+//! use tokio::io::AsyncWriteExt as _;
+//!
+//! let client = firezone::Client::connect("<token>").await?;
+//! let mut stream = client.dial_tcp("db.corp:5432").await?;
+//! stream.write_all(b"hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The tunnel ends in a userspace network stack inside this process, so there is no TUN device
+//! and we don't need any privileges. The routes and DNS settings of the system stay as they are.
+//! Names of DNS resources resolve through connlib's stub resolver, like on the other Clients.
+
+use backoff::ExponentialBackoffBuilder;
+use connlib_client_shared::{
+    keypair, Callbacks, ConnectArgs, DisconnectError, LoginUrl, LoginUrlError, Session,
+};
+use connlib_shared::{get_user_agent, DEFAULT_MTU};
+use netstack::Netstack;
+use phoenix_channel::PhoenixChannel;
+use secrecy::{Secret, SecretString};
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use url::Url;
+
+pub use connlib_shared::callbacks::ResourceDescription;
+pub use netstack::{TcpListener, TcpStream};
+
+/// Default for [`ConnectOptions::connect_timeout`]
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connection to Firezone, disconnected when dropped.
+pub struct Client {
+    session: Session,
+    netstack: Netstack,
+    state: watch::Receiver<State>,
+    resources: Arc<Mutex<Vec<ResourceDescription>>>,
+}
+
+/// Settings for [`Client::connect_with`]
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    pub api_url: Url,
+    /// Identifies this Client to the portal, AKA "Device ID"
+    ///
+    /// If `None`, we make up a random one, so every connect shows up as a new Client in the portal.
+    pub firezone_id: Option<String>,
+    /// The name of this Client in the portal, defaults to the host name
+    pub firezone_name: Option<String>,
+    pub mtu: usize,
+    /// Resolvers for names that aren't resources, unless the portal sets upstream DNS servers
+    ///
+    /// Defaults to the nameservers in `/etc/resolv.conf` on Unix.
+    pub upstream_dns: Vec<IpAddr>,
+    /// How long we keep trying to reach the portal before we give up, `None` to never give up
+    pub max_partition_time: Option<Duration>,
+    /// How long [`Client::connect_with`] waits for the tunnel to come up
+    ///
+    /// Applies even if `max_partition_time` is `None`, so an unreachable portal can't keep us waiting forever.
+    pub connect_timeout: Duration,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid login URL: {0}")]
+    LoginUrl(#[from] LoginUrlError<Infallible>),
+    #[error("Tunnel disconnected: {0}")]
+    Disconnected(String),
+    #[error("Tunnel didn't come up within {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Connecting,
+    Connected { ipv4: Ipv4Addr, ipv6: Ipv6Addr },
+    Disconnected(String),
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            api_url: Url::parse("wss://api.firezone.dev").expect("the default API URL is valid"),
+            firezone_id: None,
+            firezone_name: None,
+            mtu: DEFAULT_MTU,
+            upstream_dns: system_resolvers(),
+            max_partition_time: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl Client {
+    /// Connects to the production portal with `token`, see [`Client::connect_with`].
+    pub async fn connect(token: impl Into<String>) -> Result<Self, Error> {
+        Self::connect_with(token, ConnectOptions::default()).await
+    }
+
+    /// Signs in with `token` and waits until the tunnel is up, for at most [`ConnectOptions::connect_timeout`].
+    ///
+    /// Must be called within a Tokio runtime, the tunnel runs on it until the [`Client`] is dropped.
+    pub async fn connect_with(
+        token: impl Into<String>,
+        options: ConnectOptions,
+    ) -> Result<Self, Error> {
+        let token = SecretString::new(token.into());
+        let firezone_id = options
+            .firezone_id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let (private_key, public_key) = keypair();
+        let url = LoginUrl::client(
+            options.api_url,
+            &token,
+            firezone_id,
+            options.firezone_name,
+            public_key.to_bytes(),
+        )?;
+
+        let netstack = Netstack::new(options.mtu);
+        let (state_tx, state) = watch::channel(State::Connecting);
+        let resources = Arc::default();
+        let callbacks = CallbackHandler {
+            netstack: netstack.clone(),
+            state: Arc::new(state_tx),
+            resources: Arc::clone(&resources),
+        };
+
+        let resolved_addresses = phoenix_channel::lookup_host(&url).await?;
+        // Nothing is routed through the tunnel except for our own streams,
+        // so our sockets don't need to be excluded from it.
+        let portal = PhoenixChannel::connect_resolved(
+            Secret::new(url),
+            resolved_addresses,
+            get_user_agent(None, env!("CARGO_PKG_VERSION")),
+            "client",
+            (),
+            ExponentialBackoffBuilder::default()
+                .with_max_elapsed_time(options.max_partition_time)
+                .build(),
+            Arc::new(socket_factory::tcp),
+        );
+        let session = Session::connect(
+            ConnectArgs {
+                tcp_socket_factory: Arc::new(socket_factory::tcp),
+                udp_socket_factory: Arc::new(socket_factory::udp),
+                private_key,
+                callbacks,
+                mtu: options.mtu,
+            },
+            portal,
+            tokio::runtime::Handle::current(),
+        );
+        session.set_tun(Box::new(netstack.make_tun()));
+        session.set_dns(options.upstream_dns);
+
+        let client = Self {
+            session,
+            netstack,
+            state,
+            resources,
+        };
+        // Dropping the client on timeout disconnects the session, so it stops trying to reach the portal.
+        tokio::time::timeout(options.connect_timeout, client.wait_for_tunnel())
+            .await
+            .map_err(|_| Error::Timeout(options.connect_timeout))??;
+
+        Ok(client)
+    }
+
+    /// Opens a TCP connection to `addr` through the tunnel, e.g. `db.corp:5432` or `10.0.0.5:22`.
+    ///
+    /// Names resolve through connlib, so the names of DNS resources work.
+    /// If a name resolves to several IPs, we try them in order.
+    pub async fn dial_tcp(&self, addr: &str) -> Result<TcpStream, Error> {
+        self.check()?;
+
+        Ok(dial_tcp(&self.netstack, addr).await?)
+    }
+
+    /// Accepts TCP connections to `port` on our tunnel IPs, see [`Client::tunnel_ips`].
+    ///
+    /// Only peers behind a Gateway can reach us, and only if the Gateway forwards their traffic to us.
+    pub fn listen_tcp(&self, port: u16) -> Result<TcpListener, Error> {
+        self.check()?;
+
+        Ok(self.netstack.listen(port)?)
+    }
+
+    /// The IPs the portal assigned to this Client.
+    pub fn tunnel_ips(&self) -> Option<(Ipv4Addr, Ipv6Addr)> {
+        match &*self.state.borrow() {
+            State::Connected { ipv4, ipv6 } => Some((*ipv4, *ipv6)),
+            State::Connecting | State::Disconnected(_) => None,
+        }
+    }
+
+    /// The resources we currently have access to.
+    pub fn resources(&self) -> Vec<ResourceDescription> {
+        self.resources
+            .lock()
+            .expect("we never panic while holding the lock")
+            .clone()
+    }
+
+    async fn wait_for_tunnel(&self) -> Result<(), Error> {
+        self.state
+            .clone()
+            .wait_for(|state| !matches!(state, State::Connecting))
+            .await
+            .map_err(|_| Error::Disconnected("connlib stopped".to_owned()))?;
+
+        self.check()
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        match &*self.state.borrow() {
+            State::Connecting | State::Connected { .. } => Ok(()),
+            State::Disconnected(error) => Err(Error::Disconnected(error.clone())),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.session.clone().disconnect();
+    }
+}
+
+#[derive(Clone)]
+struct CallbackHandler {
+    netstack: Netstack,
+    state: Arc<watch::Sender<State>>,
+    resources: Arc<Mutex<Vec<ResourceDescription>>>,
+}
+
+impl Callbacks for CallbackHandler {
+    fn on_set_interface_config(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr, dns: Vec<IpAddr>) {
+        self.netstack.set_ips(ipv4, ipv6);
+        self.netstack.set_dns(dns);
+        self.state.send_replace(State::Connected { ipv4, ipv6 });
+    }
+
+    fn on_update_resources(&self, resources: Vec<ResourceDescription>) {
+        *self
+            .resources
+            .lock()
+            .expect("we never panic while holding the lock") = resources;
+    }
+
+    // Unlike the default, this must not exit the process that embeds us.
+    fn on_disconnect(&self, error: &DisconnectError) {
+        tracing::error!(?error, "Tunnel disconnected");
+        self.state
+            .send_replace(State::Disconnected(error.to_string()));
+    }
+}
+
+async fn dial_tcp(netstack: &Netstack, addr: &str) -> io::Result<TcpStream> {
+    let (host, port) = split_host_port(addr)?;
+    let ips = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => netstack.lookup(host).await?,
+    };
+
+    let mut last_error = None;
+    for ip in ips {
+        match netstack.connect(SocketAddr::new(ip, port)).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => {
+                tracing::debug!(%host, %ip, %error, "Failed to connect");
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("`{host}` has no IPs"))))
+}
+
+/// Splits `host:port` or `[ipv6]:port`
+fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("`{addr}` should look like `host:port`"),
+        )
+    };
+
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((host, port))
+}
+
+#[cfg(unix)]
+fn system_resolvers() -> Vec<IpAddr> {
+    let parsed = std::fs::read_to_string("/etc/resolv.conf")
+        .map_err(|e| e.to_string())
+        .and_then(|s| resolv_conf::Config::parse(s).map_err(|e| e.to_string()));
+
+    match parsed {
+        Ok(config) => config
+            .nameservers
+            .into_iter()
+            .map(|addr| addr.into())
+            .collect(),
+        Err(error) => {
+            tracing::warn!(%error, "Failed to read `/etc/resolv.conf`");
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn system_resolvers() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netstack::NetstackTun;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tun::Tun as _;
+
+    #[tokio::test]
+    async fn dials_through_netstack() {
+        let netstack = Netstack::new(DEFAULT_MTU);
+        netstack.set_ips(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 1),
+        );
+        // Stands in for a Resource, as if connlib and a Gateway passed the packets between the two stacks.
+        let resource = Netstack::new(DEFAULT_MTU);
+        resource.set_ips(
+            Ipv4Addr::new(100, 64, 0, 2),
+            Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 2),
+        );
+        tokio::spawn(pass_packets(netstack.make_tun(), resource.make_tun()));
+        tokio::spawn(pass_packets(resource.make_tun(), netstack.make_tun()));
+        let mut listener = resource.listen(5432).unwrap();
+
+        let mut stream = dial_tcp(&netstack, "100.64.0.2:5432").await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let (mut accepted, peer) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"hello");
+        assert_eq!(peer.ip(), IpAddr::from(Ipv4Addr::new(100, 64, 0, 1)));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_unreachable_portal() {
+        let options = ConnectOptions {
+            api_url: Url::parse("ws://127.0.0.1:1").unwrap(),
+            upstream_dns: Vec::new(),
+            connect_timeout: Duration::from_millis(100),
+            ..ConnectOptions::default()
+        };
+
+        let error = Client::connect_with("token", options).await.err().unwrap();
+
+        assert!(matches!(error, Error::Timeout(_)), "{error}");
+    }
+
+    async fn pass_packets(mut from: NetstackTun, to: NetstackTun) {
+        let mut buf = [0; DEFAULT_MTU];
+
+        loop {
+            let len = std::future::poll_fn(|cx| from.poll_read(&mut buf, cx))
+                .await
+                .unwrap();
+            to.write4(&buf[..len]).unwrap();
+        }
+    }
+
+    #[test]
+    fn host_port() {
+        assert_eq!(split_host_port("db.corp:5432").unwrap(), ("db.corp", 5432));
+        assert_eq!(split_host_port("10.0.0.5:22").unwrap(), ("10.0.0.5", 22));
+        assert_eq!(split_host_port("[fd00::1]:443").unwrap(), ("fd00::1", 443));
+
+        assert!(split_host_port("db.corp").is_err());
+        assert!(split_host_port("db.corp:http").is_err());
+        assert!(split_host_port(":80").is_err());
+    }
+}
@@ -21,6 +21,12 @@ pub trait Callbacks: Clone + Send + Sync {
     /// or if all Resources for a user are disabled by policy.
     fn on_update_resources(&self, _: Vec<ResourceDescription>) {}
 
+    /// Called as soon as we lose the connection to the portal, and again once we joined it again.
+    ///
+    /// Until then, connections to Gateways and already-known Resources keep working,
+    /// but we can't connect to new Gateways or get updates to the Resource list.
+    fn on_control_plane_degraded(&self, _degraded: bool) {}
+
     /// Called when the tunnel is disconnected.
     ///
     /// If the tunnel disconnected due to a fatal error, `error` is the error
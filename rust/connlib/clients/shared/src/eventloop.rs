@@ -7,27 +7,45 @@ use crate::{
     PHOENIX_TOPIC,
 };
 use anyhow::Result;
-use connlib_shared::messages::{
-    ClientPayload, ConnectionAccepted, GatewayResponse, RelaysPresence, RequestConnection,
-    ResourceAccepted, ResourceId, ReuseConnection,
+use chrono::{DateTime, Utc};
+use connlib_shared::control_plane::ControlPlane;
+use connlib_shared::{
+    messages::{
+        ClientPayload, ConnectionAccepted, GatewayResponse, RelaysPresence, RequestConnection,
+        ResourceAccepted, ResourceId, ReuseConnection,
+    },
+    LoginUrl,
 };
-use firezone_tunnel::ClientTunnel;
-use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixChannel};
+use firezone_tunnel::{ClientStats, ClientTunnel};
+use phoenix_channel::{ErrorReply, OutboundRequestId};
+use secrecy::Secret;
 use std::{
     collections::{BTreeMap, BTreeSet},
+    future::Future as _,
     net::IpAddr,
+    pin::Pin,
     task::{Context, Poll},
 };
 use tun::Tun;
 
-pub struct Eventloop<C: Callbacks> {
+pub struct Eventloop<C: Callbacks, P> {
     tunnel: ClientTunnel,
     callbacks: C,
 
-    portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+    portal: P,
     rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
 
     connection_intents: SentConnectionIntents,
+    /// The hosts from [`Command::SetKnownHosts`], without the portal's host.
+    known_hosts: BTreeMap<String, Vec<IpAddr>>,
+    /// True while we can't reach the portal but keep the tunnel up.
+    control_plane_degraded: bool,
+    /// When the portal's authorizations of our flows expire, by Resource.
+    ///
+    /// Authorizations without an expiry are stored as [`DateTime::<Utc>::MAX_UTC`].
+    authorizations: BTreeMap<ResourceId, DateTime<Utc>>,
+    /// Fires when the last authorization expires while we keep reconnecting to the portal after its backoff ran out.
+    partition_deadline: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
 /// Commands that can be sent to the [`Eventloop`].
@@ -37,13 +55,17 @@ pub enum Command {
     SetDns(Vec<IpAddr>),
     SetTun(Box<dyn Tun>),
     SetDisabledResources(BTreeSet<ResourceId>),
+    SetKnownHosts(BTreeMap<String, Vec<IpAddr>>),
+    /// The new URL and the addresses its host resolves to.
+    SetLoginUrl(Secret<LoginUrl>, Vec<IpAddr>),
+    Stats(tokio::sync::oneshot::Sender<ClientStats>),
 }
 
-impl<C: Callbacks> Eventloop<C> {
+impl<C: Callbacks, P> Eventloop<C, P> {
     pub(crate) fn new(
         tunnel: ClientTunnel,
         callbacks: C,
-        portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+        portal: P,
         rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
     ) -> Self {
         Self {
@@ -52,13 +74,18 @@ impl<C: Callbacks> Eventloop<C> {
             connection_intents: SentConnectionIntents::default(),
             rx,
             callbacks,
+            known_hosts: BTreeMap::default(),
+            control_plane_degraded: false,
+            authorizations: BTreeMap::default(),
+            partition_deadline: None,
         }
     }
 }
 
-impl<C> Eventloop<C>
+impl<C, P> Eventloop<C, P>
 where
     C: Callbacks + 'static,
+    P: ControlPlane<EgressMessages, IngressMessages, ReplyMessages>,
 {
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), phoenix_channel::Error>> {
         loop {
@@ -73,10 +100,25 @@ where
                     self.tunnel.set_disabled_resources(resources);
                     continue;
                 }
+                Poll::Ready(Some(Command::SetKnownHosts(known_hosts))) => {
+                    self.known_hosts = known_hosts;
+                    self.update_known_hosts();
+                    continue;
+                }
+                Poll::Ready(Some(Command::SetLoginUrl(url, resolved_addresses))) => {
+                    self.portal.set_url(url, resolved_addresses);
+                    self.update_known_hosts();
+
+                    continue;
+                }
                 Poll::Ready(Some(Command::SetTun(tun))) => {
                     self.tunnel.set_tun(tun);
                     continue;
                 }
+                Poll::Ready(Some(Command::Stats(tx))) => {
+                    let _ = tx.send(self.tunnel.stats());
+                    continue;
+                }
                 Poll::Ready(Some(Command::Reset)) => {
                     self.portal.reconnect();
                     self.tunnel.reset();
@@ -98,11 +140,37 @@ where
                 Poll::Pending => {}
             }
 
-            match self.portal.poll(cx)? {
-                Poll::Ready(event) => {
+            if let Some(deadline) = self.partition_deadline.as_mut() {
+                if deadline.as_mut().poll(cx).is_ready() {
+                    tracing::warn!(
+                        "All authorizations expired before we could reach the portal again"
+                    );
+                    return Poll::Ready(Err(phoenix_channel::Error::MaxRetriesReached));
+                }
+            }
+
+            match self.portal.poll(cx) {
+                Poll::Ready(Ok(event)) => {
                     self.handle_portal_event(event);
                     continue;
                 }
+                Poll::Ready(Err(phoenix_channel::Error::MaxRetriesReached)) => {
+                    let Some(deadline) = self.last_authorization_expiry() else {
+                        return Poll::Ready(Err(phoenix_channel::Error::MaxRetriesReached));
+                    };
+
+                    if self.partition_deadline.is_none() {
+                        tracing::warn!(%deadline, "Portal is still unreachable, keeping connections to Gateways until their authorizations expire");
+                    }
+
+                    let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+                    self.partition_deadline = Some(Box::pin(tokio::time::sleep(remaining)));
+                    self.set_control_plane_degraded(true);
+                    self.portal.reconnect();
+
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                 Poll::Pending => {}
             }
 
@@ -110,6 +178,32 @@ where
         }
     }
 
+    fn set_control_plane_degraded(&mut self, degraded: bool) {
+        if self.control_plane_degraded == degraded {
+            return;
+        }
+
+        self.control_plane_degraded = degraded;
+        self.callbacks.on_control_plane_degraded(degraded);
+    }
+
+    /// Forgets expired authorizations and returns when the last one expires, if any are left.
+    fn last_authorization_expiry(&mut self) -> Option<DateTime<Utc>> {
+        let now = Utc::now();
+        self.authorizations
+            .retain(|_, expires_at| *expires_at > now);
+
+        self.authorizations.values().max().copied()
+    }
+
+    fn update_known_hosts(&mut self) {
+        let mut known_hosts = self.known_hosts.clone();
+
+        // Always keep resolving the portal, so we can reconnect without DNS.
+        known_hosts.extend(self.portal.server_host());
+        self.tunnel.set_known_hosts(known_hosts);
+    }
+
     fn handle_tunnel_event(&mut self, event: firezone_tunnel::ClientEvent) {
         match event {
             firezone_tunnel::ClientEvent::AddedIceCandidates {
@@ -118,13 +212,12 @@ where
             } => {
                 tracing::debug!(%gateway, ?candidates, "Sending new ICE candidates to gateway");
 
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::BroadcastIceCandidates(GatewaysIceCandidates {
+                self.portal.send(EgressMessages::BroadcastIceCandidates(
+                    GatewaysIceCandidates {
                         gateway_ids: vec![gateway],
                         candidates,
-                    }),
-                );
+                    },
+                ));
             }
             firezone_tunnel::ClientEvent::RemovedIceCandidates {
                 conn_id: gateway,
@@ -132,26 +225,24 @@ where
             } => {
                 tracing::debug!(%gateway, ?candidates, "Sending invalidated ICE candidates to gateway");
 
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::BroadcastInvalidatedIceCandidates(GatewaysIceCandidates {
-                        gateway_ids: vec![gateway],
-                        candidates,
-                    }),
-                );
+                self.portal
+                    .send(EgressMessages::BroadcastInvalidatedIceCandidates(
+                        GatewaysIceCandidates {
+                            gateway_ids: vec![gateway],
+                            candidates,
+                        },
+                    ));
             }
             firezone_tunnel::ClientEvent::ConnectionIntent {
                 connected_gateway_ids,
                 resource,
-                ..
+                gateway_metrics,
             } => {
-                let id = self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::PrepareConnection {
-                        resource_id: resource,
-                        connected_gateway_ids,
-                    },
-                );
+                let id = self.portal.send(EgressMessages::PrepareConnection {
+                    resource_id: resource,
+                    connected_gateway_ids,
+                    gateway_metrics,
+                });
                 self.connection_intents.register_new_intent(id, resource);
             }
             firezone_tunnel::ClientEvent::RequestAccess {
@@ -159,14 +250,12 @@ where
                 gateway_id,
                 maybe_domain,
             } => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::ReuseConnection(ReuseConnection {
+                self.portal
+                    .send(EgressMessages::ReuseConnection(ReuseConnection {
                         resource_id,
                         gateway_id,
                         payload: maybe_domain,
-                    }),
-                );
+                    }));
             }
             firezone_tunnel::ClientEvent::ResourcesChanged { resources } => {
                 self.callbacks.on_update_resources(resources)
@@ -187,9 +276,8 @@ where
                 resource_id,
                 maybe_domain,
             } => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::RequestConnection(RequestConnection {
+                self.portal
+                    .send(EgressMessages::RequestConnection(RequestConnection {
                         gateway_id,
                         resource_id,
                         client_preshared_key: preshared_key,
@@ -197,8 +285,7 @@ where
                             ice_parameters: offer,
                             domain: maybe_domain,
                         },
-                    }),
-                );
+                    }));
             }
         }
     }
@@ -218,6 +305,21 @@ where
                 self.handle_portal_error_reply(res, topic, req_id);
             }
             phoenix_channel::Event::HeartbeatSent => {}
+            phoenix_channel::Event::Reconnecting => {
+                // Connections to Gateways don't need the portal, so keep them while we reconnect.
+                // If that takes longer than the max partition time, we keep reconnecting until the last authorization expires.
+                if !self.control_plane_degraded {
+                    tracing::warn!("Lost the connection to the portal, keeping existing connections and reconnecting in the background");
+                }
+                self.set_control_plane_degraded(true);
+            }
+            phoenix_channel::Event::JoinedRoom { topic } if topic == PHOENIX_TOPIC => {
+                if self.control_plane_degraded {
+                    tracing::info!("Reconnected to the portal");
+                }
+                self.partition_deadline = None;
+                self.set_control_plane_degraded(false);
+            }
             phoenix_channel::Event::JoinedRoom { .. } => {}
             phoenix_channel::Event::Closed => {
                 unimplemented!("Client never actively closes the portal connection")
@@ -253,6 +355,7 @@ where
                 self.tunnel.add_resource(resource);
             }
             IngressMessages::ResourceDeleted(resource) => {
+                self.authorizations.remove(&resource);
                 self.tunnel.remove_resource(resource);
             }
             IngressMessages::RelaysPresence(RelaysPresence {
@@ -299,6 +402,7 @@ where
                 gateway_id,
                 resource_id,
                 site_id,
+                expires_at,
                 ..
             }) => {
                 let should_accept = self
@@ -310,6 +414,9 @@ where
                     return;
                 }
 
+                self.authorizations
+                    .insert(resource_id, expires_at.unwrap_or(DateTime::<Utc>::MAX_UTC));
+
                 match self
                     .tunnel
                     .on_routing_details(resource_id, gateway_id, site_id)
@@ -344,7 +451,7 @@ where
                 tracing::debug!(%req_id, "Functionality is disabled");
             }
             ErrorReply::UnmatchedTopic => {
-                self.portal.join(topic, ());
+                self.portal.rejoin(topic);
             }
             reason @ (ErrorReply::InvalidVersion | ErrorReply::NotFound | ErrorReply::Other) => {
                 tracing::debug!(%req_id, %reason, "Request failed");
@@ -402,6 +509,130 @@ impl SentConnectionIntents {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use backoff::ExponentialBackoff;
+    use connlib_shared::StaticSecret;
+    use phoenix_channel::PhoenixChannel;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn keeps_reconnecting_until_the_last_authorization_expires() {
+        let callbacks = DegradedCallbacks::default();
+        let (_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut eventloop = Eventloop::new(
+            unreachable_tunnel(),
+            callbacks.clone(),
+            unreachable_portal(),
+            rx,
+        );
+
+        let resource = ResourceId::random();
+        eventloop
+            .connection_intents
+            .register_new_intent(OutboundRequestId::for_test(1), resource);
+        eventloop.handle_portal_success_reply(
+            ReplyMessages::ConnectionDetails(ConnectionDetails {
+                resource_id: resource,
+                gateway_id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+                gateway_remote_ip: "172.28.0.1".parse().unwrap(),
+                site_id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
+                expires_at: Some(Utc::now() + chrono::Duration::milliseconds(500)),
+            }),
+            OutboundRequestId::for_test(1),
+        );
+
+        let still_running = tokio::time::timeout(
+            Duration::from_millis(200),
+            std::future::poll_fn(|cx| eventloop.poll(cx)),
+        )
+        .await;
+        assert!(
+            still_running.is_err(),
+            "Eventloop should keep running while an authorization is valid"
+        );
+        assert!(callbacks.degraded.load(Ordering::SeqCst));
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            std::future::poll_fn(|cx| eventloop.poll(cx)),
+        )
+        .await
+        .expect("Eventloop should stop once the authorization expired");
+        assert!(matches!(
+            result,
+            Err(phoenix_channel::Error::MaxRetriesReached)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_retries_without_authorizations() {
+        let (_tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut eventloop = Eventloop::new(
+            unreachable_tunnel(),
+            DegradedCallbacks::default(),
+            unreachable_portal(),
+            rx,
+        );
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            std::future::poll_fn(|cx| eventloop.poll(cx)),
+        )
+        .await
+        .expect("Eventloop should stop once the backoff ran out");
+        assert!(matches!(
+            result,
+            Err(phoenix_channel::Error::MaxRetriesReached)
+        ));
+    }
+
+    #[derive(Clone, Default)]
+    struct DegradedCallbacks {
+        degraded: Arc<AtomicBool>,
+    }
+
+    impl Callbacks for DegradedCallbacks {
+        fn on_control_plane_degraded(&self, degraded: bool) {
+            self.degraded.store(degraded, Ordering::SeqCst);
+        }
+    }
+
+    fn unreachable_tunnel() -> ClientTunnel {
+        ClientTunnel::new(
+            StaticSecret::from([1; 32]),
+            Arc::new(socket_factory::tcp),
+            Arc::new(socket_factory::udp),
+            BTreeMap::default(),
+            1280,
+        )
+    }
+
+    /// A portal connection that fails right away and gives up after a few retries.
+    fn unreachable_portal() -> PhoenixChannel<(), IngressMessages, ReplyMessages> {
+        let url = LoginUrl::client(
+            "ws://127.0.0.1:1",
+            &secrecy::SecretString::from("token".to_owned()),
+            "device".to_owned(),
+            Some("name".to_owned()),
+            [0; 32],
+        )
+        .unwrap();
+
+        PhoenixChannel::connect(
+            Secret::new(url),
+            "test".to_owned(),
+            PHOENIX_TOPIC,
+            (),
+            ExponentialBackoff {
+                initial_interval: Duration::from_millis(10),
+                max_elapsed_time: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            Arc::new(socket_factory::tcp),
+        )
+        .unwrap()
+    }
 
     #[test]
     fn discards_old_connection_intent() {
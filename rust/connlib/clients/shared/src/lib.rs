@@ -4,13 +4,14 @@ pub use callbacks::{Callbacks, DisconnectError};
 pub use connlib_shared::messages::client::ResourceDescription;
 pub use connlib_shared::{LoginUrl, LoginUrlError, StaticSecret};
 pub use eventloop::Eventloop;
-pub use firezone_tunnel::keypair;
+pub use firezone_tunnel::{keypair, ClientStats, GatewayStats, InterfaceStats, ResourceTraffic};
+pub use static_control_plane::StaticControlPlane;
 
+use connlib_shared::control_plane::ControlPlane;
 use connlib_shared::messages::ResourceId;
 use eventloop::Command;
 use firezone_tunnel::ClientTunnel;
-use messages::{IngressMessages, ReplyMessages};
-use phoenix_channel::PhoenixChannel;
+use messages::{EgressMessages, IngressMessages, ReplyMessages};
 use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::collections::{BTreeMap, BTreeSet};
 use std::net::IpAddr;
@@ -23,6 +24,7 @@ mod callbacks;
 mod eventloop;
 mod messages;
 mod serde_routelist;
+mod static_control_plane;
 
 const PHOENIX_TOPIC: &str = "client";
 
@@ -40,17 +42,21 @@ pub struct ConnectArgs<CB> {
     pub udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
     pub private_key: StaticSecret,
     pub callbacks: CB,
+    /// The MTU of the TUN device.
+    pub mtu: usize,
 }
 
 impl Session {
     /// Creates a new [`Session`].
     ///
     /// This connects to the portal a specified using [`LoginUrl`] and creates a wireguard tunnel using the provided private key.
-    pub fn connect<CB: Callbacks + 'static>(
-        args: ConnectArgs<CB>,
-        portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
-        handle: tokio::runtime::Handle,
-    ) -> Self {
+    ///
+    /// `portal` is usually a [`PhoenixChannel`](phoenix_channel::PhoenixChannel), or a [`StaticControlPlane`] to run without a portal.
+    pub fn connect<CB, P>(args: ConnectArgs<CB>, portal: P, handle: tokio::runtime::Handle) -> Self
+    where
+        CB: Callbacks + 'static,
+        P: ControlPlane<EgressMessages, IngressMessages, ReplyMessages> + Send + 'static,
+    {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
         let callbacks = args.callbacks.clone();
@@ -84,6 +90,20 @@ impl Session {
         let _ = self.channel.send(Command::Reset);
     }
 
+    /// Reconnects to the portal with a new [`LoginUrl`], e.g. after the token or the API URL changed.
+    ///
+    /// Unlike [`Session::reset`], this keeps the tunnel and its connections to Gateways.
+    /// After re-joining, the portal sends us our resources again and we only drop connections to resources that we lost access to.
+    ///
+    /// `resolved_addresses` are the addresses of the URL's host, see [`phoenix_channel::lookup_host`].
+    /// We can't resolve it ourselves because the system's resolver may be our own stub resolver.
+    pub fn set_login_url(&self, url: LoginUrl, resolved_addresses: Vec<IpAddr>) {
+        let _ = self.channel.send(Command::SetLoginUrl(
+            secrecy::Secret::new(url),
+            resolved_addresses,
+        ));
+    }
+
     /// Sets a new set of upstream DNS servers for this [`Session`].
     ///
     /// Changing the DNS servers clears all cached DNS requests which may be disruptive to the UX.
@@ -100,6 +120,23 @@ impl Session {
             .send(Command::SetDisabledResources(disabled_resources));
     }
 
+    /// Sets extra hosts that connlib resolves itself, like `/etc/hosts` does.
+    ///
+    /// Replaces the hosts from the previous call. The portal's host is always known.
+    pub fn set_known_hosts(&self, known_hosts: BTreeMap<String, Vec<IpAddr>>) {
+        let _ = self.channel.send(Command::SetKnownHosts(known_hosts));
+    }
+
+    /// Takes a snapshot of the tunnel's state and statistics.
+    ///
+    /// Returns `None` if the session has already stopped.
+    pub async fn stats(&self) -> Option<ClientStats> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.channel.send(Command::Stats(tx)).ok()?;
+
+        rx.await.ok()
+    }
+
     /// Sets a new [`Tun`] device handle.
     pub fn set_tun(&self, new_tun: Box<dyn Tun>) {
         let _ = self.channel.send(Command::SetTun(new_tun));
@@ -116,26 +153,29 @@ impl Session {
 /// Connects to the portal and starts a tunnel.
 ///
 /// When this function exits, the tunnel failed unrecoverably and you need to call it again.
-async fn connect<CB>(
+async fn connect<CB, P>(
     args: ConnectArgs<CB>,
-    portal: PhoenixChannel<(), IngressMessages, ReplyMessages>,
+    portal: P,
     rx: UnboundedReceiver<Command>,
 ) -> Result<(), DisconnectError>
 where
     CB: Callbacks + 'static,
+    P: ControlPlane<EgressMessages, IngressMessages, ReplyMessages>,
 {
     let ConnectArgs {
         private_key,
         callbacks,
         udp_socket_factory,
         tcp_socket_factory,
+        mtu,
     } = args;
 
     let tunnel = ClientTunnel::new(
         private_key,
         tcp_socket_factory,
         udp_socket_factory,
-        BTreeMap::from([(portal.server_host().to_owned(), portal.resolved_addresses())]),
+        BTreeMap::from_iter(portal.server_host()),
+        mtu,
     );
 
     let mut eventloop = Eventloop::new(tunnel, callbacks, portal, rx);
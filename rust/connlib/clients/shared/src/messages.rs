@@ -1,5 +1,6 @@
+use chrono::{serde::ts_seconds_option, DateTime, Utc};
 use connlib_shared::messages::{
-    client::{ResourceDescription, SiteId},
+    client::{GatewayMetrics, ResourceDescription, SiteId},
     GatewayId, GatewayResponse, Interface, Key, Relay, RelaysPresence, RequestConnection,
     ResourceId, ReuseConnection,
 };
@@ -27,6 +28,9 @@ pub struct ConnectionDetails {
     pub gateway_remote_ip: IpAddr,
     #[serde(rename = "gateway_group_id")]
     pub site_id: SiteId,
+    /// When the portal's authorization of this flow expires, `None` if it doesn't.
+    #[serde(default, with = "ts_seconds_option")]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -89,6 +93,8 @@ pub enum EgressMessages {
     PrepareConnection {
         resource_id: ResourceId,
         connected_gateway_ids: BTreeSet<GatewayId>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        gateway_metrics: Vec<GatewayMetrics>,
     },
     RequestConnection(RequestConnection),
     ReuseConnection(ReuseConnection),
@@ -101,7 +107,6 @@ pub enum EgressMessages {
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::DateTime;
     use connlib_shared::messages::{
         client::{ResourceDescriptionCidr, ResourceDescriptionDns, Site},
         DnsServer, IpDnsServer, Turn,
@@ -239,6 +244,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                     ResourceDescription::Cidr(ResourceDescriptionCidr {
                         id: "73037362-715d-4a83-a749-f18eadd970e7".parse().unwrap(),
@@ -249,6 +255,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
@@ -259,6 +266,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dce".parse().unwrap(),
@@ -269,6 +277,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                 ],
                 relays: vec![],
@@ -347,6 +356,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
@@ -357,6 +367,7 @@ mod test {
                             name: "test".to_string(),
                             id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
                         }],
+                        health_check: None,
                     }),
                 ],
                 relays: vec![],
@@ -546,6 +557,7 @@ mod test {
             EgressMessages::PrepareConnection {
                 resource_id: "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".parse().unwrap(),
                 connected_gateway_ids: BTreeSet::new(),
+                gateway_metrics: Vec::new(),
             },
             None,
         );
@@ -564,6 +576,47 @@ mod test {
         assert_eq!(m, egress_message);
     }
 
+    #[test]
+    fn prepare_connection_with_gateway_metrics() {
+        let m = PhoenixMessage::<EgressMessages, ()>::new_message(
+            "client",
+            EgressMessages::PrepareConnection {
+                resource_id: "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".parse().unwrap(),
+                connected_gateway_ids: BTreeSet::new(),
+                gateway_metrics: vec![GatewayMetrics {
+                    gateway_id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+                    site_id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
+                    rtt_ms: Some(42),
+                    loss: 0.5,
+                    degraded: true,
+                }],
+            },
+            None,
+        );
+        let message = r#"
+            {
+                "event": "prepare_connection",
+                "payload": {
+                    "resource_id": "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3",
+                    "connected_gateway_ids": [],
+                    "gateway_metrics": [
+                        {
+                            "gateway_id": "73037362-715d-4a83-a749-f18eadd970e6",
+                            "gateway_group_id": "bf56f32d-7b2c-4f5d-a784-788977d014a4",
+                            "rtt_ms": 42,
+                            "loss": 0.5,
+                            "degraded": true
+                        }
+                    ]
+                },
+                "ref":null,
+                "topic": "client"
+            }
+        "#;
+        let egress_message = serde_json::from_str(message).unwrap();
+        assert_eq!(m, egress_message);
+    }
+
     #[test]
     fn connection_details_reply() {
         let m = PhoenixMessage::<EgressMessages, ReplyMessages>::new_ok_reply(
@@ -573,6 +626,7 @@ mod test {
                 gateway_remote_ip: "172.28.0.1".parse().unwrap(),
                 resource_id: "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".parse().unwrap(),
                 site_id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
+                expires_at: None,
             }),
             None,
         );
@@ -595,6 +649,39 @@ mod test {
         assert_eq!(m, reply_message);
     }
 
+    #[test]
+    fn connection_details_reply_with_expiry() {
+        let m = PhoenixMessage::<EgressMessages, ReplyMessages>::new_ok_reply(
+            "client",
+            ReplyMessages::ConnectionDetails(ConnectionDetails {
+                gateway_id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+                gateway_remote_ip: "172.28.0.1".parse().unwrap(),
+                resource_id: "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".parse().unwrap(),
+                site_id: "bf56f32d-7b2c-4f5d-a784-788977d014a4".parse().unwrap(),
+                expires_at: Some(DateTime::from_timestamp(1719367575, 0).unwrap()),
+            }),
+            None,
+        );
+        let message = r#"
+            {
+                "ref":null,
+                "topic":"client",
+                "event": "phx_reply",
+                "payload": {
+                    "response": {
+                        "resource_id": "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3",
+                        "gateway_id": "73037362-715d-4a83-a749-f18eadd970e6",
+                        "gateway_remote_ip": "172.28.0.1",
+                        "gateway_group_id": "bf56f32d-7b2c-4f5d-a784-788977d014a4",
+                        "expires_at": 1719367575
+                    },
+                    "status":"ok"
+                }
+            }"#;
+        let reply_message = serde_json::from_str(message).unwrap();
+        assert_eq!(m, reply_message);
+    }
+
     #[test]
     fn relays_presence() {
         let message = r#"
@@ -0,0 +1,535 @@
+//! A [`ControlPlane`] that serves a static [`Deployment`] instead of asking the portal
+//!
+//! We answer connection requests from the deployment and forward the rest of the signalling
+//! to the Gateways via [`LocalSignalling`], so they need to run in the same process with a static control plane of their own.
+
+use crate::{
+    messages::{
+        Connect, ConnectionDetails, EgressMessages, GatewayIceCandidates, GatewaysIceCandidates,
+        IngressMessages, InitClient, ReplyMessages,
+    },
+    PHOENIX_TOPIC,
+};
+use connlib_shared::{
+    control_plane::{ControlPlane, Deployment, LocalSignalling, Signal, SignallingPeer},
+    messages::{
+        client::ResourceDescription, ClientId, ConnectionAccepted, GatewayId, GatewayResponse,
+        Interface, RequestConnection, ResourceId, ReuseConnection,
+    },
+    LoginUrl,
+};
+use phoenix_channel::{ErrorReply, Event, OutboundRequestId};
+use secrecy::Secret;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io,
+    net::IpAddr,
+    task::{Context, Poll, Waker},
+};
+
+/// How often the Gateways expect WireGuard keepalives, the same as the portal tells us.
+const PERSISTENT_KEEPALIVE: u64 = 25;
+
+pub struct StaticControlPlane {
+    id: ClientId,
+    deployment: Deployment<ResourceDescription>,
+    signalling: LocalSignalling,
+
+    next_request_id: u64,
+    pending_events: VecDeque<Event<IngressMessages, ReplyMessages>>,
+    waker: Option<Waker>,
+}
+
+impl StaticControlPlane {
+    /// Serves the deployment to the Client with the given ID.
+    pub fn new(
+        id: ClientId,
+        deployment: Deployment<ResourceDescription>,
+        signalling: LocalSignalling,
+    ) -> io::Result<Self> {
+        if deployment.client(id).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Client {id} is not part of the deployment"),
+            ));
+        }
+
+        let mut this = Self {
+            id,
+            deployment,
+            signalling,
+            next_request_id: 0,
+            pending_events: VecDeque::default(),
+            waker: None,
+        };
+        this.join();
+
+        Ok(this)
+    }
+
+    /// Does what the portal does when we join our topic
+    fn join(&mut self) {
+        let client = self
+            .deployment
+            .client(self.id)
+            .expect("we checked that we are part of the deployment");
+        let interface = Interface {
+            ipv4: client.ipv4,
+            ipv6: client.ipv6,
+            upstream_dns: client.upstream_dns.clone(),
+        };
+        let init = IngressMessages::Init(InitClient {
+            interface,
+            resources: self.deployment.resources.clone(),
+            relays: self.deployment.relays.clone(),
+        });
+
+        self.push(Event::JoinedRoom {
+            topic: PHOENIX_TOPIC.to_owned(),
+        });
+        self.push(Event::InboundMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            msg: init,
+        });
+    }
+
+    fn push(&mut self, event: Event<IngressMessages, ReplyMessages>) {
+        self.pending_events.push_back(event);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn reply(&mut self, req_id: u64, res: Result<ReplyMessages, ErrorReply>) {
+        let topic = PHOENIX_TOPIC.to_owned();
+        let req_id = OutboundRequestId::new(req_id);
+
+        self.push(match res {
+            Ok(res) => Event::SuccessResponse { topic, req_id, res },
+            Err(res) => Event::ErrorResponse { topic, req_id, res },
+        });
+    }
+
+    /// Picks a Gateway in one of the Resource's Sites, preferring the ones we're already connected to
+    fn prepare_connection(
+        &self,
+        resource_id: ResourceId,
+        connected_gateway_ids: &BTreeSet<GatewayId>,
+    ) -> Result<ReplyMessages, ErrorReply> {
+        let resource = self
+            .deployment
+            .resources
+            .iter()
+            .find(|r| r.id() == resource_id)
+            .ok_or(ErrorReply::NotFound)?;
+        let sites = resource
+            .sites()
+            .into_iter()
+            .map(|site| site.id)
+            .collect::<BTreeSet<_>>();
+        let mut candidates = self
+            .deployment
+            .gateways
+            .iter()
+            .filter(|gateway| sites.contains(&gateway.site_id));
+        let gateway = candidates
+            .clone()
+            .find(|gateway| connected_gateway_ids.contains(&gateway.id))
+            .or_else(|| candidates.next())
+            .ok_or(ErrorReply::Offline)?;
+
+        Ok(ReplyMessages::ConnectionDetails(ConnectionDetails {
+            resource_id,
+            gateway_id: gateway.id,
+            gateway_remote_ip: gateway.address,
+            site_id: gateway.site_id,
+            expires_at: None,
+        }))
+    }
+
+    fn broadcast(&self, gateway_ids: Vec<GatewayId>, signal: impl Fn() -> Signal) {
+        for gateway_id in gateway_ids {
+            self.signalling
+                .send(SignallingPeer::Gateway(gateway_id), signal());
+        }
+    }
+
+    fn handle_signal(&mut self, signal: Signal) {
+        match signal {
+            Signal::ConnectionAccepted {
+                gateway_id,
+                request_id,
+                resource_id,
+                answer,
+            } => {
+                let Some(gateway) = self.deployment.gateway(gateway_id) else {
+                    tracing::debug!(%gateway_id, "Ignoring answer from unknown Gateway");
+                    return;
+                };
+                let res = ReplyMessages::Connect(Connect {
+                    gateway_payload: GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                        ice_parameters: answer,
+                        domain_response: None,
+                    }),
+                    resource_id,
+                    gateway_public_key: gateway.public_key,
+                    persistent_keepalive: PERSISTENT_KEEPALIVE,
+                });
+
+                self.reply(request_id, Ok(res));
+            }
+            Signal::IceCandidates {
+                from: SignallingPeer::Gateway(gateway_id),
+                candidates,
+            } => self.push(Event::InboundMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                msg: IngressMessages::IceCandidates(GatewayIceCandidates {
+                    gateway_id,
+                    candidates,
+                }),
+            }),
+            Signal::InvalidateIceCandidates {
+                from: SignallingPeer::Gateway(gateway_id),
+                candidates,
+            } => self.push(Event::InboundMessage {
+                topic: PHOENIX_TOPIC.to_owned(),
+                msg: IngressMessages::InvalidateIceCandidates(GatewayIceCandidates {
+                    gateway_id,
+                    candidates,
+                }),
+            }),
+            Signal::RequestConnection { .. }
+            | Signal::ReuseConnection { .. }
+            | Signal::IceCandidates {
+                from: SignallingPeer::Client(_),
+                ..
+            }
+            | Signal::InvalidateIceCandidates {
+                from: SignallingPeer::Client(_),
+                ..
+            } => {
+                tracing::debug!(?signal, "Ignoring signal meant for a Gateway");
+            }
+        }
+    }
+}
+
+impl ControlPlane<EgressMessages, IngressMessages, ReplyMessages> for StaticControlPlane {
+    fn send(&mut self, msg: EgressMessages) -> OutboundRequestId {
+        let req_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        match msg {
+            EgressMessages::PrepareConnection {
+                resource_id,
+                connected_gateway_ids,
+                ..
+            } => {
+                let res = self.prepare_connection(resource_id, &connected_gateway_ids);
+                self.reply(req_id, res);
+            }
+            EgressMessages::RequestConnection(RequestConnection {
+                gateway_id,
+                resource_id,
+                client_preshared_key,
+                client_payload,
+            }) => {
+                if self.deployment.gateway(gateway_id).is_none() {
+                    self.reply(req_id, Err(ErrorReply::NotFound));
+                    return OutboundRequestId::new(req_id);
+                }
+
+                self.signalling.send(
+                    SignallingPeer::Gateway(gateway_id),
+                    Signal::RequestConnection {
+                        client_id: self.id,
+                        request_id: req_id,
+                        resource_id,
+                        offer: client_payload.ice_parameters,
+                        preshared_key: client_preshared_key,
+                        domain: client_payload.domain,
+                    },
+                );
+            }
+            EgressMessages::ReuseConnection(ReuseConnection {
+                resource_id,
+                gateway_id,
+                payload,
+            }) => {
+                // The portal forwards these to the Gateway and the Client ignores the reply.
+                self.signalling.send(
+                    SignallingPeer::Gateway(gateway_id),
+                    Signal::ReuseConnection {
+                        client_id: self.id,
+                        resource_id,
+                        domain: payload,
+                    },
+                );
+            }
+            EgressMessages::BroadcastIceCandidates(GatewaysIceCandidates {
+                gateway_ids,
+                candidates,
+            }) => {
+                let from = SignallingPeer::Client(self.id);
+
+                self.broadcast(gateway_ids, || Signal::IceCandidates {
+                    from,
+                    candidates: Vec::from_iter(candidates.iter().cloned()),
+                });
+            }
+            EgressMessages::BroadcastInvalidatedIceCandidates(GatewaysIceCandidates {
+                gateway_ids,
+                candidates,
+            }) => {
+                let from = SignallingPeer::Client(self.id);
+
+                self.broadcast(gateway_ids, || Signal::InvalidateIceCandidates {
+                    from,
+                    candidates: Vec::from_iter(candidates.iter().cloned()),
+                });
+            }
+        }
+
+        OutboundRequestId::new(req_id)
+    }
+
+    fn rejoin(&mut self, topic: String) {
+        self.push(Event::JoinedRoom { topic });
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<IngressMessages, ReplyMessages>, phoenix_channel::Error>> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Poll::Ready(Ok(event));
+            }
+
+            match self
+                .signalling
+                .poll_recv(SignallingPeer::Client(self.id), cx)
+            {
+                Poll::Ready(signal) => {
+                    self.handle_signal(signal);
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
+            self.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+    }
+
+    fn reconnect(&mut self) {
+        self.join();
+    }
+
+    fn set_url(&mut self, _: Secret<LoginUrl>, _: Vec<IpAddr>) {
+        tracing::debug!("Ignoring new portal URL, we're not using a portal");
+    }
+
+    fn server_host(&self) -> Option<(String, Vec<IpAddr>)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::{Answer, ClientPayload, Key, Offer};
+    use std::net::Ipv4Addr;
+
+    const DEPLOYMENT: &str = r#"
+        [[clients]]
+        id = "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51"
+        public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        ipv4 = "100.64.0.1"
+        ipv6 = "fd00:2021:1111::1"
+
+        [[gateways]]
+        id = "2b1524e6-239e-4570-bc73-70a188e12101"
+        site_id = "bf56f32d-7b2c-4f5d-a784-788977d014a4"
+        public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        address = "192.168.1.10"
+        ipv4 = "100.64.0.2"
+        ipv6 = "fd00:2021:1111::2"
+
+        [[resources]]
+        type = "cidr"
+        id = "73037362-715d-4a83-a749-f18eadd970e6"
+        name = "Lab"
+        address = "10.0.0.0/24"
+        address_description = "lab"
+        filters = []
+        gateway_groups = [{ id = "bf56f32d-7b2c-4f5d-a784-788977d014a4", name = "Lab" }]
+
+        [[resources]]
+        type = "cidr"
+        id = "03000143-e25e-45c7-aafb-144990e57dcd"
+        name = "Nowhere"
+        address = "10.1.0.0/24"
+        address_description = "no gateway"
+        filters = []
+        gateway_groups = [{ id = "eb94482a-94f4-47cb-8127-14fb3afa5516", name = "Empty" }]
+    "#;
+
+    const CLIENT: &str = "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51";
+    const GATEWAY: &str = "2b1524e6-239e-4570-bc73-70a188e12101";
+    const RESOURCE: &str = "73037362-715d-4a83-a749-f18eadd970e6";
+
+    fn control_plane(signalling: LocalSignalling) -> StaticControlPlane {
+        StaticControlPlane::new(
+            CLIENT.parse().unwrap(),
+            toml::from_str(DEPLOYMENT).unwrap(),
+            signalling,
+        )
+        .unwrap()
+    }
+
+    fn next_event(
+        control_plane: &mut StaticControlPlane,
+    ) -> Option<Event<IngressMessages, ReplyMessages>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        match control_plane.poll(&mut cx) {
+            Poll::Ready(Ok(event)) => Some(event),
+            Poll::Ready(Err(_)) | Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn sends_init_after_joining() {
+        let mut control_plane = control_plane(LocalSignalling::default());
+
+        assert!(matches!(
+            next_event(&mut control_plane),
+            Some(Event::JoinedRoom { .. })
+        ));
+        let Some(Event::InboundMessage {
+            msg: IngressMessages::Init(init),
+            ..
+        }) = next_event(&mut control_plane)
+        else {
+            panic!("Expected `init`");
+        };
+        assert_eq!(init.resources.len(), 2);
+        assert_eq!(init.interface.ipv4, Ipv4Addr::new(100, 64, 0, 1));
+        assert!(next_event(&mut control_plane).is_none());
+    }
+
+    #[test]
+    fn unknown_client_is_rejected() {
+        let result = StaticControlPlane::new(
+            ClientId::from_u128(1),
+            toml::from_str(DEPLOYMENT).unwrap(),
+            LocalSignalling::default(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn picks_gateway_in_resource_site() {
+        let mut control_plane = control_plane(LocalSignalling::default());
+        while next_event(&mut control_plane).is_some() {}
+
+        let id = control_plane.send(EgressMessages::PrepareConnection {
+            resource_id: RESOURCE.parse().unwrap(),
+            connected_gateway_ids: BTreeSet::default(),
+            gateway_metrics: vec![],
+        });
+
+        let Some(Event::SuccessResponse {
+            req_id,
+            res: ReplyMessages::ConnectionDetails(details),
+            ..
+        }) = next_event(&mut control_plane)
+        else {
+            panic!("Expected connection details");
+        };
+        assert_eq!(req_id, id);
+        assert_eq!(details.gateway_id, GATEWAY.parse().unwrap());
+    }
+
+    #[test]
+    fn resource_without_gateway_is_offline() {
+        let mut control_plane = control_plane(LocalSignalling::default());
+        while next_event(&mut control_plane).is_some() {}
+
+        control_plane.send(EgressMessages::PrepareConnection {
+            resource_id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
+            connected_gateway_ids: BTreeSet::default(),
+            gateway_metrics: vec![],
+        });
+
+        assert!(matches!(
+            next_event(&mut control_plane),
+            Some(Event::ErrorResponse {
+                res: ErrorReply::Offline,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn gateway_answer_replies_to_connection_request() {
+        let signalling = LocalSignalling::default();
+        let mut control_plane = control_plane(signalling.clone());
+        while next_event(&mut control_plane).is_some() {}
+
+        let id = control_plane.send(EgressMessages::RequestConnection(RequestConnection {
+            gateway_id: GATEWAY.parse().unwrap(),
+            resource_id: RESOURCE.parse().unwrap(),
+            client_preshared_key: Secret::new(Key([1; 32])),
+            client_payload: ClientPayload {
+                ice_parameters: Offer {
+                    username: "client".to_owned(),
+                    password: "secret".to_owned(),
+                },
+                domain: None,
+            },
+        }));
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let gateway = SignallingPeer::Gateway(GATEWAY.parse().unwrap());
+        let Poll::Ready(Signal::RequestConnection {
+            client_id,
+            request_id,
+            resource_id,
+            offer,
+            ..
+        }) = signalling.poll_recv(gateway, &mut cx)
+        else {
+            panic!("Expected the request to reach the Gateway");
+        };
+        assert_eq!(client_id, CLIENT.parse().unwrap());
+        assert_eq!(offer.username, "client");
+
+        signalling.send(
+            SignallingPeer::Client(client_id),
+            Signal::ConnectionAccepted {
+                gateway_id: GATEWAY.parse().unwrap(),
+                request_id,
+                resource_id,
+                answer: Answer {
+                    username: "gateway".to_owned(),
+                    password: "secret".to_owned(),
+                },
+            },
+        );
+
+        let Some(Event::SuccessResponse {
+            req_id,
+            res: ReplyMessages::Connect(connect),
+            ..
+        }) = next_event(&mut control_plane)
+        else {
+            panic!("Expected the Gateway's answer");
+        };
+        assert_eq!(req_id, id);
+        assert_eq!(connect.resource_id, resource_id);
+    }
+}
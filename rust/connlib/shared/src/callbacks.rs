@@ -11,6 +11,8 @@ pub enum Status {
     Unknown,
     Online,
     Offline,
+    /// We are connected to a gateway for this resource but its health checks are failing.
+    Degraded,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
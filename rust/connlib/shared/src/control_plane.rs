@@ -0,0 +1,329 @@
+//! The control plane that the Client's and the Gateway's eventloops talk to
+//!
+//! In production, this is the portal over a [`PhoenixChannel`].
+//! Other implementations, e.g. one that reads a static [`Deployment`] from a file,
+//! let us run without a portal.
+
+use crate::messages::{
+    client::SiteId, Answer, ClientId, DnsServer, GatewayId, Key, Offer, Relay, ResolveRequest,
+    ResourceId, SecretKey,
+};
+use crate::LoginUrl;
+use phoenix_channel::{Error, Event, OutboundRequestId, PhoenixChannel};
+use secrecy::Secret;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Sends `TEgressMsg`s, and emits `TInboundMsg`s and the replies to our requests as [`Event`]s.
+///
+/// Errors from [`ControlPlane::poll`] are fatal, like those from [`PhoenixChannel::poll`].
+pub trait ControlPlane<TEgressMsg, TInboundMsg, TOutboundRes> {
+    /// Sends a message on our topic, the one we joined when logging in.
+    fn send(&mut self, msg: TEgressMsg) -> OutboundRequestId;
+
+    /// Joins `topic` again, e.g. after the control plane forgot about us.
+    fn rejoin(&mut self, topic: String);
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>>;
+
+    /// Reconnects, e.g. because the network changed.
+    fn reconnect(&mut self);
+
+    /// Reconnects with new credentials, see [`PhoenixChannel::set_url`].
+    fn set_url(&mut self, url: Secret<LoginUrl>, resolved_addresses: Vec<IpAddr>);
+
+    /// The host that we connect to and its addresses, if there is one.
+    ///
+    /// The tunnel keeps resolving it to these addresses, so we can reconnect without working DNS.
+    fn server_host(&self) -> Option<(String, Vec<IpAddr>)>;
+}
+
+impl<TInitReq, TEgressMsg, TInboundMsg, TOutboundRes>
+    ControlPlane<TEgressMsg, TInboundMsg, TOutboundRes>
+    for PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes>
+where
+    TInitReq: Serialize + Clone,
+    TEgressMsg: Serialize,
+    TInboundMsg: DeserializeOwned,
+    TOutboundRes: DeserializeOwned,
+{
+    fn send(&mut self, msg: TEgressMsg) -> OutboundRequestId {
+        let topic = self.login_topic();
+
+        PhoenixChannel::send(self, topic, msg)
+    }
+
+    fn rejoin(&mut self, topic: String) {
+        self.join(topic, ());
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>> {
+        PhoenixChannel::poll(self, cx)
+    }
+
+    fn reconnect(&mut self) {
+        PhoenixChannel::reconnect(self)
+    }
+
+    fn set_url(&mut self, url: Secret<LoginUrl>, resolved_addresses: Vec<IpAddr>) {
+        PhoenixChannel::set_url(self, url, resolved_addresses)
+    }
+
+    fn server_host(&self) -> Option<(String, Vec<IpAddr>)> {
+        Some((
+            PhoenixChannel::server_host(self).to_owned(),
+            self.resolved_addresses(),
+        ))
+    }
+}
+
+/// A deployment without a portal: its Clients, Gateways, Resources and Relays.
+///
+/// Clients and Gateways read the same file, each with their own view of the Resources, e.g.
+///
+/// ```toml
+/// [[clients]]
+/// id = "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51"
+/// public_key = "<base64>"
+/// ipv4 = "100.64.0.1"
+/// ipv6 = "fd00:2021:1111::1"
+///
+/// [[gateways]]
+/// id = "2b1524e6-239e-4570-bc73-70a188e12101"
+/// site_id = "bf56f32d-7b2c-4f5d-a784-788977d014a4"
+/// public_key = "<base64>"
+/// address = "192.168.1.10"
+/// ipv4 = "100.64.0.2"
+/// ipv6 = "fd00:2021:1111::2"
+///
+/// [[resources]]
+/// type = "cidr"
+/// id = "73037362-715d-4a83-a749-f18eadd970e6"
+/// name = "Lab"
+/// address = "10.0.0.0/24"
+/// address_description = "lab"
+/// filters = []
+/// gateway_groups = [{ id = "bf56f32d-7b2c-4f5d-a784-788977d014a4", name = "Lab" }]
+/// ```
+///
+/// Resources and Relays use the same format as the portal's messages.
+#[derive(Debug, Deserialize)]
+pub struct Deployment<TResource> {
+    #[serde(default)]
+    pub clients: Vec<DeployedClient>,
+    #[serde(default)]
+    pub gateways: Vec<DeployedGateway>,
+    #[serde(default)]
+    pub resources: Vec<TResource>,
+    #[serde(default)]
+    pub relays: Vec<Relay>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployedClient {
+    pub id: ClientId,
+    pub public_key: Key,
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Ipv6Addr,
+    #[serde(default)]
+    pub upstream_dns: Vec<DnsServer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployedGateway {
+    pub id: GatewayId,
+    pub site_id: SiteId,
+    pub public_key: Key,
+    /// The IP that the portal would report for the Gateway.
+    pub address: IpAddr,
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Ipv6Addr,
+}
+
+impl<TResource> Deployment<TResource>
+where
+    TResource: DeserializeOwned,
+{
+    /// Reads the deployment from a `.json` or `.toml` file.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let s = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&s).map_err(io::Error::other),
+            Some("toml") => toml::from_str(&s).map_err(io::Error::other),
+            Some(_) | None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected a `.json` or `.toml` file",
+            )),
+        }
+    }
+
+    pub fn client(&self, id: ClientId) -> Option<&DeployedClient> {
+        self.clients.iter().find(|c| c.id == id)
+    }
+
+    pub fn gateway(&self, id: GatewayId) -> Option<&DeployedGateway> {
+        self.gateways.iter().find(|g| g.id == id)
+    }
+}
+
+/// Who sent or receives a [`Signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SignallingPeer {
+    Client(ClientId),
+    Gateway(GatewayId),
+}
+
+/// What the portal would forward between a Client and a Gateway.
+#[derive(Debug)]
+pub enum Signal {
+    /// A Client asks a Gateway to accept a new connection for a Resource.
+    RequestConnection {
+        client_id: ClientId,
+        /// The Client's request that the Gateway's answer replies to.
+        request_id: u64,
+        resource_id: ResourceId,
+        offer: Offer,
+        preshared_key: SecretKey,
+        domain: Option<ResolveRequest>,
+    },
+    /// A Client asks a Gateway for access to another Resource on an existing connection.
+    ReuseConnection {
+        client_id: ClientId,
+        resource_id: ResourceId,
+        domain: Option<ResolveRequest>,
+    },
+    /// A Gateway accepted a connection from [`Signal::RequestConnection`].
+    ConnectionAccepted {
+        gateway_id: GatewayId,
+        request_id: u64,
+        resource_id: ResourceId,
+        answer: Answer,
+    },
+    IceCandidates {
+        from: SignallingPeer,
+        candidates: Vec<String>,
+    },
+    InvalidateIceCandidates {
+        from: SignallingPeer,
+        candidates: Vec<String>,
+    },
+}
+
+/// Carries [`Signal`]s between Clients and Gateways with static control planes in the same process.
+///
+/// Static control planes can't reach each other across processes.
+/// To run Clients and Gateways on different hosts without the portal, use the stub portal instead.
+#[derive(Debug, Clone, Default)]
+pub struct LocalSignalling {
+    mailboxes: Arc<Mutex<HashMap<SignallingPeer, Mailbox>>>,
+}
+
+#[derive(Debug, Default)]
+struct Mailbox {
+    signals: VecDeque<Signal>,
+    waker: Option<Waker>,
+}
+
+impl LocalSignalling {
+    pub fn send(&self, to: SignallingPeer, signal: Signal) {
+        let mut mailboxes = self
+            .mailboxes
+            .lock()
+            .expect("we never panic while holding the lock");
+        let mailbox = mailboxes.entry(to).or_default();
+
+        mailbox.signals.push_back(signal);
+        if let Some(waker) = mailbox.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn poll_recv(&self, me: SignallingPeer, cx: &mut Context<'_>) -> Poll<Signal> {
+        let mut mailboxes = self
+            .mailboxes
+            .lock()
+            .expect("we never panic while holding the lock");
+        let mailbox = mailboxes.entry(me).or_default();
+
+        if let Some(signal) = mailbox.signals.pop_front() {
+            return Poll::Ready(signal);
+        }
+
+        mailbox.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_reach_their_peer_only() {
+        let signalling = LocalSignalling::default();
+        let client = SignallingPeer::Client(ClientId::from_u128(1));
+        let gateway = SignallingPeer::Gateway(GatewayId::from_u128(2));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        signalling.send(
+            gateway,
+            Signal::IceCandidates {
+                from: client,
+                candidates: vec!["candidate".to_owned()],
+            },
+        );
+
+        assert!(signalling.poll_recv(client, &mut cx).is_pending());
+        assert!(matches!(
+            signalling.poll_recv(gateway, &mut cx),
+            Poll::Ready(Signal::IceCandidates { from, .. }) if from == client
+        ));
+        assert!(signalling.poll_recv(gateway, &mut cx).is_pending());
+    }
+
+    #[test]
+    fn reads_deployment() {
+        let deployment: Deployment<serde_json::Value> = toml::from_str(
+            r#"
+            [[clients]]
+            id = "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51"
+            public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            ipv4 = "100.64.0.1"
+            ipv6 = "fd00:2021:1111::1"
+
+            [[gateways]]
+            id = "2b1524e6-239e-4570-bc73-70a188e12101"
+            site_id = "bf56f32d-7b2c-4f5d-a784-788977d014a4"
+            public_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            address = "192.168.1.10"
+            ipv4 = "100.64.0.2"
+            ipv6 = "fd00:2021:1111::2"
+            "#,
+        )
+        .unwrap();
+
+        assert!(deployment
+            .client("5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51".parse().unwrap())
+            .is_some());
+        assert!(deployment
+            .gateway("2b1524e6-239e-4570-bc73-70a188e12101".parse().unwrap())
+            .is_some());
+        assert!(deployment.resources.is_empty());
+    }
+}
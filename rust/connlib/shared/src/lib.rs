@@ -4,6 +4,7 @@
 //! we are using the same version across our own crates.
 
 pub mod callbacks;
+pub mod control_plane;
 pub mod messages;
 
 pub use boringtun::x25519::PublicKey;
@@ -13,6 +14,8 @@ pub use phoenix_channel::{LoginUrl, LoginUrlError};
 pub type DomainName = domain::base::Name<Vec<u8>>;
 
 pub const DEFAULT_MTU: usize = 1280;
+/// The largest MTU we support for the tunnel interface, i.e. jumbo frames.
+pub const MAX_MTU: usize = 9000;
 
 const LIB_NAME: &str = "connlib";
 
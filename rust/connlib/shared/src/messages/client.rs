@@ -1,6 +1,6 @@
 //! Client related messages that are needed within connlib
 
-use std::{collections::BTreeSet, fmt, str::FromStr};
+use std::{collections::BTreeSet, fmt, net::IpAddr, str::FromStr};
 
 use ip_network::IpNetwork;
 use serde::{Deserialize, Serialize};
@@ -8,7 +8,7 @@ use uuid::Uuid;
 
 use crate::callbacks::Status;
 
-use super::ResourceId;
+use super::{GatewayId, ResourceId};
 use itertools::Itertools;
 
 /// Description of a resource that maps to a DNS record.
@@ -26,6 +26,10 @@ pub struct ResourceDescriptionDns {
     pub address_description: Option<String>,
     #[serde(rename = "gateway_groups")]
     pub sites: Vec<Site>,
+
+    /// How to probe the health of this resource whilst we are connected to it.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
 }
 
 impl ResourceDescriptionDns {
@@ -57,6 +61,10 @@ pub struct ResourceDescriptionCidr {
     pub address_description: Option<String>,
     #[serde(rename = "gateway_groups")]
     pub sites: Vec<Site>,
+
+    /// How to probe the health of this resource whilst we are connected to it.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
 }
 
 impl ResourceDescriptionCidr {
@@ -125,6 +133,55 @@ impl PartialEq for Site {
     }
 }
 
+/// Link metrics of a gateway, as measured by the client.
+///
+/// Sent along with connection intents to help the portal pick a healthy gateway within a site.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct GatewayMetrics {
+    pub gateway_id: GatewayId,
+    #[serde(rename = "gateway_group_id")]
+    pub site_id: SiteId,
+    /// The round-trip time to the gateway in milliseconds, if known.
+    pub rtt_ms: Option<u64>,
+    /// The estimated packet loss on the link to the gateway, in the range `0.0..=1.0`.
+    pub loss: f32,
+    /// Whether the client recently failed over away from this gateway because its link degraded.
+    pub degraded: bool,
+}
+
+/// An active health check of a resource, performed by the client through the tunnel.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum HealthCheck {
+    /// Send an ICMP echo request and expect a reply.
+    Icmp {
+        /// The address to probe.
+        ///
+        /// Required for CIDR resources that cover more than a single IP.
+        /// DNS resources are always probed via the IPs their domain resolved to.
+        #[serde(default)]
+        address: Option<IpAddr>,
+    },
+    /// Attempt to open a TCP connection to the given port.
+    Tcp {
+        /// The address to probe.
+        ///
+        /// Required for CIDR resources that cover more than a single IP.
+        /// DNS resources are always probed via the IPs their domain resolved to.
+        #[serde(default)]
+        address: Option<IpAddr>,
+        port: u16,
+    },
+}
+
+impl HealthCheck {
+    pub fn address(&self) -> Option<IpAddr> {
+        match self {
+            HealthCheck::Icmp { address } | HealthCheck::Tcp { address, .. } => *address,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct SiteId(Uuid);
 
@@ -175,6 +232,14 @@ impl ResourceDescription {
         }
     }
 
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        match self {
+            ResourceDescription::Dns(r) => r.health_check.as_ref(),
+            ResourceDescription::Cidr(r) => r.health_check.as_ref(),
+            ResourceDescription::Internet(_) => None,
+        }
+    }
+
     pub fn sites(&self) -> BTreeSet<&Site> {
         match self {
             ResourceDescription::Dns(r) => BTreeSet::from_iter(r.sites.iter()),
@@ -289,4 +354,44 @@ mod tests {
 
         serde_json::from_str::<Vec<ResourceDescription>>(resources).unwrap();
     }
+
+    #[test]
+    fn can_deserialize_health_checks() {
+        let resources = r#"[
+            {
+                "id": "73037362-715d-4a83-a749-f18eadd970e6",
+                "type": "cidr",
+                "name": "172.172.0.0/16",
+                "address": "172.172.0.0/16",
+                "address_description": "cidr resource",
+                "gateway_groups": [{"name": "test", "id": "bf56f32d-7b2c-4f5d-a784-788977d014a4"}],
+                "health_check": {"protocol": "icmp", "address": "172.172.0.1"}
+            },
+            {
+                "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+                "type": "dns",
+                "name": "gitlab.mycorp.com",
+                "address": "gitlab.mycorp.com",
+                "address_description": "dns resource",
+                "gateway_groups": [{"name": "test", "id": "bf56f32d-7b2c-4f5d-a784-788977d014a4"}],
+                "health_check": {"protocol": "tcp", "port": 443}
+            }
+        ]"#;
+
+        let resources = serde_json::from_str::<Vec<ResourceDescription>>(resources).unwrap();
+
+        assert_eq!(
+            resources[0].health_check(),
+            Some(&HealthCheck::Icmp {
+                address: Some("172.172.0.1".parse().unwrap())
+            })
+        );
+        assert_eq!(
+            resources[1].health_check(),
+            Some(&HealthCheck::Tcp {
+                address: None,
+                port: 443
+            })
+        );
+    }
 }
@@ -4,6 +4,8 @@ mod allocation;
 mod backoff;
 mod channel_data;
 mod index;
+mod metrics;
+mod mtu;
 mod node;
 mod ringbuffer;
 mod stats;
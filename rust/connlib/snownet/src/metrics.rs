@@ -0,0 +1,141 @@
+//! Metrics about our connections, exported via OpenTelemetry.
+//!
+//! Until the binary installs a global meter provider, all of these are no-ops.
+
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::KeyValue;
+use std::time::Duration;
+
+/// How often we add the bytes we relayed to the `relayed_bytes` counter.
+///
+/// Relayed packets are on the hot path, adding each of them to the counter would cost more than counting them ourselves.
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) struct Metrics {
+    handshakes: Counter<u64>,
+    connection_failures: Counter<u64>,
+    /// Only recorded on Clients, Gateways don't know when the intent was sent.
+    connection_setup_duration: Histogram<f64>,
+    handshake_duration: Histogram<f64>,
+    ice_nomination_duration: Histogram<f64>,
+    relayed_bytes: Counter<u64>,
+
+    /// What we relayed since the last flush.
+    relayed_tx_bytes: u64,
+    relayed_rx_bytes: u64,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter("snownet");
+
+        Self {
+            handshakes: meter
+                .u64_counter("wireguard_handshakes_total")
+                .with_description("The number of connections that completed their first WireGuard handshake")
+                .init(),
+            connection_failures: meter
+                .u64_counter("connection_failures_total")
+                .with_description("The number of connections that failed")
+                .init(),
+            connection_setup_duration: meter
+                .f64_histogram("connection_setup_duration")
+                .with_description("The time from the Client's connection intent until the first WireGuard handshake completed")
+                .with_unit("s")
+                .init(),
+            handshake_duration: meter
+                .f64_histogram("handshake_duration")
+                .with_description("The time from the end of signalling until the first WireGuard handshake completed")
+                .with_unit("s")
+                .init(),
+            ice_nomination_duration: meter
+                .f64_histogram("ice_nomination_duration")
+                .with_description("The time from the end of signalling until ICE nominated a socket")
+                .with_unit("s")
+                .init(),
+            relayed_bytes: meter
+                .u64_counter("relayed_bytes")
+                .with_description("The number of bytes we sent and received through a relay")
+                .with_unit("b")
+                .init(),
+            relayed_tx_bytes: 0,
+            relayed_rx_bytes: 0,
+        }
+    }
+
+    pub(crate) fn handshake_completed(
+        &self,
+        relayed: bool,
+        since_signalling: Duration,
+        since_intent: Option<Duration>,
+    ) {
+        let attributes = [path(relayed)];
+
+        self.handshakes.add(1, &attributes);
+        self.handshake_duration
+            .record(since_signalling.as_secs_f64(), &attributes);
+        if let Some(since_intent) = since_intent {
+            self.connection_setup_duration
+                .record(since_intent.as_secs_f64(), &attributes);
+        }
+    }
+
+    pub(crate) fn socket_nominated(&self, relayed: bool, since_signalling: Duration) {
+        self.ice_nomination_duration
+            .record(since_signalling.as_secs_f64(), &[path(relayed)]);
+    }
+
+    /// `phase` is either `signalling`, for connections that never got an answer, or `established`.
+    pub(crate) fn connection_failed(&self, phase: &'static str) {
+        self.connection_failures
+            .add(1, &[KeyValue::new("phase", phase)]);
+    }
+
+    pub(crate) fn relayed_tx(&mut self, num_bytes: usize) {
+        self.relayed_tx_bytes += num_bytes as u64;
+    }
+
+    pub(crate) fn relayed_rx(&mut self, num_bytes: usize) {
+        self.relayed_rx_bytes += num_bytes as u64;
+    }
+
+    /// Adds the bytes we relayed since the last flush to the `relayed_bytes` counter.
+    pub(crate) fn flush(&mut self) {
+        let tx = std::mem::take(&mut self.relayed_tx_bytes);
+        let rx = std::mem::take(&mut self.relayed_rx_bytes);
+
+        if tx > 0 {
+            self.relayed_bytes
+                .add(tx, &[KeyValue::new("direction", "tx")]);
+        }
+        if rx > 0 {
+            self.relayed_bytes
+                .add(rx, &[KeyValue::new("direction", "rx")]);
+        }
+    }
+}
+
+fn path(relayed: bool) -> KeyValue {
+    KeyValue::new("path", if relayed { "relayed" } else { "direct" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relayed_bytes_add_up_until_flushed() {
+        let mut metrics = Metrics::new();
+
+        metrics.relayed_tx(100);
+        metrics.relayed_tx(50);
+        metrics.relayed_rx(1280);
+        assert_eq!(
+            (metrics.relayed_tx_bytes, metrics.relayed_rx_bytes),
+            (150, 1280)
+        );
+
+        metrics.flush();
+        assert_eq!((metrics.relayed_tx_bytes, metrics.relayed_rx_bytes), (0, 0));
+    }
+}
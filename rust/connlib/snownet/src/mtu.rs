@@ -0,0 +1,328 @@
+//! Packetization Layer Path MTU Discovery (PLPMTUD) for a single connection, loosely following [RFC 8899](https://www.rfc-editor.org/rfc/rfc8899).
+//!
+//! Probes are regular IP packets that we send through the WireGuard tunnel, padded to the size we want to test.
+//! They use an unspecified source and destination IP plus the IP protocol number reserved for experimentation (253).
+//! Our peer intercepts them before they reach its TUN device and acknowledges each one with a small packet of the same kind.
+//!
+//! A peer that doesn't understand probes will drop them because they don't originate from an allowed IP.
+//! To us, this looks like a path that doesn't support anything beyond the base MTU.
+
+use ip_packet::{
+    ip::IpNextHeaderProtocol,
+    ipv4::{checksum, Ipv4Packet, MutableIpv4Packet},
+    Packet as _,
+};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// The MTU every IPv6-capable path must support and thus the MTU we start with.
+pub(crate) const BASE_MTU: usize = 1280;
+
+/// How long we wait for the acknowledgement of a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many probes of a certain size we send before concluding that the size is too big.
+const MAX_PROBES: u8 = 3;
+/// Once the search converged, we wait this long before we search for a larger MTU again.
+///
+/// The path might have changed in the meantime.
+const RAISE_TIMEOUT: Duration = Duration::from_secs(600);
+/// We stop searching once the confirmed MTU is within this many bytes of the smallest size known to be too big.
+const SEARCH_GRANULARITY: usize = 16;
+
+/// See <https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml>.
+const EXPERIMENTAL_PROTOCOL: IpNextHeaderProtocol = IpNextHeaderProtocol(253);
+
+const IPV4_HEADER_LEN: usize = 20;
+/// 1 byte kind + 4 byte probe ID.
+const MESSAGE_LEN: usize = IPV4_HEADER_LEN + 5;
+
+const KIND_PROBE: u8 = 0;
+const KIND_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Message {
+    Probe { id: u32 },
+    Ack { id: u32 },
+}
+
+#[derive(Debug)]
+pub(crate) struct PathMtu {
+    /// The largest packet size that made it through to our peer.
+    confirmed: usize,
+    /// The smallest packet size that is known to not make it through.
+    ///
+    /// This starts out as one larger than the MTU we are configured with.
+    too_big: usize,
+    max: usize,
+
+    in_flight: Option<InFlight>,
+    next_search: Option<Instant>,
+    next_id: u32,
+
+    pending_probe: Option<(u32, usize)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    id: u32,
+    size: usize,
+    attempts: u8,
+    deadline: Instant,
+}
+
+impl PathMtu {
+    /// Creates a new instance that searches for a path MTU of up to `max` bytes, starting at `now`.
+    pub(crate) fn new(max: usize, now: Instant) -> Self {
+        let max = max.max(BASE_MTU);
+
+        Self {
+            confirmed: BASE_MTU,
+            too_big: max + 1,
+            max,
+            in_flight: None,
+            next_search: Some(now),
+            next_id: 0,
+            pending_probe: None,
+        }
+    }
+
+    /// The largest IP packet that can currently be sent through the tunnel.
+    pub(crate) fn mtu(&self) -> usize {
+        self.confirmed
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        if let Some(in_flight) = self.in_flight {
+            return Some(in_flight.deadline);
+        }
+
+        self.next_search
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        if let Some(in_flight) = self.in_flight {
+            if now < in_flight.deadline {
+                return;
+            }
+
+            if in_flight.attempts < MAX_PROBES {
+                self.send_probe(in_flight.size, in_flight.attempts + 1, now);
+                return;
+            }
+
+            tracing::debug!(size = %in_flight.size, "Path MTU probe was lost {MAX_PROBES} times");
+
+            self.in_flight = None;
+            self.too_big = in_flight.size;
+        }
+
+        match self.next_search {
+            Some(next_search) if now < next_search => return,
+            Some(_) => {
+                // The raise timer expired, search for a larger MTU again.
+                self.too_big = self.max + 1;
+                self.next_search = None;
+            }
+            None => {}
+        }
+
+        if self.is_search_complete() {
+            tracing::debug!(mtu = %self.confirmed, "Path MTU search completed");
+
+            self.next_search = Some(now + RAISE_TIMEOUT);
+            return;
+        }
+
+        let size = self.confirmed + (self.too_big - self.confirmed) / 2;
+        self.send_probe(size, 1, now);
+    }
+
+    pub(crate) fn handle_ack(&mut self, id: u32, now: Instant) {
+        let Some(in_flight) = self.in_flight else {
+            return;
+        };
+
+        if in_flight.id != id {
+            return;
+        }
+
+        self.in_flight = None;
+        self.confirmed = in_flight.size;
+        self.next_search = None;
+
+        self.handle_timeout(now); // Immediately continue the search.
+    }
+
+    /// Returns the ID and size of the next probe to send.
+    pub(crate) fn poll_probe(&mut self) -> Option<(u32, usize)> {
+        self.pending_probe.take()
+    }
+
+    fn is_search_complete(&self) -> bool {
+        self.too_big - self.confirmed <= SEARCH_GRANULARITY
+    }
+
+    fn send_probe(&mut self, size: usize, attempts: u8, now: Instant) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.in_flight = Some(InFlight {
+            id,
+            size,
+            attempts,
+            deadline: now + PROBE_TIMEOUT,
+        });
+        self.pending_probe = Some((id, size));
+    }
+}
+
+impl Message {
+    /// Parses an IP packet received through the tunnel as a [`Message`].
+    pub(crate) fn parse(packet: &[u8]) -> Option<Self> {
+        let ipv4 = Ipv4Packet::new(packet)?;
+
+        if ipv4.get_version() != 4
+            || ipv4.get_next_level_protocol() != EXPERIMENTAL_PROTOCOL
+            || !ipv4.get_source().is_unspecified()
+            || !ipv4.get_destination().is_unspecified()
+        {
+            return None;
+        }
+
+        let payload = ipv4.payload();
+        let id = u32::from_be_bytes(payload.get(1..5)?.try_into().ok()?);
+
+        match *payload.first()? {
+            KIND_PROBE => Some(Message::Probe { id }),
+            KIND_ACK => Some(Message::Ack { id }),
+            _ => None,
+        }
+    }
+
+    /// Serializes this [`Message`] into an IP packet.
+    ///
+    /// Probes are padded to `size`, acknowledgements are always as small as possible.
+    pub(crate) fn to_packet(self, size: usize) -> Vec<u8> {
+        let (kind, id, size) = match self {
+            Message::Probe { id } => (KIND_PROBE, id, size.max(MESSAGE_LEN)),
+            Message::Ack { id } => (KIND_ACK, id, MESSAGE_LEN),
+        };
+
+        let mut buf = vec![0u8; size];
+
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).expect("buffer is large enough");
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(size as u16);
+        ipv4.set_ttl(64);
+        ipv4.set_next_level_protocol(EXPERIMENTAL_PROTOCOL);
+        ipv4.set_source(Ipv4Addr::UNSPECIFIED);
+        ipv4.set_destination(Ipv4Addr::UNSPECIFIED);
+        ipv4.set_checksum(checksum(&ipv4.to_immutable()));
+
+        buf[IPV4_HEADER_LEN] = kind;
+        buf[IPV4_HEADER_LEN + 1..MESSAGE_LEN].copy_from_slice(&id.to_be_bytes());
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_roundtrip() {
+        let probe = Message::Probe { id: 42 }.to_packet(1400);
+        let ack = Message::Ack { id: 42 }.to_packet(1400);
+
+        assert_eq!(probe.len(), 1400);
+        assert_eq!(ack.len(), MESSAGE_LEN);
+        assert_eq!(Message::parse(&probe), Some(Message::Probe { id: 42 }));
+        assert_eq!(Message::parse(&ack), Some(Message::Ack { id: 42 }));
+    }
+
+    #[test]
+    fn regular_packets_are_not_messages() {
+        let packet = ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 1),
+            1234,
+            53,
+            vec![0; 10],
+        )
+        .unwrap();
+
+        assert_eq!(Message::parse(packet.packet()), None);
+    }
+
+    #[test]
+    fn does_not_probe_when_max_is_base_mtu() {
+        let mut path_mtu = PathMtu::new(BASE_MTU, Instant::now());
+
+        path_mtu.handle_timeout(Instant::now());
+
+        assert_eq!(path_mtu.poll_probe(), None);
+        assert_eq!(path_mtu.mtu(), BASE_MTU);
+    }
+
+    #[test]
+    fn converges_on_path_mtu() {
+        const ACTUAL_PATH_MTU: usize = 1420;
+
+        let mut now = Instant::now();
+        let mut path_mtu = PathMtu::new(9000, now);
+
+        path_mtu.handle_timeout(now);
+
+        while let Some(timeout) = path_mtu.poll_timeout() {
+            if let Some((id, size)) = path_mtu.poll_probe() {
+                if size <= ACTUAL_PATH_MTU {
+                    path_mtu.handle_ack(id, now);
+                    continue;
+                }
+            }
+
+            if path_mtu.in_flight.is_none() && path_mtu.next_search.is_some() {
+                break; // Search completed.
+            }
+
+            now = timeout;
+            path_mtu.handle_timeout(now);
+        }
+
+        assert!(path_mtu.mtu() <= ACTUAL_PATH_MTU);
+        assert!(ACTUAL_PATH_MTU - path_mtu.mtu() <= SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn lost_probes_are_retransmitted() {
+        let now = Instant::now();
+        let mut path_mtu = PathMtu::new(1500, now);
+
+        path_mtu.handle_timeout(now);
+        let (_, first) = path_mtu.poll_probe().unwrap();
+
+        path_mtu.handle_timeout(now + PROBE_TIMEOUT);
+        let (_, second) = path_mtu.poll_probe().unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(path_mtu.mtu(), BASE_MTU);
+    }
+
+    #[test]
+    fn searches_again_after_raise_timeout() {
+        let now = Instant::now();
+        let mut path_mtu = PathMtu::new(BASE_MTU + 8, now);
+
+        path_mtu.handle_timeout(now);
+        assert_eq!(path_mtu.poll_timeout(), Some(now + RAISE_TIMEOUT));
+
+        path_mtu.handle_timeout(now + RAISE_TIMEOUT);
+        assert_eq!(path_mtu.poll_probe(), None); // Search space is smaller than the granularity.
+        assert_eq!(
+            path_mtu.poll_timeout(),
+            Some(now + RAISE_TIMEOUT + RAISE_TIMEOUT)
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crate::allocation::{Allocation, RelaySocket, Socket};
 use crate::index::IndexLfsr;
+use crate::metrics::{self, Metrics};
+use crate::mtu::{self, PathMtu};
 use crate::ringbuffer::RingBuffer;
 use crate::stats::{ConnectionStats, NodeStats};
 use crate::utils::earliest;
@@ -95,8 +97,13 @@ pub struct Node<T, TId, RId> {
     pending_events: VecDeque<Event<TId>>,
 
     buffer: Vec<u8>,
+    /// The largest IP packet we will attempt to send through a connection.
+    max_mtu: usize,
 
     stats: NodeStats,
+    metrics: Metrics,
+    /// When we next flush the relayed bytes to [`Metrics`].
+    next_metrics_flush: Option<Instant>,
 
     marker: PhantomData<T>,
     rng: StdRng,
@@ -140,9 +147,12 @@ where
             next_rate_limiter_reset: None,
             pending_events: VecDeque::default(),
             buffer: vec![0; buf_size],
+            max_mtu: mtu::BASE_MTU,
             allocations: Default::default(),
             connections: Default::default(),
             stats: Default::default(),
+            metrics: Metrics::new(),
+            next_metrics_flush: None,
         }
     }
 
@@ -195,6 +205,21 @@ where
         (self.stats, self.connections.stats())
     }
 
+    /// Sets the largest IP packet we will attempt to send through a connection.
+    ///
+    /// Each connection starts out with the minimum MTU of 1280 bytes and probes for larger packet sizes up to this value.
+    /// Only affects connections that are created after this call.
+    pub fn set_max_mtu(&mut self, mtu: usize) {
+        self.max_mtu = mtu.max(mtu::BASE_MTU);
+    }
+
+    /// The largest IP packet that can currently be sent through the given connection.
+    pub fn path_mtu(&self, connection: TId) -> Option<usize> {
+        let conn = self.connections.get_established(&connection)?;
+
+        Some(conn.path_mtu.mtu())
+    }
+
     /// Add an address as a `host` candidate.
     ///
     /// For most network topologies, [`snownet`](crate) will automatically discover host candidates via the traffic to the configured STUN and TURN servers.
@@ -302,6 +327,10 @@ where
             ControlFlow::Break(()) => return Ok(None),
         };
 
+        if relayed.is_some() {
+            self.metrics.relayed_rx(packet.len());
+        }
+
         // For our agents, it is important what the initial "destination" of the packet was.
         let destination = relayed.map(|s| s.address()).unwrap_or(local);
 
@@ -331,56 +360,45 @@ where
         packet: IpPacket<'_>,
         now: Instant,
     ) -> Result<Option<Transmit<'s>>, Error> {
-        let conn = self
-            .connections
-            .get_established_mut(&connection)
-            .ok_or(Error::NotConnected)?;
-
-        // Must bail early if we don't have a socket yet to avoid running into WG timeouts.
-        let socket = conn.socket().ok_or(Error::NotConnected)?;
-
-        // Encode the packet with an offset of 4 bytes, in case we need to wrap it in a channel-data message.
-        let Some(packet_len) = conn
-            .encapsulate(packet.packet(), &mut self.buffer[4..], now)?
-            .map(|p| p.len())
-        // Mapping to len() here terminate the mutable borrow of buffer, allowing re-borrowing further down.
-        else {
-            return Ok(None);
-        };
-
-        let packet_start = 4;
-        let packet_end = 4 + packet_len;
-
-        match socket {
-            PeerSocket::Direct {
-                dest: remote,
-                source,
-            } => {
-                // Re-borrow the actual packet.
-                let packet = &self.buffer[packet_start..packet_end];
-
-                Ok(Some(Transmit {
-                    src: Some(source),
-                    dst: remote,
-                    payload: Cow::Borrowed(packet),
-                }))
-            }
-            PeerSocket::Relay { relay, dest: peer } => {
-                let Some(allocation) = self.allocations.get(&relay) else {
-                    tracing::warn!(%relay, "No allocation");
-                    return Ok(None);
-                };
-                let packet = &mut self.buffer[..packet_end];
-
-                let Some(transmit) = allocation.encode_to_borrowed_transmit(peer, packet, now)
-                else {
-                    tracing::warn!(%peer, "No channel");
-                    return Ok(None);
-                };
+        let Self {
+            connections,
+            allocations,
+            metrics,
+            buffer,
+            ..
+        } = self;
+
+        encapsulate_into_buffer(
+            connections,
+            allocations,
+            metrics,
+            connection,
+            packet,
+            buffer,
+            now,
+        )
+    }
 
-                Ok(Some(transmit))
-            }
-        }
+    /// Like [`Node::encapsulate`] but encrypts the packet into the given buffer.
+    ///
+    /// The payload of the returned [`Transmit`] always starts at the beginning of `buffer`.
+    /// This allows callers to encrypt several packets back-to-back into one buffer and send them as a single batch.
+    pub fn encapsulate_into<'b>(
+        &mut self,
+        connection: TId,
+        packet: IpPacket<'_>,
+        now: Instant,
+        buffer: &'b mut [u8],
+    ) -> Result<Option<Transmit<'b>>, Error> {
+        encapsulate_into_buffer(
+            &mut self.connections,
+            &self.allocations,
+            &mut self.metrics,
+            connection,
+            packet,
+            buffer,
+            now,
+        )
     }
 
     /// Returns a pending [`Event`] from the pool.
@@ -407,7 +425,10 @@ where
             connection_timeout = earliest(connection_timeout, a.poll_timeout());
         }
 
-        earliest(connection_timeout, self.next_rate_limiter_reset)
+        earliest(
+            earliest(connection_timeout, self.next_rate_limiter_reset),
+            self.next_metrics_flush,
+        )
     }
 
     /// Advances time within the [`Node`].
@@ -430,7 +451,13 @@ where
         self.bindings_and_allocations_drain_events();
 
         for (id, connection) in self.connections.iter_established_mut() {
-            connection.handle_timeout(id, now, &mut self.allocations, &mut self.buffered_transmits);
+            connection.handle_timeout(
+                id,
+                now,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                &self.metrics,
+            );
         }
 
         for (id, connection) in self.connections.initial.iter_mut() {
@@ -448,6 +475,15 @@ where
             self.next_rate_limiter_reset = Some(now + Duration::from_secs(1));
         }
 
+        match self.next_metrics_flush {
+            Some(next_flush) if now >= next_flush => {
+                self.metrics.flush();
+                self.next_metrics_flush = Some(now + metrics::FLUSH_INTERVAL);
+            }
+            None => self.next_metrics_flush = Some(now + metrics::FLUSH_INTERVAL),
+            Some(_) => {}
+        }
+
         self.allocations
             .retain(|rid, allocation| match allocation.can_be_freed() {
                 Some(e) => {
@@ -457,7 +493,7 @@ where
                 }
                 None => true,
             });
-        self.connections.gc(&mut self.pending_events);
+        self.connections.gc(&mut self.pending_events, &self.metrics);
     }
 
     /// Returns buffered data that needs to be sent on the socket.
@@ -550,7 +586,7 @@ where
         remote: PublicKey,
         key: [u8; 32],
         relay: Option<RId>,
-        intent_sent_at: Instant,
+        intent_sent_at: Option<Instant>,
         now: Instant,
     ) -> Connection<RId> {
         agent.handle_timeout(now);
@@ -583,6 +619,7 @@ where
             relay,
             last_outgoing: now,
             last_incoming: now,
+            path_mtu: PathMtu::new(self.max_mtu, now),
         }
     }
 
@@ -739,7 +776,14 @@ where
 
             // I can't think of a better way to detect this ...
             if !handshake_complete_before_decapsulate && handshake_complete_after_decapsulate {
-                tracing::info!(duration_since_intent = ?conn.duration_since_intent(now), "Completed wireguard handshake");
+                let duration_since_intent = conn.duration_since_intent(now);
+                tracing::info!(?duration_since_intent, "Completed wireguard handshake");
+
+                self.metrics.handshake_completed(
+                    matches!(conn.socket(), Some(PeerSocket::Relay { .. })),
+                    now.duration_since(conn.signalling_completed_at),
+                    duration_since_intent,
+                );
 
                 self.pending_events
                     .push_back(Event::ConnectionEstablished(cid))
@@ -869,10 +913,10 @@ where
             remote,
             *initial.session_key.expose_secret(),
             selected_relay,
-            initial.intent_sent_at,
+            Some(initial.intent_sent_at),
             now,
         );
-        let duration_since_intent = connection.duration_since_intent(now);
+        let duration_since_intent = now.duration_since(initial.intent_sent_at);
 
         let existing = self.connections.established.insert(cid, connection);
 
@@ -931,7 +975,7 @@ where
             remote,
             *offer.session_key.expose_secret(),
             selected_relay,
-            now, // Technically, this isn't fully correct because gateways don't send intents so we just use the current time.
+            None, // Gateways don't send intents.
             now,
         );
         let existing = self.connections.established.insert(cid, connection);
@@ -999,9 +1043,10 @@ where
     TId: Eq + Hash + Copy + Ord + fmt::Display,
     RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
 {
-    fn gc(&mut self, events: &mut VecDeque<Event<TId>>) {
+    fn gc(&mut self, events: &mut VecDeque<Event<TId>>, metrics: &Metrics) {
         self.initial.retain(|id, conn| {
             if conn.is_failed {
+                metrics.connection_failed("signalling");
                 events.push_back(Event::ConnectionFailed(*id));
                 return false;
             }
@@ -1011,6 +1056,7 @@ where
 
         self.established.retain(|id, conn| {
             if conn.is_failed() {
+                metrics.connection_failed("established");
                 events.push_back(Event::ConnectionFailed(*id));
                 return false;
             }
@@ -1025,7 +1071,7 @@ where
     }
 
     fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1052,6 +1098,10 @@ where
         initial_agents.chain(negotiated_agents)
     }
 
+    fn get_established(&self, id: &TId) -> Option<&Connection<RId>> {
+        self.established.get(id)
+    }
+
     fn get_established_mut(&mut self, id: &TId) -> Option<&mut Connection<RId>> {
         self.established.get_mut(id)
     }
@@ -1140,6 +1190,74 @@ fn add_local_candidate_to_all<TId, RId>(
         add_local_candidate(cid, agent, candidate.clone(), pending_events);
     }
 }
+/// Encrypts the packet into `buffer`, see [`Node::encapsulate_into`].
+fn encapsulate_into_buffer<'b, TId, RId>(
+    connections: &mut Connections<TId, RId>,
+    allocations: &BTreeMap<RId, Allocation>,
+    metrics: &mut Metrics,
+    connection: TId,
+    packet: IpPacket<'_>,
+    buffer: &'b mut [u8],
+    now: Instant,
+) -> Result<Option<Transmit<'b>>, Error>
+where
+    TId: Eq + Hash + Copy + Ord + fmt::Display,
+    RId: Copy + Eq + Hash + PartialEq + Ord + fmt::Debug + fmt::Display,
+{
+    let conn = connections
+        .get_established_mut(&connection)
+        .ok_or(Error::NotConnected)?;
+
+    // Must bail early if we don't have a socket yet to avoid running into WG timeouts.
+    let socket = conn.socket().ok_or(Error::NotConnected)?;
+
+    // Reserve 4 bytes in front of the packet in case we need to wrap it in a channel-data message.
+    let packet_start = match socket {
+        PeerSocket::Direct { .. } => 0,
+        PeerSocket::Relay { .. } => 4,
+    };
+
+    let Some(packet_len) = conn
+        .encapsulate(packet.packet(), &mut buffer[packet_start..], now)?
+        .map(|p| p.len())
+    // Mapping to len() here terminate the mutable borrow of buffer, allowing re-borrowing further down.
+    else {
+        return Ok(None);
+    };
+
+    let packet_end = packet_start + packet_len;
+
+    match socket {
+        PeerSocket::Direct {
+            dest: remote,
+            source,
+        } => {
+            // Re-borrow the actual packet.
+            let packet = &buffer[..packet_end];
+
+            Ok(Some(Transmit {
+                src: Some(source),
+                dst: remote,
+                payload: Cow::Borrowed(packet),
+            }))
+        }
+        PeerSocket::Relay { relay, dest: peer } => {
+            let Some(allocation) = allocations.get(&relay) else {
+                tracing::warn!(%relay, "No allocation");
+                return Ok(None);
+            };
+            let packet = &mut buffer[..packet_end];
+
+            let Some(transmit) = allocation.encode_to_borrowed_transmit(peer, packet, now) else {
+                tracing::warn!(%peer, "No channel");
+                return Ok(None);
+            };
+            metrics.relayed_tx(transmit.payload.len());
+
+            Ok(Some(transmit))
+        }
+    }
+}
 
 fn add_local_candidate<TId>(
     id: TId,
@@ -1341,13 +1459,16 @@ struct Connection<RId> {
     relay: Option<RId>,
 
     stats: ConnectionStats,
-    intent_sent_at: Instant,
+    /// `None` on Gateways, only Clients send connection intents.
+    intent_sent_at: Option<Instant>,
     signalling_completed_at: Instant,
 
     buffer: Vec<u8>,
 
     last_outgoing: Instant,
     last_incoming: Instant,
+
+    path_mtu: PathMtu,
 }
 
 enum ConnectionState<RId> {
@@ -1433,12 +1554,24 @@ where
         }
     }
 
+    fn stats(&self) -> ConnectionStats {
+        let (_, _, _, loss, rtt) = self.tunnel.stats();
+
+        ConnectionStats {
+            rtt: rtt.map(|ms| Duration::from_millis(u64::from(ms))),
+            loss,
+            path_mtu: self.path_mtu.mtu(),
+            ..self.stats
+        }
+    }
+
     fn wg_handshake_complete(&self) -> bool {
         self.tunnel.time_since_last_handshake().is_some()
     }
 
-    fn duration_since_intent(&self, now: Instant) -> Duration {
-        now.duration_since(self.intent_sent_at)
+    fn duration_since_intent(&self, now: Instant) -> Option<Duration> {
+        self.intent_sent_at
+            .map(|intent_sent_at| now.duration_since(intent_sent_at))
     }
 
     #[must_use]
@@ -1447,13 +1580,23 @@ where
         let next_wg_timer = Some(self.next_timer_update);
         let candidate_timeout = self.candidate_timeout();
         let idle_timeout = self.idle_timeout();
+        let path_mtu_timeout = self.path_mtu_timeout();
 
         earliest(
-            Some(idle_timeout),
+            earliest(Some(idle_timeout), path_mtu_timeout),
             earliest(agent_timeout, earliest(next_wg_timer, candidate_timeout)),
         )
     }
 
+    /// We only probe the path MTU once we have a socket and a wireguard session.
+    fn path_mtu_timeout(&self) -> Option<Instant> {
+        if self.socket().is_none() || !self.wg_handshake_complete() {
+            return None;
+        }
+
+        self.path_mtu.poll_timeout()
+    }
+
     fn candidate_timeout(&self) -> Option<Instant> {
         if !self.agent.remote_candidates().is_empty() {
             return None;
@@ -1475,6 +1618,7 @@ where
         now: Instant,
         allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        metrics: &Metrics,
     ) where
         TId: Copy + Ord + fmt::Display,
         RId: Copy + Ord + fmt::Display,
@@ -1530,6 +1674,23 @@ where
             };
         }
 
+        if self
+            .path_mtu_timeout()
+            .is_some_and(|timeout| now >= timeout)
+        {
+            self.path_mtu.handle_timeout(now);
+
+            if let Some((id, size)) = self.path_mtu.poll_probe() {
+                self.send_path_mtu_message(
+                    mtu::Message::Probe { id },
+                    size,
+                    allocations,
+                    transmits,
+                    now,
+                );
+            }
+        }
+
         while let Some(event) = self.agent.poll_event() {
             match event {
                 IceAgentEvent::DiscoveredRecv { source, .. } => {
@@ -1567,6 +1728,10 @@ where
                             transmits.extend(buffered.into_iter().flat_map(|packet| {
                                 make_owned_transmit(remote_socket, &packet, allocations, now)
                             }));
+                            metrics.socket_nominated(
+                                matches!(remote_socket, PeerSocket::Relay { .. }),
+                                now.duration_since(self.signalling_completed_at),
+                            );
                             self.state = ConnectionState::Connected {
                                 peer_socket: remote_socket,
                                 possible_sockets,
@@ -1742,13 +1907,81 @@ where
             }
         };
 
-        if control_flow.is_continue() {
+        if let ControlFlow::Continue(packet) = &control_flow {
+            // Path MTU messages are handled here and don't count as activity on the connection.
+            if let Some(message) = mtu::Message::parse(packet.packet()) {
+                self.handle_path_mtu_message(message, allocations, transmits, now);
+
+                return ControlFlow::Break(Ok(()));
+            }
+
             self.last_incoming = now;
         }
 
         control_flow
     }
 
+    fn handle_path_mtu_message(
+        &mut self,
+        message: mtu::Message,
+        allocations: &mut BTreeMap<RId, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        match message {
+            mtu::Message::Probe { id } => {
+                self.send_path_mtu_message(
+                    mtu::Message::Ack { id },
+                    0,
+                    allocations,
+                    transmits,
+                    now,
+                );
+            }
+            mtu::Message::Ack { id } => {
+                self.path_mtu.handle_ack(id, now);
+
+                if let Some((id, size)) = self.path_mtu.poll_probe() {
+                    self.send_path_mtu_message(
+                        mtu::Message::Probe { id },
+                        size,
+                        allocations,
+                        transmits,
+                        now,
+                    );
+                }
+            }
+        }
+    }
+
+    fn send_path_mtu_message(
+        &mut self,
+        message: mtu::Message,
+        size: usize,
+        allocations: &mut BTreeMap<RId, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        let Some(socket) = self.socket() else {
+            return;
+        };
+
+        let packet = message.to_packet(size);
+
+        match self.tunnel.encapsulate(&packet, &mut self.buffer) {
+            TunnResult::Done => {}
+            TunnResult::Err(e) => {
+                tracing::debug!(?message, "Failed to encapsulate path MTU message: {e:?}");
+            }
+            TunnResult::WriteToNetwork(bytes) => {
+                transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
+            }
+            TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
+                unreachable!("never returned from encapsulate")
+            }
+        }
+    }
+
     fn force_handshake(
         &mut self,
         allocations: &mut BTreeMap<RId, Allocation>,
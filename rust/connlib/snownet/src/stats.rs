@@ -1,4 +1,5 @@
 use std::ops::AddAssign;
+use std::time::Duration;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -12,6 +13,12 @@ pub struct ConnectionStats {
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+    /// The round-trip time measured during the last wireguard handshake.
+    pub rtt: Option<Duration>,
+    /// The estimated loss of packets sent to us by the peer, in the range `0.0..=1.0`.
+    pub loss: f32,
+    /// The largest IP packet that we confirmed to make it through to the peer.
+    pub path_mtu: usize,
 }
 
 #[derive(Default, Clone, Copy)]
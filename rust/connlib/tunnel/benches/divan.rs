@@ -1,5 +1,410 @@
 extern crate firezone_tunnel; // Ensure benchmarks aren't optimised out.
 
+use boringtun::x25519::{PublicKey, StaticSecret};
+use bytes::Bytes;
+use chrono::Utc;
+use connlib_shared::messages::{
+    gateway::{ResourceDescription, ResourceDescriptionCidr},
+    ClientId, Key, Offer, Relay, RelayId, ResourceId, Turn,
+};
+use divan::{counter::ItemsCount, Bencher};
+use firezone_tunnel::{GatewayEvent, ShardedGatewayTunnel, IPV4_PEERS, IPV6_PEERS};
+use futures::FutureExt as _;
+use ip_packet::Packet as _;
+use rand::rngs::OsRng;
+use secrecy::{ExposeSecret as _, Secret};
+use snownet::{ClientNode, Event};
+use socket_factory::{DatagramOut, UdpSocket};
+use std::collections::{BTreeSet, HashMap};
+use std::future::{poll_fn, Future as _};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+
+/// How many packets we push through the [`ShardedGatewayTunnel`] per iteration.
+///
+/// Must fit into the channels of the shards, otherwise packets are dropped and we'd never see them arrive.
+const NUM_PACKETS: usize = 1000;
+/// How many clients the packets are spread across.
+///
+/// This is independent of the number of shards so every benchmark does the same amount of work.
+const NUM_CLIENTS: usize = 8;
+/// The only connection of each [`BenchClient`].
+const CONNECTION: u64 = 0;
+
 fn main() {
     divan::main()
 }
+
+/// Pushes packets destined for clients through a [`ShardedGatewayTunnel`] and waits until the clients decrypted all of them.
+///
+/// Clients connect through ICE and complete a WireGuard handshake with the gateway, just like they would in production.
+/// This covers reading from the TUN device, dispatching to the shards, each shard's policy and bandwidth checks, encryption and sending the packets.
+/// Each client is pinned to a single shard, thus throughput should scale with the number of shards until we run out of cores.
+#[divan::bench(args = [1, 2, 4, 8])]
+fn encapsulate_to_clients(bencher: Bencher, num_shards: usize) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let _guard = rt.enter();
+
+    let (packets_tx, clients) = rt.block_on(connect_clients(num_shards)).unwrap();
+
+    let packets = clients
+        .iter()
+        .cycle()
+        .take(NUM_PACKETS)
+        .map(|(client, _)| make_packet(*client))
+        .collect::<Vec<_>>();
+
+    bencher
+        .counter(ItemsCount::new(NUM_PACKETS))
+        .bench_local(|| {
+            rt.block_on(async {
+                for packet in &packets {
+                    packets_tx.send(packet.clone()).await.unwrap();
+                }
+
+                let all_received =
+                    futures::future::join_all(clients.iter().map(|(_, received)| {
+                        received.acquire_many((NUM_PACKETS / NUM_CLIENTS) as u32)
+                    }));
+
+                for permits in tokio::time::timeout(Duration::from_secs(1), all_received)
+                    .await
+                    .expect("Clients should receive all packets")
+                {
+                    permits.unwrap().forget();
+                }
+            })
+        });
+}
+
+/// Sets up a [`ShardedGatewayTunnel`] with [`NUM_CLIENTS`] clients that completed a handshake with it.
+///
+/// Returns the TUN device of the gateway and, for each client, its IPv4 address and a semaphore that gains a permit for every packet the client decrypts.
+async fn connect_clients(
+    num_shards: usize,
+) -> anyhow::Result<(mpsc::Sender<Bytes>, Vec<(Ipv4Addr, Arc<Semaphore>)>)> {
+    let gateway_key = StaticSecret::random_from_rng(OsRng);
+    let gateway_public_key = PublicKey::from(&gateway_key);
+
+    let mut tunnel = ShardedGatewayTunnel::new(
+        gateway_key,
+        Arc::new(socket_factory::tcp),
+        Arc::new(socket_factory::udp),
+        1280,
+        NonZeroUsize::new(num_shards).unwrap(),
+    );
+    let (packets_tx, packets_rx) = mpsc::channel(NUM_PACKETS);
+    tunnel.set_tun(Box::new(BenchTun { rx: packets_rx }));
+
+    // Shards only learn their own address once they receive a packet, thus we need a relay for them to signal a candidate to the clients.
+    let stun_server = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let stun_addr = stun_server.local_addr()?;
+    tokio::spawn(answer_binding_requests(stun_server));
+    tunnel.update_relays(
+        BTreeSet::new(),
+        vec![Relay::Turn(Turn {
+            id: RelayId::from_u128(1),
+            expires_at: Utc::now() + chrono::Duration::days(1),
+            addr: stun_addr,
+            username: "bench".to_owned(),
+            password: "bench".to_owned(),
+        })],
+    );
+
+    let (client_candidates_tx, client_candidates_rx) = mpsc::unbounded_channel();
+    let mut gateway_candidates = HashMap::new();
+    let mut clients = Vec::new();
+
+    for i in 0..NUM_CLIENTS {
+        let id = ClientId::from_u128(i as u128);
+        let ipv4 = Ipv4Addr::from(u32::from(IPV4_PEERS.network_address()) + 1 + i as u32);
+        let ipv6 = Ipv6Addr::from(u128::from(IPV6_PEERS.network_address()) + 1 + i as u128);
+
+        let socket = socket_factory::udp(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+        // Make sure the kernel doesn't drop packets while the client is busy decrypting.
+        socket2::SockRef::from(&socket).set_recv_buffer_size(16 * 1024 * 1024)?;
+        let local = socket2::SockRef::from(&socket)
+            .local_addr()?
+            .as_socket()
+            .expect("UDP socket to have an IP address");
+
+        let mut node =
+            ClientNode::<u64, u64>::new(StaticSecret::random_from_rng(OsRng), 0, rand::random());
+        node.add_local_host_candidate(local)?;
+
+        let offer = node.new_connection(CONNECTION, Instant::now(), Instant::now());
+        let answer = tunnel
+            .accept(
+                id,
+                Secret::new(Key(*offer.session_key.expose_secret())),
+                Offer {
+                    username: offer.credentials.username,
+                    password: offer.credentials.password,
+                },
+                node.public_key(),
+            )
+            .await?;
+        tunnel
+            .allow_access(
+                id,
+                ipv4,
+                ipv6,
+                None,
+                None,
+                ResourceDescription::Cidr(ResourceDescriptionCidr {
+                    id: ResourceId::from_u128(1),
+                    address: "10.0.0.0/24".parse().unwrap(),
+                    name: "bench".to_owned(),
+                    filters: Vec::new(),
+                }),
+            )
+            .await?;
+        node.accept_answer(
+            CONNECTION,
+            gateway_public_key,
+            snownet::Answer {
+                credentials: snownet::Credentials {
+                    username: answer.username,
+                    password: answer.password,
+                },
+            },
+            Instant::now(),
+        );
+
+        let (candidates_tx, candidates_rx) = mpsc::unbounded_channel();
+        let received = Arc::new(Semaphore::new(0));
+        gateway_candidates.insert(id, candidates_tx);
+        clients.push((ipv4, received.clone()));
+
+        let mut client = BenchClient {
+            id,
+            node,
+            socket,
+            timeout: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            remote_candidates: candidates_rx,
+            local_candidates: client_candidates_tx.clone(),
+            received,
+            recv_buf: vec![0u8; 65535],
+            decrypt_buf: vec![0u8; 65535],
+        };
+        tokio::spawn(poll_fn(move |cx| client.poll(cx)).map(|result| result.unwrap()));
+    }
+
+    tokio::spawn(exchange_candidates(
+        tunnel,
+        client_candidates_rx,
+        gateway_candidates,
+    ));
+
+    // The WireGuard handshake completes once the gateway tries to send the first packet.
+    // Keep sending packets until every client received one, the shards drop packets until then.
+    for (client, received) in &clients {
+        loop {
+            packets_tx.send(make_packet(*client)).await?;
+
+            if let Ok(permit) =
+                tokio::time::timeout(Duration::from_millis(10), received.acquire()).await
+            {
+                permit?.forget();
+                break;
+            }
+        }
+    }
+
+    // Discard late arrivals of the packets sent during the handshake.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for (_, received) in &clients {
+        received.forget_permits(received.available_permits());
+    }
+
+    Ok((packets_tx, clients))
+}
+
+fn make_packet(client: Ipv4Addr) -> Bytes {
+    let packet = ip_packet::make::udp_packet(
+        Ipv4Addr::new(10, 0, 0, 1),
+        client,
+        5000,
+        5000,
+        vec![0u8; 1200],
+    )
+    .unwrap();
+
+    Bytes::copy_from_slice(packet.packet())
+}
+
+/// Acts as the portal for the ICE candidates of the gateway and the clients.
+///
+/// Owns the [`ShardedGatewayTunnel`] for the remainder of the benchmark.
+async fn exchange_candidates(
+    mut tunnel: ShardedGatewayTunnel,
+    mut client_candidates: mpsc::UnboundedReceiver<(ClientId, String)>,
+    gateway_candidates: HashMap<ClientId, mpsc::UnboundedSender<String>>,
+) {
+    poll_fn(|cx| loop {
+        if let Poll::Ready(Some((client, candidate))) = client_candidates.poll_recv(cx) {
+            tunnel.add_ice_candidate(client, candidate);
+            continue;
+        }
+
+        if let Poll::Ready(event) = tunnel.poll_next_event(cx) {
+            if let Ok(GatewayEvent::AddedIceCandidates {
+                conn_id,
+                candidates,
+            }) = event
+            {
+                for candidate in candidates {
+                    let _ = gateway_candidates[&conn_id].send(candidate);
+                }
+            }
+            continue;
+        }
+
+        return Poll::<()>::Pending;
+    })
+    .await
+}
+
+/// A minimal STUN server that answers `BINDING` requests and ignores everything else.
+///
+/// This is all the shards need from a relay to learn their own address.
+async fn answer_binding_requests(socket: tokio::net::UdpSocket) -> io::Result<()> {
+    let mut buf = [0u8; 1024];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let request = &buf[..len];
+
+        if len < 20 || request[..2] != [0x00, 0x01] {
+            continue;
+        }
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+
+        let mut response = Vec::with_capacity(32);
+        response.extend_from_slice(&[0x01, 0x01, 0x00, 0x0c]); // Success response, 12 bytes of attributes.
+        response.extend_from_slice(&request[4..20]); // Magic cookie and transaction ID.
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]); // `XOR-MAPPED-ADDRESS` for IPv4.
+        response.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+        response.extend_from_slice(&(u32::from(*from.ip()) ^ 0x2112_a442).to_be_bytes());
+
+        socket.send_to(&response, from).await?;
+    }
+}
+
+/// A client with a single connection to the gateway that counts every packet it decrypts.
+struct BenchClient {
+    id: ClientId,
+    node: ClientNode<u64, u64>,
+    socket: UdpSocket,
+    timeout: Pin<Box<tokio::time::Sleep>>,
+
+    remote_candidates: mpsc::UnboundedReceiver<String>,
+    local_candidates: mpsc::UnboundedSender<(ClientId, String)>,
+    received: Arc<Semaphore>,
+
+    recv_buf: Vec<u8>,
+    decrypt_buf: Vec<u8>,
+}
+
+impl BenchClient {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.socket.poll_flush(cx))?;
+
+            if let Some(transmit) = self.node.poll_transmit() {
+                self.socket.send(DatagramOut {
+                    src: transmit.src,
+                    dst: transmit.dst,
+                    packet: transmit.payload,
+                    segment_size: None,
+                })?;
+                continue;
+            }
+
+            if let Some(event) = self.node.poll_event() {
+                if let Event::NewIceCandidate { candidate, .. } = event {
+                    let _ = self.local_candidates.send((self.id, candidate));
+                }
+                continue;
+            }
+
+            if let Poll::Ready(Some(candidate)) = self.remote_candidates.poll_recv(cx) {
+                self.node
+                    .add_remote_candidate(CONNECTION, candidate, Instant::now());
+                continue;
+            }
+
+            if let Poll::Ready(datagrams) = self.socket.poll_recv_from(&mut self.recv_buf, cx) {
+                let now = Instant::now();
+
+                for datagram in datagrams? {
+                    match self.node.decapsulate(
+                        datagram.local,
+                        datagram.from,
+                        datagram.packet,
+                        now,
+                        &mut self.decrypt_buf,
+                    ) {
+                        Ok(Some(_)) => self.received.add_permits(1),
+                        Ok(None) => {}
+                        Err(e) => tracing::debug!("Failed to decapsulate: {e}"),
+                    }
+                }
+                continue;
+            }
+
+            if let Some(deadline) = self.node.poll_timeout() {
+                if deadline != self.timeout.deadline().into_std() {
+                    self.timeout.as_mut().reset(deadline.into());
+                }
+            }
+            if self.timeout.as_mut().poll(cx).is_ready() {
+                self.node.handle_timeout(Instant::now());
+                // Park the timer until the node asks for a new timeout.
+                self.timeout
+                    .as_mut()
+                    .reset((Instant::now() + Duration::from_secs(60)).into());
+                continue;
+            }
+
+            return Poll::Pending;
+        }
+    }
+}
+
+/// A TUN device that hands out the packets we send to it and discards everything written to it.
+struct BenchTun {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl tun::Tun for BenchTun {
+    fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let Some(packet) = ready!(self.rx.poll_recv(cx)) else {
+            return Poll::Ready(Ok(0));
+        };
+
+        buf[..packet.len()].copy_from_slice(&packet);
+
+        Poll::Ready(Ok(packet.len()))
+    }
+
+    fn name(&self) -> &str {
+        "bench"
+    }
+}
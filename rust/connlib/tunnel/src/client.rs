@@ -4,17 +4,20 @@ use crate::{dns, TunConfig, BUF_SIZE};
 use anyhow::Context;
 use bimap::BiMap;
 use connlib_shared::callbacks::Status;
-use connlib_shared::messages::client::{Site, SiteId};
+use connlib_shared::messages::client::{GatewayMetrics, Site, SiteId};
 use connlib_shared::messages::ResolveRequest;
 use connlib_shared::messages::{
     client::ResourceDescription, client::ResourceDescriptionCidr, Answer, DnsServer, GatewayId,
     Interface as InterfaceConfig, IpDnsServer, Key, Offer, Relay, RelayId, ResourceId,
 };
 use connlib_shared::{callbacks, PublicKey, StaticSecret};
+use health_probe::HealthProbes;
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_network_table::IpNetworkTable;
 use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use itertools::Itertools;
+use link_quality::LinkQuality;
+use metrics::{DnsQuery, Metrics};
 
 use crate::peer::GatewayOnClient;
 use crate::utils::{self, earliest, turn};
@@ -31,6 +34,13 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::time::{Duration, Instant};
 
+mod health_probe;
+mod link_quality;
+mod metrics;
+mod stats;
+
+pub use stats::{ClientStats, GatewayStats, InterfaceStats, ResourceTraffic};
+
 pub(crate) const IPV4_RESOURCES: Ipv4Network =
     match Ipv4Network::new(Ipv4Addr::new(100, 96, 0, 0), 11) {
         Ok(n) => n,
@@ -69,6 +79,15 @@ const IDS_EXPIRE: std::time::Duration = std::time::Duration::from_secs(60);
 /// We only store [`GatewayId`]s so the memory footprint is negligible.
 const MAX_REMEMBERED_GATEWAYS: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(100) };
 
+/// How often we sample the link metrics of our connected gateways.
+const LINK_QUALITY_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// For how long we don't fail over away from a gateway again after we did so once.
+///
+/// The portal may hand us the same gateway again (e.g. if it is the only one in its site).
+/// We don't want to keep bouncing in that case.
+const FAILOVER_COOLDOWN: Duration = Duration::from_secs(60);
+
 impl ClientTunnel {
     pub fn set_resources(&mut self, resources: Vec<ResourceDescription>) {
         self.role_state.set_resources(resources);
@@ -162,6 +181,15 @@ impl ClientTunnel {
         self.role_state.on_connection_failed(id);
     }
 
+    pub fn stats(&self) -> ClientStats {
+        self.role_state.stats()
+    }
+
+    /// Replaces the hosts that we resolve locally, like `/etc/hosts` does.
+    pub fn set_known_hosts(&mut self, known_hosts: BTreeMap<String, Vec<IpAddr>>) {
+        self.role_state.set_known_hosts(known_hosts);
+    }
+
     pub fn set_resource_offline(&mut self, id: ResourceId) {
         self.role_state.set_resource_offline(id);
 
@@ -281,6 +309,26 @@ pub struct ClientState {
     /// We use this as a hint to the portal to re-connect us to the same gateway for a resource.
     recently_connected_gateways: LruCache<GatewayId, ()>,
 
+    /// The measured link quality of the gateways we are (or recently were) connected to.
+    link_quality: HashMap<GatewayId, LinkQuality>,
+    /// Gateways we recently failed over away from, indexed by when we did so.
+    degraded_gateways: HashMap<GatewayId, Instant>,
+    /// Resources that were routed through a gateway we failed over away from and haven't been routed again yet.
+    failed_over_resources: HashSet<ResourceId>,
+    /// When we next sample the link quality of our gateways.
+    next_link_quality_sample: Option<Instant>,
+
+    /// Active health checks of the resources we are connected to.
+    health_probes: HealthProbes,
+
+    /// Traffic counters of the resources we know about.
+    resource_traffic: HashMap<ResourceId, ResourceTraffic>,
+    metrics: Metrics,
+    /// When we next flush the traffic of our resources to [`Metrics`].
+    next_metrics_flush: Option<Instant>,
+    /// Traces the connection setup of resources from their connection intent until their first packet.
+    connection_setup_spans: HashMap<ResourceId, tracing::Span>,
+
     buffered_events: VecDeque<ClientEvent>,
     buffered_packets: VecDeque<IpPacket<'static>>,
     buffered_transmits: VecDeque<Transmit<'static>>,
@@ -319,6 +367,15 @@ impl ClientState {
             buffered_transmits: Default::default(),
             internet_resource: None,
             recently_connected_gateways: LruCache::new(MAX_REMEMBERED_GATEWAYS),
+            link_quality: Default::default(),
+            degraded_gateways: Default::default(),
+            failed_over_resources: Default::default(),
+            next_link_quality_sample: None,
+            health_probes: Default::default(),
+            resource_traffic: Default::default(),
+            next_metrics_flush: None,
+            metrics: Metrics::new(),
+            connection_setup_spans: Default::default(),
             upstream_dns: Default::default(),
         }
     }
@@ -359,6 +416,10 @@ impl ClientState {
                 .get(&s.id)
                 .is_some_and(|s| *s == Status::Online)
         }) {
+            if self.health_probes.is_degraded(&resource.id()) {
+                return Status::Degraded;
+            }
+
             return Status::Online;
         }
 
@@ -467,12 +528,37 @@ impl ClientState {
 
         let gid = peer.id();
 
+        // Packets that routers may fragment are sent as is, everything else gets rejected with an ICMP error.
+        if let Some(mtu) = self
+            .node
+            .path_mtu(gid)
+            .filter(|mtu| packet.packet().len() > *mtu && !may_fragment(&packet))
+        {
+            tracing::trace!(%gid, %mtu, len = %packet.packet().len(), "Packet exceeds path MTU, replying with ICMP error");
+
+            let mtu = u16::try_from(mtu).unwrap_or(u16::MAX);
+            self.buffered_packets.push_back(
+                ip_packet::make::icmp_packet_too_big(packet.as_immutable(), mtu).into_immutable(),
+            );
+            return None;
+        }
+
+        let len = packet.packet().len();
         let transmit = self
             .node
             .encapsulate(gid, packet.as_immutable(), now)
             .inspect_err(|e| tracing::debug!(%gid, "Failed to encapsulate: {e}"))
             .ok()??;
 
+        self.resource_traffic
+            .entry(resource)
+            .or_default()
+            .record_tx(len);
+
+        if let Some(span) = self.connection_setup_spans.remove(&resource) {
+            span.record("outcome", "first_packet");
+        }
+
         Some(transmit)
     }
 
@@ -508,6 +594,28 @@ impl ClientState {
             .inspect_err(|e| tracing::debug!(%gid, %local, %from, "{e}"))
             .ok()?;
 
+        if let Some(health_changed) = self.health_probes.handle_packet(&packet) {
+            self.send_health_probes(now);
+
+            if health_changed {
+                self.buffered_events
+                    .push_back(ClientEvent::ResourcesChanged {
+                        resources: self.resources(),
+                    });
+            }
+
+            return None;
+        }
+
+        if let Some(resource) = self.get_resource_by_destination(packet.source()) {
+            let len = packet.packet().len();
+
+            self.resource_traffic
+                .entry(resource)
+                .or_default()
+                .record_rx(len);
+        }
+
         let packet = maybe_mangle_dns_response_from_cidr_resource(
             packet,
             &self.dns_mapping,
@@ -573,6 +681,10 @@ impl ClientState {
             .context("No connection details found for resource")?;
         let ips = get_addresses_for_awaiting_resource(desc, &awaiting_connection_details);
 
+        if let Some(span) = self.connection_setup_spans.get(&resource_id) {
+            span.record("gateway", tracing::field::display(gateway_id));
+        }
+
         if let Some(old_gateway_id) = self.resources_gateways.insert(resource_id, gateway_id) {
             if self.peers.get(&old_gateway_id).is_some() {
                 assert_eq!(old_gateway_id, gateway_id, "Resources are not expected to change gateways without a previous message, resource_id = {resource_id}");
@@ -655,7 +767,11 @@ impl ClientState {
             .stub_resolver
             .handle(&self.dns_mapping, packet.as_immutable())
         {
-            Some(dns::ResolveStrategy::LocalResponse(query)) => Ok(Some(query)),
+            Some(dns::ResolveStrategy::LocalResponse(query)) => {
+                self.metrics.record_dns_query(DnsQuery::Local);
+
+                Ok(Some(query))
+            }
             Some(dns::ResolveStrategy::ForwardQuery {
                 upstream: server,
                 query_id,
@@ -665,9 +781,13 @@ impl ClientState {
                 let ip = server.ip();
 
                 if self.should_forward_dns_query_to_gateway(ip) {
+                    self.metrics.record_dns_query(DnsQuery::ThroughTunnel);
+
                     return Err((packet, ip));
                 }
 
+                self.metrics.record_dns_query(DnsQuery::Upstream);
+
                 tracing::trace!(%server, %query_id, "Forwarding DNS query");
 
                 self.forwarded_dns_queries
@@ -716,6 +836,10 @@ impl ClientState {
     pub fn on_connection_failed(&mut self, resource: ResourceId) {
         self.awaiting_connection_details.remove(&resource);
         self.resources_gateways.remove(&resource);
+
+        if let Some(span) = self.connection_setup_spans.remove(&resource) {
+            span.record("outcome", "failed");
+        }
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(%resource))]
@@ -767,8 +891,24 @@ impl ClientState {
 
         tracing::debug!("Sending connection intent");
 
+        self.connection_setup_spans
+            .entry(resource)
+            .or_insert_with(|| metrics::connection_setup_span(resource));
+
+        if self.failed_over_resources.remove(&resource) {
+            if let Some((gateway_id, site_id)) = self.healthy_gateway_for(resource, now) {
+                tracing::debug!(%gateway_id, "Failing over to connected gateway in the same site");
+
+                match self.on_routing_details(resource, gateway_id, site_id, now) {
+                    Ok(()) => return,
+                    Err(e) => tracing::debug!("Failed to fail over to {gateway_id}: {e:#}"),
+                }
+            }
+        }
+
         // We tell the portal about all gateways we ever connected to, to encourage re-connecting us to the same ones during a session.
         // The LRU cache visits them in MRU order, meaning a gateway that we recently connected to should still be preferred.
+        // Gateways we failed over away from are not part of the LRU cache.
         let connected_gateway_ids = self
             .recently_connected_gateways
             .iter()
@@ -779,9 +919,218 @@ impl ClientState {
             .push_back(ClientEvent::ConnectionIntent {
                 resource,
                 connected_gateway_ids,
+                gateway_metrics: self.gateway_metrics(),
             });
     }
 
+    /// A gateway we are connected to, that isn't degraded and is in one of the sites of the given resource.
+    fn healthy_gateway_for(
+        &self,
+        resource: ResourceId,
+        now: Instant,
+    ) -> Option<(GatewayId, SiteId)> {
+        let sites = self
+            .resources_by_id
+            .get(&resource)?
+            .sites()
+            .into_iter()
+            .map(|s| s.id)
+            .collect::<BTreeSet<_>>();
+        let (_, connections) = self.node.stats();
+
+        connections
+            .map(|(gateway_id, _)| gateway_id)
+            .filter(|gateway_id| !self.degraded_gateways.contains_key(gateway_id))
+            .filter(|gateway_id| {
+                !self
+                    .link_quality
+                    .get(gateway_id)
+                    .is_some_and(|link| link.should_fail_over(now))
+            })
+            .find_map(|gateway_id| {
+                let site_id = *self.gateways_site.get(&gateway_id)?;
+
+                sites.contains(&site_id).then_some((gateway_id, site_id))
+            })
+    }
+
+    /// The link metrics of all gateways we have measured, to be sent to the portal as a hint for gateway selection.
+    fn gateway_metrics(&self) -> Vec<GatewayMetrics> {
+        self.link_quality
+            .iter()
+            .filter_map(|(gateway_id, link)| {
+                Some(GatewayMetrics {
+                    gateway_id: *gateway_id,
+                    site_id: *self.gateways_site.get(gateway_id)?,
+                    rtt_ms: link.rtt().map(|rtt| rtt.as_millis() as u64),
+                    loss: link.loss(),
+                    degraded: self.degraded_gateways.contains_key(gateway_id),
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn stats(&self) -> ClientStats {
+        let interface = self.tun_config.as_ref().map(|config| InterfaceStats {
+            ip4: config.ip4,
+            ip6: config.ip6,
+            dns_by_sentinel: config
+                .dns_by_sentinel
+                .iter()
+                .map(|(sentinel, server)| (*sentinel, *server))
+                .collect(),
+        });
+        let (_, connections) = self.node.stats();
+        let gateways = connections
+            .map(|(id, stats)| GatewayStats {
+                id,
+                site: self.gateways_site.get(&id).copied(),
+                rtt_ms: stats.rtt.map(|rtt| rtt.as_millis() as u64),
+                loss: stats.loss,
+                path_mtu: stats.path_mtu,
+                stun_bytes_to_peer_direct: stats.stun_bytes_to_peer_direct.0,
+                stun_bytes_to_peer_relayed: stats.stun_bytes_to_peer_relayed.0,
+                degraded: self.degraded_gateways.contains_key(&id),
+            })
+            .collect();
+        let resources = self
+            .resource_traffic
+            .iter()
+            .map(|(id, traffic)| (*id, *traffic))
+            .collect();
+
+        ClientStats {
+            interface,
+            gateways,
+            resources,
+        }
+    }
+
+    fn sample_link_quality(&mut self, now: Instant) {
+        let (_, connections) = self.node.stats();
+
+        for (gateway_id, stats) in connections {
+            self.link_quality
+                .entry(gateway_id)
+                .or_default()
+                .sample(stats.rtt, stats.loss, now);
+        }
+
+        // Only keep metrics for gateways that we still might report to the portal.
+        self.link_quality.retain(|gateway_id, _| {
+            self.recently_connected_gateways.contains(gateway_id)
+                || self.degraded_gateways.contains_key(gateway_id)
+                || self.peers.get(gateway_id).is_some()
+        });
+        self.degraded_gateways
+            .retain(|_, failed_over_at| now.duration_since(*failed_over_at) < FAILOVER_COOLDOWN);
+
+        let to_fail_over = self
+            .link_quality
+            .iter()
+            .filter(|(gateway_id, link)| {
+                link.should_fail_over(now)
+                    && !self.degraded_gateways.contains_key(*gateway_id)
+                    && self.resources_gateways.values().any(|g| g == *gateway_id)
+            })
+            .map(|(gateway_id, _)| *gateway_id)
+            .collect_vec();
+
+        for gateway_id in to_fail_over {
+            self.fail_over(gateway_id, now);
+        }
+    }
+
+    /// The resources we should probe, i.e. all connected resources that have a health check configured.
+    fn health_probe_targets(&self) -> Vec<health_probe::Target> {
+        let Some(tun_config) = self.tun_config.as_ref() else {
+            return Vec::new();
+        };
+
+        self.resources_by_id
+            .iter()
+            .filter(|(id, _)| self.is_resource_enabled(id))
+            .filter_map(|(id, resource)| {
+                let check = *resource.health_check()?;
+                let gateway_id = self.resources_gateways.get(id)?;
+                let peer = self.peers.get(gateway_id)?;
+
+                let dst = match resource {
+                    ResourceDescription::Cidr(cidr) => check
+                        .address()
+                        .or_else(|| {
+                            is_single_ip(cidr.address).then_some(cidr.address.network_address())
+                        })
+                        .filter(|address| cidr.address.contains(*address))?,
+                    // DNS resources can only be reached via the proxy IPs we handed out for them.
+                    ResourceDescription::Dns(_) => peer
+                        .allowed_ips
+                        .iter()
+                        .find(|(_, resources)| resources.contains(id))
+                        .map(|(ip, _)| ip.network_address())?,
+                    ResourceDescription::Internet(_) => return None,
+                };
+                let src = match dst {
+                    IpAddr::V4(_) => IpAddr::from(tun_config.ip4),
+                    IpAddr::V6(_) => IpAddr::from(tun_config.ip6),
+                };
+
+                Some(health_probe::Target {
+                    resource: *id,
+                    check,
+                    src,
+                    dst,
+                })
+            })
+            .collect()
+    }
+
+    fn send_health_probes(&mut self, now: Instant) {
+        while let Some((resource, packet)) = self.health_probes.poll_packet() {
+            let Some(gid) = self.resources_gateways.get(&resource).copied() else {
+                continue;
+            };
+
+            let Some(transmit) = self
+                .node
+                .encapsulate(gid, packet.as_immutable(), now)
+                .inspect_err(
+                    |e| tracing::debug!(%gid, %resource, "Failed to encapsulate health probe: {e}"),
+                )
+                .ok()
+                .flatten()
+            else {
+                continue;
+            };
+
+            self.buffered_transmits.push_back(transmit.into_owned());
+        }
+    }
+
+    /// Stops routing resources through the given gateway because its link has degraded.
+    ///
+    /// We don't close the connection to the gateway.
+    /// Instead, the next packet for each affected resource re-routes it through another connected, healthy gateway in the same site.
+    /// Only if there is none, we send a new connection intent, allowing the portal to pick a different gateway in the same site.
+    /// If the portal picks the same gateway again, we simply re-use the existing connection.
+    #[tracing::instrument(level = "info", skip_all, fields(gateway = %gateway_id))]
+    fn fail_over(&mut self, gateway_id: GatewayId, now: Instant) {
+        let link = self.link_quality.get(&gateway_id);
+
+        tracing::info!(rtt = ?link.and_then(|l| l.rtt()), loss = ?link.map(|l| l.loss()), "Link to gateway degraded, failing over");
+
+        self.degraded_gateways.insert(gateway_id, now);
+        self.recently_connected_gateways.pop(&gateway_id);
+        self.resources_gateways.retain(|resource, g| {
+            if *g != gateway_id {
+                return true;
+            }
+
+            self.failed_over_resources.insert(*resource);
+            false
+        });
+    }
+
     pub fn gateway_by_resource(&self, resource: &ResourceId) -> Option<GatewayId> {
         self.resources_gateways.get(resource).copied()
     }
@@ -818,6 +1167,14 @@ impl ClientState {
     pub fn cleanup_connected_gateway(&mut self, gateway_id: &GatewayId) {
         self.update_site_status_by_gateway(gateway_id, Status::Unknown);
         self.peers.remove(gateway_id);
+        self.connection_setup_spans.retain(|resource, span| {
+            if self.resources_gateways.get(resource) != Some(gateway_id) {
+                return true;
+            }
+
+            span.record("outcome", "failed");
+            false
+        });
         self.resources_gateways.retain(|_, g| g != gateway_id);
     }
 
@@ -861,6 +1218,12 @@ impl ClientState {
             .or(self.internet_resource)
     }
 
+    pub(crate) fn set_known_hosts(&mut self, known_hosts: BTreeMap<String, Vec<IpAddr>>) {
+        tracing::debug!(hosts = ?known_hosts.keys().collect::<Vec<_>>(), "Setting known hosts");
+
+        self.stub_resolver.set_known_hosts(known_hosts);
+    }
+
     pub(crate) fn update_system_resolvers(&mut self, new_dns: Vec<IpAddr>) {
         tracing::debug!(servers = ?new_dns, "Received system-defined DNS servers");
 
@@ -902,7 +1265,16 @@ impl ClientState {
         let next_dns_query_expiry = self.mangled_dns_queries.values().min().copied();
         let next_node_timeout = self.node.poll_timeout();
 
-        earliest(next_dns_query_expiry, next_node_timeout)
+        earliest(
+            earliest(
+                self.next_link_quality_sample,
+                self.health_probes.poll_timeout(),
+            ),
+            earliest(
+                earliest(next_dns_query_expiry, next_node_timeout),
+                self.next_metrics_flush,
+            ),
+        )
     }
 
     pub fn handle_timeout(&mut self, now: Instant) {
@@ -910,6 +1282,38 @@ impl ClientState {
         self.mangled_dns_queries.retain(|_, exp| now < *exp);
         self.forwarded_dns_queries.retain(|_, (_, exp)| now < *exp);
 
+        match self.next_link_quality_sample {
+            Some(next_sample) if now >= next_sample => {
+                self.sample_link_quality(now);
+                self.next_link_quality_sample = Some(now + LINK_QUALITY_SAMPLE_INTERVAL);
+            }
+            None => self.next_link_quality_sample = Some(now + LINK_QUALITY_SAMPLE_INTERVAL),
+            Some(_) => {}
+        }
+
+        match self.next_metrics_flush {
+            Some(next_flush) if now >= next_flush => {
+                self.metrics.flush(&self.resource_traffic);
+                self.next_metrics_flush = Some(now + metrics::FLUSH_INTERVAL);
+            }
+            None => self.next_metrics_flush = Some(now + metrics::FLUSH_INTERVAL),
+            Some(_) => {}
+        }
+
+        let mut health_changed = self.health_probes.handle_timeout(now);
+        if self.health_probes.poll_round(now) {
+            let targets = self.health_probe_targets();
+            health_changed |= self.health_probes.start_round(targets, now);
+        }
+        self.send_health_probes(now);
+
+        if health_changed {
+            self.buffered_events
+                .push_back(ClientEvent::ResourcesChanged {
+                    resources: self.resources(),
+                });
+        }
+
         self.drain_node_events();
     }
 
@@ -993,6 +1397,9 @@ impl ClientState {
 
         self.node.reset();
         self.recently_connected_gateways.clear(); // Ensure we don't have sticky gateways when we roam.
+        self.link_quality.clear(); // Metrics measured on the old network are meaningless.
+        self.degraded_gateways.clear();
+        self.failed_over_resources.clear();
         self.drain_node_events();
     }
 
@@ -1080,6 +1487,10 @@ impl ClientState {
     pub(crate) fn remove_resource(&mut self, id: ResourceId) {
         self.disable_resource(id);
         self.resources_by_id.remove(&id);
+        self.metrics
+            .forget_resource(&id, self.resource_traffic.remove(&id).as_ref());
+        self.failed_over_resources.remove(&id);
+        self.connection_setup_spans.remove(&id);
     }
 
     fn disable_resource(&mut self, id: ResourceId) {
@@ -1196,6 +1607,19 @@ impl ClientState {
     ) {
         self.node.update_relays(to_remove, &to_add, now);
     }
+
+    /// Sets the MTU of the TUN device, i.e. the largest IP packet we will attempt to send through the tunnel.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.node.set_max_mtu(mtu);
+    }
+}
+
+/// Whether routers are allowed to fragment this packet, i.e. it is an IPv4 packet without the "don't fragment" flag.
+fn may_fragment(packet: &MutableIpPacket) -> bool {
+    match packet.as_immutable() {
+        IpPacket::Ipv4(ipv4) => ipv4.get_flags() & ip_packet::ipv4::Ipv4Flags::DontFragment == 0,
+        IpPacket::Ipv6(_) => false,
+    }
 }
 
 fn peer_by_resource_mut<'p>(
@@ -1283,6 +1707,15 @@ fn sentinel_dns_mapping(
         })
         .collect()
 }
+
+/// Whether the network covers just a single host, i.e. is a `/32` or `/128`.
+fn is_single_ip(network: IpNetwork) -> bool {
+    match network {
+        IpNetwork::V4(v4) => v4.netmask() == 32,
+        IpNetwork::V6(v6) => v6.netmask() == 128,
+    }
+}
+
 /// Compares the given [`IpAddr`] against a static set of ignored IPs that are definitely not resources.
 fn is_definitely_not_a_resource(ip: IpAddr) -> bool {
     /// Source: https://en.wikipedia.org/wiki/Multicast_address#Notable_IPv4_multicast_addresses
@@ -1530,6 +1963,12 @@ mod proptests {
     use connlib_shared::messages::client::ResourceDescriptionDns;
     use prop::collection;
     use proptest::prelude::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::{span, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt as _};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
 
     #[test_strategy::proptest]
     fn cidr_resources_are_turned_into_routes(
@@ -1625,6 +2064,7 @@ mod proptests {
             name: resource.name,
             address_description: resource.address_description,
             sites: resource.sites,
+            health_check: resource.health_check,
         };
 
         client_state.add_resource(ResourceDescription::Cidr(dns_as_cidr_resource.clone()));
@@ -1758,6 +2198,40 @@ mod proptests {
         }
     }
 
+    #[test_strategy::proptest]
+    fn connections_survive_a_new_init_with_the_same_resources(
+        #[strategy(resources_sharing_n_sites(1))] resources: Vec<ResourceDescription>,
+        #[strategy(gateway_id())] gateway: GatewayId,
+    ) {
+        let mut client_state = ClientState::for_test();
+        client_state.set_resources(resources.clone());
+        let ids = resources.iter().map(|r| r.id()).collect::<HashSet<_>>();
+        client_state.peers.insert(
+            GatewayOnClient::new(
+                gateway,
+                &[IpNetwork::from(Ipv4Addr::new(100, 64, 0, 1))],
+                ids.clone(),
+            ),
+            &[],
+        );
+        for id in &ids {
+            client_state.resources_gateways.insert(*id, gateway);
+        }
+
+        // After a new token or API URL, we re-join and the portal sends us `init` again.
+        client_state.set_resources(resources);
+
+        assert!(client_state.peers.get(&gateway).is_some());
+        for id in &ids {
+            assert_eq!(client_state.resources_gateways.get(id), Some(&gateway));
+        }
+
+        // Losing access to the resources is what tears down the connection.
+        client_state.set_resources(vec![]);
+
+        assert!(client_state.peers.get(&gateway).is_none());
+    }
+
     #[test_strategy::proptest]
     fn setting_resource_offline_doesnt_set_all_related_resources_offline(
         #[strategy(resources_sharing_n_sites(2))] multi_site_resources: Vec<ResourceDescription>,
@@ -1780,6 +2254,89 @@ mod proptests {
         }
     }
 
+    #[test_strategy::proptest]
+    fn connection_setup_span_lives_from_intent_until_failure(
+        #[strategy(cidr_resource())] resource: ResourceDescriptionCidr,
+    ) {
+        let spans = RecordedSpans::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let mut client_state = ClientState::for_test();
+        client_state.add_resource(ResourceDescription::Cidr(resource.clone()));
+
+        client_state.on_not_connected_resource(
+            resource.id,
+            &resource.address.network_address(),
+            Instant::now(),
+        );
+
+        assert!(client_state
+            .connection_setup_spans
+            .contains_key(&resource.id));
+        assert!(spans.events().is_empty());
+
+        client_state.on_connection_failed(resource.id);
+
+        assert!(!client_state
+            .connection_setup_spans
+            .contains_key(&resource.id));
+        assert_eq!(spans.events(), ["outcome=failed", "closed"]);
+    }
+
+    /// Records the outcome and the closing of all `connection_setup` spans.
+    #[derive(Clone, Default)]
+    struct RecordedSpans(Arc<Mutex<Vec<String>>>);
+
+    impl RecordedSpans {
+        fn events(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl<S> Layer<S> for RecordedSpans
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+            if !is_connection_setup(id, &ctx) {
+                return;
+            }
+
+            values.record(&mut OutcomeVisitor(&self.0));
+        }
+
+        fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+            if !is_connection_setup(&id, &ctx) {
+                return;
+            }
+
+            self.0.lock().unwrap().push("closed".to_owned());
+        }
+    }
+
+    fn is_connection_setup<S>(id: &span::Id, ctx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        ctx.span(id)
+            .is_some_and(|span| span.name() == "connection_setup")
+    }
+
+    struct OutcomeVisitor<'a>(&'a Mutex<Vec<String>>);
+
+    impl Visit for OutcomeVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "outcome" {
+                self.0.lock().unwrap().push(format!("outcome={value}"));
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.record_str(field, &format!("{value:?}"))
+        }
+    }
+
     pub fn expected_routes(resource_routes: Vec<IpNetwork>) -> HashSet<IpNetwork> {
         HashSet::from_iter(
             resource_routes
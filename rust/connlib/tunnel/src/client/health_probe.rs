@@ -0,0 +1,560 @@
+//! Actively probes the health of resources through the tunnel.
+//!
+//! A gateway may happily accept WireGuard handshakes and yet be unable to reach the resources behind it.
+//! To detect this, we periodically send an ICMP echo request or a TCP SYN to every connected resource that has a health check configured.
+
+use connlib_shared::messages::{client::HealthCheck, ResourceId};
+use ip_packet::{make, tcp::TcpFlags, IpPacket, MutableIpPacket};
+use rand::Rng as _;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// How often we probe each resource.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// For how long we wait for the response to a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// After how many consecutive failed probes we consider a resource to be degraded.
+const MAX_FAILED_PROBES: u32 = 3;
+
+/// The source ports we pick our TCP SYNs' from, the IANA ephemeral port range.
+///
+/// Together with a random ISN, this makes the SYN-ACK impossible to guess for an off-path host that wants to make a resource look healthy.
+const TCP_SOURCE_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// A resource we should probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub(crate) resource: ResourceId,
+    pub(crate) check: HealthCheck,
+    /// Our tunnel IP.
+    pub(crate) src: IpAddr,
+    /// The IP to probe.
+    pub(crate) dst: IpAddr,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct HealthProbes {
+    probes: HashMap<ResourceId, ProbeState>,
+    /// Which resource each in-flight probe belongs to, so we don't have to check every packet against all of them.
+    in_flight: HashMap<ProbeId, ResourceId>,
+
+    /// When we next send a probe to all targets.
+    next_round: Option<Instant>,
+    next_seq: u16,
+
+    buffered_packets: VecDeque<(ResourceId, MutableIpPacket<'static>)>,
+}
+
+#[derive(Debug, Default)]
+struct ProbeState {
+    failed_probes: u32,
+    in_flight: Option<InFlight>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    src: IpAddr,
+    dst: IpAddr,
+    id: ProbeId,
+    deadline: Instant,
+}
+
+/// What identifies a probe, and its response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ProbeId {
+    Icmp {
+        identifier: u16,
+        seq: u16,
+    },
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        /// The initial sequence number of our SYN.
+        isn: u32,
+    },
+}
+
+impl HealthProbes {
+    /// Whether the given resource is failing its health checks.
+    pub(crate) fn is_degraded(&self, resource: &ResourceId) -> bool {
+        self.probes
+            .get(resource)
+            .is_some_and(|probe| probe.is_degraded())
+    }
+
+    /// Whether it is time to send another round of probes.
+    pub(crate) fn poll_round(&mut self, now: Instant) -> bool {
+        match self.next_round {
+            Some(next_round) if now >= next_round => {
+                self.next_round = Some(now + PROBE_INTERVAL);
+
+                true
+            }
+            None => {
+                self.next_round = Some(now + PROBE_INTERVAL);
+
+                false
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Sends a probe to each of the given targets.
+    ///
+    /// Resources that are no longer a target are forgotten.
+    /// Returns `true` if the health of any resource changed.
+    pub(crate) fn start_round(&mut self, targets: Vec<Target>, now: Instant) -> bool {
+        let mut health_changed = false;
+
+        self.probes.retain(|resource, probe| {
+            let is_target = targets.iter().any(|t| t.resource == *resource);
+            health_changed |= !is_target && probe.is_degraded();
+
+            is_target
+        });
+        self.in_flight
+            .retain(|_, resource| self.probes.contains_key(resource));
+
+        for target in targets {
+            let probe = self.probes.entry(target.resource).or_default();
+
+            if probe.in_flight.is_some() {
+                continue; // Wait for the previous probe to time out.
+            }
+
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+
+            let (id, packet) = match target.check {
+                HealthCheck::Icmp { .. } => {
+                    let identifier = rand::random();
+
+                    (
+                        ProbeId::Icmp { identifier, seq },
+                        make::icmp_request_packet(target.src, target.dst, seq, identifier, &[]),
+                    )
+                }
+                HealthCheck::Tcp { port, .. } => {
+                    let src_port = rand::thread_rng().gen_range(TCP_SOURCE_PORTS);
+                    let isn = rand::random();
+
+                    (
+                        ProbeId::Tcp {
+                            src_port,
+                            dst_port: port,
+                            isn,
+                        },
+                        make::tcp_syn_packet(target.src, target.dst, src_port, port, isn),
+                    )
+                }
+            };
+
+            let Ok(packet) = packet else {
+                tracing::debug!(resource = %target.resource, src = %target.src, dst = %target.dst, "Cannot probe resource: IP version mismatch");
+                continue;
+            };
+
+            probe.in_flight = Some(InFlight {
+                src: target.src,
+                dst: target.dst,
+                id,
+                deadline: now + PROBE_TIMEOUT,
+            });
+            self.in_flight.insert(id, target.resource);
+            self.buffered_packets.push_back((target.resource, packet));
+        }
+
+        health_changed
+    }
+
+    /// Handles a packet received from a gateway.
+    ///
+    /// Returns `None` if the packet isn't a response to one of our probes and should be written to the TUN device.
+    /// Otherwise, returns `true` if the health of any resource changed.
+    pub(crate) fn handle_packet(&mut self, packet: &MutableIpPacket) -> Option<bool> {
+        let packet = packet.as_immutable();
+
+        let id = ProbeId::of_response(&packet)?;
+        let resource = *self.in_flight.get(&id)?;
+        let probe = self.probes.get_mut(&resource)?;
+
+        if !probe
+            .in_flight
+            .as_ref()
+            .is_some_and(|in_flight| in_flight.is_response(&packet))
+        {
+            return None;
+        }
+
+        self.in_flight.remove(&id);
+        let in_flight = probe.in_flight.take()?;
+
+        let is_healthy = match in_flight.id {
+            ProbeId::Icmp { .. } => true,
+            ProbeId::Tcp {
+                src_port,
+                dst_port,
+                isn,
+            } => {
+                let flags = packet
+                    .as_tcp()
+                    .map(|tcp| tcp.get_flags())
+                    .unwrap_or_default();
+                let is_syn_ack =
+                    flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK;
+
+                if is_syn_ack {
+                    // Nobody on our side is going to complete this handshake, abort it so the resource doesn't have to wait for a timeout.
+                    if let Ok(rst) = make::tcp_rst_packet(
+                        in_flight.src,
+                        in_flight.dst,
+                        src_port,
+                        dst_port,
+                        isn.wrapping_add(1),
+                    ) {
+                        self.buffered_packets.push_back((resource, rst));
+                    }
+                }
+
+                is_syn_ack // A RST means the resource is reachable but nobody is listening on the port.
+            }
+        };
+
+        if is_healthy {
+            Some(probe.on_success(resource))
+        } else {
+            Some(probe.on_failure(resource))
+        }
+    }
+
+    /// Returns `true` if the health of any resource changed.
+    pub(crate) fn handle_timeout(&mut self, now: Instant) -> bool {
+        let mut health_changed = false;
+
+        for (resource, probe) in self.probes.iter_mut() {
+            let Some(in_flight) = probe
+                .in_flight
+                .take_if(|in_flight| now >= in_flight.deadline)
+            else {
+                continue;
+            };
+
+            self.in_flight.remove(&in_flight.id);
+            health_changed |= probe.on_failure(*resource);
+        }
+
+        health_changed
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.probes
+            .values()
+            .filter_map(|probe| Some(probe.in_flight.as_ref()?.deadline))
+            .chain(self.next_round)
+            .min()
+    }
+
+    pub(crate) fn poll_packet(&mut self) -> Option<(ResourceId, MutableIpPacket<'static>)> {
+        self.buffered_packets.pop_front()
+    }
+}
+
+impl ProbeState {
+    fn is_degraded(&self) -> bool {
+        self.failed_probes >= MAX_FAILED_PROBES
+    }
+
+    /// Returns `true` if the resource became healthy again.
+    fn on_success(&mut self, resource: ResourceId) -> bool {
+        let was_degraded = self.is_degraded();
+        self.failed_probes = 0;
+
+        if was_degraded {
+            tracing::info!(%resource, "Resource is passing its health checks again");
+        }
+
+        was_degraded
+    }
+
+    /// Returns `true` if the resource just became degraded.
+    fn on_failure(&mut self, resource: ResourceId) -> bool {
+        self.failed_probes = self.failed_probes.saturating_add(1);
+
+        if self.failed_probes == MAX_FAILED_PROBES {
+            tracing::info!(%resource, "Resource is failing its health checks");
+
+            return true;
+        }
+
+        false
+    }
+}
+
+impl InFlight {
+    fn is_response(&self, packet: &IpPacket) -> bool {
+        packet.source() == self.dst
+            && packet.destination() == self.src
+            && ProbeId::of_response(packet) == Some(self.id)
+    }
+}
+
+impl ProbeId {
+    /// The probe that `packet` would be the response to, if it is an ICMP echo reply or a TCP segment.
+    fn of_response(packet: &IpPacket) -> Option<Self> {
+        if let Some(icmp) = packet.as_icmp() {
+            let reply = icmp.as_echo_reply()?;
+
+            return Some(Self::Icmp {
+                identifier: reply.identifier(),
+                seq: reply.sequence(),
+            });
+        }
+
+        let tcp = packet.as_tcp()?;
+
+        Some(Self::Tcp {
+            src_port: tcp.get_destination(),
+            dst_port: tcp.get_source(),
+            isn: tcp.get_acknowledgement().wrapping_sub(1),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1));
+    const DST: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn answered_icmp_probe_is_healthy() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![icmp_target()], now);
+        let (_, probe) = probes.poll_packet().unwrap();
+
+        assert_eq!(probes.handle_packet(&icmp_reply(&probe)), Some(false));
+        assert!(!probes.handle_timeout(now + PROBE_TIMEOUT));
+        assert!(!probes.is_degraded(&resource()));
+    }
+
+    #[test]
+    fn unanswered_probes_degrade_resource() {
+        let mut probes = HealthProbes::default();
+        let mut now = Instant::now();
+
+        for _ in 0..(MAX_FAILED_PROBES - 1) {
+            probes.start_round(vec![icmp_target()], now);
+            now += PROBE_TIMEOUT;
+            assert!(!probes.handle_timeout(now));
+        }
+
+        probes.start_round(vec![icmp_target()], now);
+        now += PROBE_TIMEOUT;
+
+        assert!(probes.handle_timeout(now));
+        assert!(probes.is_degraded(&resource()));
+    }
+
+    #[test]
+    fn answered_probe_recovers_degraded_resource() {
+        let mut probes = HealthProbes::default();
+        let mut now = Instant::now();
+
+        for _ in 0..MAX_FAILED_PROBES {
+            probes.start_round(vec![icmp_target()], now);
+            now += PROBE_TIMEOUT;
+            probes.handle_timeout(now);
+        }
+        while probes.poll_packet().is_some() {}
+
+        probes.start_round(vec![icmp_target()], now);
+        let (_, probe) = probes.poll_packet().unwrap();
+
+        assert_eq!(probes.handle_packet(&icmp_reply(&probe)), Some(true));
+        assert!(!probes.is_degraded(&resource()));
+    }
+
+    #[test]
+    fn syn_ack_is_healthy_and_aborted() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![tcp_target()], now);
+        let (_, syn) = probes.poll_packet().unwrap();
+        let seq = syn.as_immutable_tcp().unwrap().get_sequence();
+
+        let syn_ack = tcp_response(&syn, TcpFlags::SYN | TcpFlags::ACK);
+
+        assert_eq!(probes.handle_packet(&syn_ack), Some(false));
+
+        let (_, rst) = probes.poll_packet().unwrap();
+        let rst = rst.as_immutable_tcp().unwrap();
+        assert_eq!(rst.get_flags(), TcpFlags::RST);
+        assert_eq!(rst.get_sequence(), seq.wrapping_add(1));
+    }
+
+    #[test]
+    fn rst_is_a_failed_probe() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![tcp_target()], now);
+        let (_, syn) = probes.poll_packet().unwrap();
+
+        let rst = tcp_response(&syn, TcpFlags::RST | TcpFlags::ACK);
+
+        assert_eq!(probes.handle_packet(&rst), Some(false));
+        assert_eq!(probes.probes[&resource()].failed_probes, 1);
+        assert!(probes.poll_packet().is_none());
+    }
+
+    #[test]
+    fn unrelated_packets_are_not_consumed() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![icmp_target()], now);
+        let (_, probe) = probes.poll_packet().unwrap();
+        let probe = probe.as_immutable();
+        let icmp = probe.as_icmp().unwrap();
+
+        let other_identifier = make::icmp_reply_packet(
+            DST,
+            SRC,
+            icmp.sequence().unwrap(),
+            icmp.identifier().unwrap().wrapping_add(1),
+            &[],
+        )
+        .unwrap();
+        let udp = make::udp_packet(DST, SRC, 53, 53, vec![]).unwrap();
+
+        assert_eq!(probes.handle_packet(&other_identifier), None);
+        assert_eq!(probes.handle_packet(&udp), None);
+    }
+
+    #[test]
+    fn forgetting_degraded_resource_changes_health() {
+        let mut probes = HealthProbes::default();
+        let mut now = Instant::now();
+
+        for _ in 0..MAX_FAILED_PROBES {
+            probes.start_round(vec![icmp_target()], now);
+            now += PROBE_TIMEOUT;
+            probes.handle_timeout(now);
+        }
+
+        assert!(probes.start_round(vec![], now));
+        assert!(!probes.is_degraded(&resource()));
+    }
+
+    #[test]
+    fn tcp_probes_use_unpredictable_ports_and_sequence_numbers() {
+        let mut probes = HealthProbes::default();
+        let mut now = Instant::now();
+
+        let mut syns = Vec::new();
+        for _ in 0..2 {
+            probes.start_round(vec![tcp_target()], now);
+            let (_, syn) = probes.poll_packet().unwrap();
+            let syn = syn.as_immutable_tcp().unwrap();
+            syns.push((syn.get_source(), syn.get_sequence()));
+
+            now += PROBE_TIMEOUT;
+            probes.handle_timeout(now);
+        }
+
+        assert!(TCP_SOURCE_PORTS.contains(&syns[0].0));
+        assert_ne!(syns[0], syns[1]);
+    }
+
+    #[test]
+    fn guessed_syn_ack_is_not_consumed() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![tcp_target()], now);
+        let (_, syn) = probes.poll_packet().unwrap();
+        let seq = syn.as_immutable_tcp().unwrap().get_sequence();
+
+        let mut guess = tcp_response(&syn, TcpFlags::SYN | TcpFlags::ACK);
+        guess
+            .as_tcp()
+            .unwrap()
+            .set_acknowledgement(seq.wrapping_add(2));
+        guess.update_checksum();
+
+        assert_eq!(probes.handle_packet(&guess), None);
+        assert!(probes.probes[&resource()].in_flight.is_some());
+    }
+
+    #[test]
+    fn timed_out_probes_are_forgotten() {
+        let mut probes = HealthProbes::default();
+        let now = Instant::now();
+
+        probes.start_round(vec![icmp_target()], now);
+        let (_, probe) = probes.poll_packet().unwrap();
+        probes.handle_timeout(now + PROBE_TIMEOUT);
+
+        assert!(probes.in_flight.is_empty());
+        assert_eq!(probes.handle_packet(&icmp_reply(&probe)), None);
+    }
+
+    fn icmp_reply(probe: &MutableIpPacket) -> MutableIpPacket<'static> {
+        let probe = probe.as_immutable();
+        let icmp = probe.as_icmp().unwrap();
+
+        make::icmp_reply_packet(
+            DST,
+            SRC,
+            icmp.sequence().unwrap(),
+            icmp.identifier().unwrap(),
+            &[],
+        )
+        .unwrap()
+    }
+
+    fn tcp_response(syn: &MutableIpPacket, flags: u8) -> MutableIpPacket<'static> {
+        let syn = syn.as_immutable_tcp().unwrap();
+        let mut packet =
+            make::tcp_packet(DST, SRC, syn.get_destination(), syn.get_source(), vec![]).unwrap();
+
+        let mut tcp = packet.as_tcp().unwrap();
+        tcp.set_flags(flags);
+        tcp.set_acknowledgement(syn.get_sequence().wrapping_add(1));
+        packet.update_checksum();
+
+        packet
+    }
+
+    fn icmp_target() -> Target {
+        Target {
+            resource: resource(),
+            check: HealthCheck::Icmp { address: None },
+            src: SRC,
+            dst: DST,
+        }
+    }
+
+    fn tcp_target() -> Target {
+        Target {
+            resource: resource(),
+            check: HealthCheck::Tcp {
+                address: None,
+                port: 443,
+            },
+            src: SRC,
+            dst: DST,
+        }
+    }
+
+    fn resource() -> ResourceId {
+        ResourceId::from_u128(1)
+    }
+}
@@ -0,0 +1,129 @@
+//! Tracks the quality of the link to a gateway, as observed by the client.
+
+use std::time::{Duration, Instant};
+
+/// The weight of a new sample in the exponentially weighted moving averages.
+const EWMA_WEIGHT: f64 = 0.2;
+
+/// Above this RTT, we consider a link to be degraded.
+const MAX_RTT: Duration = Duration::from_millis(1000);
+/// Above this (estimated) packet loss, we consider a link to be degraded.
+const MAX_LOSS: f32 = 0.1;
+
+/// For how long a link needs to be degraded before we fail over to a different gateway.
+///
+/// This prevents a single bad sample from causing us to switch gateways.
+const DEGRADED_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub(crate) struct LinkQuality {
+    /// Smoothed round-trip time.
+    rtt: Option<Duration>,
+    /// Smoothed packet loss.
+    loss: f32,
+
+    /// Since when the link has been continuously degraded.
+    degraded_since: Option<Instant>,
+}
+
+impl LinkQuality {
+    pub(crate) fn sample(&mut self, rtt: Option<Duration>, loss: f32, now: Instant) {
+        self.rtt = match (self.rtt, rtt) {
+            (Some(smoothed), Some(sample)) => {
+                Some(smoothed.mul_f64(1.0 - EWMA_WEIGHT) + sample.mul_f64(EWMA_WEIGHT))
+            }
+            (None, Some(sample)) => Some(sample),
+            (smoothed, None) => smoothed,
+        };
+        self.loss =
+            self.loss * (1.0 - EWMA_WEIGHT as f32) + loss.clamp(0.0, 1.0) * EWMA_WEIGHT as f32;
+
+        if self.is_degraded() {
+            self.degraded_since.get_or_insert(now);
+        } else {
+            self.degraded_since = None;
+        }
+    }
+
+    /// Whether the link has been degraded for long enough to warrant a fail-over.
+    pub(crate) fn should_fail_over(&self, now: Instant) -> bool {
+        self.degraded_since
+            .is_some_and(|since| now.duration_since(since) >= DEGRADED_GRACE_PERIOD)
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub(crate) fn loss(&self) -> f32 {
+        self.loss
+    }
+
+    fn is_degraded(&self) -> bool {
+        self.rtt.is_some_and(|rtt| rtt > MAX_RTT) || self.loss > MAX_LOSS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_link_does_not_fail_over() {
+        let mut link = LinkQuality::default();
+        let mut now = Instant::now();
+
+        for _ in 0..60 {
+            link.sample(Some(Duration::from_millis(30)), 0.0, now);
+            now += Duration::from_secs(1);
+        }
+
+        assert!(!link.should_fail_over(now));
+    }
+
+    #[test]
+    fn lossy_link_fails_over_after_grace_period() {
+        let mut link = LinkQuality::default();
+        let mut now = Instant::now();
+
+        for _ in 0..30 {
+            link.sample(Some(Duration::from_millis(30)), 0.5, now);
+            now += Duration::from_secs(1);
+        }
+
+        assert!(link.loss() > MAX_LOSS);
+        assert!(link.should_fail_over(now));
+    }
+
+    #[test]
+    fn single_bad_sample_does_not_fail_over() {
+        let mut link = LinkQuality::default();
+        let mut now = Instant::now();
+
+        for _ in 0..10 {
+            link.sample(Some(Duration::from_millis(30)), 0.0, now);
+            now += Duration::from_secs(1);
+        }
+
+        link.sample(Some(Duration::from_secs(5)), 0.0, now);
+        now += Duration::from_secs(1);
+
+        for _ in 0..10 {
+            link.sample(Some(Duration::from_millis(30)), 0.0, now);
+            now += Duration::from_secs(1);
+        }
+
+        assert!(!link.should_fail_over(now));
+    }
+
+    #[test]
+    fn missing_rtt_sample_keeps_previous_value() {
+        let mut link = LinkQuality::default();
+        let now = Instant::now();
+
+        link.sample(Some(Duration::from_millis(30)), 0.0, now);
+        link.sample(None, 0.0, now);
+
+        assert_eq!(link.rtt(), Some(Duration::from_millis(30)));
+    }
+}
@@ -0,0 +1,185 @@
+//! Metrics and traces of the Client's tunnel, exported via OpenTelemetry if the binary sets it up.
+
+use super::ResourceTraffic;
+use connlib_shared::messages::ResourceId;
+use opentelemetry::metrics::Counter;
+use opentelemetry::{KeyValue, StringValue, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we add the traffic of our resources to the `resource_bytes` counter.
+///
+/// We count every packet in [`ResourceTraffic`] anyway, so there is no need to also touch the counter for each of them.
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The target of the spans we want to export as traces.
+///
+/// The OTLP exporter in `firezone-bin-shared` filters on it, so it must stay the same as over there.
+const TELEMETRY_TARGET: &str = "telemetry";
+
+pub(crate) struct Metrics {
+    resource_bytes: Counter<u64>,
+    dns_queries: Counter<u64>,
+
+    /// What we already added to `resource_bytes`, per resource.
+    reported: HashMap<ResourceId, Reported>,
+}
+
+struct Reported {
+    tx_bytes: u64,
+    rx_bytes: u64,
+
+    tx: [KeyValue; 2],
+    rx: [KeyValue; 2],
+}
+
+/// What happened to a DNS query that was sent to one of our sentinel IPs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DnsQuery {
+    /// We answered it from the stub resolver.
+    Local,
+    /// It goes to a DNS server that is a CIDR resource.
+    ThroughTunnel,
+    /// We forwarded it to an upstream server outside the tunnel.
+    Upstream,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter("connlib");
+
+        Self {
+            resource_bytes: meter
+                .u64_counter("resource_bytes")
+                .with_description(
+                    "The number of bytes of IP packets sent to and received from a resource",
+                )
+                .with_unit("b")
+                .init(),
+            dns_queries: meter
+                .u64_counter("dns_queries_total")
+                .with_description("The number of DNS queries sent to our sentinel IPs")
+                .init(),
+            reported: HashMap::default(),
+        }
+    }
+
+    /// Adds the bytes each resource saw since the last flush to the `resource_bytes` counter.
+    pub(crate) fn flush(&mut self, traffic: &HashMap<ResourceId, ResourceTraffic>) {
+        for (resource, traffic) in traffic {
+            self.flush_one(*resource, traffic);
+        }
+    }
+
+    pub(crate) fn record_dns_query(&self, query: DnsQuery) {
+        let strategy = match query {
+            DnsQuery::Local => "local",
+            DnsQuery::ThroughTunnel => "tunnel",
+            DnsQuery::Upstream => "upstream",
+        };
+
+        self.dns_queries
+            .add(1, &[KeyValue::new("strategy", strategy)]);
+    }
+
+    /// Flushes the final `traffic` of `resource` and forgets about it.
+    pub(crate) fn forget_resource(
+        &mut self,
+        resource: &ResourceId,
+        traffic: Option<&ResourceTraffic>,
+    ) {
+        if let Some(traffic) = traffic {
+            self.flush_one(*resource, traffic);
+        }
+
+        self.reported.remove(resource);
+    }
+
+    fn flush_one(&mut self, resource: ResourceId, traffic: &ResourceTraffic) {
+        let reported = self
+            .reported
+            .entry(resource)
+            .or_insert_with(|| Reported::new(resource));
+        let (tx, rx) = reported.advance(traffic);
+
+        if tx > 0 {
+            self.resource_bytes.add(tx, &reported.tx);
+        }
+        if rx > 0 {
+            self.resource_bytes.add(rx, &reported.rx);
+        }
+    }
+}
+
+impl Reported {
+    fn new(resource: ResourceId) -> Self {
+        let id = Value::String(StringValue::from(Arc::<str>::from(resource.to_string())));
+
+        Self {
+            tx_bytes: 0,
+            rx_bytes: 0,
+            tx: [
+                KeyValue::new("resource_id", id.clone()),
+                KeyValue::new("direction", "tx"),
+            ],
+            rx: [
+                KeyValue::new("resource_id", id),
+                KeyValue::new("direction", "rx"),
+            ],
+        }
+    }
+
+    /// Returns the tx and rx bytes `traffic` saw since the last call and remembers them as reported.
+    fn advance(&mut self, traffic: &ResourceTraffic) -> (u64, u64) {
+        let tx = traffic.tx_bytes.saturating_sub(self.tx_bytes);
+        let rx = traffic.rx_bytes.saturating_sub(self.rx_bytes);
+
+        self.tx_bytes = traffic.tx_bytes;
+        self.rx_bytes = traffic.rx_bytes;
+
+        (tx, rx)
+    }
+}
+
+/// A span from the connection intent for `resource` until we sent its first packet through the tunnel.
+pub(crate) fn connection_setup_span(resource: ResourceId) -> tracing::Span {
+    tracing::info_span!(
+        target: TELEMETRY_TARGET,
+        parent: None,
+        "connection_setup",
+        %resource,
+        gateway = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_bytes_since_last_flush() {
+        let mut reported = Reported::new(ResourceId::random());
+        let mut traffic = ResourceTraffic::default();
+
+        traffic.record_tx(100);
+        traffic.record_rx(1280);
+        assert_eq!(reported.advance(&traffic), (100, 1280));
+
+        traffic.record_tx(50);
+        assert_eq!(reported.advance(&traffic), (50, 0));
+        assert_eq!(reported.advance(&traffic), (0, 0));
+    }
+
+    #[test]
+    fn restarted_counters_report_nothing() {
+        let mut reported = Reported::new(ResourceId::random());
+        let mut traffic = ResourceTraffic::default();
+
+        traffic.record_tx(100);
+        reported.advance(&traffic);
+
+        assert_eq!(reported.advance(&ResourceTraffic::default()), (0, 0));
+    }
+}
@@ -0,0 +1,100 @@
+//! A point-in-time snapshot of the client's tunnel, for status pages and the like.
+
+use connlib_shared::messages::{client::SiteId, GatewayId, ResourceId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientStats {
+    /// `None` until the portal sent us our interface config.
+    pub interface: Option<InterfaceStats>,
+    /// All gateways we have an established connection to.
+    pub gateways: Vec<GatewayStats>,
+    /// Traffic counters of all resources that saw any traffic.
+    pub resources: BTreeMap<ResourceId, ResourceTraffic>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceStats {
+    pub ip4: Ipv4Addr,
+    pub ip6: Ipv6Addr,
+    /// Maps the sentinel IPs we give to the OS to the DNS servers we forward queries to.
+    pub dns_by_sentinel: BTreeMap<IpAddr, SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GatewayStats {
+    pub id: GatewayId,
+    /// `None` if the portal didn't tell us which site the gateway belongs to.
+    pub site: Option<SiteId>,
+    pub rtt_ms: Option<u64>,
+    /// The estimated loss of packets sent to us by the gateway, in the range `0.0..=1.0`.
+    pub loss: f32,
+    pub path_mtu: usize,
+    pub stun_bytes_to_peer_direct: usize,
+    pub stun_bytes_to_peer_relayed: usize,
+    /// Whether we recently failed over away from this gateway.
+    pub degraded: bool,
+}
+
+/// Counters of IP packets to and from a single resource, as seen on the TUN device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceTraffic {
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+}
+
+impl ResourceTraffic {
+    pub(crate) fn record_tx(&mut self, num_bytes: usize) {
+        self.tx_packets += 1;
+        self.tx_bytes += num_bytes as u64;
+    }
+
+    pub(crate) fn record_rx(&mut self, num_bytes: usize) {
+        self.rx_packets += 1;
+        self.rx_bytes += num_bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_both_directions_separately() {
+        let mut traffic = ResourceTraffic::default();
+
+        traffic.record_tx(100);
+        traffic.record_tx(50);
+        traffic.record_rx(1280);
+
+        assert_eq!(
+            traffic,
+            ResourceTraffic {
+                tx_packets: 2,
+                tx_bytes: 150,
+                rx_packets: 1,
+                rx_bytes: 1280,
+            }
+        );
+    }
+
+    #[test]
+    fn serializes_sentinel_mapping_as_object() {
+        let interface = InterfaceStats {
+            ip4: Ipv4Addr::new(100, 64, 0, 1),
+            ip6: Ipv6Addr::LOCALHOST,
+            dns_by_sentinel: BTreeMap::from([(
+                IpAddr::from([100, 100, 111, 1]),
+                SocketAddr::from(([1, 1, 1, 1], 53)),
+            )]),
+        };
+
+        let json = serde_json::to_value(&interface).unwrap();
+
+        assert_eq!(json["dns_by_sentinel"]["100.100.111.1"], "1.1.1.1:53");
+    }
+}
@@ -1,6 +1,7 @@
+use crate::BUF_SIZE;
 use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use std::io;
-use std::task::{Context, Poll, Waker};
+use std::task::{ready, Context, Poll, Waker};
 use tun::Tun;
 
 pub struct Device {
@@ -26,37 +27,52 @@ impl Device {
         }
     }
 
-    pub(crate) fn poll_read<'b>(
+    /// Reads as many packets as are available from the device, up to one per `BUF_SIZE` chunk of `buf`.
+    pub(crate) fn poll_read_many<'b>(
         &mut self,
         buf: &'b mut [u8],
         cx: &mut Context<'_>,
-    ) -> Poll<io::Result<MutableIpPacket<'b>>> {
-        use ip_packet::Packet as _;
-
+    ) -> Poll<io::Result<Vec<MutableIpPacket<'b>>>> {
         let Some(tun) = self.tun.as_mut() else {
             self.waker = Some(cx.waker().clone());
             return Poll::Pending;
         };
 
-        let n = std::task::ready!(tun.poll_read(&mut buf[20..], cx))?;
+        let mut lens = vec![0; buf.len() / BUF_SIZE];
+        let mut bufs = buf
+            .chunks_exact_mut(BUF_SIZE)
+            .map(|chunk| &mut chunk[20..])
+            .collect::<Vec<_>>();
+        let num_packets = ready!(tun.poll_read_many(&mut bufs, &mut lens, cx))?;
+        drop(bufs);
 
-        if n == 0 {
+        if num_packets == 0 {
             return Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
                 "device is closed",
             )));
         }
 
-        let packet = MutableIpPacket::new(&mut buf[..(n + 20)]).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "received bytes are not an IP packet",
-            )
-        })?;
+        let mut packets = Vec::with_capacity(num_packets);
+
+        for (chunk, n) in buf.chunks_exact_mut(BUF_SIZE).zip(&lens[..num_packets]) {
+            let Some(packet) = MutableIpPacket::new(&mut chunk[..(n + 20)]) else {
+                tracing::debug!("Received bytes are not an IP packet");
+                continue;
+            };
+
+            tracing::trace!(target: "wire::dev::recv", dst = %packet.destination(), src = %packet.source(), bytes = %packet.packet().len());
+
+            packets.push(packet);
+        }
+
+        if packets.is_empty() {
+            cx.waker().wake_by_ref(); // We only read garbage but the device may have more packets for us.
 
-        tracing::trace!(target: "wire::dev::recv", dst = %packet.destination(), src = %packet.source(), bytes = %packet.packet().len());
+            return Poll::Pending;
+        }
 
-        Poll::Ready(Ok(packet))
+        Poll::Ready(Ok(packets))
     }
 
     pub fn write(&self, packet: IpPacket<'_>) -> io::Result<usize> {
@@ -68,6 +84,26 @@ impl Device {
         }
     }
 
+    /// Writes all `packets`, letting the device coalesce them where it can.
+    pub fn write_many(&self, packets: &[IpPacket<'_>]) -> io::Result<()> {
+        let tun = self.tun()?;
+
+        for packet in packets {
+            tracing::trace!(target: "wire::dev::send", dst = %packet.destination(), src = %packet.source(), bytes = %packet.packet().len());
+        }
+
+        let bufs = packets.iter().map(|p| p.packet()).collect::<Vec<_>>();
+        let mut remaining = bufs.as_slice();
+
+        // Fails like writing the packets one by one would: At the first packet we can't write.
+        while !remaining.is_empty() {
+            let num_written = tun.write_many(remaining)?;
+            remaining = &remaining[num_written..];
+        }
+
+        Ok(())
+    }
+
     fn tun(&self) -> io::Result<&dyn Tun> {
         Ok(self
             .tun
@@ -97,6 +97,10 @@ impl StubResolver {
         }
     }
 
+    pub(crate) fn set_known_hosts(&mut self, known_hosts: BTreeMap<String, Vec<IpAddr>>) {
+        self.known_hosts = KnownHosts::new(known_hosts);
+    }
+
     /// Attempts to resolve an IP to a given resource.
     ///
     /// Semantically, this is like a PTR query, i.e. we check whether we handed out this IP as part of answering a DNS query for one of our resources.
@@ -580,6 +584,24 @@ mod tests {
 
         assert!(!matches);
     }
+
+    #[test]
+    fn set_known_hosts_replaces_previous_hosts() {
+        let old = DomainName::vec_from_str("old.example.com").unwrap();
+        let new = DomainName::vec_from_str("new.example.com").unwrap();
+        let mut resolver = StubResolver::new(BTreeMap::from([(
+            "old.example.com".to_owned(),
+            vec![IpAddr::from([10, 0, 0, 1])],
+        )]));
+
+        resolver.set_known_hosts(BTreeMap::from([(
+            "new.example.com".to_owned(),
+            vec![IpAddr::from([10, 0, 0, 2])],
+        )]));
+
+        assert!(resolver.known_hosts.get_records(Rtype::A, &old).is_none());
+        assert!(resolver.known_hosts.get_records(Rtype::A, &new).is_some());
+    }
 }
 
 #[cfg(feature = "divan")]
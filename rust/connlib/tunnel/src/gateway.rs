@@ -11,14 +11,21 @@ use connlib_shared::messages::{
 };
 use connlib_shared::{DomainName, StaticSecret};
 use ip_network::{Ipv4Network, Ipv6Network};
-use ip_packet::{IpPacket, MutableIpPacket};
+use ip_packet::{IpPacket, MutableIpPacket, Packet as _};
 use secrecy::{ExposeSecret as _, Secret};
+use shaper::{ResourceBuckets, Shaper};
 use snownet::{RelaySocket, ServerNode};
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 use tun::Tun;
 
+pub use shaper::{BandwidthLimits, DirectionStats, ShapingStats};
+pub use sharded::ShardedGatewayTunnel;
+
+mod shaper;
+mod sharded;
+
 pub const IPV4_PEERS: Ipv4Network = match Ipv4Network::new(Ipv4Addr::new(100, 64, 0, 0), 11) {
     Ok(n) => n,
     Err(_) => unreachable!(),
@@ -121,6 +128,15 @@ impl GatewayTunnel {
     pub fn remove_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
         self.role_state.remove_ice_candidate(conn_id, ice_candidate);
     }
+
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits) {
+        self.role_state.set_bandwidth_limits(limits);
+    }
+
+    /// Per-client counters of forwarded and dropped (due to bandwidth limits) traffic.
+    pub fn shaping_stats(&self) -> &BTreeMap<ClientId, ShapingStats> {
+        self.role_state.shaping_stats()
+    }
 }
 
 /// A SANS-IO implementation of a gateway's functionality.
@@ -134,6 +150,9 @@ pub struct GatewayState {
     /// All clients we are connected to and the associated, connection-specific state.
     peers: PeerStore<ClientId, ClientOnGateway>,
 
+    /// Enforces the configured bandwidth limits per client and resource.
+    shaper: Shaper,
+
     /// When to next check whether a resource-access policy has expired.
     next_expiry_resources_check: Option<Instant>,
 
@@ -145,6 +164,7 @@ impl GatewayState {
         Self {
             peers: Default::default(),
             node: ServerNode::new(private_key.into(), BUF_SIZE, seed),
+            shaper: Shaper::default(),
             next_expiry_resources_check: Default::default(),
             buffered_events: VecDeque::default(),
         }
@@ -160,6 +180,35 @@ impl GatewayState {
         packet: MutableIpPacket<'_>,
         now: Instant,
     ) -> Option<snownet::Transmit<'s>> {
+        let (cid, packet) = self.prepare_egress(packet, now)?;
+
+        self.node
+            .encapsulate(cid, packet.as_immutable(), now)
+            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
+            .ok()?
+    }
+
+    /// Like [`GatewayState::encapsulate`] but encrypts the packet into `buffer`, see [`snownet::Node::encapsulate_into`].
+    pub(crate) fn encapsulate_into<'b>(
+        &mut self,
+        packet: MutableIpPacket<'_>,
+        now: Instant,
+        buffer: &'b mut [u8],
+    ) -> Option<snownet::Transmit<'b>> {
+        let (cid, packet) = self.prepare_egress(packet, now)?;
+
+        self.node
+            .encapsulate_into(cid, packet.as_immutable(), now, buffer)
+            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
+            .ok()?
+    }
+
+    /// Applies the bandwidth limits and NAT of the client the packet is destined for.
+    fn prepare_egress<'a>(
+        &mut self,
+        packet: MutableIpPacket<'a>,
+        now: Instant,
+    ) -> Option<(ClientId, MutableIpPacket<'a>)> {
         let dst = packet.destination();
 
         if !is_client(dst) {
@@ -172,19 +221,21 @@ impl GatewayState {
             return None;
         };
         let cid = peer.id();
+        let resource = peer.resource_by_ip(packet.source());
+
+        if !self
+            .shaper
+            .allow_egress(cid, resource, packet.packet().len(), now)
+        {
+            return None;
+        }
 
         let packet = peer
             .encapsulate(packet, now)
             .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e:#}"))
             .ok()??;
 
-        let transmit = self
-            .node
-            .encapsulate(peer.id(), packet.as_immutable(), now)
-            .inspect_err(|e| tracing::debug!(%cid, "Failed to encapsulate: {e}"))
-            .ok()??;
-
-        Some(transmit)
+        Some((cid, packet))
     }
 
     pub(crate) fn decapsulate<'b>(
@@ -216,6 +267,15 @@ impl GatewayState {
             .inspect_err(|e| tracing::debug!(%cid, "Invalid packet: {e:#}"))
             .ok()?;
 
+        let resource = peer.resource_by_ip(packet.destination());
+
+        if !self
+            .shaper
+            .allow_ingress(cid, resource, packet.packet().len(), now)
+        {
+            return None;
+        }
+
         Some(packet.into_immutable())
     }
 
@@ -304,6 +364,24 @@ impl GatewayState {
         Ok(())
     }
 
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits) {
+        self.shaper.set_limits(limits);
+    }
+
+    /// Enforces the per-resource limits together with all other [`GatewayState`]s sharing `buckets`.
+    pub(crate) fn share_resource_buckets(&mut self, buckets: ResourceBuckets) {
+        self.shaper.share_resource_buckets(buckets);
+    }
+
+    /// Sets the MTU of the TUN device, i.e. the largest IP packet we will attempt to send through the tunnel.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.node.set_max_mtu(mtu);
+    }
+
+    pub fn shaping_stats(&self) -> &BTreeMap<ClientId, ShapingStats> {
+        self.shaper.stats()
+    }
+
     pub fn poll_timeout(&mut self) -> Option<Instant> {
         // TODO: This should check when the next resource actually expires instead of doing it at a fixed interval.
         earliest(self.next_expiry_resources_check, self.node.poll_timeout())
@@ -319,6 +397,14 @@ impl GatewayState {
                     p.handle_timeout(now)
                 });
                 self.peers.retain(|_, p| !p.is_emptied());
+                self.shaper.retain_clients(|c| self.peers.get(c).is_some());
+                let resources = self
+                    .peers
+                    .iter()
+                    .flat_map(|p| p.resources())
+                    .collect::<HashSet<_>>();
+                self.shaper.retain_resources(|r| resources.contains(r));
+                self.shaper.report_metrics();
 
                 self.next_expiry_resources_check = Some(now + EXPIRE_RESOURCES_INTERVAL);
             }
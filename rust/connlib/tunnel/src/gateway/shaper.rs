@@ -0,0 +1,604 @@
+//! Token-bucket based bandwidth shaping for traffic routed through a gateway.
+//!
+//! Limits are enforced per client and per resource.
+//! Per-resource buckets are shared by the [`Shaper`]s of all shards via [`ResourceBuckets`], because a resource's traffic may come from clients on any shard.
+//! Packets exceeding a limit are dropped; TCP's congestion control (and well-behaved UDP applications) will back off accordingly.
+
+use connlib_shared::messages::{ClientId, ResourceId};
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+/// The largest packet we may ever need to admit.
+///
+/// A bucket's capacity is never smaller than this, otherwise very low limits would drop every packet.
+const MIN_BUCKET_CAPACITY: u64 = (1 << 16) - 1;
+
+/// Bandwidth limits for traffic flowing through a gateway, in bytes per second.
+///
+/// - "Ingress" is traffic sent by clients through the tunnel, i.e. towards resources.
+/// - "Egress" is traffic sent by resources back to clients.
+///
+/// A limit of `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    /// How many bytes per second a single client may send.
+    pub client_ingress: Option<u64>,
+    /// How many bytes per second a single client may receive.
+    pub client_egress: Option<u64>,
+    /// How many bytes per second a single resource may receive, summed across all clients.
+    pub resource_ingress: Option<u64>,
+    /// How many bytes per second a single resource may send, summed across all clients.
+    pub resource_egress: Option<u64>,
+}
+
+/// Counters for one direction of traffic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionStats {
+    pub forwarded_packets: u64,
+    pub forwarded_bytes: u64,
+    pub dropped_packets: u64,
+    pub dropped_bytes: u64,
+}
+
+impl DirectionStats {
+    fn record(&mut self, num_bytes: usize, allowed: bool) {
+        if allowed {
+            self.forwarded_packets += 1;
+            self.forwarded_bytes += num_bytes as u64;
+        } else {
+            self.dropped_packets += 1;
+            self.dropped_bytes += num_bytes as u64;
+        }
+    }
+
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            forwarded_packets: self
+                .forwarded_packets
+                .saturating_sub(earlier.forwarded_packets),
+            forwarded_bytes: self.forwarded_bytes.saturating_sub(earlier.forwarded_bytes),
+            dropped_packets: self.dropped_packets.saturating_sub(earlier.dropped_packets),
+            dropped_bytes: self.dropped_bytes.saturating_sub(earlier.dropped_bytes),
+        }
+    }
+}
+
+/// Shaping counters of a single client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShapingStats {
+    pub ingress: DirectionStats,
+    pub egress: DirectionStats,
+}
+
+impl ShapingStats {
+    /// What was counted between `earlier` and `self`.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            ingress: self.ingress.since(&earlier.ingress),
+            egress: self.egress.since(&earlier.egress),
+        }
+    }
+
+    pub fn dropped_packets(&self) -> u64 {
+        self.ingress.dropped_packets + self.egress.dropped_packets
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Ingress,
+    Egress,
+}
+
+#[derive(Default)]
+pub(crate) struct Shaper {
+    limits: BandwidthLimits,
+
+    client_ingress: HashMap<ClientId, TokenBucket>,
+    client_egress: HashMap<ClientId, TokenBucket>,
+    /// Our handles to the shared buckets, so we only need to lock [`ResourceBuckets`] for the first packet of a resource.
+    resource_ingress: HashMap<ResourceId, Arc<SharedTokenBucket>>,
+    resource_egress: HashMap<ResourceId, Arc<SharedTokenBucket>>,
+    resource_buckets: ResourceBuckets,
+
+    stats: BTreeMap<ClientId, ShapingStats>,
+
+    /// Counted across all clients since the last [`Shaper::report_metrics`].
+    ///
+    /// Adding to the OTel counters for every packet would be too expensive.
+    unreported: ShapingStats,
+    metrics: Metrics,
+}
+
+/// The per-resource buckets of all [`Shaper`]s that enforce the same limits.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ResourceBuckets {
+    inner: Arc<Mutex<HashMap<(ResourceId, Direction), Weak<SharedTokenBucket>>>>,
+}
+
+impl ResourceBuckets {
+    fn get_or_create(
+        &self,
+        resource: ResourceId,
+        direction: Direction,
+        rate: u64,
+        now: Instant,
+    ) -> Arc<SharedTokenBucket> {
+        let mut buckets = self
+            .inner
+            .lock()
+            .expect("we never panic while holding the lock");
+
+        if let Some(bucket) = buckets
+            .get(&(resource, direction))
+            .and_then(Weak::upgrade)
+            .filter(|bucket| bucket.rate == rate)
+        {
+            return bucket;
+        }
+
+        // Buckets are dropped once no shard routes traffic to their resource anymore.
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+        let bucket = Arc::new(SharedTokenBucket::new(rate, now));
+        buckets.insert((resource, direction), Arc::downgrade(&bucket));
+
+        bucket
+    }
+}
+
+impl Shaper {
+    /// Shares the per-resource buckets with other [`Shaper`]s.
+    pub(crate) fn share_resource_buckets(&mut self, buckets: ResourceBuckets) {
+        self.resource_ingress.clear();
+        self.resource_egress.clear();
+        self.resource_buckets = buckets;
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: BandwidthLimits) {
+        if self.limits == limits {
+            return;
+        }
+
+        tracing::info!(?limits, "Updating bandwidth limits");
+
+        // Buckets are lazily re-created with the new rate on the next packet.
+        self.client_ingress.clear();
+        self.client_egress.clear();
+        self.resource_ingress.clear();
+        self.resource_egress.clear();
+        self.limits = limits;
+    }
+
+    /// Whether a packet sent by `client` to `resource` should be forwarded.
+    pub(crate) fn allow_ingress(
+        &mut self,
+        client: ClientId,
+        resource: Option<ResourceId>,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        self.allow(Direction::Ingress, client, resource, num_bytes, now)
+    }
+
+    /// Whether a packet sent by `resource` to `client` should be forwarded.
+    pub(crate) fn allow_egress(
+        &mut self,
+        client: ClientId,
+        resource: Option<ResourceId>,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        self.allow(Direction::Egress, client, resource, num_bytes, now)
+    }
+
+    pub(crate) fn stats(&self) -> &BTreeMap<ClientId, ShapingStats> {
+        &self.stats
+    }
+
+    /// Drop all state of clients for which `f` returns `false`.
+    pub(crate) fn retain_clients(&mut self, f: impl Fn(&ClientId) -> bool) {
+        self.client_ingress.retain(|c, _| f(c));
+        self.client_egress.retain(|c, _| f(c));
+        self.stats.retain(|c, _| f(c));
+    }
+
+    /// Drop the buckets of resources for which `f` returns `false`.
+    pub(crate) fn retain_resources(&mut self, f: impl Fn(&ResourceId) -> bool) {
+        self.resource_ingress.retain(|r, _| f(r));
+        self.resource_egress.retain(|r, _| f(r));
+    }
+
+    /// Adds everything we counted since the last call to the OTel counters.
+    pub(crate) fn report_metrics(&mut self) {
+        let unreported = std::mem::take(&mut self.unreported);
+
+        self.metrics.record("ingress", unreported.ingress);
+        self.metrics.record("egress", unreported.egress);
+    }
+
+    fn allow(
+        &mut self,
+        direction: Direction,
+        client: ClientId,
+        resource: Option<ResourceId>,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        let (client_limit, resource_limit, client_buckets, resource_buckets) = match direction {
+            Direction::Ingress => (
+                self.limits.client_ingress,
+                self.limits.resource_ingress,
+                &mut self.client_ingress,
+                &mut self.resource_ingress,
+            ),
+            Direction::Egress => (
+                self.limits.client_egress,
+                self.limits.resource_egress,
+                &mut self.client_egress,
+                &mut self.resource_egress,
+            ),
+        };
+
+        let mut client_bucket = client_limit.map(|rate| {
+            client_buckets
+                .entry(client)
+                .or_insert_with(|| TokenBucket::new(rate, now))
+        });
+        let shared = &self.resource_buckets;
+        let resource_bucket = resource.zip(resource_limit).map(|(resource, rate)| {
+            resource_buckets
+                .entry(resource)
+                .or_insert_with(|| shared.get_or_create(resource, direction, rate, now))
+        });
+
+        let num_bytes_u64 = num_bytes as u64;
+
+        // Only consume tokens if _all_ applicable buckets admit the packet, otherwise a client's budget would be drained by packets we drop anyway.
+        // Other shards draw from the resource's bucket concurrently, so it is checked and consumed in one go, last.
+        let allowed = client_bucket
+            .as_mut()
+            .map_or(true, |b| b.has_tokens(num_bytes_u64, now))
+            && resource_bucket.map_or(true, |b| b.try_consume(num_bytes_u64, now));
+
+        if allowed {
+            if let Some(b) = client_bucket {
+                b.consume(num_bytes_u64);
+            }
+        } else {
+            tracing::trace!(%client, ?resource, ?direction, %num_bytes, "Dropping packet: bandwidth limit exceeded");
+        }
+
+        for stats in [self.stats.entry(client).or_default(), &mut self.unreported] {
+            match direction {
+                Direction::Ingress => stats.ingress.record(num_bytes, allowed),
+                Direction::Egress => stats.egress.record(num_bytes, allowed),
+            }
+        }
+
+        allowed
+    }
+}
+
+struct Metrics {
+    packets: Counter<u64>,
+    bytes: Counter<u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let meter = opentelemetry::global::meter("connlib");
+
+        Self {
+            packets: meter
+                .u64_counter("shaped_packets_total")
+                .with_description("The number of packets that passed through the bandwidth limits")
+                .init(),
+            bytes: meter
+                .u64_counter("shaped_bytes")
+                .with_description("The number of bytes that passed through the bandwidth limits")
+                .with_unit("b")
+                .init(),
+        }
+    }
+}
+
+impl Metrics {
+    fn record(&self, direction: &'static str, stats: DirectionStats) {
+        for (outcome, packets, bytes) in [
+            ("forwarded", stats.forwarded_packets, stats.forwarded_bytes),
+            ("dropped", stats.dropped_packets, stats.dropped_bytes),
+        ] {
+            if packets == 0 {
+                continue;
+            }
+
+            let attributes = [
+                KeyValue::new("direction", direction),
+                KeyValue::new("outcome", outcome),
+            ];
+
+            self.packets.add(packets, &attributes);
+            self.bytes.add(bytes, &attributes);
+        }
+    }
+}
+
+/// A classic token bucket, refilled at `rate` bytes per second.
+///
+/// The bucket's capacity (i.e. the max burst size) equals one second worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let capacity = rate.max(MIN_BUCKET_CAPACITY);
+
+        Self {
+            rate,
+            capacity,
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    fn has_tokens(&mut self, num_bytes: u64, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= num_bytes as f64
+    }
+
+    fn consume(&mut self, num_bytes: u64) {
+        self.tokens = (self.tokens - num_bytes as f64).max(0.0);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.capacity as f64);
+    }
+}
+
+/// A token bucket that several shards draw from concurrently, with the same capacity as a [`TokenBucket`].
+///
+/// Instead of the number of tokens, it tracks when the bucket will be full again in a single atomic (GCRA), so shards never block each other.
+#[derive(Debug)]
+struct SharedTokenBucket {
+    rate: u64,
+    /// How long it takes to fill an empty bucket.
+    capacity_nanos: u64,
+    epoch: Instant,
+    /// Nanoseconds since `epoch` when the bucket is full again.
+    full_at: AtomicU64,
+}
+
+impl SharedTokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity_nanos: nanos_to_refill(rate.max(MIN_BUCKET_CAPACITY), rate),
+            epoch: now,
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    fn try_consume(&self, num_bytes: u64, now: Instant) -> bool {
+        let now = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+        let cost = nanos_to_refill(num_bytes, self.rate);
+
+        self.full_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |full_at| {
+                let full_at = full_at.max(now) + cost;
+
+                (full_at - now <= self.capacity_nanos).then_some(full_at)
+            })
+            .is_ok()
+    }
+}
+
+fn nanos_to_refill(num_bytes: u64, rate: u64) -> u64 {
+    (u128::from(num_bytes) * 1_000_000_000 / u128::from(rate.max(1))) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn unlimited_by_default() {
+        let mut shaper = Shaper::default();
+        let now = Instant::now();
+
+        for _ in 0..1000 {
+            assert!(shaper.allow_ingress(client(), Some(resource()), 1500, now));
+            assert!(shaper.allow_egress(client(), Some(resource()), 1500, now));
+        }
+    }
+
+    #[test]
+    fn drops_packets_over_client_limit() {
+        let mut shaper = limited(BandwidthLimits {
+            client_ingress: Some(100_000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let num_allowed = (0..100)
+            .filter(|_| shaper.allow_ingress(client(), None, 10_000, now))
+            .count();
+
+        assert_eq!(num_allowed, 10);
+
+        let stats = shaper.stats()[&client()];
+        assert_eq!(stats.ingress.forwarded_bytes, 100_000);
+        assert_eq!(stats.ingress.dropped_packets, 90);
+        assert_eq!(stats.egress, DirectionStats::default());
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut shaper = limited(BandwidthLimits {
+            client_egress: Some(100_000),
+            ..Default::default()
+        });
+        let mut now = Instant::now();
+
+        while shaper.allow_egress(client(), None, 10_000, now) {}
+
+        now += Duration::from_millis(150);
+
+        assert!(shaper.allow_egress(client(), None, 10_000, now));
+        assert!(!shaper.allow_egress(client(), None, 10_000, now));
+    }
+
+    #[test]
+    fn resource_limit_is_shared_across_clients() {
+        let mut shaper = limited(BandwidthLimits {
+            resource_ingress: Some(100_000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let other_client = ClientId::from_u128(2);
+
+        while shaper.allow_ingress(client(), Some(resource()), 10_000, now) {}
+
+        assert!(!shaper.allow_ingress(other_client, Some(resource()), 10_000, now));
+        assert!(shaper.allow_ingress(other_client, Some(ResourceId::from_u128(2)), 10_000, now));
+    }
+
+    #[test]
+    fn resource_limit_is_shared_across_shards() {
+        let limits = BandwidthLimits {
+            resource_egress: Some(100_000),
+            ..Default::default()
+        };
+        let buckets = ResourceBuckets::default();
+        let mut shard1 = limited(limits);
+        let mut shard2 = limited(limits);
+        shard1.share_resource_buckets(buckets.clone());
+        shard2.share_resource_buckets(buckets);
+        let now = Instant::now();
+
+        let num_allowed = (0..20)
+            .filter(|_| shard1.allow_egress(client(), Some(resource()), 10_000, now))
+            .count();
+
+        assert_eq!(num_allowed, 10, "a single shard gets the full limit");
+        assert!(!shard2.allow_egress(ClientId::from_u128(2), Some(resource()), 10_000, now));
+    }
+
+    #[test]
+    fn shared_bucket_refills_over_time() {
+        let mut now = Instant::now();
+        let bucket = SharedTokenBucket::new(100_000, now);
+
+        while bucket.try_consume(10_000, now) {}
+
+        now += Duration::from_millis(150);
+
+        assert!(bucket.try_consume(10_000, now));
+        assert!(!bucket.try_consume(10_000, now));
+    }
+
+    #[test]
+    fn dropped_packets_dont_consume_client_budget() {
+        let mut shaper = limited(BandwidthLimits {
+            client_ingress: Some(100_000),
+            resource_ingress: Some(100_000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let other_client = ClientId::from_u128(2);
+
+        while shaper.allow_ingress(other_client, Some(resource()), 10_000, now) {}
+
+        for _ in 0..10 {
+            assert!(!shaper.allow_ingress(client(), Some(resource()), 10_000, now));
+        }
+
+        assert!(shaper.allow_ingress(client(), Some(ResourceId::from_u128(2)), 10_000, now));
+    }
+
+    #[test]
+    fn retaining_resources_drops_their_buckets() {
+        let mut shaper = limited(BandwidthLimits {
+            resource_ingress: Some(100_000),
+            resource_egress: Some(100_000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let other_resource = ResourceId::from_u128(2);
+
+        shaper.allow_ingress(client(), Some(resource()), 10_000, now);
+        shaper.allow_egress(client(), Some(other_resource), 10_000, now);
+
+        shaper.retain_resources(|r| r == &resource());
+
+        assert!(shaper.resource_ingress.contains_key(&resource()));
+        assert!(!shaper.resource_egress.contains_key(&other_resource));
+    }
+
+    #[test]
+    fn reporting_metrics_resets_unreported_stats() {
+        let mut shaper = Shaper::default();
+        let now = Instant::now();
+
+        shaper.allow_ingress(client(), None, 1_000, now);
+        assert_eq!(shaper.unreported.ingress.forwarded_bytes, 1_000);
+
+        shaper.report_metrics();
+
+        assert_eq!(shaper.unreported, ShapingStats::default());
+        assert_eq!(shaper.stats()[&client()].ingress.forwarded_bytes, 1_000);
+    }
+
+    #[test]
+    fn stats_since_only_count_new_traffic() {
+        let mut shaper = limited(BandwidthLimits {
+            client_ingress: Some(100_000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        for _ in 0..11 {
+            shaper.allow_ingress(client(), None, 10_000, now);
+        }
+        let earlier = shaper.stats()[&client()];
+
+        shaper.allow_ingress(client(), None, 10_000, now);
+        let delta = shaper.stats()[&client()].since(&earlier);
+
+        assert_eq!(earlier.dropped_packets(), 1);
+        assert_eq!(delta.dropped_packets(), 1);
+        assert_eq!(delta.ingress.dropped_bytes, 10_000);
+        assert_eq!(delta.ingress.forwarded_packets, 0);
+        assert_eq!(earlier.since(&earlier), ShapingStats::default());
+    }
+
+    fn limited(limits: BandwidthLimits) -> Shaper {
+        let mut shaper = Shaper::default();
+        shaper.set_limits(limits);
+
+        shaper
+    }
+
+    fn client() -> ClientId {
+        ClientId::from_u128(1)
+    }
+
+    fn resource() -> ResourceId {
+        ResourceId::from_u128(1)
+    }
+}
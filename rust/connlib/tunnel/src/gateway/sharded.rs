@@ -0,0 +1,640 @@
+//! Shards the gateway's data plane across several [`GatewayTunnel`]s, each one driven by its own task.
+//!
+//! Every client is pinned to a single shard.
+//! Shards don't share any state: each one has its own [`GatewayState`](super::GatewayState), UDP sockets and relay allocations.
+//! Thus, the ICE candidates we send to a client point to the sockets of its shard and all network traffic of a client ends up at the right shard.
+//! Each relay is only used by some of the shards, so we don't make an allocation per relay on every shard.
+//!
+//! A shard's task owns its tunnel.
+//! The control plane never blocks on a busy shard, instead it sends commands to the shard's task.
+//!
+//! There is only a single TUN device though, possibly with several queues.
+//! A dedicated task per queue reads packets from it and dispatches them to the shard that owns the destination IP.
+//! Shards write packets to one of the queues directly.
+
+use super::{BandwidthLimits, ResourceBuckets, ShapingStats};
+use crate::{GatewayEvent, GatewayTunnel, BUF_SIZE};
+use anyhow::Context as _;
+use boringtun::x25519::PublicKey;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{
+    gateway::{ResolvedResourceDescriptionDns, ResourceDescription},
+    Answer, ClientId, Key, Offer, Relay, RelayId, ResourceId,
+};
+use connlib_shared::{DomainName, StaticSecret};
+use futures::channel::{mpsc as futures_mpsc, oneshot};
+use futures::{Future, StreamExt as _};
+use ip_packet::IpPacket;
+use secrecy::Secret;
+use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tun::Tun;
+
+/// How many packets we buffer per shard before we start dropping packets read from the TUN device.
+const DEVICE_CHANNEL_CAPACITY: usize = 1024;
+/// How many packets a dispatcher reads from its TUN queue at once.
+const DEVICE_READ_BATCH_SIZE: usize = 32;
+/// How long a dispatcher waits at most before it reads again from a TUN queue that keeps failing.
+const MAX_READ_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A mutation of a shard's tunnel, applied on the shard's task.
+type Command = Box<dyn FnOnce(&mut GatewayTunnel) + Send>;
+
+pub struct ShardedGatewayTunnel {
+    shards: Vec<Shard>,
+
+    /// The shard each client is assigned to, together with the tunnel IPs of the client.
+    clients: HashMap<ClientId, (usize, BTreeSet<IpAddr>)>,
+    /// Which shard packets to a certain tunnel IP need to be dispatched to.
+    routes: Arc<RwLock<HashMap<IpAddr, usize>>>,
+    next_shard: usize,
+
+    /// All relays we know about, there may be one entry per IP version.
+    relays: BTreeMap<RelayId, Vec<Relay>>,
+
+    events_rx: futures_mpsc::UnboundedReceiver<io::Result<GatewayEvent>>,
+    device_dispatchers: Vec<JoinHandle<()>>,
+}
+
+struct Shard {
+    commands: futures_mpsc::UnboundedSender<Command>,
+    /// The relays this shard makes allocations on.
+    relays: BTreeSet<RelayId>,
+    task: JoinHandle<()>,
+}
+
+impl ShardedGatewayTunnel {
+    /// Creates a new [`ShardedGatewayTunnel`] with `num_shards` shards.
+    ///
+    /// Must be called within a Tokio runtime context so we can bind the sockets and spawn the tasks driving the shards.
+    pub fn new(
+        private_key: StaticSecret,
+        tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
+        mtu: usize,
+        num_shards: NonZeroUsize,
+    ) -> Self {
+        let (events_tx, events_rx) = futures_mpsc::unbounded();
+        let resource_buckets = ResourceBuckets::default();
+
+        let shards = (0..num_shards.get())
+            .map(|_| {
+                let mut tunnel = GatewayTunnel::new(
+                    private_key.clone(),
+                    tcp_socket_factory.clone(),
+                    udp_socket_factory.clone(),
+                    mtu,
+                );
+                tunnel
+                    .role_state
+                    .share_resource_buckets(resource_buckets.clone());
+                let (commands, commands_rx) = futures_mpsc::unbounded();
+                let task = tokio::spawn(drive_shard(tunnel, commands_rx, events_tx.clone()));
+
+                Shard {
+                    commands,
+                    relays: BTreeSet::default(),
+                    task,
+                }
+            })
+            .collect();
+
+        tracing::info!(%num_shards, "Created gateway tunnel");
+
+        Self {
+            shards,
+            clients: HashMap::default(),
+            routes: Arc::default(),
+            next_shard: 0,
+            relays: BTreeMap::default(),
+            events_rx,
+            device_dispatchers: Vec::new(),
+        }
+    }
+
+    pub fn set_tun(&mut self, tun: Box<dyn Tun>) {
+        self.set_tun_queues(vec![tun]);
+    }
+
+    /// Uses all `queues` of a multi-queue TUN device.
+    ///
+    /// Each queue gets its own dispatcher and shards are spread evenly across the queues for writing.
+    pub fn set_tun_queues(&mut self, queues: Vec<Box<dyn Tun>>) {
+        let Some(name) = queues.first().map(|tun| tun.name().to_owned()) else {
+            tracing::warn!("Cannot use a TUN device without queues");
+            return;
+        };
+        let queues = queues
+            .into_iter()
+            .map(|tun| Arc::new(Mutex::new(tun)))
+            .collect::<Vec<_>>();
+
+        let mut senders = Vec::with_capacity(self.shards.len());
+
+        for (i, shard) in self.shards.iter().enumerate() {
+            let (tx, rx) = mpsc::channel(DEVICE_CHANNEL_CAPACITY);
+            senders.push(tx);
+
+            let shard_tun = Box::new(ShardTun {
+                name: name.clone(),
+                inner: queues[i % queues.len()].clone(),
+                rx,
+            });
+            shard.with_tunnel(move |tunnel| tunnel.set_tun(shard_tun));
+        }
+
+        tracing::debug!(num_queues = %queues.len(), "Dispatching packets from TUN device");
+
+        let dispatchers = queues
+            .into_iter()
+            .map(|tun| {
+                tokio::spawn(dispatch_device_packets(
+                    tun,
+                    senders.clone(),
+                    self.routes.clone(),
+                ))
+            })
+            .collect();
+
+        for previous in std::mem::replace(&mut self.device_dispatchers, dispatchers) {
+            previous.abort();
+        }
+    }
+
+    /// Accept a connection request from a client.
+    ///
+    /// The returned future resolves once the client's shard created the connection.
+    pub fn accept(
+        &mut self,
+        client_id: ClientId,
+        key: Secret<Key>,
+        offer: Offer,
+        client: PublicKey,
+    ) -> impl Future<Output = anyhow::Result<Answer>> + Send + 'static {
+        let num_shards = self.shards.len();
+        let (shard, _) = self.clients.entry(client_id).or_insert_with(|| {
+            let shard = self.next_shard;
+            self.next_shard = (shard + 1) % num_shards;
+
+            (shard, BTreeSet::new())
+        });
+
+        tracing::debug!(client = %client_id, %shard, "Assigned client to shard");
+
+        let answer =
+            self.shards[*shard].query(move |tunnel| tunnel.accept(client_id, key, offer, client));
+
+        async move { answer.await.context("Shard is gone") }
+    }
+
+    pub fn cleanup_connection(&mut self, id: &ClientId) {
+        let Some((shard, ips)) = self.clients.remove(id) else {
+            return;
+        };
+
+        let mut routes = write_routes(&self.routes);
+        for ip in ips {
+            routes.remove(&ip);
+        }
+        drop(routes);
+
+        let id = *id;
+        self.shards[shard].with_tunnel(move |tunnel| tunnel.cleanup_connection(&id));
+    }
+
+    /// Allows the client to access the given resource.
+    ///
+    /// The returned future resolves once the client's shard applied the access.
+    pub fn allow_access(
+        &mut self,
+        client: ClientId,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        domain: Option<(DomainName, Vec<IpAddr>)>,
+        expires_at: Option<DateTime<Utc>>,
+        resource: ResourceDescription<ResolvedResourceDescriptionDns>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let result = self.clients.get_mut(&client).map(|(shard, ips)| {
+            let mut routes = write_routes(&self.routes);
+            for ip in [IpAddr::from(ipv4), IpAddr::from(ipv6)] {
+                ips.insert(ip);
+                routes.insert(ip, *shard);
+            }
+            drop(routes);
+
+            self.shards[*shard].query(move |tunnel| {
+                tunnel.allow_access(client, ipv4, ipv6, domain, expires_at, resource)
+            })
+        });
+
+        async move {
+            result
+                .with_context(|| format!("Unknown client {client}"))?
+                .await
+                .context("Shard is gone")?
+        }
+    }
+
+    pub fn refresh_translation(
+        &mut self,
+        client: ClientId,
+        resource_id: ResourceId,
+        name: DomainName,
+        resolved_ips: Vec<IpAddr>,
+    ) {
+        self.with_client_shard(&client, move |tunnel| {
+            tunnel.refresh_translation(client, resource_id, name, resolved_ips)
+        });
+    }
+
+    pub fn update_resource(&mut self, resource: ResourceDescription) {
+        for shard in &self.shards {
+            let resource = resource.clone();
+            shard.with_tunnel(move |tunnel| tunnel.update_resource(resource));
+        }
+    }
+
+    pub fn remove_access(&mut self, client: &ClientId, resource: &ResourceId) {
+        let (client, resource) = (*client, *resource);
+        self.with_client_shard(&client, move |tunnel| {
+            tunnel.remove_access(&client, &resource)
+        });
+    }
+
+    pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
+        self.with_client_shard(&conn_id, move |tunnel| {
+            tunnel.add_ice_candidate(conn_id, ice_candidate)
+        });
+    }
+
+    pub fn remove_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String) {
+        self.with_client_shard(&conn_id, move |tunnel| {
+            tunnel.remove_ice_candidate(conn_id, ice_candidate)
+        });
+    }
+
+    /// Spreads the relays across the shards, see [`relays_of_shard`].
+    pub fn update_relays(&mut self, to_remove: BTreeSet<RelayId>, to_add: Vec<Relay>) {
+        for id in &to_remove {
+            self.relays.remove(id);
+        }
+
+        let mut added = BTreeMap::<RelayId, Vec<Relay>>::new();
+        for relay in to_add {
+            added.entry(relay_id(&relay)).or_default().push(relay);
+        }
+        self.relays.extend(added.clone());
+
+        let num_shards = self.shards.len();
+        let all_relays = self.relays.keys().copied().collect::<Vec<_>>();
+
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            let relays = relays_of_shard(i, num_shards, &all_relays);
+
+            let to_remove = shard
+                .relays
+                .difference(&relays)
+                .copied()
+                .collect::<BTreeSet<_>>();
+            // Relays we didn't use on this shard so far, or whose credentials changed.
+            let to_add = relays
+                .iter()
+                .filter(|id| !shard.relays.contains(id) || added.contains_key(id))
+                .flat_map(|id| self.relays[id].clone())
+                .collect::<Vec<_>>();
+
+            shard.relays = relays;
+
+            if to_remove.is_empty() && to_add.is_empty() {
+                continue;
+            }
+
+            shard.with_tunnel(move |tunnel| tunnel.update_relays(to_remove, to_add));
+        }
+    }
+
+    /// Applies the given bandwidth limits to all shards.
+    ///
+    /// Clients are pinned to a single shard, thus per-client limits are enforced by that shard alone.
+    /// Traffic to a single resource may come from clients on any shard, thus all shards draw from the same per-resource buckets.
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits) {
+        for shard in &self.shards {
+            shard.with_tunnel(move |tunnel| tunnel.set_bandwidth_limits(limits));
+        }
+    }
+
+    /// Per-client counters of forwarded and dropped (due to bandwidth limits) traffic, across all shards.
+    pub fn shaping_stats(
+        &self,
+    ) -> impl Future<Output = BTreeMap<ClientId, ShapingStats>> + Send + 'static {
+        let stats = self
+            .shards
+            .iter()
+            .map(|shard| shard.query(|tunnel| tunnel.shaping_stats().clone()))
+            .collect::<Vec<_>>();
+
+        async move {
+            futures::future::join_all(stats)
+                .await
+                .into_iter()
+                .flatten() // Skip shards that are gone.
+                .flatten()
+                .collect()
+        }
+    }
+
+    pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<GatewayEvent>> {
+        match self.events_rx.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(event),
+            Poll::Ready(None) => {
+                unreachable!("We hold a sender for as long as the shards are alive")
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn with_client_shard(
+        &self,
+        client: &ClientId,
+        f: impl FnOnce(&mut GatewayTunnel) + Send + 'static,
+    ) {
+        let Some((shard, _)) = self.clients.get(client) else {
+            tracing::debug!(%client, "Client is not assigned to a shard");
+            return;
+        };
+
+        self.shards[*shard].with_tunnel(f);
+    }
+}
+
+impl Drop for ShardedGatewayTunnel {
+    fn drop(&mut self) {
+        for shard in &self.shards {
+            shard.task.abort();
+        }
+
+        for dispatcher in &self.device_dispatchers {
+            dispatcher.abort();
+        }
+    }
+}
+
+impl Shard {
+    /// Runs `f` against this shard's tunnel, on the task driving it.
+    fn with_tunnel(&self, f: impl FnOnce(&mut GatewayTunnel) + Send + 'static) {
+        if self.commands.unbounded_send(Box::new(f)).is_err() {
+            tracing::debug!("Shard task is gone");
+        }
+    }
+
+    /// Like [`Shard::with_tunnel`] but hands the result of `f` back.
+    ///
+    /// The returned receiver fails if the shard's task is gone.
+    fn query<R>(
+        &self,
+        f: impl FnOnce(&mut GatewayTunnel) -> R + Send + 'static,
+    ) -> oneshot::Receiver<R>
+    where
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.with_tunnel(move |tunnel| {
+            let _ = tx.send(f(tunnel));
+        });
+
+        rx
+    }
+}
+
+/// Drives a single shard, applying all commands and forwarding all its events to the [`ShardedGatewayTunnel`].
+async fn drive_shard(
+    mut tunnel: GatewayTunnel,
+    mut commands: futures_mpsc::UnboundedReceiver<Command>,
+    events: futures_mpsc::UnboundedSender<io::Result<GatewayEvent>>,
+) {
+    std::future::poll_fn(|cx| {
+        loop {
+            match commands.poll_next_unpin(cx) {
+                Poll::Ready(Some(command)) => {
+                    command(&mut tunnel);
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(()), // The `ShardedGatewayTunnel` is gone.
+                Poll::Pending => {}
+            }
+
+            match tunnel.poll_next_event(cx) {
+                Poll::Ready(event) => {
+                    if events.unbounded_send(event).is_err() {
+                        return Poll::Ready(()); // The `ShardedGatewayTunnel` is gone.
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    })
+    .await
+}
+
+/// Reads packets from a TUN queue and dispatches them to the shard owning the destination IP.
+///
+/// Packets are handed to the shards as slices of a larger buffer, so we only allocate once per [`DEVICE_READ_BATCH_SIZE`] packets.
+async fn dispatch_device_packets(
+    tun: Arc<Mutex<Box<dyn Tun>>>,
+    shards: Vec<mpsc::Sender<Bytes>>,
+    routes: Arc<RwLock<HashMap<IpAddr, usize>>>,
+) {
+    let mut spare = BytesMut::new();
+    let mut bufs = Vec::<BytesMut>::with_capacity(DEVICE_READ_BATCH_SIZE);
+    let mut lens = [0; DEVICE_READ_BATCH_SIZE];
+    let mut backoff = Duration::ZERO;
+
+    loop {
+        while bufs.len() < DEVICE_READ_BATCH_SIZE {
+            if spare.len() < BUF_SIZE {
+                spare = BytesMut::zeroed(BUF_SIZE * DEVICE_READ_BATCH_SIZE);
+            }
+
+            bufs.push(spare.split_to(BUF_SIZE));
+        }
+
+        let result = std::future::poll_fn(|cx| {
+            let mut slices = bufs.iter_mut().map(|b| b.as_mut()).collect::<Vec<_>>();
+
+            tun.lock()
+                .expect("shards never panic while holding the lock")
+                .poll_read_many(&mut slices, &mut lens, cx)
+        })
+        .await;
+
+        let num_packets = match result {
+            Ok(0) => {
+                tracing::info!("TUN device is closed");
+                return;
+            }
+            Ok(num_packets) => {
+                backoff = Duration::ZERO;
+                num_packets
+            }
+            Err(e) => {
+                // Don't spin on errors that persist, e.g. because the device is gone.
+                backoff = (backoff * 2).clamp(Duration::from_millis(1), MAX_READ_ERROR_BACKOFF);
+                tracing::debug!(?backoff, "Failed to read from TUN device: {e}");
+
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+
+        let routes = routes
+            .read()
+            .expect("control plane never panics while holding the lock");
+
+        for (mut packet, len) in bufs.drain(..num_packets).zip(&lens[..num_packets]) {
+            packet.truncate(*len);
+            let packet = packet.freeze();
+
+            let Some(dst) = IpPacket::new(&packet).map(|p| p.destination()) else {
+                tracing::debug!("Received bytes are not an IP packet");
+                continue;
+            };
+
+            let Some(shard) = routes.get(&dst).copied() else {
+                tracing::trace!(%dst, "No shard for destination");
+                continue;
+            };
+
+            if shards[shard].try_send(packet).is_err() {
+                tracing::trace!(%dst, %shard, "Shard is busy, dropping packet");
+            }
+        }
+    }
+}
+
+fn write_routes(
+    routes: &RwLock<HashMap<IpAddr, usize>>,
+) -> RwLockWriteGuard<'_, HashMap<IpAddr, usize>> {
+    routes
+        .write()
+        .expect("we never panic while holding the lock")
+}
+
+fn relay_id(relay: &Relay) -> RelayId {
+    match relay {
+        Relay::Stun(stun) => stun.id,
+        Relay::Turn(turn) => turn.id,
+    }
+}
+
+/// The relays the given shard makes allocations on.
+///
+/// Each relay is assigned to a single shard, based on the hash of its ID so the assignment stays stable as relays come and go.
+/// A shard that doesn't get any relay that way borrows one, otherwise its clients couldn't connect through a relay at all.
+fn relays_of_shard(shard: usize, num_shards: usize, relays: &[RelayId]) -> BTreeSet<RelayId> {
+    let assigned = relays
+        .iter()
+        .copied()
+        .filter(|id| shard_of_relay(id, num_shards) == shard)
+        .collect::<BTreeSet<_>>();
+
+    if !assigned.is_empty() || relays.is_empty() {
+        return assigned;
+    }
+
+    BTreeSet::from([relays[shard % relays.len()]])
+}
+
+fn shard_of_relay(id: &RelayId, num_shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+
+    (hasher.finish() % num_shards as u64) as usize
+}
+
+/// A shard's view of the TUN device: packets are read from the dispatcher, writes go straight to the device.
+///
+/// The lock on a TUN queue is only ever held for non-blocking syscalls.
+struct ShardTun {
+    name: String,
+    inner: Arc<Mutex<Box<dyn Tun>>>,
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl ShardTun {
+    fn inner(&self) -> MutexGuard<'_, Box<dyn Tun>> {
+        self.inner
+            .lock()
+            .expect("neither shards nor the dispatcher panic while holding the lock")
+    }
+}
+
+impl Tun for ShardTun {
+    fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write4(buf)
+    }
+
+    fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write6(buf)
+    }
+
+    fn write_many(&self, packets: &[&[u8]]) -> io::Result<usize> {
+        self.inner().write_many(packets)
+    }
+
+    fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let Some(packet) = std::task::ready!(self.rx.poll_recv(cx)) else {
+            return Poll::Ready(Ok(0)); // The dispatcher is gone, signal EOF.
+        };
+
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_relay_is_used_by_a_shard() {
+        let relays = (0..10).map(RelayId::from_u128).collect::<Vec<_>>();
+
+        let assigned = (0..4)
+            .map(|shard| relays_of_shard(shard, 4, &relays))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            assigned.iter().flatten().copied().collect::<BTreeSet<_>>(),
+            BTreeSet::from_iter(relays.clone())
+        );
+        assert!(assigned.iter().map(|r| r.len()).sum::<usize>() <= relays.len() + 4);
+    }
+
+    #[test]
+    fn every_shard_gets_a_relay() {
+        let relays = vec![RelayId::from_u128(1)];
+
+        for shard in 0..8 {
+            assert_eq!(
+                relays_of_shard(shard, 8, &relays),
+                BTreeSet::from([relays[0]])
+            );
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{device_channel::Device, sockets::Sockets, BUF_SIZE};
 use futures_util::FutureExt as _;
 use ip_packet::{IpPacket, MutableIpPacket};
-use socket_factory::{DatagramIn, DatagramOut, SocketFactory, TcpSocket, UdpSocket};
+use socket_factory::{
+    BatchedDatagram, DatagramIn, DatagramOut, SocketFactory, TcpSocket, UdpSocket,
+};
 use std::{
     io,
     pin::Pin,
@@ -27,7 +29,7 @@ pub struct Io {
 
 pub enum Input<'a, I> {
     Timeout(Instant),
-    Device(MutableIpPacket<'a>),
+    Device(Vec<MutableIpPacket<'a>>),
     Network(I),
 }
 
@@ -68,8 +70,8 @@ impl Io {
 
         ready!(self.sockets.poll_flush(cx))?;
 
-        if let Poll::Ready(packet) = self.device.poll_read(device_buffer, cx)? {
-            return Poll::Ready(Ok(Input::Device(packet)));
+        if let Poll::Ready(packets) = self.device.poll_read_many(device_buffer, cx)? {
+            return Poll::Ready(Ok(Input::Device(packets)));
         }
 
         if let Some(timeout) = self.timeout.as_mut() {
@@ -109,11 +111,26 @@ impl Io {
             src: transmit.src,
             dst: transmit.dst,
             packet: transmit.payload,
+            segment_size: None,
         })?;
 
         Ok(())
     }
 
+    /// Sends a batch of transmits, using GSO for consecutive transmits to the same destination where possible.
+    /// Sends the datagrams laid out back-to-back in `buffer`.
+    ///
+    /// Consecutive datagrams to the same destination are sent with a single syscall where possible.
+    pub fn send_network_batch(
+        &mut self,
+        buffer: &[u8],
+        datagrams: &[BatchedDatagram],
+    ) -> io::Result<()> {
+        self.sockets.send_batch(buffer, datagrams)?;
+
+        Ok(())
+    }
+
     pub fn send_device(&self, packet: IpPacket<'_>) -> io::Result<()> {
         self.device.write(packet)?;
 
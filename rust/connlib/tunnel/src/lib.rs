@@ -8,13 +8,16 @@ use boringtun::x25519::StaticSecret;
 use chrono::Utc;
 use connlib_shared::{
     callbacks,
-    messages::{ClientId, GatewayId, Offer, Relay, RelayId, ResolveRequest, ResourceId, SecretKey},
-    DomainName, PublicKey, DEFAULT_MTU,
+    messages::{
+        client::GatewayMetrics, ClientId, GatewayId, Offer, Relay, RelayId, ResolveRequest,
+        ResourceId, SecretKey,
+    },
+    DomainName, PublicKey, DEFAULT_MTU, MAX_MTU,
 };
 use io::Io;
 use ip_network::{Ipv4Network, Ipv6Network};
 use rand::rngs::OsRng;
-use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
+use socket_factory::{BatchedDatagram, SocketFactory, TcpSocket, UdpSocket};
 use std::{
     collections::{BTreeMap, BTreeSet},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
@@ -57,13 +60,21 @@ const NAT46_OVERHEAD: usize = 20;
 /// TURN's data channels have a 4 byte overhead.
 const DATA_CHANNEL_OVERHEAD: usize = 4;
 
-const BUF_SIZE: usize = DEFAULT_MTU + WG_OVERHEAD + NAT46_OVERHEAD + DATA_CHANNEL_OVERHEAD;
+const BUF_SIZE: usize = MAX_MTU + WG_OVERHEAD + NAT46_OVERHEAD + DATA_CHANNEL_OVERHEAD;
+
+/// How many packets the gateway reads from the TUN device at most before encapsulating and sending them as a batch.
+///
+/// Also how many packets it decrypts at most before writing them to the TUN device as a batch.
+const MAX_DEVICE_BATCH: usize = 32;
 
 pub type GatewayTunnel = Tunnel<GatewayState>;
 pub type ClientTunnel = Tunnel<ClientState>;
 
-pub use client::ClientState;
-pub use gateway::{GatewayState, IPV4_PEERS, IPV6_PEERS};
+pub use client::{ClientState, ClientStats, GatewayStats, InterfaceStats, ResourceTraffic};
+pub use gateway::{
+    BandwidthLimits, DirectionStats, GatewayState, ShapingStats, ShardedGatewayTunnel, IPV4_PEERS,
+    IPV6_PEERS,
+};
 
 /// [`Tunnel`] glues together connlib's [`Io`] component and the respective (pure) state of a client or gateway.
 ///
@@ -81,8 +92,16 @@ pub struct Tunnel<TRoleState> {
     ip4_read_buf: Box<[u8; MAX_UDP_SIZE]>,
     ip6_read_buf: Box<[u8; MAX_UDP_SIZE]>,
 
-    /// Buffer for processing a single IP packet.
-    packet_buffer: Box<[u8; BUF_SIZE]>,
+    /// Buffer for processing IP packets.
+    ///
+    /// Packets are read from the TUN device in batches of up to one packet per [`BUF_SIZE`] chunk of this buffer.
+    packet_buffer: Box<[u8]>,
+    /// Buffer the gateway encrypts a batch of packets into, back-to-back, so they can be sent straight out of it.
+    ///
+    /// Empty on the client, it encrypts and sends one packet at a time.
+    encrypt_buffer: Box<[u8]>,
+    /// The datagrams in `encrypt_buffer`.
+    encrypted_datagrams: Vec<BatchedDatagram>,
 }
 
 impl ClientTunnel {
@@ -91,11 +110,17 @@ impl ClientTunnel {
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
         known_hosts: BTreeMap<String, Vec<IpAddr>>,
+        mtu: usize,
     ) -> Self {
+        let mut role_state = ClientState::new(private_key, known_hosts, rand::random());
+        role_state.set_mtu(clamp_mtu(mtu));
+
         Self {
             io: Io::new(tcp_socket_factory, udp_socket_factory),
-            role_state: ClientState::new(private_key, known_hosts, rand::random()),
-            packet_buffer: Box::new([0u8; BUF_SIZE]),
+            role_state,
+            packet_buffer: vec![0u8; BUF_SIZE].into_boxed_slice(),
+            encrypt_buffer: Box::default(),
+            encrypted_datagrams: Vec::new(),
             ip4_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip6_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
         }
@@ -138,12 +163,16 @@ impl ClientTunnel {
                     self.role_state.handle_timeout(timeout);
                     continue;
                 }
-                Poll::Ready(io::Input::Device(packet)) => {
-                    let Some(transmit) = self.role_state.encapsulate(packet, Instant::now()) else {
-                        continue;
-                    };
+                Poll::Ready(io::Input::Device(packets)) => {
+                    let now = Instant::now();
+
+                    for packet in packets {
+                        let Some(transmit) = self.role_state.encapsulate(packet, now) else {
+                            continue;
+                        };
 
-                    self.io.send_network(transmit)?;
+                        self.io.send_network(transmit)?;
+                    }
 
                     continue;
                 }
@@ -181,11 +210,17 @@ impl GatewayTunnel {
         private_key: StaticSecret,
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
+        mtu: usize,
     ) -> Self {
+        let mut role_state = GatewayState::new(private_key, rand::random());
+        role_state.set_mtu(clamp_mtu(mtu));
+
         Self {
             io: Io::new(tcp_socket_factory, udp_socket_factory),
-            role_state: GatewayState::new(private_key, rand::random()),
-            packet_buffer: Box::new([0u8; BUF_SIZE]),
+            role_state,
+            packet_buffer: vec![0u8; BUF_SIZE * MAX_DEVICE_BATCH].into_boxed_slice(),
+            encrypt_buffer: vec![0u8; BUF_SIZE * MAX_DEVICE_BATCH].into_boxed_slice(),
+            encrypted_datagrams: Vec::with_capacity(MAX_DEVICE_BATCH),
             ip4_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
             ip6_read_buf: Box::new([0u8; MAX_UDP_SIZE]),
         }
@@ -223,31 +258,63 @@ impl GatewayTunnel {
                     self.role_state.handle_timeout(timeout, Utc::now());
                     continue;
                 }
-                Poll::Ready(io::Input::Device(packet)) => {
-                    let Some(transmit) = self
-                        .role_state
-                        .encapsulate(packet, std::time::Instant::now())
-                    else {
-                        continue;
-                    };
+                Poll::Ready(io::Input::Device(mut packets)) => {
+                    let now = Instant::now();
 
-                    self.io.send_network(transmit)?;
+                    // Encrypt packets to the same client back-to-back so they can be sent as a single GSO batch.
+                    // The sort is stable, i.e. packets to the same client are not reordered.
+                    packets.sort_by_key(|packet| packet.destination());
 
-                    continue;
-                }
-                Poll::Ready(io::Input::Network(packets)) => {
-                    for received in packets {
-                        let Some(packet) = self.role_state.decapsulate(
-                            received.local,
-                            received.from,
-                            received.packet,
-                            std::time::Instant::now(),
-                            self.packet_buffer.as_mut(),
+                    self.encrypted_datagrams.clear();
+                    let mut len = 0;
+
+                    for packet in packets {
+                        let Some(transmit) = self.role_state.encapsulate_into(
+                            packet,
+                            now,
+                            &mut self.encrypt_buffer[len..],
                         ) else {
                             continue;
                         };
 
-                        self.io.device_mut().write(packet)?;
+                        let datagram = BatchedDatagram {
+                            src: transmit.src,
+                            dst: transmit.dst,
+                            len: transmit.payload.len(),
+                        };
+                        len += datagram.len;
+                        self.encrypted_datagrams.push(datagram);
+                    }
+
+                    self.io.send_network_batch(
+                        &self.encrypt_buffer[..len],
+                        &self.encrypted_datagrams,
+                    )?;
+
+                    continue;
+                }
+                Poll::Ready(io::Input::Network(packets)) => {
+                    let now = Instant::now();
+                    let mut packets = packets.peekable();
+
+                    // Decrypt up to one packet per `BUF_SIZE` chunk and write them as a batch, so the device can coalesce them.
+                    while packets.peek().is_some() {
+                        let decrypted = self
+                            .packet_buffer
+                            .chunks_exact_mut(BUF_SIZE)
+                            .zip(packets.by_ref())
+                            .filter_map(|(buffer, received)| {
+                                self.role_state.decapsulate(
+                                    received.local,
+                                    received.from,
+                                    received.packet,
+                                    now,
+                                    buffer,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+
+                        self.io.device_mut().write_many(&decrypted)?;
                     }
 
                     continue;
@@ -277,6 +344,8 @@ pub enum ClientEvent {
     ConnectionIntent {
         resource: ResourceId,
         connected_gateway_ids: BTreeSet<GatewayId>,
+        /// The link metrics of gateways we have measured.
+        gateway_metrics: Vec<GatewayMetrics>,
     },
     RequestAccess {
         /// The resource we want to access.
@@ -345,3 +414,14 @@ pub fn keypair() -> (StaticSecret, PublicKey) {
 
     (private_key, public_key)
 }
+
+/// Clamps a user-provided MTU to the range that our buffers are sized for.
+fn clamp_mtu(mtu: usize) -> usize {
+    let clamped = mtu.clamp(DEFAULT_MTU, MAX_MTU);
+
+    if clamped != mtu {
+        tracing::warn!(%mtu, %clamped, "MTU must be between {DEFAULT_MTU} and {MAX_MTU}");
+    }
+
+    clamped
+}
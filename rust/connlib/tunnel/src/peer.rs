@@ -144,6 +144,7 @@ impl ClientOnGateway {
             ipv6,
             resources: HashMap::new(),
             filters: IpNetworkTable::new(),
+            resource_by_ip: IpNetworkTable::new(),
             permanent_translations: Default::default(),
             nat_table: Default::default(),
             buffered_events: Default::default(),
@@ -259,6 +260,10 @@ impl ClientOnGateway {
         self.resources.is_empty()
     }
 
+    pub(crate) fn resources(&self) -> impl Iterator<Item = ResourceId> + '_ {
+        self.resources.keys().copied()
+    }
+
     pub(crate) fn expire_resources(&mut self, now: DateTime<Utc>) {
         for resource in self.resources.values_mut() {
             resource.retain(|r| !r.expires_at.is_some_and(|e| e <= now));
@@ -354,8 +359,15 @@ impl ClientOnGateway {
     // in case that 2 or more resources have overlapping rules.
     fn recalculate_filters(&mut self) {
         self.filters = IpNetworkTable::new();
-        for resource in self.resources.values().flatten() {
+        self.resource_by_ip = IpNetworkTable::new();
+        for (id, resource) in self
+            .resources
+            .iter()
+            .flat_map(|(id, r)| r.iter().map(move |r| (id, r)))
+        {
             for ip in &resource.ips {
+                self.resource_by_ip.insert(*ip, *id);
+
                 let mut filter_engine = FilterEngine::empty();
                 let filters = self.resources.values().flatten().filter_map(|r| {
                     r.ips
@@ -464,6 +476,15 @@ impl ClientOnGateway {
         Ok(())
     }
 
+    /// The resource the given (real) IP belongs to, if any.
+    ///
+    /// In case of overlapping resources, the most specific one wins.
+    pub(crate) fn resource_by_ip(&self, ip: IpAddr) -> Option<ResourceId> {
+        self.resource_by_ip
+            .longest_match(ip)
+            .map(|(_, resource)| *resource)
+    }
+
     pub fn id(&self) -> ClientId {
         self.id
     }
@@ -598,6 +619,7 @@ pub struct ClientOnGateway {
     ipv6: Ipv6Addr,
     resources: HashMap<ResourceId, Vec<ResourceOnGateway>>,
     filters: IpNetworkTable<FilterEngine>,
+    resource_by_ip: IpNetworkTable<ResourceId>,
     permanent_translations: BTreeMap<IpAddr, TranslationState>,
     nat_table: NatTable,
     buffered_events: VecDeque<GatewayEvent>,
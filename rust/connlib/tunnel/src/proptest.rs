@@ -49,6 +49,7 @@ pub fn dns_resource(
                 name,
                 sites,
                 address_description,
+                health_check: None,
             }
         })
 }
@@ -71,6 +72,7 @@ pub fn cidr_resource(
                 name,
                 sites,
                 address_description,
+                health_check: None,
             }
         })
 }
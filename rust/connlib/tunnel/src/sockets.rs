@@ -1,4 +1,4 @@
-use socket_factory::{DatagramIn, DatagramOut, SocketFactory, UdpSocket};
+use socket_factory::{BatchedDatagram, DatagramIn, DatagramOut, SocketFactory, UdpSocket};
 use std::{
     io,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
@@ -61,7 +61,29 @@ impl Sockets {
     }
 
     pub fn send(&mut self, datagram: DatagramOut) -> io::Result<()> {
-        let socket = match datagram.dst {
+        self.socket_for(datagram.dst)?.send(datagram)?;
+
+        Ok(())
+    }
+
+    /// Sends a batch of datagrams that are laid out back-to-back in `buffer`, see [`UdpSocket::send_batch`].
+    pub fn send_batch(&mut self, buffer: &[u8], datagrams: &[BatchedDatagram]) -> io::Result<()> {
+        let mut start = 0;
+
+        for run in datagrams.chunk_by(|a, b| a.dst.is_ipv4() == b.dst.is_ipv4()) {
+            let len = run.iter().map(|d| d.len).sum::<usize>();
+
+            self.socket_for(run[0].dst)?
+                .send_batch(&buffer[start..start + len], run.iter().copied())?;
+
+            start += len;
+        }
+
+        Ok(())
+    }
+
+    fn socket_for(&mut self, dst: SocketAddr) -> io::Result<&mut UdpSocket> {
+        let socket = match dst {
             SocketAddr::V4(dst) => self.socket_v4.as_mut().ok_or(io::Error::new(
                 io::ErrorKind::NotConnected,
                 format!("failed send packet to {dst}: no IPv4 socket"),
@@ -71,9 +93,8 @@ impl Sockets {
                 format!("failed send packet to {dst}: no IPv6 socket"),
             ))?,
         };
-        socket.send(datagram)?;
 
-        Ok(())
+        Ok(socket)
     }
 
     pub fn poll_recv_from<'b>(
@@ -632,6 +632,7 @@ impl TunnelTest {
             ClientEvent::ConnectionIntent {
                 resource,
                 connected_gateway_ids,
+                ..
             } => {
                 let (gateway, site) =
                     portal.handle_connection_intent(resource, connected_gateway_ids);
@@ -0,0 +1,185 @@
+//! The optional TOML config file of the Gateway, `/etc/firezone/gateway.toml` by default
+//!
+//! CLI args and env vars override the file. On SIGHUP we read it again and apply
+//! the log filter and bandwidth limits. Everything else needs a restart.
+
+use crate::{BandwidthArgs, Cli};
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+use connlib_shared::{DEFAULT_MTU, MAX_MTU};
+use firezone_bin_shared::config_file;
+use firezone_tunnel::BandwidthLimits;
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_PATH: &str = "/etc/firezone/gateway.toml";
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) api_url: Option<url::Url>,
+    /// A file containing the token, instead of passing it in `FIREZONE_TOKEN`
+    pub(crate) token_path: Option<PathBuf>,
+    pub(crate) firezone_id: Option<String>,
+    pub(crate) firezone_name: Option<String>,
+    /// Used if `RUST_LOG` is not set
+    pub(crate) log_filter: Option<String>,
+    pub(crate) mtu: Option<usize>,
+    pub(crate) num_shards: Option<NonZeroUsize>,
+    pub(crate) tun_offload: Option<bool>,
+    pub(crate) health_check_addr: Option<SocketAddr>,
+    pub(crate) client_ingress_limit: Option<u64>,
+    pub(crate) client_egress_limit: Option<u64>,
+    pub(crate) resource_ingress_limit: Option<u64>,
+    pub(crate) resource_egress_limit: Option<u64>,
+}
+
+impl Config {
+    /// Reads the config file from `path`, or from the default path if that's `None`
+    ///
+    /// Only the default config file is allowed to be missing.
+    pub(crate) fn read(path: Option<&Path>) -> Result<Self> {
+        let config: Self = match path {
+            Some(path) => config_file::read(path, true)?,
+            None => config_file::read(Path::new(DEFAULT_PATH), false)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(mtu) = self.mtu {
+            if !(DEFAULT_MTU..=MAX_MTU).contains(&mtu) {
+                bail!("`mtu` in the config file must be between {DEFAULT_MTU} and {MAX_MTU}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in the CLI args that the user didn't set on the CLI or in env vars
+    ///
+    /// Bandwidth limits are left alone, see [`Config::bandwidth_limits`].
+    pub(crate) fn apply_to_cli(&self, cli: &mut Cli, matches: &ArgMatches) {
+        config_file::merge(matches, "api_url", &mut cli.api_url, self.api_url.clone());
+        config_file::merge(matches, "mtu", &mut cli.mtu, self.mtu);
+        config_file::merge(
+            matches,
+            "tun_offload",
+            &mut cli.tun_offload,
+            self.tun_offload,
+        );
+        config_file::merge(
+            matches,
+            "health_check_addr",
+            &mut cli.health_check.health_check_addr,
+            self.health_check_addr,
+        );
+
+        cli.token_path = cli.token_path.take().or_else(|| self.token_path.clone());
+        cli.firezone_id = cli.firezone_id.take().or_else(|| self.firezone_id.clone());
+        cli.firezone_name = cli
+            .firezone_name
+            .take()
+            .or_else(|| self.firezone_name.clone());
+        cli.num_shards = cli.num_shards.or(self.num_shards);
+    }
+
+    /// Bandwidth limits from the CLI, falling back to the ones in the config file
+    ///
+    /// Kept separate from [`Config::apply_to_cli`] so we can re-compute them on SIGHUP.
+    pub(crate) fn bandwidth_limits(&self, cli: &BandwidthArgs) -> BandwidthLimits {
+        BandwidthArgs {
+            client_ingress_limit: cli.client_ingress_limit.or(self.client_ingress_limit),
+            client_egress_limit: cli.client_egress_limit.or(self.client_egress_limit),
+            resource_ingress_limit: cli.resource_ingress_limit.or(self.resource_ingress_limit),
+            resource_egress_limit: cli.resource_egress_limit.or(self.resource_egress_limit),
+        }
+        .limits()
+    }
+
+    /// Returns the names of settings that changed in `new` but only take effect after a restart
+    pub(crate) fn needs_restart(&self, new: &Self) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.api_url != new.api_url {
+            changed.push("api_url");
+        }
+        if self.token_path != new.token_path {
+            changed.push("token_path");
+        }
+        if self.firezone_id != new.firezone_id {
+            changed.push("firezone_id");
+        }
+        if self.firezone_name != new.firezone_name {
+            changed.push("firezone_name");
+        }
+        if self.mtu != new.mtu {
+            changed.push("mtu");
+        }
+        if self.num_shards != new.num_shards {
+            changed.push("num_shards");
+        }
+        if self.tun_offload != new.tun_offload {
+            changed.push("tun_offload");
+        }
+        if self.health_check_addr != new.health_check_addr {
+            changed.push("health_check_addr");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Config {
+        let config: Config = config_file::parse(s).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    #[test]
+    fn cli_limits_override_file() {
+        let config = parse(
+            r#"
+            client_ingress_limit = 1000
+            resource_egress_limit = 5000
+            "#,
+        );
+        let cli = BandwidthArgs {
+            client_ingress_limit: Some(2000),
+            client_egress_limit: None,
+            resource_ingress_limit: None,
+            resource_egress_limit: None,
+        };
+
+        assert_eq!(
+            config.bandwidth_limits(&cli),
+            BandwidthLimits {
+                client_ingress: Some(2000),
+                client_egress: None,
+                resource_ingress: None,
+                resource_egress: Some(5000),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_mtu() {
+        let config: Config = config_file::parse("mtu = 65536").unwrap();
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn limits_can_change_without_restart() {
+        let old = parse("client_ingress_limit = 1000\nmtu = 1280");
+        let new = parse("client_ingress_limit = 2000\nmtu = 1280");
+
+        assert!(old.needs_restart(&new).is_empty());
+    }
+}
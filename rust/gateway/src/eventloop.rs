@@ -4,17 +4,19 @@ use crate::messages::{
 };
 use anyhow::Result;
 use boringtun::x25519::PublicKey;
+use connlib_shared::control_plane::ControlPlane;
 use connlib_shared::messages::{
-    ClientId, ConnectionAccepted, Interface, RelaysPresence, ResourceAccepted, ResourceId,
+    Answer, ClientId, ConnectionAccepted, DomainResponse, Interface, RelaysPresence,
+    ResourceAccepted, ResourceId,
 };
 use connlib_shared::{messages::GatewayResponse, DomainName};
 #[cfg(not(target_os = "windows"))]
 use dns_lookup::{AddrInfoHints, AddrInfoIter, LookupError};
-use firezone_tunnel::GatewayTunnel;
+use firezone_tunnel::{BandwidthLimits, ShapingStats, ShardedGatewayTunnel};
 use futures::channel::mpsc;
+use futures::StreamExt as _;
 use futures_bounded::Timeout;
-use phoenix_channel::PhoenixChannel;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::Infallible;
 use std::net::IpAddr;
 use std::task::{Context, Poll};
@@ -22,9 +24,17 @@ use std::time::Duration;
 
 pub const PHOENIX_TOPIC: &str = "gateway";
 
+/// How often we log the per-client bandwidth shaping counters.
+const SHAPING_STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
 /// How long we allow a DNS resolution via `libc::get_addr_info`.
 const DNS_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How long we wait for a shard of the tunnel to apply a connection request or an access grant.
+///
+/// Shards apply them in between handling packets, so this only elapses if a shard is stuck.
+const SHARD_TIMEOUT: Duration = Duration::from_secs(5);
+
 // DNS resolution happens as part of every connection setup.
 // For a connection to succeed, DNS resolution must be less than `snownet`'s handshake timeout.
 static_assertions::const_assert!(
@@ -38,32 +48,75 @@ enum ResolveTrigger {
     Refresh(DomainName, ClientId, ResourceId),
 }
 
-pub struct Eventloop {
-    tunnel: GatewayTunnel,
-    portal: PhoenixChannel<(), IngressMessages, ()>,
+/// What we reply to the portal once the client's shard accepted a connection.
+#[derive(Debug)]
+struct ConnectionReply {
+    reference: String,
+    client: ClientId,
+    domain_response: Option<DomainResponse>,
+}
+
+/// What we reply to the portal once the client's shard allowed access to a resource.
+#[derive(Debug)]
+struct AccessReply {
+    reference: String,
+    domain_response: Option<DomainResponse>,
+}
+
+pub struct Eventloop<P> {
+    tunnel: ShardedGatewayTunnel,
+    portal: P,
     tun_device_channel: mpsc::Sender<Interface>,
+    /// New bandwidth limits from reloading the config file
+    bandwidth_limits_rx: mpsc::Receiver<BandwidthLimits>,
 
     resolve_tasks: futures_bounded::FuturesTupleSet<Vec<IpAddr>, ResolveTrigger>,
+    connection_tasks: futures_bounded::FuturesTupleSet<Result<Answer>, ConnectionReply>,
+    access_tasks: futures_bounded::FuturesTupleSet<Result<()>, AccessReply>,
+
+    shaping_stats_log_interval: tokio::time::Interval,
+    shaping_stats: futures_bounded::FuturesSet<BTreeMap<ClientId, ShapingStats>>,
+    /// The shaping counters as of the last time we logged them.
+    logged_shaping_stats: BTreeMap<ClientId, ShapingStats>,
 }
 
-impl Eventloop {
+impl<P> Eventloop<P> {
     pub(crate) fn new(
-        tunnel: GatewayTunnel,
-        portal: PhoenixChannel<(), IngressMessages, ()>,
+        tunnel: ShardedGatewayTunnel,
+        portal: P,
         tun_device_channel: mpsc::Sender<Interface>,
+        bandwidth_limits_rx: mpsc::Receiver<BandwidthLimits>,
     ) -> Self {
         Self {
             tunnel,
             portal,
+            bandwidth_limits_rx,
             resolve_tasks: futures_bounded::FuturesTupleSet::new(DNS_RESOLUTION_TIMEOUT, 100),
+            connection_tasks: futures_bounded::FuturesTupleSet::new(SHARD_TIMEOUT, 100),
+            access_tasks: futures_bounded::FuturesTupleSet::new(SHARD_TIMEOUT, 100),
             tun_device_channel,
+            shaping_stats_log_interval: tokio::time::interval(SHAPING_STATS_LOG_INTERVAL),
+            shaping_stats: futures_bounded::FuturesSet::new(SHARD_TIMEOUT, 1),
+            logged_shaping_stats: BTreeMap::default(),
         }
     }
 }
 
-impl Eventloop {
+impl<P> Eventloop<P>
+where
+    P: ControlPlane<EgressMessages, IngressMessages, ()>,
+{
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<Infallible>> {
         loop {
+            match self.bandwidth_limits_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(limits)) => {
+                    tracing::info!(?limits, "Applying new bandwidth limits");
+                    self.tunnel.set_bandwidth_limits(limits);
+                    continue;
+                }
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+
             match self.tunnel.poll_next_event(cx) {
                 Poll::Ready(Ok(event)) => {
                     self.handle_tunnel_event(event);
@@ -92,6 +145,22 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            match self.connection_tasks.poll_unpin(cx) {
+                Poll::Ready((result, reply)) => {
+                    self.connection_ready(result, reply);
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
+            match self.access_tasks.poll_unpin(cx) {
+                Poll::Ready((result, reply)) => {
+                    self.access_ready(result, reply);
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
             match self.portal.poll(cx)? {
                 Poll::Ready(event) => {
                     self.handle_portal_event(event);
@@ -100,6 +169,28 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            if self.shaping_stats_log_interval.poll_tick(cx).is_ready() {
+                if self
+                    .shaping_stats
+                    .try_push(self.tunnel.shaping_stats())
+                    .is_err()
+                {
+                    tracing::debug!("Still waiting for the last shaping stats");
+                }
+                continue;
+            }
+
+            if let Poll::Ready(stats) = self.shaping_stats.poll_unpin(cx) {
+                match stats {
+                    Ok(stats) => {
+                        log_shaping_stats(&stats, &self.logged_shaping_stats);
+                        self.logged_shaping_stats = stats;
+                    }
+                    Err(e) => tracing::debug!("Failed to collect shaping stats: {e}"),
+                }
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
@@ -110,25 +201,24 @@ impl Eventloop {
                 conn_id: client,
                 candidates,
             } => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::BroadcastIceCandidates(ClientsIceCandidates {
+                self.portal.send(EgressMessages::BroadcastIceCandidates(
+                    ClientsIceCandidates {
                         client_ids: vec![client],
                         candidates,
-                    }),
-                );
+                    },
+                ));
             }
             firezone_tunnel::GatewayEvent::RemovedIceCandidates {
                 conn_id: client,
                 candidates,
             } => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::BroadcastInvalidatedIceCandidates(ClientsIceCandidates {
-                        client_ids: vec![client],
-                        candidates,
-                    }),
-                );
+                self.portal
+                    .send(EgressMessages::BroadcastInvalidatedIceCandidates(
+                        ClientsIceCandidates {
+                            client_ids: vec![client],
+                            candidates,
+                        },
+                    ));
             }
             firezone_tunnel::GatewayEvent::RefreshDns {
                 name,
@@ -252,6 +342,7 @@ impl Eventloop {
             }
             phoenix_channel::Event::SuccessResponse { res: (), .. }
             | phoenix_channel::Event::HeartbeatSent
+            | phoenix_channel::Event::Reconnecting
             | phoenix_channel::Event::JoinedRoom { .. } => {}
         }
     }
@@ -264,43 +355,69 @@ impl Eventloop {
         let addresses = result
             .inspect_err(|e| tracing::debug!(client = %req.client.id, reference = %req.reference, "DNS resolution timed out as part of connection request: {e}"))
             .unwrap_or_default();
+        let client = req.client.id;
 
         let answer = self.tunnel.accept(
-            req.client.id,
+            client,
             req.client.peer.preshared_key,
             req.client.payload.ice_parameters,
             PublicKey::from(req.client.peer.public_key.0),
         );
-
-        match self.tunnel.allow_access(
-            req.client.id,
+        let access = self.tunnel.allow_access(
+            client,
             req.client.peer.ipv4,
             req.client.peer.ipv6,
             req.client.payload.domain.as_ref().map(|r| r.as_tuple()),
             req.expires_at,
             req.resource.into_resolved(addresses.clone()),
-        ) {
-            Ok(()) => {
-                self.portal.send(
-                    PHOENIX_TOPIC,
-                    EgressMessages::ConnectionReady(ConnectionReady {
-                        reference: req.reference,
+        );
+        let reply = ConnectionReply {
+            reference: req.reference,
+            client,
+            domain_response: req.client.payload.domain.map(|r| DomainResponse {
+                domain: r.name(),
+                address: addresses,
+            }),
+        };
+
+        if self
+            .connection_tasks
+            .try_push(
+                async move {
+                    let answer = answer.await?;
+                    access.await?;
+
+                    Ok(answer)
+                },
+                reply,
+            )
+            .is_err()
+        {
+            tracing::warn!(%client, "Too many connections requests, dropping new one");
+            self.tunnel.cleanup_connection(&client);
+        }
+    }
+
+    fn connection_ready(
+        &mut self,
+        result: Result<Result<Answer>, Timeout>,
+        reply: ConnectionReply,
+    ) {
+        match result.map_err(anyhow::Error::new).and_then(|r| r) {
+            Ok(answer) => {
+                self.portal
+                    .send(EgressMessages::ConnectionReady(ConnectionReady {
+                        reference: reply.reference,
                         gateway_payload: GatewayResponse::ConnectionAccepted(ConnectionAccepted {
                             ice_parameters: answer,
-                            domain_response: req.client.payload.domain.map(|r| {
-                                connlib_shared::messages::DomainResponse {
-                                    domain: r.name(),
-                                    address: addresses,
-                                }
-                            }),
+                            domain_response: reply.domain_response,
                         }),
-                    }),
-                );
+                    }));
 
                 // TODO: If outbound request fails, cleanup connection.
             }
             Err(e) => {
-                let client = req.client.id;
+                let client = reply.client;
 
                 self.tunnel.cleanup_connection(&client);
                 tracing::debug!(%client, "Connection request failed: {e:#}");
@@ -313,30 +430,45 @@ impl Eventloop {
             .inspect_err(|e| tracing::debug!(client = %req.client_id, reference = %req.reference, "DNS resolution timed out as part of allow access request: {e}"))
             .unwrap_or_default();
 
-        if let (Ok(()), Some(resolve_request)) = (
-            self.tunnel.allow_access(
-                req.client_id,
-                req.client_ipv4,
-                req.client_ipv6,
-                req.payload.as_ref().map(|r| r.as_tuple()),
-                req.expires_at,
-                req.resource.into_resolved(addresses.clone()),
-            ),
-            req.payload,
-        ) {
-            self.portal.send(
-                PHOENIX_TOPIC,
-                EgressMessages::ConnectionReady(ConnectionReady {
-                    reference: req.reference,
-                    gateway_payload: GatewayResponse::ResourceAccepted(ResourceAccepted {
-                        domain_response: connlib_shared::messages::DomainResponse {
-                            domain: resolve_request.name(),
-                            address: addresses,
-                        },
-                    }),
-                }),
-            );
+        let access = self.tunnel.allow_access(
+            req.client_id,
+            req.client_ipv4,
+            req.client_ipv6,
+            req.payload.as_ref().map(|r| r.as_tuple()),
+            req.expires_at,
+            req.resource.into_resolved(addresses.clone()),
+        );
+        let reply = AccessReply {
+            reference: req.reference,
+            domain_response: req.payload.map(|r| DomainResponse {
+                domain: r.name(),
+                address: addresses,
+            }),
+        };
+
+        if self.access_tasks.try_push(access, reply).is_err() {
+            tracing::warn!(client = %req.client_id, "Too many allow access requests, dropping new one");
+        }
+    }
+
+    fn access_ready(&mut self, result: Result<Result<()>, Timeout>, reply: AccessReply) {
+        if let Err(e) = result.map_err(anyhow::Error::new).and_then(|r| r) {
+            tracing::debug!("Allow access request failed: {e:#}");
+            return;
         }
+
+        // Only DNS resources need a reply.
+        let Some(domain_response) = reply.domain_response else {
+            return;
+        };
+
+        self.portal
+            .send(EgressMessages::ConnectionReady(ConnectionReady {
+                reference: reply.reference,
+                gateway_payload: GatewayResponse::ResourceAccepted(ResourceAccepted {
+                    domain_response,
+                }),
+            }));
     }
 
     pub fn refresh_translation(
@@ -355,6 +487,31 @@ impl Eventloop {
     }
 }
 
+/// Logs what each client's shaping counters did since the last report, for clients that had packets dropped in the meantime.
+fn log_shaping_stats(
+    stats: &BTreeMap<ClientId, ShapingStats>,
+    last: &BTreeMap<ClientId, ShapingStats>,
+) {
+    for (client, stats) in stats {
+        let stats = stats.since(&last.get(client).copied().unwrap_or_default());
+
+        if stats.dropped_packets() == 0 {
+            continue;
+        }
+
+        tracing::info!(
+            %client,
+            ingress_forwarded_bytes = %stats.ingress.forwarded_bytes,
+            ingress_dropped_bytes = %stats.ingress.dropped_bytes,
+            ingress_dropped_packets = %stats.ingress.dropped_packets,
+            egress_forwarded_bytes = %stats.egress.forwarded_bytes,
+            egress_dropped_bytes = %stats.egress.dropped_bytes,
+            egress_dropped_packets = %stats.egress.dropped_packets,
+            "Bandwidth limits exceeded"
+        );
+    }
+}
+
 async fn resolve(domain: Option<DomainName>) -> Vec<IpAddr> {
     let Some(domain) = domain.clone() else {
         return vec![];
@@ -1,31 +1,40 @@
+use crate::config::Config;
 use crate::eventloop::{Eventloop, PHOENIX_TOPIC};
 use anyhow::{Context, Result};
 use backoff::ExponentialBackoffBuilder;
-use clap::Parser;
-use connlib_shared::{get_user_agent, messages::Interface, LoginUrl, StaticSecret, DEFAULT_MTU};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use connlib_shared::{
+    get_user_agent, messages::Interface, LoginUrl, StaticSecret, DEFAULT_MTU, MAX_MTU,
+};
 use firezone_bin_shared::{
     http_health_check,
     linux::{tcp_socket_factory, udp_socket_factory},
-    TunDeviceManager,
+    otlp, TunDeviceManager,
 };
-use firezone_tunnel::{keypair, GatewayTunnel, IPV4_PEERS, IPV6_PEERS};
+use firezone_logging::{ship, FilterReloadHandle, LogFormat};
+use firezone_tunnel::{keypair, BandwidthLimits, ShardedGatewayTunnel, IPV4_PEERS, IPV6_PEERS};
 
 use futures::channel::mpsc;
-use futures::{future, StreamExt, TryFutureExt};
+use futures::{future, SinkExt, StreamExt, TryFutureExt};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
 use std::convert::Infallible;
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::signal::ctrl_c;
-use tracing_subscriber::layer;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tun::Tun;
 use url::Url;
 use uuid::Uuid;
 
+mod config;
 mod eventloop;
 mod messages;
+#[cfg(test)]
+mod static_control_plane;
 
 const ID_PATH: &str = "/var/lib/firezone/gateway_id";
 
@@ -47,22 +56,47 @@ async fn main() {
 }
 
 async fn try_main() -> Result<()> {
-    let cli = Cli::parse();
-    firezone_logging::setup_global_subscriber(layer::Identity::new());
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    let config = Config::read(cli.config.as_deref());
+    // Set up logging before bailing out on a broken config file, so the error gets logged
+    let (log_filter_reloader, _log_shipper) = setup_logging(config.as_ref().ok(), &cli)?;
+    let config = config?;
+    config.apply_to_cli(&mut cli, &matches);
 
+    let token = get_token(cli.token.take(), cli.token_path.as_deref()).await?;
     let firezone_id = get_firezone_id(cli.firezone_id).await
         .context("Couldn't read FIREZONE_ID or write it to disk: Please provide it through the env variable or provide rw access to /var/lib/firezone/")?;
 
     let (private_key, public_key) = keypair();
     let login = LoginUrl::gateway(
         cli.api_url,
-        &SecretString::new(cli.token),
+        &token,
         firezone_id,
         cli.firezone_name,
         public_key.to_bytes(),
     )?;
 
-    let task = tokio::spawn(run(login, private_key)).err_into();
+    let (bandwidth_limits_tx, bandwidth_limits_rx) = mpsc::channel(1);
+    let task = tokio::spawn(run(
+        login,
+        private_key,
+        config.bandwidth_limits(&cli.bandwidth),
+        bandwidth_limits_rx,
+        cli.mtu,
+        cli.num_shards.unwrap_or_else(default_num_shards),
+        cli.tun_offload,
+    ))
+    .err_into();
+
+    tokio::spawn(reload_config_on_sighup(
+        signal(SignalKind::hangup())?,
+        cli.config,
+        config,
+        cli.bandwidth,
+        bandwidth_limits_tx,
+        log_filter_reloader,
+    ));
 
     let ctrl_c = pin!(ctrl_c().map_err(anyhow::Error::new));
 
@@ -84,6 +118,135 @@ async fn try_main() -> Result<()> {
     Ok(())
 }
 
+/// `RUST_LOG` overrides the config file, the same as other env vars
+fn log_filter(config: Option<&Config>) -> String {
+    std::env::var("RUST_LOG")
+        .ok()
+        .or_else(|| config.and_then(|config| config.log_filter.clone()))
+        .unwrap_or_default()
+}
+
+fn setup_logging(
+    config: Option<&Config>,
+    cli: &Cli,
+) -> Result<(FilterReloadHandle, Option<ship::Handle>)> {
+    let (ship_layer, ship_handle, ship_error) = match cli
+        .log_ship
+        .as_ref()
+        .map(|destination| {
+            ship::layer(
+                destination,
+                "firezone-gateway",
+                Arc::new(tcp_socket_factory),
+                Arc::new(udp_socket_factory),
+            )
+        })
+        .transpose()
+    {
+        Ok(shipper) => {
+            let (layer, handle) = shipper.unzip();
+            (layer, handle, None)
+        }
+        Err(error) => (None, None, Some(error)),
+    };
+    let (otlp_layer, otlp_error) = match cli
+        .otlp_grpc_endpoint
+        .as_deref()
+        .map(|endpoint| otlp::layer(endpoint, "firezone-gateway"))
+        .transpose()
+    {
+        Ok(layer) => (layer, None),
+        Err(error) => (None, Some(error)),
+    };
+
+    let directives = log_filter(config);
+    let filter_error = firezone_logging::try_filter(&directives).err();
+    let reloader = firezone_logging::setup_reloadable_global_subscriber(
+        cli.log_format,
+        if filter_error.is_some() {
+            ""
+        } else {
+            &directives
+        },
+        ship_layer.into_iter().chain(otlp_layer).collect::<Vec<_>>(),
+    )?;
+    if let Some(error) = filter_error {
+        tracing::error!(%error, %directives, "Invalid log filter, using the default");
+    }
+    // Only now that logging works, so the error gets logged
+    if let Some(error) = ship_error {
+        return Err(anyhow::Error::new(error).context("Couldn't set up log shipping"));
+    }
+    if let Some(error) = otlp_error {
+        return Err(error.context("Couldn't set up OTLP export"));
+    }
+
+    Ok((reloader, ship_handle))
+}
+
+async fn get_token(token: Option<String>, token_path: Option<&Path>) -> Result<SecretString> {
+    if let Some(token) = token {
+        return Ok(SecretString::new(token));
+    }
+    let path =
+        token_path.context("No token: Set FIREZONE_TOKEN or `token_path` in the config file")?;
+    let token = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Couldn't read token from `{}`", path.display()))?;
+
+    Ok(SecretString::new(token.trim().to_owned()))
+}
+
+/// Reads the config file again on every SIGHUP and applies the log filter and bandwidth limits
+///
+/// If the file is broken, we keep running with the old settings.
+async fn reload_config_on_sighup(
+    mut sighup: Signal,
+    path: Option<PathBuf>,
+    mut config: Config,
+    cli_bandwidth: BandwidthArgs,
+    mut bandwidth_limits_tx: mpsc::Sender<BandwidthLimits>,
+    log_filter_reloader: FilterReloadHandle,
+) {
+    while sighup.recv().await.is_some() {
+        tracing::info!("Caught SIGHUP, reloading config file");
+        let new_config = match Config::read(path.as_deref()) {
+            Ok(x) => x,
+            Err(error) => {
+                tracing::error!("Couldn't reload config file, keeping the old settings: {error:#}");
+                continue;
+            }
+        };
+
+        let needs_restart = config.needs_restart(&new_config);
+        if !needs_restart.is_empty() {
+            tracing::warn!(
+                settings = ?needs_restart,
+                "Some settings only take effect after restarting the Gateway"
+            );
+        }
+        if new_config.log_filter != config.log_filter {
+            let directives = log_filter(Some(&new_config));
+            match firezone_logging::try_filter(&directives) {
+                Ok(filter) => {
+                    if let Err(e) = log_filter_reloader.reload(filter) {
+                        tracing::error!("Couldn't reload log filter: {e}");
+                    }
+                }
+                Err(e) => tracing::error!("Invalid log filter in config file: {e}"),
+            }
+        }
+        let limits = new_config.bandwidth_limits(&cli_bandwidth);
+        if limits != config.bandwidth_limits(&cli_bandwidth)
+            && bandwidth_limits_tx.send(limits).await.is_err()
+        {
+            tracing::warn!("Eventloop stopped, can't apply new bandwidth limits");
+        }
+
+        config = new_config;
+    }
+}
+
 async fn get_firezone_id(env_id: Option<String>) -> Result<String> {
     if let Some(id) = env_id {
         if !id.is_empty() {
@@ -105,12 +268,23 @@ async fn get_firezone_id(env_id: Option<String>) -> Result<String> {
     Ok(id)
 }
 
-async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
-    let mut tunnel = GatewayTunnel::new(
+async fn run(
+    login: LoginUrl,
+    private_key: StaticSecret,
+    bandwidth_limits: BandwidthLimits,
+    bandwidth_limits_rx: mpsc::Receiver<BandwidthLimits>,
+    mtu: usize,
+    num_shards: NonZeroUsize,
+    tun_offload: bool,
+) -> Result<Infallible> {
+    let mut tunnel = ShardedGatewayTunnel::new(
         private_key,
         Arc::new(tcp_socket_factory),
         Arc::new(udp_socket_factory),
+        mtu,
+        num_shards,
     );
+    tunnel.set_bandwidth_limits(bandwidth_limits);
     let portal = PhoenixChannel::connect(
         Secret::new(login),
         get_user_agent(None, env!("CARGO_PKG_VERSION")),
@@ -123,13 +297,18 @@ async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
     )?;
 
     let (sender, receiver) = mpsc::channel::<Interface>(10);
-    let mut tun_device_manager = TunDeviceManager::new(DEFAULT_MTU)?;
-    let tun = tun_device_manager.make_tun()?;
-    tunnel.set_tun(Box::new(tun));
+    let mut tun_device_manager = TunDeviceManager::new(mtu)?;
+    let queues = tun_device_manager.make_tun_queues(num_shards, tun_offload)?;
+    tunnel.set_tun_queues(
+        queues
+            .into_iter()
+            .map(|tun| Box::new(tun) as Box<dyn Tun>)
+            .collect(),
+    );
 
     let update_device_task = update_device_task(tun_device_manager, receiver);
 
-    let mut eventloop = Eventloop::new(tunnel, portal, sender);
+    let mut eventloop = Eventloop::new(tunnel, portal, sender, bandwidth_limits_rx);
     let eventloop_task = future::poll_fn(move |cx| eventloop.poll(cx));
 
     let ((), result) = futures::join!(update_device_task, eventloop_task);
@@ -139,6 +318,10 @@ async fn run(login: LoginUrl, private_key: StaticSecret) -> Result<Infallible> {
     unreachable!()
 }
 
+fn default_num_shards() -> NonZeroUsize {
+    std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
 async fn update_device_task(
     mut tun_device: TunDeviceManager,
     mut receiver: mpsc::Receiver<Interface>,
@@ -173,7 +356,15 @@ struct Cli {
     api_url: Url,
     /// Token generated by the portal to authorize websocket connection.
     #[arg(env = "FIREZONE_TOKEN")]
-    token: String,
+    token: Option<String>,
+    /// A file containing the token, used if `FIREZONE_TOKEN` is not set
+    #[arg(long, env = "FIREZONE_TOKEN_PATH")]
+    token_path: Option<PathBuf>,
+    /// A TOML config file with settings for the other args, `/etc/firezone/gateway.toml` by default.
+    ///
+    /// Args from the CLI or from env vars override it. SIGHUP reloads it.
+    #[arg(long, env = "FIREZONE_CONFIG")]
+    config: Option<PathBuf>,
     /// Friendly name to display in the UI
     #[arg(short = 'n', long, env = "FIREZONE_NAME")]
     firezone_name: Option<String>,
@@ -181,7 +372,76 @@ struct Cli {
     #[command(flatten)]
     health_check: http_health_check::HealthCheckArgs,
 
+    #[command(flatten)]
+    bandwidth: BandwidthArgs,
+
+    /// MTU of the tunnel interface, between 1280 and 9000 bytes.
+    #[arg(
+        long,
+        env = "FIREZONE_MTU",
+        default_value_t = DEFAULT_MTU,
+        value_parser = clap::value_parser!(u16).range(DEFAULT_MTU as i64..=MAX_MTU as i64).map(usize::from)
+    )]
+    mtu: usize,
+
+    /// How many shards to split the data plane into, each one processing the packets of a subset of clients.
+    ///
+    /// Defaults to the number of available CPU cores.
+    #[arg(long, env = "FIREZONE_NUM_SHARDS")]
+    num_shards: Option<NonZeroUsize>,
+
+    /// Let the kernel hand us TCP segments of up to 64 KiB from the TUN device and split them up ourselves.
+    ///
+    /// Saves syscalls when resources send bulk TCP traffic to clients.
+    #[arg(long, env = "FIREZONE_TUN_OFFLOAD")]
+    tun_offload: bool,
+
     /// Identifier generated by the portal to identify and display the device.
     #[arg(short = 'i', long, env = "FIREZONE_ID")]
     pub firezone_id: Option<String>,
+
+    /// How to format the logs on stdout, `human` or `json`.
+    #[arg(long, env = "FIREZONE_LOG_FORMAT", default_value_t = LogFormat::Human)]
+    log_format: LogFormat,
+
+    /// Also ship logs to `syslog://host[:port]`, `syslog:///dev/log` or to an OTLP collector at `otlp://host[:port]`.
+    #[arg(long, env = "FIREZONE_LOG_SHIP")]
+    log_ship: Option<ship::Destination>,
+
+    /// Report connection metrics and traces to the OTLP collector at this `host:port`, via gRPC.
+    #[arg(long, env = "FIREZONE_OTLP_GRPC_ENDPOINT")]
+    otlp_grpc_endpoint: Option<String>,
+}
+
+/// Bandwidth limits, in bytes per second.
+///
+/// "Ingress" is traffic sent by clients towards resources, "egress" is traffic sent by resources back to clients.
+/// Omitting a limit (or setting it to 0) disables it.
+#[derive(clap::Args, Debug, Clone)]
+struct BandwidthArgs {
+    /// How many bytes per second a single client may send through this gateway.
+    #[arg(long, env = "FIREZONE_CLIENT_INGRESS_LIMIT")]
+    client_ingress_limit: Option<u64>,
+    /// How many bytes per second a single client may receive through this gateway.
+    #[arg(long, env = "FIREZONE_CLIENT_EGRESS_LIMIT")]
+    client_egress_limit: Option<u64>,
+    /// How many bytes per second all clients combined may send to a single resource.
+    #[arg(long, env = "FIREZONE_RESOURCE_INGRESS_LIMIT")]
+    resource_ingress_limit: Option<u64>,
+    /// How many bytes per second all clients combined may receive from a single resource.
+    #[arg(long, env = "FIREZONE_RESOURCE_EGRESS_LIMIT")]
+    resource_egress_limit: Option<u64>,
+}
+
+impl BandwidthArgs {
+    fn limits(&self) -> BandwidthLimits {
+        let non_zero = |limit: Option<u64>| limit.filter(|l| *l > 0);
+
+        BandwidthLimits {
+            client_ingress: non_zero(self.client_ingress_limit),
+            client_egress: non_zero(self.client_egress_limit),
+            resource_ingress: non_zero(self.resource_ingress_limit),
+            resource_egress: non_zero(self.resource_egress_limit),
+        }
+    }
 }
@@ -0,0 +1,526 @@
+//! A [`ControlPlane`] that serves a static [`Deployment`] instead of talking to the portal
+//!
+//! Clients reach us via [`LocalSignalling`], so they need to run in the same process with a static control plane of their own.
+//! The Gateway binary never has Clients in its process, thus only our tests use this for now.
+
+use crate::eventloop::PHOENIX_TOPIC;
+use crate::messages::{
+    AllowAccess, Client, ClientIceCandidates, ClientPayload, ClientsIceCandidates, Config,
+    ConnectionReady, EgressMessages, IngressMessages, InitGateway, RequestConnection,
+    ResolveRequest,
+};
+use connlib_shared::{
+    control_plane::{ControlPlane, Deployment, LocalSignalling, Signal, SignallingPeer},
+    messages::{
+        client::Site, gateway::ResourceDescription, ClientId, ConnectionAccepted, GatewayId,
+        GatewayResponse, Interface, Peer, ResourceId,
+    },
+    LoginUrl,
+};
+use phoenix_channel::{Event, OutboundRequestId};
+use secrecy::Secret;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::IpAddr,
+    task::{Context, Poll, Waker},
+};
+
+/// How often Clients send WireGuard keepalives, the same as the portal tells them.
+const PERSISTENT_KEEPALIVE: u16 = 25;
+
+/// Our view of a Resource in the [`Deployment`].
+#[derive(Debug, Deserialize)]
+pub struct Resource {
+    #[serde(flatten)]
+    description: ResourceDescription,
+    gateway_groups: Vec<Site>,
+}
+
+pub struct StaticControlPlane {
+    id: GatewayId,
+    deployment: Deployment<Resource>,
+    signalling: LocalSignalling,
+
+    /// The Client requests that each `ref` we handed to the eventloop answers.
+    pending_connections: HashMap<String, PendingConnection>,
+    next_reference: u64,
+
+    next_request_id: u64,
+    pending_events: VecDeque<Event<IngressMessages, ()>>,
+    waker: Option<Waker>,
+}
+
+struct PendingConnection {
+    client_id: ClientId,
+    request_id: u64,
+    resource_id: ResourceId,
+}
+
+impl StaticControlPlane {
+    /// Serves the deployment to the Gateway with the given ID.
+    pub fn new(
+        id: GatewayId,
+        deployment: Deployment<Resource>,
+        signalling: LocalSignalling,
+    ) -> io::Result<Self> {
+        if deployment.gateway(id).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Gateway {id} is not part of the deployment"),
+            ));
+        }
+
+        let mut this = Self {
+            id,
+            deployment,
+            signalling,
+            pending_connections: HashMap::default(),
+            next_reference: 0,
+            next_request_id: 0,
+            pending_events: VecDeque::default(),
+            waker: None,
+        };
+        this.join();
+
+        Ok(this)
+    }
+
+    /// Does what the portal does when we join our topic
+    fn join(&mut self) {
+        let gateway = self
+            .deployment
+            .gateway(self.id)
+            .expect("we checked that we are part of the deployment");
+        let init = IngressMessages::Init(InitGateway {
+            interface: Interface {
+                ipv4: gateway.ipv4,
+                ipv6: gateway.ipv6,
+                upstream_dns: Vec::default(),
+            },
+            config: Config {
+                ipv4_masquerade_enabled: true,
+                ipv6_masquerade_enabled: true,
+            },
+            relays: self.deployment.relays.clone(),
+        });
+
+        self.push(Event::JoinedRoom {
+            topic: PHOENIX_TOPIC.to_owned(),
+        });
+        self.push(Event::InboundMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            msg: init,
+        });
+    }
+
+    fn push(&mut self, event: Event<IngressMessages, ()>) {
+        self.pending_events.push_back(event);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn push_message(&mut self, msg: IngressMessages) {
+        self.push(Event::InboundMessage {
+            topic: PHOENIX_TOPIC.to_owned(),
+            msg,
+        });
+    }
+
+    /// The Resource, if it is in our Site.
+    fn resource(&self, id: ResourceId) -> Option<ResourceDescription> {
+        let gateway = self.deployment.gateway(self.id)?;
+
+        self.deployment
+            .resources
+            .iter()
+            .find(|r| {
+                r.description.id() == id
+                    && r.gateway_groups
+                        .iter()
+                        .any(|site| site.id == gateway.site_id)
+            })
+            .map(|r| r.description.clone())
+    }
+
+    fn new_reference(&mut self) -> String {
+        let reference = format!("static-{}", self.next_reference);
+        self.next_reference += 1;
+
+        reference
+    }
+
+    fn handle_signal(&mut self, signal: Signal) {
+        match signal {
+            Signal::RequestConnection {
+                client_id,
+                request_id,
+                resource_id,
+                offer,
+                preshared_key,
+                domain,
+            } => {
+                let Some(client) = self.deployment.client(client_id) else {
+                    tracing::debug!(%client_id, "Ignoring connection request from unknown Client");
+                    return;
+                };
+                let peer = Peer {
+                    persistent_keepalive: Some(PERSISTENT_KEEPALIVE),
+                    public_key: client.public_key,
+                    ipv4: client.ipv4,
+                    ipv6: client.ipv6,
+                    preshared_key,
+                };
+                let Some(resource) = self.resource(resource_id) else {
+                    tracing::debug!(%client_id, %resource_id, "Ignoring connection request for Resource outside of our Site");
+                    return;
+                };
+                let reference = self.new_reference();
+
+                self.pending_connections.insert(
+                    reference.clone(),
+                    PendingConnection {
+                        client_id,
+                        request_id,
+                        resource_id,
+                    },
+                );
+                self.push_message(IngressMessages::RequestConnection(RequestConnection {
+                    resource,
+                    client: Client {
+                        id: client_id,
+                        payload: ClientPayload {
+                            ice_parameters: offer,
+                            domain: domain.map(|r| ResolveRequest::MapResponse {
+                                name: r.name,
+                                proxy_ips: r.proxy_ips,
+                            }),
+                        },
+                        peer,
+                    },
+                    reference,
+                    expires_at: None,
+                }));
+            }
+            Signal::ReuseConnection {
+                client_id,
+                resource_id,
+                domain,
+            } => {
+                let Some(client) = self.deployment.client(client_id) else {
+                    tracing::debug!(%client_id, "Ignoring access request from unknown Client");
+                    return;
+                };
+                let (client_ipv4, client_ipv6) = (client.ipv4, client.ipv6);
+                let Some(resource) = self.resource(resource_id) else {
+                    tracing::debug!(%client_id, %resource_id, "Ignoring access request for Resource outside of our Site");
+                    return;
+                };
+                let reference = self.new_reference();
+
+                self.push_message(IngressMessages::AllowAccess(AllowAccess {
+                    client_id,
+                    resource,
+                    expires_at: None,
+                    payload: domain.map(|r| ResolveRequest::MapResponse {
+                        name: r.name,
+                        proxy_ips: r.proxy_ips,
+                    }),
+                    reference,
+                    client_ipv4,
+                    client_ipv6,
+                }));
+            }
+            Signal::IceCandidates {
+                from: SignallingPeer::Client(client_id),
+                candidates,
+            } => self.push_message(IngressMessages::IceCandidates(ClientIceCandidates {
+                client_id,
+                candidates,
+            })),
+            Signal::InvalidateIceCandidates {
+                from: SignallingPeer::Client(client_id),
+                candidates,
+            } => self.push_message(IngressMessages::InvalidateIceCandidates(
+                ClientIceCandidates {
+                    client_id,
+                    candidates,
+                },
+            )),
+            Signal::ConnectionAccepted { .. }
+            | Signal::IceCandidates {
+                from: SignallingPeer::Gateway(_),
+                ..
+            }
+            | Signal::InvalidateIceCandidates {
+                from: SignallingPeer::Gateway(_),
+                ..
+            } => {
+                tracing::debug!(?signal, "Ignoring signal meant for a Client");
+            }
+        }
+    }
+
+    fn broadcast(&self, client_ids: Vec<ClientId>, signal: impl Fn() -> Signal) {
+        for client_id in client_ids {
+            self.signalling
+                .send(SignallingPeer::Client(client_id), signal());
+        }
+    }
+}
+
+impl ControlPlane<EgressMessages, IngressMessages, ()> for StaticControlPlane {
+    fn send(&mut self, msg: EgressMessages) -> OutboundRequestId {
+        let req_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        match msg {
+            EgressMessages::ConnectionReady(ConnectionReady {
+                reference,
+                gateway_payload,
+            }) => {
+                let Some(pending) = self.pending_connections.remove(&reference) else {
+                    // Replies to `allow_access`: The Client ignores them.
+                    return OutboundRequestId::new(req_id);
+                };
+                let GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                    ice_parameters, ..
+                }) = gateway_payload
+                else {
+                    tracing::debug!(%reference, "Expected to accept the connection");
+                    return OutboundRequestId::new(req_id);
+                };
+
+                self.signalling.send(
+                    SignallingPeer::Client(pending.client_id),
+                    Signal::ConnectionAccepted {
+                        gateway_id: self.id,
+                        request_id: pending.request_id,
+                        resource_id: pending.resource_id,
+                        answer: ice_parameters,
+                    },
+                );
+            }
+            EgressMessages::BroadcastIceCandidates(ClientsIceCandidates {
+                client_ids,
+                candidates,
+            }) => {
+                let from = SignallingPeer::Gateway(self.id);
+
+                self.broadcast(client_ids, || Signal::IceCandidates {
+                    from,
+                    candidates: Vec::from_iter(candidates.iter().cloned()),
+                });
+            }
+            EgressMessages::BroadcastInvalidatedIceCandidates(ClientsIceCandidates {
+                client_ids,
+                candidates,
+            }) => {
+                let from = SignallingPeer::Gateway(self.id);
+
+                self.broadcast(client_ids, || Signal::InvalidateIceCandidates {
+                    from,
+                    candidates: Vec::from_iter(candidates.iter().cloned()),
+                });
+            }
+        }
+
+        OutboundRequestId::new(req_id)
+    }
+
+    fn rejoin(&mut self, topic: String) {
+        self.push(Event::JoinedRoom { topic });
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<IngressMessages, ()>, phoenix_channel::Error>> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Poll::Ready(Ok(event));
+            }
+
+            match self
+                .signalling
+                .poll_recv(SignallingPeer::Gateway(self.id), cx)
+            {
+                Poll::Ready(signal) => {
+                    self.handle_signal(signal);
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+
+            self.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+    }
+
+    fn reconnect(&mut self) {
+        self.join();
+    }
+
+    fn set_url(&mut self, _: Secret<LoginUrl>, _: Vec<IpAddr>) {
+        tracing::debug!("Ignoring new portal URL, we're not using a portal");
+    }
+
+    fn server_host(&self) -> Option<(String, Vec<IpAddr>)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::messages::{Answer, Key, Offer};
+    use std::net::Ipv4Addr;
+
+    const DEPLOYMENT: &str = r#"{
+        "clients": [{
+            "id": "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51",
+            "public_key": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "ipv4": "100.64.0.1",
+            "ipv6": "fd00:2021:1111::1"
+        }],
+        "gateways": [{
+            "id": "2b1524e6-239e-4570-bc73-70a188e12101",
+            "site_id": "bf56f32d-7b2c-4f5d-a784-788977d014a4",
+            "public_key": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "address": "192.168.1.10",
+            "ipv4": "100.64.0.2",
+            "ipv6": "fd00:2021:1111::2"
+        }],
+        "resources": [{
+            "type": "cidr",
+            "id": "73037362-715d-4a83-a749-f18eadd970e6",
+            "name": "Lab",
+            "address": "10.0.0.0/24",
+            "address_description": "lab",
+            "filters": [],
+            "gateway_groups": [{ "id": "bf56f32d-7b2c-4f5d-a784-788977d014a4", "name": "Lab" }]
+        }, {
+            "type": "cidr",
+            "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+            "name": "Elsewhere",
+            "address": "10.1.0.0/24",
+            "address_description": "another site",
+            "filters": [],
+            "gateway_groups": [{ "id": "eb94482a-94f4-47cb-8127-14fb3afa5516", "name": "Other" }]
+        }]
+    }"#;
+
+    const CLIENT: &str = "5f3ad1a4-8b9b-4d3c-9d4e-3b1f1a7e0c51";
+    const GATEWAY: &str = "2b1524e6-239e-4570-bc73-70a188e12101";
+    const RESOURCE: &str = "73037362-715d-4a83-a749-f18eadd970e6";
+
+    fn control_plane(signalling: LocalSignalling) -> StaticControlPlane {
+        let mut control_plane = StaticControlPlane::new(
+            GATEWAY.parse().unwrap(),
+            serde_json::from_str(DEPLOYMENT).unwrap(),
+            signalling,
+        )
+        .unwrap();
+        while next_event(&mut control_plane).is_some() {}
+
+        control_plane
+    }
+
+    fn next_event(control_plane: &mut StaticControlPlane) -> Option<Event<IngressMessages, ()>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        match control_plane.poll(&mut cx) {
+            Poll::Ready(Ok(event)) => Some(event),
+            Poll::Ready(Err(_)) | Poll::Pending => None,
+        }
+    }
+
+    fn request_connection(signalling: &LocalSignalling, resource_id: &str) {
+        signalling.send(
+            SignallingPeer::Gateway(GATEWAY.parse().unwrap()),
+            Signal::RequestConnection {
+                client_id: CLIENT.parse().unwrap(),
+                request_id: 7,
+                resource_id: resource_id.parse().unwrap(),
+                offer: Offer {
+                    username: "client".to_owned(),
+                    password: "secret".to_owned(),
+                },
+                preshared_key: Secret::new(Key([1; 32])),
+                domain: None,
+            },
+        );
+    }
+
+    #[test]
+    fn sends_init_after_joining() {
+        let mut control_plane = StaticControlPlane::new(
+            GATEWAY.parse().unwrap(),
+            serde_json::from_str(DEPLOYMENT).unwrap(),
+            LocalSignalling::default(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            next_event(&mut control_plane),
+            Some(Event::JoinedRoom { .. })
+        ));
+        assert!(matches!(
+            next_event(&mut control_plane),
+            Some(Event::InboundMessage {
+                msg: IngressMessages::Init(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn client_request_becomes_request_connection() {
+        let signalling = LocalSignalling::default();
+        let mut control_plane = control_plane(signalling.clone());
+
+        request_connection(&signalling, RESOURCE);
+
+        let Some(Event::InboundMessage {
+            msg: IngressMessages::RequestConnection(req),
+            ..
+        }) = next_event(&mut control_plane)
+        else {
+            panic!("Expected a connection request");
+        };
+        assert_eq!(req.client.id, CLIENT.parse().unwrap());
+        assert_eq!(req.client.peer.ipv4, Ipv4Addr::new(100, 64, 0, 1));
+        assert_eq!(req.resource.id(), RESOURCE.parse().unwrap());
+
+        control_plane.send(EgressMessages::ConnectionReady(ConnectionReady {
+            reference: req.reference,
+            gateway_payload: GatewayResponse::ConnectionAccepted(ConnectionAccepted {
+                ice_parameters: Answer {
+                    username: "gateway".to_owned(),
+                    password: "secret".to_owned(),
+                },
+                domain_response: None,
+            }),
+        }));
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let client = SignallingPeer::Client(CLIENT.parse().unwrap());
+        assert!(matches!(
+            signalling.poll_recv(client, &mut cx),
+            Poll::Ready(Signal::ConnectionAccepted { request_id: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn ignores_resources_of_other_sites() {
+        let signalling = LocalSignalling::default();
+        let mut control_plane = control_plane(signalling.clone());
+
+        request_connection(&signalling, "03000143-e25e-45c7-aafb-144990e57dcd");
+
+        assert!(next_event(&mut control_plane).is_none());
+    }
+}
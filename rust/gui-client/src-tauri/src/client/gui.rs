@@ -450,6 +450,8 @@ struct Controller {
     // Sign-in state with the portal / deep links
     auth: client::auth::Auth,
     clear_logs_callback: Option<oneshot::Sender<Result<(), String>>>,
+    /// True while connlib can't reach the portal, but keeps the tunnel up
+    control_plane_degraded: bool,
     ctlr_tx: CtlrTx,
     ipc_client: ipc::Client,
     log_filter_reloader: LogFilterReloader,
@@ -673,6 +675,14 @@ impl Controller {
                 Ok(())
             }
             IpcServerMsg::ConnectResult(result) => self.handle_connect_result(result).await,
+            IpcServerMsg::ControlPlaneDegraded(degraded) => {
+                tracing::info!(degraded, "Control plane status changed");
+                self.control_plane_degraded = degraded;
+                if let Err(error) = self.refresh_system_tray_menu() {
+                    tracing::error!(?error, "Failed to refresh menu");
+                }
+                Ok(())
+            }
             IpcServerMsg::OnDisconnect {
                 error_msg,
                 is_authentication_error,
@@ -706,11 +716,21 @@ impl Controller {
 
                 Ok(())
             }
+            IpcServerMsg::Status(_) => {
+                // The GUI tracks its own status from the pushed events, it never sends `GetStatus`
+                tracing::debug!("Ignoring unsolicited `Status`");
+                Ok(())
+            }
             IpcServerMsg::TerminatingGracefully => {
                 tracing::info!("Caught TerminatingGracefully");
                 self.tray.set_icon(system_tray::Icon::terminating()).ok();
                 Err(Error::IpcServiceTerminating)
             }
+            IpcServerMsg::Unauthorized(error) => {
+                // e.g. another GUI on the same machine owns the session
+                tracing::error!(?error, "IPC service refused our message");
+                Ok(())
+            }
             IpcServerMsg::TunnelReady => {
                 if self.auth.session().is_none() {
                     // This could maybe happen if the user cancels the sign-in
@@ -829,6 +849,7 @@ impl Controller {
                         favorite_resources: &self.advanced_settings.favorite_resources,
                         disabled_resources: &self.advanced_settings.disabled_resources,
                         resources,
+                        control_plane_degraded: self.control_plane_degraded,
                     })
                 }
                 Status::WaitingForPortal { .. } => system_tray::ConnlibState::WaitingForPortal,
@@ -866,6 +887,7 @@ impl Controller {
     async fn sign_out(&mut self) -> Result<()> {
         self.auth.sign_out()?;
         self.status = Status::Disconnected;
+        self.control_plane_degraded = false;
         tracing::debug!("disconnecting connlib");
         // This is redundant if the token is expired, in that case
         // connlib already disconnected itself.
@@ -910,6 +932,7 @@ async fn run_controller(
         app: app.clone(),
         auth: client::auth::Auth::new()?,
         clear_logs_callback: None,
+        control_plane_degraded: false,
         ctlr_tx,
         ipc_client,
         log_filter_reloader,
@@ -34,6 +34,8 @@ const QUIT_TEXT_SIGNED_OUT: &str = "Quit Firezone";
 const NO_ACTIVITY: &str = "[-] No activity";
 const GATEWAY_CONNECTED: &str = "[O] Gateway connected";
 const ALL_GATEWAYS_OFFLINE: &str = "[X] All Gateways offline";
+const RESOURCE_DEGRADED: &str = "[!] Gateway connected, Resource degraded";
+const CONTROL_PLANE_DEGRADED: &str = "[!] Can't reach Firezone, connected Resources still work";
 
 const ADD_FAVORITE: &str = "Add to favorites";
 const REMOVE_FAVORITE: &str = "Remove from favorites";
@@ -83,6 +85,7 @@ pub(crate) struct SignedIn<'a> {
     pub(crate) favorite_resources: &'a HashSet<ResourceId>,
     pub(crate) resources: &'a [ResourceDescription],
     pub(crate) disabled_resources: &'a HashSet<ResourceId>,
+    pub(crate) control_plane_degraded: bool,
 }
 
 impl<'a> SignedIn<'a> {
@@ -122,6 +125,7 @@ impl<'a> SignedIn<'a> {
                 Status::Unknown => NO_ACTIVITY,
                 Status::Online => GATEWAY_CONNECTED,
                 Status::Offline => ALL_GATEWAYS_OFFLINE,
+                Status::Degraded => RESOURCE_DEGRADED,
             };
 
             submenu
@@ -264,6 +268,7 @@ fn signed_in(signed_in: &SignedIn) -> Menu {
         actor_name,
         favorite_resources,
         resources, // Make sure these are presented in the order we receive them
+        control_plane_degraded,
         ..
     } = signed_in;
 
@@ -271,10 +276,11 @@ fn signed_in(signed_in: &SignedIn) -> Menu {
         .iter()
         .any(|res| favorite_resources.contains(&res.id()));
 
-    let mut menu = Menu::default()
-        .disabled(format!("Signed in as {actor_name}"))
-        .item(Event::SignOut, SIGN_OUT)
-        .separator();
+    let mut menu = Menu::default().disabled(format!("Signed in as {actor_name}"));
+    if *control_plane_degraded {
+        menu = menu.disabled(CONTROL_PLANE_DEGRADED);
+    }
+    menu = menu.item(Event::SignOut, SIGN_OUT).separator();
 
     tracing::debug!(
         resource_count = resources.len(),
@@ -395,6 +401,7 @@ mod tests {
                 favorite_resources,
                 resources,
                 disabled_resources,
+                control_plane_degraded: false,
             }),
             release: None,
         }
@@ -458,6 +465,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn control_plane_degraded() {
+        let resources = vec![];
+        let favorites = Default::default();
+        let disabled_resources = Default::default();
+        let mut input = signed_in(&resources, &favorites, &disabled_resources);
+        if let ConnlibState::SignedIn(signed_in) = &mut input.connlib {
+            signed_in.control_plane_degraded = true;
+        }
+        let actual = input.into_menu();
+        let expected = Menu::default()
+            .disabled("Signed in as Jane Doe")
+            .disabled(CONTROL_PLANE_DEGRADED)
+            .item(Event::SignOut, SIGN_OUT)
+            .separator()
+            .disabled(RESOURCES)
+            .add_bottom_section(None, DISCONNECT_AND_QUIT); // Skip testing the bottom section, it's simple
+
+        assert_eq!(
+            actual,
+            expected,
+            "{}",
+            serde_json::to_string_pretty(&actual).unwrap()
+        );
+    }
+
     #[test]
     fn no_resources_invalid_favorite() {
         let resources = vec![];
@@ -5,6 +5,8 @@ use anyhow::{bail, Context, Result};
 use firezone_headless_client::{known_dirs, LogFilterReloader};
 use serde::Serialize;
 use std::{
+    cmp::Reverse,
+    ffi::OsStr,
     fs,
     io::{self, ErrorKind::NotFound},
     path::{Path, PathBuf},
@@ -14,6 +16,11 @@ use tracing::subscriber::set_global_default;
 use tracing_log::LogTracer;
 use tracing_subscriber::{fmt, layer::SubscriberExt, reload, Layer, Registry};
 
+/// At most this many bytes of files from each log directory go into an export, the newest ones first.
+///
+/// The same as the default cap of the log directories, so usually the whole directory fits.
+const MAX_EXPORT_SIZE_PER_DIR: u64 = 100 * 1024 * 1024;
+
 /// If you don't store `Handles` in a variable, the file logger handle will drop immediately,
 /// resulting in empty log files.
 #[must_use]
@@ -54,7 +61,8 @@ pub(crate) fn setup(directives: &str) -> Result<Handles> {
     let log_path = known_dirs::logs().context("Can't compute app log dir")?;
 
     std::fs::create_dir_all(&log_path).map_err(Error::CreateDirAll)?;
-    let (layer, logger) = firezone_logging::file::layer(&log_path);
+    let (layer, logger) =
+        firezone_logging::file::layer_with_retention(&log_path, Default::default());
     let layer = layer.and_then(fmt::layer());
     let (filter, reloader) = reload::Layer::new(firezone_logging::try_filter(directives)?);
     let subscriber = Registry::default().with(layer.with_filter(filter));
@@ -165,7 +173,7 @@ pub(crate) async fn export_logs_to(path: PathBuf, stem: PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// Reads all files in a directory and adds them to a zip file
+/// Reads the files in a directory and adds them to a zip file, up to [`MAX_EXPORT_SIZE_PER_DIR`]
 ///
 /// Does not recurse.
 /// All files will have the same modified time. Doing otherwise seems to be difficult
@@ -186,8 +194,25 @@ fn add_dir_to_zip(
             return Err(error.into());
         }
     };
-    for entry in dir {
-        let entry = entry.context("Got bad entry from `read_dir`")?;
+    let mut entries = dir
+        .collect::<io::Result<Vec<_>>>()
+        .context("Got bad entry from `read_dir`")?;
+    // Log files have a timestamp in their names, so this puts the newest ones first.
+    entries.sort_by_key(|entry| Reverse(entry.file_name()));
+
+    let mut budget = MAX_EXPORT_SIZE_PER_DIR;
+    let mut num_skipped = 0;
+    for entry in entries {
+        let len = entry
+            .metadata()
+            .context("Failed to get metadata of log file")?
+            .len();
+        let Some(remaining) = budget.checked_sub(len) else {
+            num_skipped += 1;
+            continue;
+        };
+        budget = remaining;
+
         let Some(path) = dst_stem
             .join(entry.file_name())
             .to_str()
@@ -195,11 +220,24 @@ fn add_dir_to_zip(
         else {
             bail!("log filename isn't valid Unicode")
         };
+        // Rotated logs are gzipped already, compressing them again would only cost time.
+        let options = if entry.path().extension() == Some(OsStr::new("gz")) {
+            options.compression_method(zip::CompressionMethod::Stored)
+        } else {
+            options
+        };
         zip.start_file(path, options)
             .context("`ZipWriter::start_file` failed")?;
         let mut f = fs::File::open(entry.path()).context("Failed to open log file")?;
         io::copy(&mut f, zip).context("Failed to copy log file into zip")?;
     }
+    if num_skipped > 0 {
+        tracing::info!(
+            ?src_dir,
+            num_skipped,
+            "Left the oldest log files out of the export to keep it small"
+        );
+    }
     Ok(())
 }
 
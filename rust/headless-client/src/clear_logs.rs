@@ -5,7 +5,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-/// Deletes all `.log` and `.jsonl` files in `path` except the most recent
+/// Deletes all `.log` and `.jsonl` files in `path` except the most recent, and all rotated `.gz` files
 pub async fn clear_logs(path: &Path) -> Result<()> {
     let mut dir = match tokio::fs::read_dir(path).await {
         Ok(x) => x,
@@ -85,6 +85,7 @@ mod tests {
         let paths: Vec<_> = [
             "connlib.2024-08-05-19-41-46.jsonl",
             "connlib.2024-08-05-19-41-46.log",
+            "connlib.2024-08-05-08-12-03.log.gz",
             "connlib.2024-08-07-14-17-56.jsonl",
             "connlib.2024-08-07-14-17-56.log",
             "connlib.2024-08-06-14-21-13.jsonl",
@@ -103,6 +104,7 @@ mod tests {
             [
                 "/bogus/connlib.2024-08-05-19-41-46.jsonl",
                 "/bogus/connlib.2024-08-05-19-41-46.log",
+                "/bogus/connlib.2024-08-05-08-12-03.log.gz",
                 "/bogus/connlib.2024-08-06-14-21-13.jsonl",
                 "/bogus/connlib.2024-08-06-14-21-13.log",
                 "/bogus/connlib.2024-08-06-14-51-19.jsonl",
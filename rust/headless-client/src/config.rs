@@ -0,0 +1,235 @@
+//! The optional TOML config file of the Headless Client
+//!
+//! e.g.
+//!
+//! ```toml
+//! api_url = "wss://api.firezone.dev"
+//! token_path = "/etc/dev.firezone.client/token"
+//! dns_control = "systemd-resolved"
+//! log_filter = "info"
+//! mtu = 1280
+//! disabled_resources = ["73037362-715d-4a83-a749-f18eadd970e6"]
+//!
+//! [known_hosts]
+//! "git.internal" = ["10.0.0.5"]
+//! ```
+//!
+//! CLI args and env vars override the file. On SIGHUP we read it again and apply
+//! whatever can change without restarting.
+
+use crate::Cli;
+use anyhow::{bail, Context as _, Result};
+use clap::ArgMatches;
+use connlib_shared::{messages::ResourceId, DEFAULT_MTU, MAX_MTU};
+use firezone_bin_shared::{config_file, platform::DnsControlMethod};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) api_url: Option<url::Url>,
+    pub(crate) token_path: Option<PathBuf>,
+    pub(crate) firezone_id: Option<String>,
+    pub(crate) firezone_name: Option<String>,
+    pub(crate) dns_control: Option<DnsControlMethod>,
+    pub(crate) log_dir: Option<PathBuf>,
+    /// Used if `RUST_LOG` is not set
+    pub(crate) log_filter: Option<String>,
+    /// e.g. "30d"
+    pub(crate) max_partition_time: Option<String>,
+    pub(crate) mtu: Option<usize>,
+    /// Use the userspace network stack behind a proxy on this address, instead of a TUN device
+    pub(crate) proxy_listen: Option<SocketAddr>,
+    /// Resources that we don't route through Firezone, as if disabled from the GUI
+    pub(crate) disabled_resources: BTreeSet<ResourceId>,
+    /// Names that connlib resolves itself, like `/etc/hosts`
+    pub(crate) known_hosts: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl Config {
+    /// Reads the config file from `path`, or from the default path if that's `None`
+    ///
+    /// Only the default config file is allowed to be missing.
+    pub(crate) fn read(path: Option<&Path>) -> Result<Self> {
+        let config: Self = match path {
+            Some(path) => config_file::read(path, true)?,
+            None => config_file::read(&crate::platform::default_config_path()?, false)?,
+        };
+        if let Some(mtu) = config.mtu {
+            if !(DEFAULT_MTU..=MAX_MTU).contains(&mtu) {
+                bail!("`mtu` in the config file must be between {DEFAULT_MTU} and {MAX_MTU}");
+            }
+        }
+        Ok(config)
+    }
+
+    /// Fills in the CLI args that the user didn't set on the CLI or in env vars
+    pub(crate) fn apply_to_cli(&self, cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+        config_file::merge(matches, "api_url", &mut cli.api_url, self.api_url.clone());
+        config_file::merge(
+            matches,
+            "token_path",
+            &mut cli.token_path,
+            self.token_path.clone(),
+        );
+        config_file::merge(
+            matches,
+            "dns_control",
+            &mut cli.common.dns_control,
+            self.dns_control,
+        );
+        config_file::merge(matches, "mtu", &mut cli.common.mtu, self.mtu);
+
+        cli.firezone_id = cli.firezone_id.take().or_else(|| self.firezone_id.clone());
+        cli.firezone_name = cli
+            .firezone_name
+            .take()
+            .or_else(|| self.firezone_name.clone());
+        cli.common.log_dir = cli.common.log_dir.take().or_else(|| self.log_dir.clone());
+        cli.proxy_listen = cli.proxy_listen.or(self.proxy_listen);
+        if cli.common.max_partition_time.is_none() {
+            cli.common.max_partition_time = self
+                .max_partition_time
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .context("Invalid `max_partition_time` in the config file")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the names of settings that changed in `new` but only take effect after a restart
+    pub(crate) fn needs_restart(&self, new: &Self) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.firezone_id != new.firezone_id {
+            changed.push("firezone_id");
+        }
+        if self.firezone_name != new.firezone_name {
+            changed.push("firezone_name");
+        }
+        if self.dns_control != new.dns_control {
+            changed.push("dns_control");
+        }
+        if self.log_dir != new.log_dir {
+            changed.push("log_dir");
+        }
+        if self.max_partition_time != new.max_partition_time {
+            changed.push("max_partition_time");
+        }
+        if self.proxy_listen != new.proxy_listen {
+            changed.push("proxy_listen");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory as _, FromArgMatches as _};
+
+    const EXE_NAME: &str = "firezone-headless-client";
+
+    fn parse(s: &str) -> Config {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        std::fs::write(&path, s).unwrap();
+        Config::read(Some(&path)).unwrap()
+    }
+
+    #[test]
+    fn full_config() {
+        let config = parse(
+            r#"
+            api_url = "wss://api.firez.one"
+            token_path = "/etc/firezone/token"
+            firezone_name = "build-server"
+            dns_control = "etc-resolv-conf"
+            log_filter = "debug"
+            max_partition_time = "1h"
+            mtu = 1400
+            proxy_listen = "127.0.0.1:1080"
+            disabled_resources = ["73037362-715d-4a83-a749-f18eadd970e6"]
+
+            [known_hosts]
+            "git.internal" = ["10.0.0.5", "fd00::5"]
+            "#,
+        );
+
+        assert_eq!(config.log_filter.as_deref(), Some("debug"));
+        assert_eq!(config.mtu, Some(1400));
+        assert_eq!(
+            config.proxy_listen,
+            Some(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+        assert_eq!(config.disabled_resources.len(), 1);
+        assert_eq!(
+            config.known_hosts["git.internal"],
+            vec![
+                IpAddr::from([10, 0, 0, 5]),
+                "fd00::5".parse::<IpAddr>().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_mtu() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        std::fs::write(&path, "mtu = 100").unwrap();
+
+        assert!(Config::read(Some(&path)).is_err());
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let config = parse(
+            r#"
+            api_url = "wss://api.firez.one"
+            firezone_name = "from-file"
+            mtu = 1400
+            "#,
+        );
+
+        let matches = Cli::command()
+            .try_get_matches_from([EXE_NAME, "--firezone-name", "from-cli"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply_to_cli(&mut cli, &matches).unwrap();
+
+        assert_eq!(cli.api_url.as_str(), "wss://api.firez.one/");
+        assert_eq!(cli.firezone_name.as_deref(), Some("from-cli"));
+        assert_eq!(cli.common.mtu, 1400);
+
+        let matches = Cli::command()
+            .try_get_matches_from([EXE_NAME, "--mtu", "1300"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply_to_cli(&mut cli, &matches).unwrap();
+
+        assert_eq!(cli.firezone_name.as_deref(), Some("from-file"));
+        assert_eq!(cli.common.mtu, 1300);
+    }
+
+    #[test]
+    fn reload_detects_restart_only_changes() {
+        let old = parse("dns_control = \"systemd-resolved\"\nmtu = 1400\nlog_filter = \"info\"");
+        let new = parse("dns_control = \"etc-resolv-conf\"\nmtu = 1500\nlog_filter = \"debug\"");
+
+        assert_eq!(old.needs_restart(&new), vec!["dns_control"]);
+    }
+
+    #[test]
+    fn api_url_and_token_path_reload_live() {
+        let old = parse("api_url = \"wss://api.firez.one\"\ntoken_path = \"/etc/firezone/token\"");
+        let new =
+            parse("api_url = \"wss://api.firezone.dev\"\ntoken_path = \"/etc/firezone/token2\"");
+
+        assert!(old.needs_restart(&new).is_empty());
+    }
+}
@@ -0,0 +1,397 @@
+//! Local control of a running Client, over the same IPC protocol that the GUI uses
+//!
+//! The Headless Client listens on its own socket, next to the IPC service's.
+//! These subcommands try the Headless Client first and then the IPC service,
+//! so they work against whichever of the two is running on the machine.
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use connlib_shared::messages::ResourceId;
+use firezone_headless_client::{
+    ipc::{self, Peer, ServiceId},
+    IpcClientMsg, IpcServerMsg, IpcServiceStatus,
+};
+use futures::{SinkExt as _, StreamExt as _};
+use std::{io::Write as _, net::IpAddr, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+/// How long a control command may take, including connecting
+///
+/// The Headless Client serves one control connection at a time, so we might
+/// otherwise wait forever behind a stuck one.
+/// The Headless Client drops control connections that are open for longer than this.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(clap::Subcommand)]
+pub(crate) enum Cmd {
+    /// Print the status of the tunnel
+    Status,
+    /// List, disable, or enable Resources
+    Resources {
+        #[command(subcommand)]
+        command: ResourcesCmd,
+    },
+    /// Control how the tunnel resolves DNS
+    Dns {
+        #[command(subcommand)]
+        command: DnsCmd,
+    },
+    /// Manage the logs of the tunnel process
+    Logs {
+        #[command(subcommand)]
+        command: LogsCmd,
+    },
+    /// Reconnect to the portal and to all Gateways, e.g. after a network change
+    Reset,
+    /// Sign out and tear down the tunnel
+    ///
+    /// The Headless Client exits after this.
+    Disconnect,
+}
+
+#[derive(clap::Subcommand)]
+pub(crate) enum ResourcesCmd {
+    /// Print all Resources that the portal gave us
+    List,
+    /// Stop routing traffic for a Resource through Firezone
+    Disable { id: ResourceId },
+    /// Undo `disable`
+    Enable { id: ResourceId },
+}
+
+#[derive(clap::Subcommand)]
+pub(crate) enum DnsCmd {
+    /// Set the upstream resolvers for non-Resource DNS queries, instead of the system's resolvers
+    Set {
+        #[arg(required = true)]
+        servers: Vec<IpAddr>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub(crate) enum LogsCmd {
+    /// Delete the log files of the tunnel process
+    Clear,
+}
+
+/// Runs a control command against the tunnel process and prints its reply to stdout as JSON
+///
+/// Commands that change something print the resulting status.
+pub(crate) fn run(cmd: Cmd) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let output = rt.block_on(async {
+        tokio::time::timeout(TIMEOUT, request(cmd))
+            .await
+            .context("Timed out talking to the tunnel process")?
+    })?;
+
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &output)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+async fn request(cmd: Cmd) -> Result<serde_json::Value> {
+    let mut client = Client::connect().await?;
+
+    let output = match cmd {
+        Cmd::Status => serde_json::to_value(client.status().await?)?,
+        Cmd::Resources {
+            command: ResourcesCmd::List,
+        } => serde_json::to_value(client.resources().await?)?,
+        Cmd::Resources {
+            command: ResourcesCmd::Disable { id },
+        } => {
+            let resource = client
+                .resources()
+                .await?
+                .into_iter()
+                .find(|r| r.id() == id)
+                .with_context(|| format!("No Resource with ID `{id}`"))?;
+            if !resource.can_be_disabled() {
+                bail!("Resource `{}` can't be disabled", resource.name());
+            }
+            let mut disabled_resources = client.status().await?.disabled_resources;
+            disabled_resources.insert(id);
+            client
+                .send(&IpcClientMsg::SetDisabledResources(disabled_resources))
+                .await?;
+            serde_json::to_value(client.status().await?)?
+        }
+        Cmd::Resources {
+            command: ResourcesCmd::Enable { id },
+        } => {
+            let mut disabled_resources = client.status().await?.disabled_resources;
+            disabled_resources.remove(&id);
+            client
+                .send(&IpcClientMsg::SetDisabledResources(disabled_resources))
+                .await?;
+            serde_json::to_value(client.status().await?)?
+        }
+        Cmd::Dns {
+            command: DnsCmd::Set { servers },
+        } => {
+            client.send(&IpcClientMsg::SetDns(servers)).await?;
+            serde_json::to_value(client.status().await?)?
+        }
+        Cmd::Logs {
+            command: LogsCmd::Clear,
+        } => {
+            client.send(&IpcClientMsg::ClearLogs).await?;
+            client
+                .recv(|msg| {
+                    if let IpcServerMsg::ClearedLogs(result) = msg {
+                        Some(result)
+                    } else {
+                        None
+                    }
+                })
+                .await?
+                .context("Tunnel process terminated before clearing its logs")?
+                .map_err(|error| anyhow!(error).context("Couldn't clear logs"))?;
+            serde_json::to_value(client.status().await?)?
+        }
+        Cmd::Reset => {
+            client.send(&IpcClientMsg::Reset).await?;
+            serde_json::to_value(client.status().await?)?
+        }
+        Cmd::Disconnect => {
+            client.send(&IpcClientMsg::Disconnect).await?;
+            // The Headless Client exits instead of replying
+            let status = client
+                .try_status()
+                .await
+                .unwrap_or_default()
+                .unwrap_or_default();
+            serde_json::to_value(status)?
+        }
+    };
+    Ok(output)
+}
+
+struct Client {
+    rx: ipc::ClientRead,
+    tx: ipc::ClientWrite,
+}
+
+impl Client {
+    async fn connect() -> Result<Self> {
+        let (rx, tx) = match ipc::connect_to_service(ServiceId::Headless).await {
+            Ok(x) => x,
+            Err(_) => ipc::connect_to_service(ServiceId::Prod).await.context(
+                "Couldn't connect to the tunnel process. Is it running, and are we root?",
+            )?,
+        };
+        Ok(Self { rx, tx })
+    }
+
+    async fn send(&mut self, msg: &IpcClientMsg) -> Result<()> {
+        self.tx.send(msg).await.context("Couldn't send IPC message")
+    }
+
+    async fn resources(&mut self) -> Result<Vec<connlib_shared::callbacks::ResourceDescription>> {
+        self.send(&IpcClientMsg::GetResources).await?;
+        self.recv(|msg| {
+            if let IpcServerMsg::OnUpdateResources(resources) = msg {
+                Some(resources)
+            } else {
+                None
+            }
+        })
+        .await?
+        .context("Tunnel process terminated before listing Resources")
+    }
+
+    async fn status(&mut self) -> Result<IpcServiceStatus> {
+        self.try_status()
+            .await?
+            .context("Tunnel process terminated before sending its status")
+    }
+
+    /// Returns `Ok(None)` if the tunnel process went away instead of answering
+    async fn try_status(&mut self) -> Result<Option<IpcServiceStatus>> {
+        // The tunnel process handles messages in order, so this also tells us
+        // that anything we sent before has been handled.
+        self.send(&IpcClientMsg::GetStatus).await?;
+        self.recv(|msg| {
+            if let IpcServerMsg::Status(status) = msg {
+                Some(status)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Waits for the reply that `f` picks out, skipping events that are pushed to every client
+    ///
+    /// Returns `Ok(None)` if the tunnel process terminates first.
+    async fn recv<T>(&mut self, f: impl Fn(IpcServerMsg) -> Option<T>) -> Result<Option<T>> {
+        while let Some(msg) = self.rx.next().await {
+            let msg = msg.context("Error while reading IPC message")?;
+            if let IpcServerMsg::TerminatingGracefully = msg {
+                return Ok(None);
+            }
+            if let IpcServerMsg::Unauthorized(error) = msg {
+                bail!("The tunnel process refused: {error}");
+            }
+            if let Some(reply) = f(msg) {
+                return Ok(Some(reply));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Serves control commands inside the Headless Client, one IPC client at a time
+pub(crate) struct Server {
+    clients: mpsc::Receiver<ServerClient>,
+    client: Option<ServerClient>,
+}
+
+struct ServerClient {
+    rx: ipc::ServerRead,
+    tx: ipc::ServerWrite,
+    peer: Peer,
+    /// When we drop this client, so a client that never sends or closes doesn't block everyone else
+    deadline: Instant,
+    /// Dropped when we're done with this client, so the accept task can move on to the next one
+    _done: oneshot::Sender<()>,
+}
+
+impl Server {
+    pub(crate) async fn new(id: ServiceId) -> Result<Self> {
+        Self::with_timeout(id, TIMEOUT).await
+    }
+
+    async fn with_timeout(id: ServiceId, timeout: Duration) -> Result<Self> {
+        let mut server = ipc::Server::new(id).await?;
+        let (clients_tx, clients) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let (rx, tx, peer) = match server.next_client_split().await {
+                    Ok(x) => x,
+                    Err(error) => {
+                        tracing::error!(
+                            ?error,
+                            "Couldn't accept IPC client, control commands won't work"
+                        );
+                        break;
+                    }
+                };
+                let (done_tx, done_rx) = oneshot::channel();
+                let client = ServerClient {
+                    rx,
+                    tx,
+                    peer,
+                    deadline: Instant::now() + timeout,
+                    _done: done_tx,
+                };
+                if clients_tx.send(client).await.is_err() {
+                    break;
+                }
+                // Only accept the next client after this one is gone.
+                // Control commands are short-lived, so they can wait their turn.
+                done_rx.await.ok();
+            }
+        });
+        Ok(Self {
+            clients,
+            client: None,
+        })
+    }
+
+    /// A server that never gets any clients, for when we don't listen on the control socket
+    pub(crate) fn disabled() -> Self {
+        let (_, clients) = mpsc::channel(1);
+
+        Self {
+            clients,
+            client: None,
+        }
+    }
+
+    /// Returns the next message from any IPC client
+    ///
+    /// Cancel-safe. Never returns if the accept task has stopped.
+    pub(crate) async fn next_msg(&mut self) -> IpcClientMsg {
+        loop {
+            let Some(client) = &mut self.client else {
+                match self.clients.recv().await {
+                    Some(client) => self.client = Some(client),
+                    None => std::future::pending().await,
+                }
+                continue;
+            };
+            let Ok(msg) = tokio::time::timeout_at(client.deadline, client.rx.next()).await else {
+                tracing::warn!("IPC client took too long, dropping it");
+                self.client = None;
+                continue;
+            };
+            match msg {
+                Some(Ok(msg)) => return msg,
+                Some(Err(error)) => {
+                    tracing::error!(?error, "Error while deserializing IPC message");
+                }
+                None => {
+                    tracing::info!("IPC client disconnected");
+                    self.client = None;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the current IPC client may send messages that change the tunnel
+    ///
+    /// The control socket is group-accessible, but only root may do more than look.
+    pub(crate) fn may_control(&self) -> bool {
+        self.client
+            .as_ref()
+            .is_some_and(|client| client.peer.privileged)
+    }
+
+    /// Sends a reply to the current IPC client, if there is one
+    ///
+    /// A broken IPC client is dropped, since it shouldn't take the tunnel down with it.
+    pub(crate) async fn send(&mut self, msg: &IpcServerMsg) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        if let Err(error) = client.tx.send(msg).await {
+            tracing::error!(?error, "Couldn't send IPC message, dropping IPC client");
+            self.client = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stuck_client_does_not_block_others() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        const ID: ServiceId = ServiceId::Test("C7TQ2MXA");
+
+        let mut server = Server::with_timeout(ID, Duration::from_millis(100)).await?;
+        // Never sends anything and never closes
+        let (mut stuck_rx, _stuck_tx) = ipc::connect_to_service(ID).await?;
+        let (_rx, mut tx) = ipc::connect_to_service(ID).await?;
+        tx.send(&IpcClientMsg::GetStatus).await?;
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), server.next_msg())
+            .await
+            .context("Second client should be served once the first one timed out")?;
+        assert!(matches!(msg, IpcClientMsg::GetStatus));
+        assert!(
+            stuck_rx.next().await.is_none(),
+            "Stuck client should be disconnected"
+        );
+
+        Ok(())
+    }
+}
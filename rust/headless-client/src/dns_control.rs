@@ -1,7 +1,7 @@
 //! Platform-specific code to control the system's DNS resolution
 //!
 //! On Linux, we use `systemd-resolved` by default. We can also control
-//! `/etc/resolv.conf` or explicitly not control DNS.
+//! `/etc/resolv.conf`, go through `resolvconf` or NetworkManager, or explicitly not control DNS.
 //!
 //! On Windows, we use NRPT by default. We can also explicitly not control DNS.
 
@@ -21,6 +21,9 @@ use windows as platform;
 
 use platform::system_resolvers;
 
+#[cfg(target_os = "linux")]
+pub(crate) use platform::revert;
+
 /// Controls system-wide DNS.
 ///
 /// Always call `deactivate` when Firezone starts.
@@ -1,18 +1,20 @@
 use super::DnsController;
-use anyhow::{bail, Context as _, Result};
+use crate::restore::{self, Undo};
+use anyhow::{Context as _, Result};
+use connlib_shared::callbacks::ResourceDescription;
 use firezone_bin_shared::{platform::DnsControlMethod, TunDeviceManager};
-use std::{net::IpAddr, process::Command, str::FromStr};
+use std::{ffi::CString, net::IpAddr};
 
 mod etc_resolv_conf;
+mod network_manager;
+mod resolvconf;
+mod systemd_resolved;
 
 impl DnsController {
-    #[allow(clippy::unnecessary_wraps)]
     pub fn deactivate(&mut self) -> Result<()> {
         tracing::debug!("Deactivating DNS control...");
-        if let DnsControlMethod::EtcResolvConf = self.dns_control_method {
-            // TODO: Check that nobody else modified the file while we were running.
-            etc_resolv_conf::revert()?;
-        }
+        revert(self.dns_control_method)?;
+        restore::forget(Undo::Dns(self.dns_control_method));
         Ok(())
     }
 
@@ -26,15 +28,43 @@ impl DnsController {
         match self.dns_control_method {
             DnsControlMethod::Disabled => Ok(()),
             DnsControlMethod::EtcResolvConf => {
+                restore::record(Undo::Dns(self.dns_control_method));
                 tokio::task::spawn_blocking(move || etc_resolv_conf::configure(&dns_config))
                     .await
                     .context("Failed to `spawn_blocking` DNS control task")?
             }
-            DnsControlMethod::SystemdResolved => configure_systemd_resolved(&dns_config).await,
+            DnsControlMethod::SystemdResolved => systemd_resolved::configure(&dns_config).await,
+            DnsControlMethod::Resolvconf => {
+                restore::record(Undo::Dns(self.dns_control_method));
+                resolvconf::configure(&dns_config).await
+            }
+            DnsControlMethod::NetworkManager => network_manager::configure(&dns_config).await,
         }
         .context("Failed to control DNS")
     }
 
+    /// Routes only the domains of DNS Resources to our sentinels, if the control method can do that
+    ///
+    /// With the other methods, all queries go to our sentinels.
+    ///
+    /// Cancel safety: Try not to cancel this.
+    pub async fn set_resources(&mut self, resources: &[ResourceDescription]) -> Result<()> {
+        let domains = RoutingDomains::from_resources(resources);
+
+        match self.dns_control_method {
+            DnsControlMethod::Disabled
+            | DnsControlMethod::EtcResolvConf
+            | DnsControlMethod::Resolvconf => Ok(()),
+            DnsControlMethod::SystemdResolved => {
+                systemd_resolved::set_routing_domains(&domains).await
+            }
+            DnsControlMethod::NetworkManager => {
+                network_manager::set_routing_domains(&domains).await
+            }
+        }
+        .context("Failed to set DNS routing domains")
+    }
+
     /// Flush systemd-resolved's system-wide DNS cache
     ///
     /// Does nothing if we're using other DNS control methods or none at all
@@ -42,42 +72,90 @@ impl DnsController {
         // Flushing is only implemented for systemd-resolved
         if matches!(self.dns_control_method, DnsControlMethod::SystemdResolved) {
             tracing::debug!("Flushing systemd-resolved DNS cache...");
-            Command::new("resolvectl").arg("flush-caches").status()?;
+            systemd_resolved::flush()?;
             tracing::debug!("Flushed DNS.");
         }
         Ok(())
     }
 }
 
-/// Sets the system-wide resolvers by configuring `systemd-resolved`
+/// Undoes what `set_dns` did with `dns_control_method`
 ///
-/// Cancel safety: Cancelling the future may leave running subprocesses
-/// which should eventually exit on their own.
-async fn configure_systemd_resolved(dns_config: &[IpAddr]) -> Result<()> {
-    configure_dns_for_tun("dns", dns_config).await?;
-    configure_dns_for_tun("domain", &["~."]).await?;
-    configure_dns_for_tun("llmnr", &[false]).await?; // Must disable LLMNR to not interfere with local search domains.
+/// Must be sync because it's called in `Drop` impls
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn revert(dns_control_method: DnsControlMethod) -> Result<()> {
+    match dns_control_method {
+        // TODO: Check that nobody else modified the file while we were running.
+        DnsControlMethod::EtcResolvConf => etc_resolv_conf::revert()?,
+        DnsControlMethod::Resolvconf => resolvconf::revert()?,
+        // Both forget the tunnel's DNS settings when we delete the interface.
+        DnsControlMethod::Disabled
+        | DnsControlMethod::SystemdResolved
+        | DnsControlMethod::NetworkManager => {}
+    }
+    Ok(())
+}
 
-    tracing::info!(?dns_config, "Configured DNS sentinels with `resolvectl`");
+/// Which DNS queries `systemd-resolved` or NetworkManager should send to our sentinels
+#[derive(Debug, PartialEq)]
+enum RoutingDomains {
+    /// The Internet Resource needs every query.
+    All,
+    /// Only queries for these domains and their subdomains.
+    Only(Vec<String>),
+}
 
-    Ok(())
+impl RoutingDomains {
+    fn from_resources(resources: &[ResourceDescription]) -> Self {
+        let mut domains = Vec::new();
+
+        for resource in resources {
+            match resource {
+                ResourceDescription::Dns(resource) => match routing_domain(&resource.address) {
+                    "" => return Self::All, // e.g. `*`
+                    domain => domains.push(domain.to_owned()),
+                },
+                ResourceDescription::Cidr(_) => {}
+                ResourceDescription::Internet(_) => return Self::All,
+            }
+        }
+
+        domains.sort();
+        domains.dedup();
+
+        Self::Only(domains)
+    }
+
+    /// In the `~domain` notation that `resolvectl` and NetworkManager use for routing-only domains
+    fn to_search_domains(&self) -> Vec<String> {
+        match self {
+            RoutingDomains::All => vec!["~.".to_owned()],
+            RoutingDomains::Only(domains) => domains.iter().map(|d| format!("~{d}")).collect(),
+        }
+    }
 }
 
-/// Executes the provided `resolvectl` command for our TUN device.
-async fn configure_dns_for_tun(cmd: &str, params: &[impl ToString]) -> Result<()> {
-    let status = tokio::process::Command::new("resolvectl")
-        .arg(cmd)
-        .arg(TunDeviceManager::IFACE_NAME)
-        .args(params.iter().map(ToString::to_string))
-        .status()
-        .await
-        .with_context(|| format!("failed to execute `resolvectl {cmd}`"))?;
-
-    if !status.success() {
-        bail!("`resolvectl {cmd}` returned non-zero");
+/// The part of a DNS Resource's address below any wildcards, e.g. `example.com` for `*.example.com`
+fn routing_domain(address: &str) -> &str {
+    match address.rfind(['*', '?']) {
+        Some(i) => address[i..]
+            .split_once('.')
+            .map_or("", |(_, domain)| domain),
+        None => address,
     }
+}
 
-    Ok(())
+fn tun_ifindex() -> Result<u32> {
+    let name = CString::new(TunDeviceManager::IFACE_NAME)?;
+
+    // Safety: `name` is a valid, NUL-terminated C string that outlives the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Can't find `{}`", TunDeviceManager::IFACE_NAME));
+    }
+
+    Ok(index)
 }
 
 pub(crate) fn system_resolvers(dns_control_method: DnsControlMethod) -> Result<Vec<IpAddr>> {
@@ -85,7 +163,9 @@ pub(crate) fn system_resolvers(dns_control_method: DnsControlMethod) -> Result<V
         DnsControlMethod::Disabled | DnsControlMethod::EtcResolvConf => {
             get_system_default_resolvers_resolv_conf()
         }
-        DnsControlMethod::SystemdResolved => get_system_default_resolvers_systemd_resolved(),
+        DnsControlMethod::SystemdResolved => systemd_resolved::system_resolvers(),
+        DnsControlMethod::Resolvconf => resolvconf::system_resolvers(),
+        DnsControlMethod::NetworkManager => network_manager::system_resolvers(),
     }
 }
 
@@ -106,62 +186,74 @@ fn get_system_default_resolvers_resolv_conf() -> Result<Vec<IpAddr>> {
     Ok(nameservers)
 }
 
-/// Returns the DNS servers listed in `resolvectl dns`
-fn get_system_default_resolvers_systemd_resolved() -> Result<Vec<IpAddr>> {
-    // Unfortunately systemd-resolved does not have a machine-readable
-    // text output for this command: <https://github.com/systemd/systemd/issues/29755>
-    //
-    // The officially supported way is probably to use D-Bus.
-    let output = std::process::Command::new("resolvectl")
-        .arg("dns")
-        .output()
-        .context("Failed to run `resolvectl dns` and read output")?;
-    if !output.status.success() {
-        anyhow::bail!("`resolvectl dns` returned non-zero exit code");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connlib_shared::{
+        callbacks::{ResourceDescriptionDns, ResourceDescriptionInternet, Status},
+        messages::ResourceId,
+    };
+
+    #[test]
+    fn routing_domain_strips_wildcards() {
+        assert_eq!(routing_domain("app.example.com"), "app.example.com");
+        assert_eq!(routing_domain("*.example.com"), "example.com");
+        assert_eq!(routing_domain("?.example.com"), "example.com");
+        assert_eq!(routing_domain("app.*.example.com"), "example.com");
+        assert_eq!(routing_domain("*"), "");
     }
-    let output = String::from_utf8(output.stdout).context("`resolvectl` output was not UTF-8")?;
-    Ok(parse_resolvectl_output(&output))
-}
 
-/// Parses the text output of `resolvectl dns`
-///
-/// Cannot fail. If the parsing code is wrong, the IP address vec will just be incomplete.
-fn parse_resolvectl_output(s: &str) -> Vec<IpAddr> {
-    s.lines()
-        .flat_map(|line| line.split(' '))
-        .filter_map(|word| IpAddr::from_str(word).ok())
-        .collect()
-}
+    #[test]
+    fn routing_domains_from_resources() {
+        let resources = [
+            dns("*.lab.internal"),
+            dns("app.lab.internal"),
+            dns("*.lab.internal"),
+        ];
 
-#[cfg(test)]
-mod tests {
-    use std::net::IpAddr;
+        let domains = RoutingDomains::from_resources(&resources);
+
+        assert_eq!(
+            domains,
+            RoutingDomains::Only(vec![
+                "app.lab.internal".to_owned(),
+                "lab.internal".to_owned()
+            ])
+        );
+        assert_eq!(
+            domains.to_search_domains(),
+            ["~app.lab.internal", "~lab.internal"]
+        );
+    }
 
     #[test]
-    fn parse_resolvectl_output() {
-        let cases = [
-            // WSL
-            (
-                r"Global: 172.24.80.1
-Link 2 (eth0):
-Link 3 (docker0):
-Link 24 (br-fc0b71997a3c):
-Link 25 (br-0c129dafb204):
-Link 26 (br-e67e83b19dce):
-",
-                [IpAddr::from([172, 24, 80, 1])],
-            ),
-            // Ubuntu 20.04
-            (
-                r"Global:
-Link 2 (enp0s3): 192.168.1.1",
-                [IpAddr::from([192, 168, 1, 1])],
-            ),
+    fn internet_resource_routes_all_domains() {
+        let resources = [
+            dns("*.lab.internal"),
+            ResourceDescription::Internet(ResourceDescriptionInternet {
+                name: "Internet".to_owned(),
+                id: ResourceId::random(),
+                sites: vec![],
+                status: Status::Unknown,
+                can_be_disabled: true,
+            }),
         ];
 
-        for (i, (input, expected)) in cases.iter().enumerate() {
-            let actual = super::parse_resolvectl_output(input);
-            assert_eq!(actual, expected, "Case {i} failed");
-        }
+        let domains = RoutingDomains::from_resources(&resources);
+
+        assert_eq!(domains, RoutingDomains::All);
+        assert_eq!(domains.to_search_domains(), ["~."]);
+    }
+
+    fn dns(address: &str) -> ResourceDescription {
+        ResourceDescription::Dns(ResourceDescriptionDns {
+            id: ResourceId::random(),
+            address: address.to_owned(),
+            name: address.to_owned(),
+            address_description: None,
+            sites: vec![],
+            status: Status::Unknown,
+            can_be_disabled: false,
+        })
     }
 }
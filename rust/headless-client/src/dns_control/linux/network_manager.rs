@@ -0,0 +1,234 @@
+//! Configures DNS on our tunnel interface through NetworkManager's D-Bus API
+//!
+//! NetworkManager treats the tunnel like any other device: We read the connection it applied,
+//! change its DNS settings and re-apply it. NetworkManager then pushes them into whatever
+//! DNS backend it is set up with.
+//!
+//! NetworkManager must manage the tunnel, otherwise it has no applied connection to change.
+//! It does so by default: It assumes interfaces that others created, like ours, with a connection
+//! generated from their current addresses and routes. Addresses and routes remain ours though,
+//! so we re-apply that connection without any routes, see [`leave_routes_alone`].
+//!
+//! <https://networkmanager.dev/docs/api/latest/gdbus-org.freedesktop.NetworkManager.Device.html>
+
+use super::RoutingDomains;
+use anyhow::{Context as _, Result};
+use firezone_bin_shared::TunDeviceManager;
+use std::{collections::HashMap, net::IpAddr};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+/// Lower values win, this is the default for VPNs.
+const DNS_PRIORITY: i32 = 50;
+/// Negative values exclude all devices with a higher value, so we get every query.
+const DNS_PRIORITY_EXCLUSIVE: i32 = -50;
+
+/// `a{sa{sv}}`, e.g. `ipv4` -> `dns` -> `[16843009]`
+type Settings = HashMap<String, HashMap<String, OwnedValue>>;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    fn get_device_by_ip_iface(&self, iface: &str) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Device",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait Device {
+    fn get_applied_connection(&self, flags: u32) -> zbus::Result<(Settings, u64)>;
+
+    fn reapply(&self, connection: &Settings, version_id: u64, flags: u32) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn managed(&self) -> zbus::Result<bool>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.DnsManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager/DnsManager"
+)]
+trait DnsManager {
+    /// One entry per device, with `nameservers` and `interface` among others.
+    #[zbus(property)]
+    fn configuration(&self) -> zbus::Result<Vec<HashMap<String, OwnedValue>>>;
+}
+
+/// Routes all queries to our sentinels, until [`set_routing_domains`] narrows them down to our DNS Resources
+pub(crate) async fn configure(dns_config: &[IpAddr]) -> Result<()> {
+    let ipv4 = dns_config
+        .iter()
+        .filter_map(|ip| match ip {
+            // NetworkManager wants them in network byte order.
+            IpAddr::V4(ip) => Some(u32::from_ne_bytes(ip.octets())),
+            IpAddr::V6(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let ipv6 = dns_config
+        .iter()
+        .filter_map(|ip| match ip {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip.octets().to_vec()),
+        })
+        .collect::<Vec<_>>();
+
+    reapply_with(move |settings| {
+        set(settings, "ipv4", "dns", ipv4)?;
+        set(settings, "ipv6", "dns", ipv6)?;
+        set_routing(settings, &RoutingDomains::All)
+    })
+    .await?;
+
+    tracing::info!(?dns_config, "Configured DNS sentinels with NetworkManager");
+
+    Ok(())
+}
+
+pub(crate) async fn set_routing_domains(domains: &RoutingDomains) -> Result<()> {
+    reapply_with(|settings| set_routing(settings, domains)).await?;
+
+    tracing::debug!(
+        ?domains,
+        "Configured DNS routing domains with NetworkManager"
+    );
+
+    Ok(())
+}
+
+/// The resolvers NetworkManager knows about for all other devices
+pub(crate) fn system_resolvers() -> Result<Vec<IpAddr>> {
+    let cxn = zbus::blocking::Connection::system()?;
+    let configuration = DnsManagerProxyBlocking::new(&cxn)?
+        .configuration()
+        .context("Failed to read DNS configuration from NetworkManager")?;
+
+    let resolvers = configuration
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .get("interface")
+                .and_then(|iface| <&str>::try_from(&**iface).ok())
+                != Some(TunDeviceManager::IFACE_NAME)
+        })
+        .filter_map(|mut entry| Vec::<String>::try_from(entry.remove("nameservers")?).ok())
+        .flatten()
+        .filter_map(|ip| ip.parse::<IpAddr>().ok())
+        .collect();
+
+    Ok(resolvers)
+}
+
+/// Changes the connection applied to our tunnel and applies it again
+///
+/// Re-reads the applied connection every time, so the DNS servers and routing domains can be set independently.
+async fn reapply_with(f: impl FnOnce(&mut Settings) -> Result<()>) -> Result<()> {
+    let cxn = zbus::Connection::system()
+        .await
+        .context("Failed to connect to the system D-Bus")?;
+    let path = NetworkManagerProxy::new(&cxn)
+        .await?
+        .get_device_by_ip_iface(TunDeviceManager::IFACE_NAME)
+        .await
+        .context("NetworkManager doesn't know our tunnel interface")?;
+    let device = DeviceProxy::builder(&cxn).path(path)?.build().await?;
+
+    // Only managed devices have an applied connection, e.g. not if the user told NetworkManager to ignore `tun*`.
+    anyhow::ensure!(
+        device.managed().await?,
+        "NetworkManager doesn't manage our tunnel interface, use a different DNS control method"
+    );
+
+    let (mut settings, version_id) = device
+        .get_applied_connection(0)
+        .await
+        .context("Failed to get the connection applied to our tunnel interface")?;
+
+    leave_routes_alone(&mut settings);
+    f(&mut settings)?;
+
+    device
+        .reapply(&settings, version_id, 0)
+        .await
+        .context("Failed to re-apply the connection of our tunnel interface")?;
+
+    Ok(())
+}
+
+/// Removes the routes from the connection, so re-applying it doesn't replace the tunnel's routes
+///
+/// The generated connection only has the routes that existed when NetworkManager assumed the tunnel,
+/// re-applying them would bring back routes that connlib removed since.
+/// Without `route-table`, NetworkManager only removes routes that it added itself, so the ones that connlib adds stay.
+fn leave_routes_alone(settings: &mut Settings) {
+    for family in ["ipv4", "ipv6"] {
+        if let Some(settings) = settings.get_mut(family) {
+            // `addresses` and `routes` are deprecated aliases of `address-data` and `route-data`.
+            // `Reapply` fails if they disagree, so only keep `address-data`.
+            settings.remove("addresses");
+            settings.remove("routes");
+            settings.remove("route-data");
+            settings.remove("route-table");
+        }
+    }
+}
+
+fn set_routing(settings: &mut Settings, domains: &RoutingDomains) -> Result<()> {
+    let search = domains.to_search_domains();
+    let priority = match domains {
+        RoutingDomains::All => DNS_PRIORITY_EXCLUSIVE,
+        RoutingDomains::Only(_) => DNS_PRIORITY,
+    };
+
+    for family in ["ipv4", "ipv6"] {
+        set(settings, family, "dns-search", search.clone())?;
+        set(settings, family, "dns-priority", priority)?;
+    }
+
+    Ok(())
+}
+
+fn set<'a>(
+    settings: &mut Settings,
+    family: &str,
+    key: &str,
+    value: impl Into<Value<'a>>,
+) -> Result<()> {
+    settings
+        .entry(family.to_owned())
+        .or_default()
+        .insert(key.to_owned(), value.into().try_to_owned()?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reapplies_without_routes() {
+        let mut settings = Settings::new();
+        for family in ["ipv4", "ipv6"] {
+            set(&mut settings, family, "method", "manual").unwrap();
+            set(&mut settings, family, "address-data", vec!["address"]).unwrap();
+            set(&mut settings, family, "route-data", vec!["route"]).unwrap();
+            set(&mut settings, family, "routes", vec!["route"]).unwrap();
+            set(&mut settings, family, "route-table", 52u32).unwrap();
+        }
+
+        leave_routes_alone(&mut settings);
+
+        for family in ["ipv4", "ipv6"] {
+            let mut keys = settings[family]
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            keys.sort();
+            assert_eq!(keys, ["address-data", "method"]);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Hands our resolvers to `resolvconf`, which then regenerates `/etc/resolv.conf`
+//!
+//! Debian's `resolvconf` and openresolv both accept a record per interface on stdin
+//! and forget it again with `-d`.
+
+use anyhow::{bail, Context as _, Result};
+use firezone_bin_shared::TunDeviceManager;
+use std::{
+    fs,
+    net::IpAddr,
+    path::Path,
+    process::{Command, Stdio},
+};
+use tokio::io::AsyncWriteExt as _;
+
+/// The name of our record, `resolvconf` orders records with a `tun*` name before physical interfaces.
+const RECORD: &str = TunDeviceManager::IFACE_NAME;
+
+/// Where Debian's `resolvconf` and openresolv keep the records of all interfaces.
+const RECORD_DIRS: [&str; 2] = ["/run/resolvconf/interface", "/run/resolvconf/interfaces"];
+
+/// Cancel safety: Cancelling the future may leave a running subprocess
+/// which should eventually exit on its own.
+pub(crate) async fn configure(dns_config: &[IpAddr]) -> Result<()> {
+    if dns_config.is_empty() {
+        tracing::warn!("`dns_config` is empty, leaving `resolvconf` unchanged");
+        return Ok(());
+    }
+
+    let mut child = tokio::process::Command::new("resolvconf")
+        .args(["-a", RECORD])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to execute `resolvconf -a`")?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("`resolvconf` should have a stdin")?;
+    stdin.write_all(record(dns_config).as_bytes()).await?;
+    drop(stdin); // `resolvconf` reads until EOF.
+
+    if !child.wait().await?.success() {
+        bail!("`resolvconf -a` returned non-zero");
+    }
+
+    tracing::info!(?dns_config, "Configured DNS sentinels with `resolvconf`");
+
+    Ok(())
+}
+
+/// Must be sync because it's called in `Drop` impls
+#[cfg_attr(test, mutants::skip)] // Would modify system-wide DNS
+pub(crate) fn revert() -> Result<()> {
+    // `-f` ignores a record that doesn't exist, e.g. if we never configured DNS.
+    let status = Command::new("resolvconf")
+        .args(["-f", "-d", RECORD])
+        .status()
+        .context("Failed to execute `resolvconf -d`")?;
+
+    if !status.success() {
+        bail!("`resolvconf -d` returned non-zero");
+    }
+
+    Ok(())
+}
+
+/// The resolvers in the records of all other interfaces
+///
+/// `/etc/resolv.conf` would also contain our sentinels.
+pub(crate) fn system_resolvers() -> Result<Vec<IpAddr>> {
+    let dir = RECORD_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|dir| dir.is_dir())
+        .context("Can't find the records of `resolvconf`")?;
+
+    nameservers_in(dir)
+}
+
+fn record(dns_config: &[IpAddr]) -> String {
+    dns_config
+        .iter()
+        .map(|ip| format!("nameserver {ip}\n"))
+        .collect()
+}
+
+fn nameservers_in(dir: &Path) -> Result<Vec<IpAddr>> {
+    let mut nameservers = Vec::new();
+
+    for entry in fs::read_dir(dir).context("Failed to list `resolvconf` records")? {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name == RECORD) {
+            continue;
+        }
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        let parsed = resolv_conf::Config::parse(text)
+            .with_context(|| format!("Failed to parse `{}`", path.display()))?;
+
+        nameservers.extend(parsed.nameservers.into_iter().map(IpAddr::from));
+    }
+
+    Ok(nameservers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_lists_nameservers() {
+        let record = record(&[
+            IpAddr::from([100, 100, 111, 1]),
+            "fd00:2021:1111:8000:100:100:111:0".parse().unwrap(),
+        ]);
+
+        assert_eq!(
+            record,
+            "nameserver 100.100.111.1\nnameserver fd00:2021:1111:8000:100:100:111:0\n"
+        );
+    }
+
+    #[test]
+    fn ignores_our_own_record() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("eth0.dhclient"), "nameserver 192.168.1.1\n")?;
+        fs::write(dir.path().join(RECORD), "nameserver 100.100.111.1\n")?;
+
+        let nameservers = nameservers_in(dir.path())?;
+
+        assert_eq!(nameservers, [IpAddr::from([192, 168, 1, 1])]);
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Configures `systemd-resolved` over D-Bus instead of running `resolvectl`
+//!
+//! <https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.resolve1.html>
+
+use super::{tun_ifindex, RoutingDomains};
+use anyhow::{Context as _, Result};
+use std::net::IpAddr;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.resolve1.Manager",
+    default_service = "org.freedesktop.resolve1",
+    default_path = "/org/freedesktop/resolve1"
+)]
+trait Manager {
+    #[zbus(name = "SetLinkDNS")]
+    fn set_link_dns(&self, ifindex: i32, addresses: &[(i32, Vec<u8>)]) -> zbus::Result<()>;
+
+    /// The `bool` marks routing-only domains, i.e. `~example.com` in `resolvectl`.
+    fn set_link_domains(&self, ifindex: i32, domains: &[(&str, bool)]) -> zbus::Result<()>;
+
+    fn set_link_default_route(&self, ifindex: i32, enable: bool) -> zbus::Result<()>;
+
+    #[zbus(name = "SetLinkLLMNR")]
+    fn set_link_llmnr(&self, ifindex: i32, mode: &str) -> zbus::Result<()>;
+
+    fn flush_caches(&self) -> zbus::Result<()>;
+
+    /// `(ifindex, address family, address)`, where `ifindex` 0 means a global resolver.
+    #[zbus(property, name = "DNS")]
+    fn dns(&self) -> zbus::Result<Vec<(i32, i32, Vec<u8>)>>;
+}
+
+/// Routes all queries to our sentinels, until [`set_routing_domains`] narrows them down to our DNS Resources
+///
+/// Cancel safety: Cancelling may leave the link with only some of its settings applied.
+pub(crate) async fn configure(dns_config: &[IpAddr]) -> Result<()> {
+    let manager = manager().await?;
+    let ifindex = tun_ifindex()? as i32;
+
+    let addresses = dns_config
+        .iter()
+        .map(|ip| match ip {
+            IpAddr::V4(ip) => (libc::AF_INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (libc::AF_INET6, ip.octets().to_vec()),
+        })
+        .collect::<Vec<_>>();
+
+    manager
+        .set_link_dns(ifindex, &addresses)
+        .await
+        .context("Failed to set DNS servers")?;
+    // Must disable LLMNR to not interfere with local search domains.
+    manager
+        .set_link_llmnr(ifindex, "no")
+        .await
+        .context("Failed to disable LLMNR")?;
+    set_link_routing(&manager, ifindex, &RoutingDomains::All).await?;
+
+    tracing::info!(
+        ?dns_config,
+        "Configured DNS sentinels with `systemd-resolved`"
+    );
+
+    Ok(())
+}
+
+pub(crate) async fn set_routing_domains(domains: &RoutingDomains) -> Result<()> {
+    let manager = manager().await?;
+    let ifindex = tun_ifindex()? as i32;
+
+    set_link_routing(&manager, ifindex, domains).await?;
+
+    tracing::debug!(
+        ?domains,
+        "Configured DNS routing domains with `systemd-resolved`"
+    );
+
+    Ok(())
+}
+
+async fn set_link_routing(
+    manager: &ManagerProxy<'_>,
+    ifindex: i32,
+    domains: &RoutingDomains,
+) -> Result<()> {
+    let (link_domains, default_route) = match domains {
+        RoutingDomains::All => (vec![(".", true)], true),
+        RoutingDomains::Only(domains) => {
+            (domains.iter().map(|d| (d.as_str(), true)).collect(), false)
+        }
+    };
+
+    manager
+        .set_link_domains(ifindex, &link_domains)
+        .await
+        .context("Failed to set routing domains")?;
+    // Otherwise, `systemd-resolved` may still send queries for other domains to us.
+    manager
+        .set_link_default_route(ifindex, default_route)
+        .await
+        .context("Failed to set default route")?;
+
+    Ok(())
+}
+
+/// Must be sync because [`DnsController::flush`](super::DnsController::flush) is sync on all platforms
+pub(crate) fn flush() -> Result<()> {
+    let cxn = zbus::blocking::Connection::system()?;
+    ManagerProxyBlocking::new(&cxn)?.flush_caches()?;
+
+    Ok(())
+}
+
+/// The resolvers of all links except ours, and the global ones
+pub(crate) fn system_resolvers() -> Result<Vec<IpAddr>> {
+    let cxn = zbus::blocking::Connection::system()?;
+    let dns = ManagerProxyBlocking::new(&cxn)?
+        .dns()
+        .context("Failed to read DNS servers from `systemd-resolved`")?;
+    let tun = tun_ifindex().ok().map(|index| index as i32);
+
+    let resolvers = dns
+        .into_iter()
+        .filter(|(ifindex, _, _)| Some(*ifindex) != tun)
+        .filter_map(|(_, family, address)| match family {
+            libc::AF_INET => Some(IpAddr::from(<[u8; 4]>::try_from(address).ok()?)),
+            libc::AF_INET6 => Some(IpAddr::from(<[u8; 16]>::try_from(address).ok()?)),
+            _ => None,
+        })
+        .collect();
+
+    Ok(resolvers)
+}
+
+async fn manager() -> Result<ManagerProxy<'static>> {
+    let cxn = zbus::Connection::system()
+        .await
+        .context("Failed to connect to the system D-Bus")?;
+
+    Ok(ManagerProxy::new(&cxn).await?)
+}
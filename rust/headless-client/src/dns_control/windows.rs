@@ -15,6 +15,7 @@
 
 use super::DnsController;
 use anyhow::{Context as _, Result};
+use connlib_shared::callbacks::ResourceDescription;
 use firezone_bin_shared::platform::{DnsControlMethod, CREATE_NO_WINDOW};
 use std::{
     io::ErrorKind, net::IpAddr, os::windows::process::CommandExt, path::Path, process::Command,
@@ -63,6 +64,14 @@ impl DnsController {
         Ok(())
     }
 
+    /// Does nothing yet, our NRPT rule claims all domains
+    ///
+    /// Must be async to match the Linux signature
+    #[allow(clippy::unused_async)]
+    pub async fn set_resources(&mut self, _resources: &[ResourceDescription]) -> Result<()> {
+        Ok(())
+    }
+
     /// Flush Windows' system-wide DNS cache
     ///
     /// `&self` is needed to match the Linux signature
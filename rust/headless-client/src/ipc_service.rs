@@ -4,27 +4,30 @@ use crate::{
 };
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
-use connlib_client_shared::{keypair, ConnectArgs, LoginUrl, Session};
+use connlib_client_shared::{keypair, ClientStats, ConnectArgs, LoginUrl, Session};
 use connlib_shared::callbacks::ResourceDescription;
 use firezone_bin_shared::{
+    otlp,
     platform::{tcp_socket_factory, udp_socket_factory, DnsControlMethod},
-    TunDeviceManager, TOKEN_ENV_KEY,
+    KillSwitch, TunDeviceManager, TOKEN_ENV_KEY,
 };
+use firezone_logging::{file, ship};
 use futures::{
     future::poll_fn,
     task::{Context, Poll},
-    Future as _, SinkExt as _, Stream as _,
 };
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeSet, net::IpAddr, path::PathBuf, pin::pin, sync::Arc, time::Duration};
+use std::{collections::BTreeSet, iter, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{sync::mpsc, task::spawn_blocking, time::Instant};
 use tracing::subscriber::set_global_default;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer, Registry};
 use url::Url;
 
+mod clients;
 pub mod ipc;
 use backoff::ExponentialBackoffBuilder;
+use clients::{ClientId, Clients};
 use connlib_shared::{get_user_agent, messages::ResourceId, DEFAULT_MTU};
 use ipc::{Server as IpcServer, ServiceId};
 use phoenix_channel::PhoenixChannel;
@@ -75,12 +78,23 @@ impl Default for Cmd {
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum ClientMsg {
     ClearLogs,
-    Connect { api_url: String, token: String },
+    Connect {
+        api_url: String,
+        token: String,
+    },
     Disconnect,
+    /// Asks for the current Resources, answered with `ServerMsg::OnUpdateResources`
+    GetResources,
+    /// Answered with `ServerMsg::Status`
+    GetStatus,
     ReloadLogFilter,
     Reset,
     SetDns(Vec<IpAddr>),
     SetDisabledResources(BTreeSet<ResourceId>),
+    /// Asks for connlib events, even though we don't own the session
+    ///
+    /// The IPC client that sent `Connect` always gets events.
+    Subscribe,
 }
 
 /// Messages that end up in the GUI, either forwarded from connlib or from the IPC service.
@@ -89,11 +103,15 @@ pub enum ServerMsg {
     /// The IPC service finished clearing its log dir.
     ClearedLogs(Result<(), String>),
     ConnectResult(Result<(), Error>),
+    /// True while connlib can't reach the portal, see `Callbacks::on_control_plane_degraded`
+    ControlPlaneDegraded(bool),
     OnDisconnect {
         error_msg: String,
         is_authentication_error: bool,
     },
     OnUpdateResources(Vec<ResourceDescription>),
+    /// Reply to `ClientMsg::GetStatus`
+    Status(Status),
     /// The IPC service is terminating, maybe due to a software update
     ///
     /// This is a hint that the Client should exit with a message like,
@@ -102,15 +120,39 @@ pub enum ServerMsg {
     TerminatingGracefully,
     /// The interface and tunnel are ready for traffic.
     TunnelReady,
+    /// Another IPC client owns the session, so we ignored the message
+    Unauthorized(String),
+}
+
+/// A snapshot of the tunnel's state, for the CLI and other IPC clients that connect after the fact
+///
+/// Has everything a UI needs to render, so it doesn't have to wait for the next event.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Status {
+    /// True if we have a connlib session, even if it's still signing in
+    pub connected: bool,
+    /// True once connlib has configured the tunnel interface
+    pub tunnel_ready: bool,
+    /// True while we can't reach the portal, but existing connections keep working
+    pub control_plane_degraded: bool,
+    pub resources: Vec<ResourceDescription>,
+    pub disabled_resources: BTreeSet<ResourceId>,
+    /// Interface IPs, DNS sentinels, Gateways and traffic counters from connlib
+    ///
+    /// `None` if there's no connlib session
+    pub tunnel: Option<ClientStats>,
 }
 
 // All variants are `String` because almost no error type implements `Serialize`
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Error {
     DeviceId(String),
+    KillSwitch(String),
     LoginUrl(String),
     PortalConnection(String),
     TunnelDevice(String),
+    /// Another IPC client already owns the session
+    Unauthorized(String),
     UrlParse(String),
 }
 
@@ -151,6 +193,8 @@ fn run_debug_ipc_service(cli: Cli) -> Result<()> {
 
     rt.block_on(ipc_listen(
         cli.common.dns_control,
+        cli.common.kill_switch,
+        cli.common.mtu,
         &log_filter_reloader,
         &mut signals,
     ))
@@ -161,7 +205,7 @@ fn run_smoke_test() -> Result<()> {
     anyhow::bail!("Smoke test is not built for release binaries.");
 }
 
-/// Listen for exactly one connection from a GUI, then exit
+/// Serve IPC clients until the last one disconnects, then exit
 ///
 /// This makes the timing neater in case the GUI starts up slowly.
 #[cfg(debug_assertions)]
@@ -175,126 +219,148 @@ fn run_smoke_test() -> Result<()> {
     let mut dns_controller = DnsController {
         dns_control_method: Default::default(),
     };
-    // Deactivate Firezone DNS control in case the system or IPC service crashed
-    // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
-    dns_controller.deactivate()?;
     let mut signals = signals::Terminate::new()?;
 
     // Couldn't get the loop to work here yet, so SIGHUP is not implemented
     rt.block_on(async {
         device_id::get_or_create().context("Failed to read / create device ID")?;
-        let mut server = IpcServer::new(ServiceId::Prod).await?;
-        let _ = Handler::new(&mut server, &mut dns_controller, &log_filter_reloader)
-            .await?
-            .run(&mut signals)
-            .await;
+        let server = IpcServer::new(ServiceId::Prod).await?;
+        platform::notify_service_controller()?;
+        let _ = Handler::new(
+            server,
+            &mut dns_controller,
+            false,
+            DEFAULT_MTU,
+            &log_filter_reloader,
+        )
+        .await?
+        .run(&mut signals)
+        .await;
         Ok::<_, anyhow::Error>(())
     })
 }
 
 /// Run the IPC service and terminate gracefully if we catch a terminate signal
 ///
-/// If an IPC client is connected when we catch a terminate signal, we send the
-/// client a hint about that before we exit.
+/// If IPC clients are connected when we catch a terminate signal, we send the
+/// clients a hint about that before we exit.
 async fn ipc_listen(
     dns_control_method: DnsControlMethod,
+    kill_switch: bool,
+    mtu: usize,
     log_filter_reloader: &LogFilterReloader,
     signals: &mut signals::Terminate,
 ) -> Result<()> {
     // Create the device ID and IPC service config dir if needed
     // This also gives the GUI a safe place to put the log filter config
     device_id::get_or_create().context("Failed to read / create device ID")?;
-    let mut server = IpcServer::new(ServiceId::Prod).await?;
+    let server = IpcServer::new(ServiceId::Prod).await?;
+    platform::notify_service_controller()?;
     let mut dns_controller = DnsController { dns_control_method };
+    let mut handler = Handler::new(
+        server,
+        &mut dns_controller,
+        kill_switch,
+        mtu,
+        log_filter_reloader,
+    )
+    .await?;
     loop {
-        let mut handler_fut = pin!(Handler::new(
-            &mut server,
-            &mut dns_controller,
-            log_filter_reloader
-        ));
-        let Some(handler) = poll_fn(|cx| {
-            if let Poll::Ready(()) = signals.poll_recv(cx) {
-                Poll::Ready(None)
-            } else if let Poll::Ready(handler) = handler_fut.as_mut().poll(cx) {
-                Poll::Ready(Some(handler))
-            } else {
-                Poll::Pending
-            }
-        })
-        .await
-        else {
-            tracing::info!("Caught SIGINT / SIGTERM / Ctrl+C while waiting on the next client.");
-            break;
-        };
-        let mut handler = handler?;
-        if let HandlerOk::ServiceTerminating = handler.run(signals).await {
-            break;
+        match handler.run(signals).await {
+            HandlerOk::ClientDisconnected => {}
+            HandlerOk::Err => bail!("Handler stopped with an error"),
+            HandlerOk::ServiceTerminating => break,
         }
     }
     Ok(())
 }
 
-/// Handles one IPC client
+/// Handles all IPC clients and the connlib session that one of them owns
 struct Handler<'a> {
     callback_handler: CallbackHandler,
     cb_rx: mpsc::Receiver<ConnlibMsg>,
     connlib: Option<connlib_client_shared::Session>,
+    control_plane_degraded: bool,
+    /// The last set of disabled Resources that the owner gave us, for `Status`
+    disabled_resources: BTreeSet<ResourceId>,
     dns_controller: &'a mut DnsController,
-    ipc_rx: ipc::ServerRead,
-    ipc_tx: ipc::ServerWrite,
+    ipc_clients: Clients,
+    /// Enabled while connlib is running, if `use_kill_switch` is set
+    kill_switch: KillSwitch,
     last_connlib_start_instant: Option<Instant>,
     log_filter_reloader: &'a LogFilterReloader,
+    mtu: usize,
+    /// Our public key in the current session, so the owner can reconnect it with a new token
+    public_key: Option<[u8; 32]>,
+    /// The last Resources that connlib gave us, for `GetResources` and `GetStatus`
+    resources: Vec<ResourceDescription>,
+    /// Held until we exit, so the next run knows we're gone
+    #[cfg(target_os = "linux")]
+    _restore_owner: Option<crate::restore::Owner>,
     tun_device: TunDeviceManager,
+    use_kill_switch: bool,
 }
 
 enum Event {
     Callback(ConnlibMsg),
     CallbackChannelClosed,
-    Ipc(ClientMsg),
-    IpcDisconnected,
-    IpcError(anyhow::Error),
+    Ipc(clients::Event),
     Terminate,
 }
 
 // Open to better names
 #[must_use]
 enum HandlerOk {
+    /// The last IPC client disconnected
     ClientDisconnected,
     Err,
     ServiceTerminating,
 }
 
 impl<'a> Handler<'a> {
+    /// Panics if there's no Tokio runtime
     async fn new(
-        server: &mut IpcServer,
+        server: IpcServer,
         dns_controller: &'a mut DnsController,
+        use_kill_switch: bool,
+        mtu: usize,
         log_filter_reloader: &'a LogFilterReloader,
     ) -> Result<Self> {
+        // Deactivate Firezone DNS control in case the system or IPC service crashed
+        // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
         dns_controller.deactivate()?;
-        let (ipc_rx, ipc_tx) = server
-            .next_client_split()
-            .await
-            .context("Failed to wait for incoming IPC connection from a GUI")?;
         let (cb_tx, cb_rx) = mpsc::channel(1_000);
-        let tun_device = TunDeviceManager::new(DEFAULT_MTU)?;
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut tun_device = TunDeviceManager::new(mtu)?;
+        // Undo whatever the last run couldn't, e.g. because it was killed.
+        #[cfg(target_os = "linux")]
+        let restore_owner = crate::restore::take_over(&mut tun_device).await?;
 
         Ok(Self {
             callback_handler: CallbackHandler { cb_tx },
             cb_rx,
             connlib: None,
+            control_plane_degraded: false,
+            disabled_resources: Default::default(),
             dns_controller,
-            ipc_rx,
-            ipc_tx,
+            ipc_clients: Clients::new(server),
+            kill_switch: Default::default(),
             last_connlib_start_instant: None,
             log_filter_reloader,
+            mtu,
+            public_key: None,
+            #[cfg(target_os = "linux")]
+            _restore_owner: restore_owner,
+            resources: Default::default(),
             tun_device,
+            use_kill_switch,
         })
     }
 
-    /// Run the event loop to communicate with an IPC client.
+    /// Run the event loop to communicate with IPC clients, until the last one disconnects.
     ///
     /// If the IPC service needs to terminate, we catch that from `signals` and send
-    /// the client a hint to shut itself down gracefully.
+    /// the clients a hint to shut themselves down gracefully.
     ///
     /// The return type is infallible so that we only give up on an IPC client explicitly
     async fn run(&mut self, signals: &mut signals::Terminate) -> HandlerOk {
@@ -310,32 +376,42 @@ impl<'a> Handler<'a> {
                     tracing::error!("Impossible - Callback channel closed");
                     break HandlerOk::Err;
                 }
-                Event::Ipc(msg) => {
+                Event::Ipc(clients::Event::Connected(id, peer)) => {
+                    tracing::info!(%id, ?peer, "IPC client connected");
+                }
+                Event::Ipc(clients::Event::Msg(id, msg)) => {
                     let msg_variant = serde_variant::to_variant_name(&msg)
                         .expect("IPC messages should be enums, not structs or anything else.");
                     let _entered =
-                        tracing::error_span!("handle_ipc_msg", msg = %msg_variant).entered();
-                    if let Err(error) = self.handle_ipc_msg(msg).await {
+                        tracing::error_span!("handle_ipc_msg", %id, msg = %msg_variant).entered();
+                    if let Err(error) = self.handle_ipc_msg(id, msg).await {
                         tracing::error!(?error, "Error while handling IPC message from client");
                         continue;
                     }
                 }
-                Event::IpcDisconnected => {
-                    tracing::info!("IPC client disconnected");
-                    break HandlerOk::ClientDisconnected;
+                Event::Ipc(clients::Event::Disconnected { id, was_owner }) => {
+                    tracing::info!(%id, "IPC client disconnected");
+                    if was_owner {
+                        // The GUI closes IPC without `Disconnect` when it quits, so clean up for it.
+                        if let Err(error) = self.disconnect_connlib() {
+                            tracing::error!(?error, "Error while tearing down connlib session");
+                        }
+                    }
+                    if self.ipc_clients.is_empty() {
+                        break HandlerOk::ClientDisconnected;
+                    }
                 }
-                Event::IpcError(error) => {
-                    tracing::error!(?error, "Error while deserializing IPC message");
+                Event::Ipc(clients::Event::Error(id, error)) => {
+                    tracing::error!(?error, %id, "Error while deserializing IPC message");
                     continue;
                 }
                 Event::Terminate => {
-                    tracing::info!(
-                        "Caught SIGINT / SIGTERM / Ctrl+C while an IPC client is connected"
-                    );
-                    self.ipc_tx
-                        .send(&ServerMsg::TerminatingGracefully)
-                        .await
-                        .unwrap();
+                    tracing::info!("Caught SIGINT / SIGTERM / Ctrl+C");
+                    self.ipc_clients
+                        .send_to_all(ServerMsg::TerminatingGracefully)
+                        .await;
+                    #[cfg(target_os = "linux")]
+                    self.remove_routing().await;
                     break HandlerOk::ServiceTerminating;
                 }
             }
@@ -351,13 +427,9 @@ impl<'a> Handler<'a> {
         if let Poll::Ready(()) = signals.poll_recv(cx) {
             return Poll::Ready(Event::Terminate);
         }
-        // `FramedRead::next` is cancel-safe.
-        if let Poll::Ready(result) = pin!(&mut self.ipc_rx).poll_next(cx) {
-            return match result {
-                Some(Ok(x)) => Poll::Ready(Event::Ipc(x)),
-                Some(Err(error)) => Poll::Ready(Event::IpcError(error)),
-                None => Poll::Ready(Event::IpcDisconnected),
-            };
+        // `Clients::poll_next` is cancel-safe.
+        if let Poll::Ready(event) = self.ipc_clients.poll_next(cx) {
+            return Poll::Ready(Event::Ipc(event));
         }
         // `tokio::sync::mpsc::Receiver::recv` is cancel-safe.
         if let Poll::Ready(option) = self.cb_rx.poll_recv(cx) {
@@ -371,35 +443,44 @@ impl<'a> Handler<'a> {
 
     async fn handle_connlib_cb(&mut self, msg: ConnlibMsg) -> Result<()> {
         match msg {
+            ConnlibMsg::OnControlPlaneDegraded(degraded) => {
+                self.control_plane_degraded = degraded;
+                self.ipc_clients
+                    .broadcast(ServerMsg::ControlPlaneDegraded(degraded))
+                    .await;
+            }
             ConnlibMsg::OnDisconnect {
                 error_msg,
                 is_authentication_error,
-            } => self
-                .ipc_tx
-                .send(&ServerMsg::OnDisconnect {
-                    error_msg,
-                    is_authentication_error,
-                })
-                .await
-                .context("Error while sending IPC message `OnDisconnect`")?,
+            } => {
+                self.ipc_clients
+                    .broadcast(ServerMsg::OnDisconnect {
+                        error_msg,
+                        is_authentication_error,
+                    })
+                    .await
+            }
             ConnlibMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
+                #[cfg(target_os = "linux")]
+                crate::restore::record(crate::restore::Undo::Routing);
                 self.tun_device.set_ips(ipv4, ipv6).await?;
                 self.dns_controller.set_dns(dns).await?;
                 if let Some(instant) = self.last_connlib_start_instant.take() {
                     tracing::info!(elapsed = ?instant.elapsed(), "Tunnel ready");
                 }
-                self.ipc_tx
-                    .send(&ServerMsg::TunnelReady)
-                    .await
-                    .context("Error while sending IPC message `TunnelReady`")?;
+                self.ipc_clients.broadcast(ServerMsg::TunnelReady).await;
             }
             ConnlibMsg::OnUpdateResources(resources) => {
                 // On every resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                 self.dns_controller.flush()?;
-                self.ipc_tx
-                    .send(&ServerMsg::OnUpdateResources(resources))
-                    .await
-                    .context("Error while sending IPC message `OnUpdateResources`")?;
+                // `set_dns` routed all queries to our sentinels, they keep reaching them without split DNS, so keep going.
+                if let Err(error) = self.dns_controller.set_resources(&resources).await {
+                    tracing::error!(?error, "Failed to route DNS Resources to our sentinels");
+                }
+                self.resources.clone_from(&resources);
+                self.ipc_clients
+                    .broadcast(ServerMsg::OnUpdateResources(resources))
+                    .await;
             }
             ConnlibMsg::OnUpdateRoutes { ipv4, ipv6 } => {
                 self.tun_device.set_routes(ipv4, ipv6).await?;
@@ -413,38 +494,79 @@ impl<'a> Handler<'a> {
         self.last_connlib_start_instant.is_none() && self.connlib.is_some()
     }
 
-    async fn handle_ipc_msg(&mut self, msg: ClientMsg) -> Result<()> {
+    async fn handle_ipc_msg(&mut self, id: ClientId, msg: ClientMsg) -> Result<()> {
+        if !msg.is_read_only() && !self.ipc_clients.may_control(id) {
+            tracing::warn!("IPC client may not change a session that another client owns");
+            return self
+                .ipc_clients
+                .send(
+                    id,
+                    ServerMsg::Unauthorized("Another client owns the session".to_string()),
+                )
+                .await;
+        }
+
         match msg {
             ClientMsg::ClearLogs => {
                 let result = crate::clear_logs(
                     &crate::known_dirs::ipc_service_logs().context("Can't compute logs dir")?,
                 )
                 .await;
-                self.ipc_tx
-                    .send(&ServerMsg::ClearedLogs(result.map_err(|e| e.to_string())))
-                    .await
-                    .context("Error while sending IPC message")?
+                self.ipc_clients
+                    .send(
+                        id,
+                        ServerMsg::ClearedLogs(result.map_err(|e| e.to_string())),
+                    )
+                    .await?
             }
             ClientMsg::Connect { api_url, token } => {
                 // Warning: Connection errors don't bubble to callers of `handle_ipc_msg`.
                 let token = secrecy::SecretString::from(token);
-                let result = self.connect_to_firezone(&api_url, token);
-                if let Err(error) = &result {
-                    tracing::error!(?error, "Failed to connect connlib session");
+                let result = if self.connlib.is_none() {
+                    self.connect_to_firezone(&api_url, token)
+                } else if self.ipc_clients.is_owner(id) {
+                    // e.g. the GUI signed in again, keep the tunnel
+                    self.reconnect_to_portal(&api_url, &token).await
+                } else {
+                    Err(Error::Unauthorized(
+                        "Another client already started a session".to_string(),
+                    ))
+                };
+                match &result {
+                    Ok(()) => self.ipc_clients.set_owner(Some(id)),
+                    Err(error) => tracing::error!(?error, "Failed to connect connlib session"),
                 }
-                self.ipc_tx
-                    .send(&ServerMsg::ConnectResult(result))
-                    .await
-                    .context("Failed to send `ConnectResult`")?
+                self.ipc_clients
+                    .send(id, ServerMsg::ConnectResult(result))
+                    .await?
             }
             ClientMsg::Disconnect => {
-                if let Some(connlib) = self.connlib.take() {
-                    connlib.disconnect();
-                    self.dns_controller.deactivate()?;
+                if self.connlib.is_some() {
+                    self.disconnect_connlib()?;
                 } else {
                     tracing::error!("Error - Got Disconnect when we're already not connected");
                 }
             }
+            ClientMsg::GetResources => {
+                self.ipc_clients
+                    .send(id, ServerMsg::OnUpdateResources(self.resources.clone()))
+                    .await?
+            }
+            ClientMsg::GetStatus => {
+                let tunnel = match &self.connlib {
+                    Some(connlib) => connlib.stats().await,
+                    None => None,
+                };
+                let status = Status {
+                    connected: self.connlib.is_some(),
+                    tunnel_ready: self.tunnel_is_ready(),
+                    control_plane_degraded: self.control_plane_degraded,
+                    resources: self.resources.clone(),
+                    disabled_resources: self.disabled_resources.clone(),
+                    tunnel,
+                };
+                self.ipc_clients.send(id, ServerMsg::Status(status)).await?
+            }
             ClientMsg::ReloadLogFilter => {
                 let filter = spawn_blocking(get_log_filter).await??;
                 self.log_filter_reloader.reload(filter)?;
@@ -458,21 +580,53 @@ impl<'a> Handler<'a> {
             }
             ClientMsg::SetDns(resolvers) => {
                 tracing::debug!(?resolvers);
+                self.kill_switch.set_resolvers(&resolvers)?;
                 self.connlib
                     .as_mut()
                     .context("No connlib session")?
                     .set_dns(resolvers)
             }
             ClientMsg::SetDisabledResources(disabled_resources) => {
+                self.disabled_resources.clone_from(&disabled_resources);
                 self.connlib
                     .as_mut()
                     .context("No connlib session")?
                     .set_disabled_resources(disabled_resources);
             }
+            ClientMsg::Subscribe => self.ipc_clients.subscribe(id),
         }
         Ok(())
     }
 
+    /// Deletes our `ip rule`s on a clean exit, the kernel only deletes our routes along with the TUN device
+    #[cfg(target_os = "linux")]
+    async fn remove_routing(&mut self) {
+        match self.tun_device.remove_routing().await {
+            Ok(()) => crate::restore::forget(crate::restore::Undo::Routing),
+            Err(error) => tracing::error!(?error, "Failed to remove our routing"),
+        }
+    }
+
+    /// Tears down the connlib session, if there is one, and gives up ownership of it
+    fn disconnect_connlib(&mut self) -> Result<()> {
+        self.ipc_clients.set_owner(None);
+        // Even without a session, `connect_to_firezone` may have enabled it before failing, or we may have crashed.
+        #[cfg(target_os = "linux")]
+        crate::restore::remove_kill_switch(&mut self.kill_switch)?;
+        #[cfg(not(target_os = "linux"))]
+        self.kill_switch.disable()?;
+        let Some(connlib) = self.connlib.take() else {
+            return Ok(());
+        };
+        connlib.disconnect();
+        self.last_connlib_start_instant = None;
+        self.public_key = None;
+        self.control_plane_degraded = false;
+        self.resources.clear();
+        self.dns_controller.deactivate()?;
+        Ok(())
+    }
+
     /// Connects connlib
     ///
     /// Panics if there's no Tokio runtime or if connlib is already connected
@@ -483,17 +637,8 @@ impl<'a> Handler<'a> {
         // right now because `Session::disconnect` is fire-and-forget:
         // <https://github.com/firezone/firezone/blob/663367b6055ced7432866a40a60f9525db13288b/rust/connlib/clients/shared/src/lib.rs#L98-L103>
         assert!(self.connlib.is_none());
-        let device_id = device_id::get_or_create().map_err(|e| Error::DeviceId(e.to_string()))?;
         let (private_key, public_key) = keypair();
-
-        let url = LoginUrl::client(
-            Url::parse(api_url).map_err(|e| Error::UrlParse(e.to_string()))?,
-            &token,
-            device_id.id,
-            None,
-            public_key.to_bytes(),
-        )
-        .map_err(|e| Error::LoginUrl(e.to_string()))?;
+        let url = login_url(api_url, &token, public_key.to_bytes())?;
 
         self.last_connlib_start_instant = Some(Instant::now());
         let args = ConnectArgs {
@@ -501,6 +646,7 @@ impl<'a> Handler<'a> {
             udp_socket_factory: Arc::new(udp_socket_factory),
             private_key,
             callbacks: self.callback_handler.clone(),
+            mtu: self.mtu,
         };
 
         // Synchronous DNS resolution here
@@ -518,6 +664,17 @@ impl<'a> Handler<'a> {
 
         // Read the resolvers before starting connlib, in case connlib's startup interferes.
         let dns = self.dns_controller.system_resolvers();
+
+        // After `PhoenixChannel::connect`, because its DNS lookup doesn't go through our sockets,
+        // and before connlib starts, so we never run a session without it.
+        // Later lookups still need the system's resolvers, so it lets DNS to them through.
+        if self.use_kill_switch {
+            #[cfg(target_os = "linux")]
+            crate::restore::record(crate::restore::Undo::KillSwitch);
+            self.kill_switch
+                .enable(&dns)
+                .map_err(|e| Error::KillSwitch(e.to_string()))?;
+        }
         let new_session = Session::connect(args, portal, tokio::runtime::Handle::current());
         // Call `set_dns` before `set_tun` so that the tunnel starts up with a valid list of resolvers.
         tracing::debug!(?dns, "Calling `set_dns`...");
@@ -528,18 +685,65 @@ impl<'a> Handler<'a> {
             .map_err(|e| Error::TunnelDevice(e.to_string()))?;
         new_session.set_tun(Box::new(tun));
         self.connlib = Some(new_session);
+        self.public_key = Some(public_key.to_bytes());
 
         Ok(())
     }
+
+    /// Reconnects the existing session to the portal with a new token or API URL
+    ///
+    /// Connections to Gateways survive, as long as the portal still gives us the same Resources.
+    ///
+    /// Panics if connlib isn't connected
+    async fn reconnect_to_portal(
+        &mut self,
+        api_url: &str,
+        token: &SecretString,
+    ) -> Result<(), Error> {
+        let (Some(connlib), Some(public_key)) = (&self.connlib, self.public_key) else {
+            panic!("Can't reconnect to the portal without a session");
+        };
+        let url = login_url(api_url, token, public_key)?;
+        // Off the eventloop, since connlib may be the system's resolver
+        let resolved_addresses = phoenix_channel::lookup_host(&url)
+            .await
+            .map_err(|e| Error::PortalConnection(e.to_string()))?;
+        connlib.set_login_url(url, resolved_addresses);
+        tracing::info!("Reconnecting to the portal with new credentials");
+
+        Ok(())
+    }
+}
+
+fn login_url(api_url: &str, token: &SecretString, public_key: [u8; 32]) -> Result<LoginUrl, Error> {
+    let device_id = device_id::get_or_create().map_err(|e| Error::DeviceId(e.to_string()))?;
+
+    LoginUrl::client(
+        Url::parse(api_url).map_err(|e| Error::UrlParse(e.to_string()))?,
+        token,
+        device_id.id,
+        None,
+        public_key,
+    )
+    .map_err(|e| Error::LoginUrl(e.to_string()))
 }
 
 /// Starts logging for the production IPC service
 ///
-/// Returns: A `Handle` that must be kept alive. Dropping it stops logging
-/// and flushes the log file.
+/// Returns: `Handle`s that must be kept alive. Dropping them stops logging
+/// and flushes the log file and the log shipper.
+///
+/// Must be called within a Tokio runtime if `otlp_grpc_endpoint` is set, the OTLP exporters run on it.
 fn setup_logging(
     log_dir: Option<PathBuf>,
-) -> Result<(firezone_logging::file::Handle, LogFilterReloader)> {
+    log_ship: Option<&ship::Destination>,
+    otlp_grpc_endpoint: Option<&str>,
+    retention: file::Retention,
+) -> Result<(
+    firezone_logging::file::Handle,
+    Option<ship::Handle>,
+    LogFilterReloader,
+)> {
     // If `log_dir` is Some, use that. Else call `ipc_service_logs`
     let log_dir = log_dir.map_or_else(
         || known_dirs::ipc_service_logs().context("Should be able to compute IPC service logs dir"),
@@ -547,11 +751,33 @@ fn setup_logging(
     )?;
     std::fs::create_dir_all(&log_dir)
         .context("We should have permissions to create our log dir")?;
-    let (layer, handle) = firezone_logging::file::layer(&log_dir);
+    let (layer, handle) = file::layer_with_retention(&log_dir, retention);
+    let (ship_layer, ship_handle) = log_ship
+        .map(|destination| {
+            ship::layer(
+                destination,
+                "firezone-client-ipc",
+                Arc::new(tcp_socket_factory),
+                Arc::new(udp_socket_factory),
+            )
+            .with_context(|| format!("Couldn't ship logs to `{destination}`"))
+        })
+        .transpose()?
+        .unzip();
+    let otlp_layer = otlp_grpc_endpoint
+        .map(|endpoint| {
+            otlp::layer(endpoint, "firezone-client-ipc")
+                .with_context(|| format!("Couldn't export to the OTLP collector at `{endpoint}`"))
+        })
+        .transpose()?;
     let directives = get_log_filter().context("Couldn't read log filter")?;
     let (filter, reloader) =
         tracing_subscriber::reload::Layer::new(firezone_logging::try_filter(&directives)?);
-    let subscriber = Registry::default().with(layer.with_filter(filter));
+    let layers = iter::once(layer)
+        .chain(ship_layer)
+        .chain(otlp_layer)
+        .collect::<Vec<_>>();
+    let subscriber = Registry::default().with(layers.with_filter(filter));
     set_global_default(subscriber).context("`set_global_default` should always work)")?;
     tracing::info!(
         arch = std::env::consts::ARCH,
@@ -559,7 +785,7 @@ fn setup_logging(
         system_uptime_seconds = crate::uptime::get().map(|dur| dur.as_secs()),
         ?directives
     );
-    Ok((handle, reloader))
+    Ok((handle, ship_handle, reloader))
 }
 
 /// Reads the log filter for the IPC service or for debug commands
@@ -592,7 +818,7 @@ pub(crate) fn get_log_filter() -> Result<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Cli, Cmd};
+    use super::{Cli, ClientStats, Cmd, ServerMsg, Status};
     use clap::Parser;
     use std::path::PathBuf;
 
@@ -610,4 +836,25 @@ mod tests {
         let actual = Cli::try_parse_from([EXE_NAME, "run"]).unwrap();
         assert!(matches!(actual.command, Cmd::Run));
     }
+
+    #[test]
+    fn status_round_trips() {
+        let status = Status {
+            connected: true,
+            tunnel_ready: true,
+            control_plane_degraded: false,
+            resources: vec![],
+            disabled_resources: ["73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap()].into(),
+            tunnel: Some(ClientStats::default()),
+        };
+
+        let json = serde_json::to_string(&ServerMsg::Status(status)).unwrap();
+        let ServerMsg::Status(actual) = serde_json::from_str(&json).unwrap() else {
+            panic!("Expected `Status`");
+        };
+
+        assert!(actual.tunnel_ready);
+        assert_eq!(actual.disabled_resources.len(), 1);
+        assert_eq!(actual.tunnel, Some(ClientStats::default()));
+    }
 }
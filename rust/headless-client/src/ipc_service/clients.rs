@@ -0,0 +1,382 @@
+//! Bookkeeping for the IPC clients that are connected to the IPC service at the same time
+//!
+//! The client that starts a connlib session owns it. Only the owner, or a privileged
+//! client like the CLI running as root, may change the session. All other clients
+//! are observers. They may query the status and subscribe to events.
+//!
+//! Every client has a bounded queue that a background task drains into its socket,
+//! so an observer that stops reading can't stall the IPC service. Observers that
+//! fall behind by more than a queue's worth of messages are disconnected.
+
+use super::{
+    ipc::{self, Peer},
+    ClientMsg, ServerMsg,
+};
+use anyhow::{Context as _, Result};
+use futures::{
+    stream::{self, AbortHandle, BoxStream, SelectAll},
+    task::{Context, Poll},
+    SinkExt as _, StreamExt as _,
+};
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+/// How many messages we queue for a client before we consider it too slow
+const QUEUE_SIZE: usize = 64;
+
+/// How long we wait for the last messages to reach the clients when the IPC service terminates
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Identifies an IPC connection for as long as the IPC service runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ClientId(u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub(crate) enum Event {
+    Connected(ClientId, Peer),
+    Msg(ClientId, ClientMsg),
+    /// `was_owner` is true if the client owned the connlib session
+    Disconnected {
+        id: ClientId,
+        was_owner: bool,
+    },
+    Error(ClientId, anyhow::Error),
+}
+
+pub(crate) struct Clients {
+    accept_task: JoinHandle<()>,
+    new_clients: mpsc::Receiver<(ipc::ServerRead, ipc::ServerWrite, Peer)>,
+    next_id: u64,
+    /// The client that started the current connlib session, if any
+    owner: Option<ClientId>,
+    reads: SelectAll<BoxStream<'static, (ClientId, Option<Result<ClientMsg>>)>>,
+    writes: BTreeMap<ClientId, Client>,
+}
+
+struct Client {
+    peer: Peer,
+    /// True if the client asked for events with `ClientMsg::Subscribe`
+    subscribed: bool,
+    /// Queue for the task that writes to the client's socket
+    tx: mpsc::Sender<Arc<ServerMsg>>,
+    /// Ends the client's read stream, which makes `poll_next` report it as disconnected
+    reads: AbortHandle,
+    write_task: JoinHandle<()>,
+}
+
+impl Drop for Clients {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl Clients {
+    /// Starts accepting IPC clients in the background
+    ///
+    /// Panics if there's no Tokio runtime
+    pub(crate) fn new(mut server: ipc::Server) -> Self {
+        let (clients_tx, new_clients) = mpsc::channel(4);
+        let accept_task = tokio::spawn(async move {
+            loop {
+                match server.next_client_split().await {
+                    Ok(client) => {
+                        if clients_tx.send(client).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        tracing::error!(?error, "Failed to wait for incoming IPC connection");
+                        // Don't spin if the error persists, e.g. if we're out of file descriptors
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            accept_task,
+            new_clients,
+            next_id: 0,
+            owner: None,
+            reads: Default::default(),
+            writes: Default::default(),
+        }
+    }
+
+    /// Cancel-safe
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Event> {
+        if let Poll::Ready(Some((rx, tx, peer))) = self.new_clients.poll_recv(cx) {
+            let id = ClientId(self.next_id);
+            self.next_id += 1;
+            let (rx, reads) = stream::abortable(rx);
+            self.reads.push(
+                rx.map(move |msg| (id, Some(msg)))
+                    .chain(stream::once(async move { (id, None) }))
+                    .boxed(),
+            );
+            let (queue_tx, queue_rx) = mpsc::channel(QUEUE_SIZE);
+            self.writes.insert(
+                id,
+                Client {
+                    peer,
+                    subscribed: false,
+                    tx: queue_tx,
+                    reads,
+                    write_task: tokio::spawn(write_to_client(id, tx, queue_rx)),
+                },
+            );
+            return Poll::Ready(Event::Connected(id, peer));
+        }
+
+        // `SelectAll` is `Ready(None)` while there are no clients, new ones wake us through `new_clients`.
+        if let Poll::Ready(Some((id, msg))) = self.reads.poll_next_unpin(cx) {
+            return Poll::Ready(match msg {
+                Some(Ok(msg)) => Event::Msg(id, msg),
+                Some(Err(error)) => Event::Error(id, error),
+                None => {
+                    self.writes.remove(&id);
+                    let was_owner = self.owner == Some(id);
+                    if was_owner {
+                        self.owner = None;
+                    }
+                    Event::Disconnected { id, was_owner }
+                }
+            });
+        }
+
+        Poll::Pending
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub(crate) fn set_owner(&mut self, owner: Option<ClientId>) {
+        self.owner = owner;
+    }
+
+    pub(crate) fn is_owner(&self, id: ClientId) -> bool {
+        self.owner == Some(id)
+    }
+
+    pub(crate) fn subscribe(&mut self, id: ClientId) {
+        if let Some(client) = self.writes.get_mut(&id) {
+            client.subscribed = true;
+        }
+    }
+
+    /// Returns true if the client may send messages that change the session
+    ///
+    /// While there's no session, anyone may start one.
+    pub(crate) fn may_control(&self, id: ClientId) -> bool {
+        match self.owner {
+            None => true,
+            Some(owner) if owner == id => true,
+            Some(_) => self
+                .writes
+                .get(&id)
+                .is_some_and(|client| client.peer.privileged),
+        }
+    }
+
+    /// Replies to a single client
+    pub(crate) async fn send(&mut self, id: ClientId, msg: ServerMsg) -> Result<()> {
+        anyhow::ensure!(
+            self.writes.contains_key(&id),
+            "IPC client {id} already disconnected"
+        );
+        self.queue(id, Arc::new(msg)).await;
+        Ok(())
+    }
+
+    /// Sends an event to the owner and to all subscribers
+    pub(crate) async fn broadcast(&mut self, msg: ServerMsg) {
+        let msg = Arc::new(msg);
+        let owner = self.owner;
+        let ids = self
+            .writes
+            .iter()
+            .filter(|(id, client)| Some(**id) == owner || client.subscribed)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.queue(id, Arc::clone(&msg)).await;
+        }
+    }
+
+    /// Sends a message to every client, e.g. to tell them that we're terminating
+    ///
+    /// Closes all queues and waits up to `FLUSH_TIMEOUT` for the messages to go out,
+    /// so this must be the last message we send.
+    pub(crate) async fn send_to_all(&mut self, msg: ServerMsg) {
+        let msg = Arc::new(msg);
+        let mut write_tasks = vec![];
+        for (id, client) in std::mem::take(&mut self.writes) {
+            if let Err(error) = client.tx.try_send(Arc::clone(&msg)) {
+                tracing::warn!(%error, %id, "Couldn't queue IPC message");
+            }
+            write_tasks.push(client.write_task);
+        }
+        if tokio::time::timeout(FLUSH_TIMEOUT, futures::future::join_all(write_tasks))
+            .await
+            .is_err()
+        {
+            tracing::warn!("Timed out while sending the last IPC messages");
+        }
+    }
+
+    /// Queues a message for a client without waiting for the client to read it
+    ///
+    /// Observers that don't keep up get disconnected. The owner's session depends on
+    /// these messages, so for the owner we wait until there's room in its queue.
+    async fn queue(&mut self, id: ClientId, msg: Arc<ServerMsg>) {
+        let Some(client) = self.writes.get(&id) else {
+            return;
+        };
+        match client.tx.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) if self.owner == Some(id) => {
+                tracing::debug!(%id, "IPC owner is slow to read, waiting for its queue");
+                // If the write task is gone, the client is gone and we'll find out when reading from it
+                let _ = client.tx.send(msg).await;
+            }
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(%id, "IPC client is too slow to read events, disconnecting it");
+                if let Some(client) = self.writes.remove(&id) {
+                    client.reads.abort();
+                    client.write_task.abort();
+                }
+            }
+            // If the client is gone, we'll find out when reading from it
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Drains a client's queue into its socket
+async fn write_to_client(
+    id: ClientId,
+    mut tx: ipc::ServerWrite,
+    mut queue: mpsc::Receiver<Arc<ServerMsg>>,
+) {
+    while let Some(msg) = queue.recv().await {
+        if let Err(error) = tx.send(&*msg).await {
+            tracing::warn!(?error, %id, "Error while sending IPC message");
+            break;
+        }
+    }
+}
+
+impl ClientMsg {
+    /// Returns true for messages that only read state, which observers may also send
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            ClientMsg::GetResources | ClientMsg::GetStatus | ClientMsg::Subscribe => true,
+            ClientMsg::ClearLogs
+            | ClientMsg::Connect { .. }
+            | ClientMsg::Disconnect
+            | ClientMsg::ReloadLogFilter
+            | ClientMsg::Reset
+            | ClientMsg::SetDns(_)
+            | ClientMsg::SetDisabledResources(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::ServiceId;
+
+    #[tokio::test]
+    async fn only_owner_and_privileged_peers_may_control() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        const ID: ServiceId = ServiceId::Test("R4PJ6Q2W");
+
+        let mut clients = Clients::new(ipc::Server::new(ID).await?);
+        let _owner_conn = ipc::connect_to_service(ID).await?;
+        let _observer_conn = ipc::connect_to_service(ID).await?;
+
+        let mut ids = vec![];
+        while ids.len() < 2 {
+            if let Event::Connected(id, _) =
+                futures::future::poll_fn(|cx| clients.poll_next(cx)).await
+            {
+                ids.push(id);
+            }
+        }
+        let (owner, observer) = (ids[0], ids[1]);
+
+        // Nobody owns a session yet
+        assert!(clients.may_control(owner));
+        assert!(clients.may_control(observer));
+
+        clients.set_owner(Some(owner));
+        assert!(clients.may_control(owner));
+        // The test runs unprivileged, unless someone runs the tests as root
+        let observer_privileged = clients.writes[&observer].peer.privileged;
+        assert_eq!(clients.may_control(observer), observer_privileged);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_observers_get_disconnected() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        const ID: ServiceId = ServiceId::Test("9VFK3TQE");
+
+        let mut clients = Clients::new(ipc::Server::new(ID).await?);
+        // Never reads, so its socket and then its queue fill up
+        let _observer_conn = ipc::connect_to_service(ID).await?;
+
+        let observer = loop {
+            if let Event::Connected(id, _) =
+                futures::future::poll_fn(|cx| clients.poll_next(cx)).await
+            {
+                break id;
+            }
+        };
+        clients.subscribe(observer);
+
+        for _ in 0..1_000_000 {
+            if !clients.writes.contains_key(&observer) {
+                break;
+            }
+            clients.broadcast(ServerMsg::TunnelReady).await;
+            // Let the write task fill the socket
+            tokio::task::yield_now().await;
+        }
+        assert!(!clients.writes.contains_key(&observer));
+
+        let event = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::poll_fn(|cx| clients.poll_next(cx)),
+        )
+        .await?;
+        assert!(matches!(
+            event,
+            Event::Disconnected {
+                id,
+                was_owner: false
+            } if id == observer
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_msgs() {
+        assert!(ClientMsg::GetStatus.is_read_only());
+        assert!(ClientMsg::Subscribe.is_read_only());
+        assert!(!ClientMsg::Reset.is_read_only());
+        assert!(!ClientMsg::SetDns(vec![]).is_read_only());
+    }
+}
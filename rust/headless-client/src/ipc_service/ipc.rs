@@ -16,13 +16,13 @@ mod platform;
 #[path = "ipc/windows.rs"]
 pub mod platform;
 
-pub(crate) use platform::Server;
+pub use platform::Server;
 use platform::{ClientStream, ServerStream};
 
-pub(crate) type ClientRead = FramedRead<ReadHalf<ClientStream>, Decoder<IpcServerMsg>>;
+pub type ClientRead = FramedRead<ReadHalf<ClientStream>, Decoder<IpcServerMsg>>;
 pub type ClientWrite = FramedWrite<WriteHalf<ClientStream>, Encoder<IpcClientMsg>>;
-pub(crate) type ServerRead = FramedRead<ReadHalf<ServerStream>, Decoder<IpcClientMsg>>;
-pub(crate) type ServerWrite = FramedWrite<WriteHalf<ServerStream>, Encoder<IpcServerMsg>>;
+pub type ServerRead = FramedRead<ReadHalf<ServerStream>, Decoder<IpcClientMsg>>;
+pub type ServerWrite = FramedWrite<WriteHalf<ServerStream>, Encoder<IpcServerMsg>>;
 
 // pub so that the GUI can display a human-friendly message
 #[derive(Debug, thiserror::Error)]
@@ -36,6 +36,16 @@ pub enum Error {
     Other(anyhow::Error),
 }
 
+/// Who is on the other end of an IPC connection, as far as the OS can tell us
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub pid: Option<u32>,
+    /// True if the peer runs as root
+    ///
+    /// Privileged peers may control a session that another IPC client started.
+    pub privileged: bool,
+}
+
 /// A name that both the server and client can use to find each other
 ///
 /// In the platform-specific code, this is translated to a Unix Domain Socket
@@ -63,6 +73,11 @@ pub enum ServiceId {
     /// This must go in `/run/dev.firezone.client` on Linux, which requires
     /// root permission
     Prod,
+    /// The control socket of the Headless Client
+    ///
+    /// Separate from `Prod`, so the Headless Client and the IPC service can't
+    /// take over each other's socket if both are started.
+    Headless,
     /// An IPC service used for unit tests.
     ///
     /// This must go in `/run/user/$UID/dev.firezone.client` on Linux so
@@ -159,18 +174,19 @@ pub async fn connect_to_service(id: ServiceId) -> Result<(ClientRead, ClientWrit
 }
 
 impl platform::Server {
-    pub(crate) async fn next_client_split(&mut self) -> Result<(ServerRead, ServerWrite)> {
-        let (rx, tx) = tokio::io::split(self.next_client().await?);
+    pub async fn next_client_split(&mut self) -> Result<(ServerRead, ServerWrite, Peer)> {
+        let (stream, peer) = self.next_client().await?;
+        let (rx, tx) = tokio::io::split(stream);
         let rx = FramedRead::new(rx, Decoder::default());
         let tx = FramedWrite::new(tx, Encoder::default());
-        Ok((rx, tx))
+        Ok((rx, tx, peer))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{platform::Server, *};
-    use anyhow::{bail, ensure, Result};
+    use anyhow::{bail, ensure, Context as _, Result};
     use futures::{SinkExt, StreamExt};
     use std::time::Duration;
     use tokio::{task::JoinHandle, time::timeout};
@@ -186,6 +202,25 @@ mod tests {
         Ok(())
     }
 
+    /// A second server must not steal the socket of a server that's still running
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn refuses_live_socket() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        const ID: ServiceId = ServiceId::Test("K2DXQ7ZT");
+
+        let server = Server::new(ID).await?;
+        ensure!(
+            Server::new(ID).await.is_err(),
+            "Second server should refuse to start"
+        );
+        drop(server);
+        Server::new(ID)
+            .await
+            .context("Should be able to start after the first server is gone")?;
+        Ok(())
+    }
+
     /// Make sure the IPC client and server can exchange messages
     #[tokio::test]
     async fn smoke() -> Result<()> {
@@ -199,7 +234,7 @@ mod tests {
 
         let server_task: tokio::task::JoinHandle<Result<()>> = tokio::spawn(async move {
             for _ in 0..loops {
-                let (mut rx, mut tx) = server
+                let (mut rx, mut tx, _peer) = server
                     .next_client_split()
                     .await
                     .expect("Error while waiting for next IPC client");
@@ -1,10 +1,10 @@
-use super::{Error, ServiceId};
-use anyhow::{anyhow, Context as _, Result};
+use super::{Error, Peer, ServiceId};
+use anyhow::{anyhow, bail, Context as _, Result};
 use firezone_bin_shared::BUNDLE_ID;
 use std::{io::ErrorKind, os::unix::fs::PermissionsExt, path::PathBuf};
 use tokio::net::{UnixListener, UnixStream};
 
-pub(crate) struct Server {
+pub struct Server {
     listener: UnixListener,
 }
 
@@ -14,7 +14,7 @@ pub type ClientStream = UnixStream;
 /// Alias for the server's half of a platform-specific IPC stream
 ///
 /// On Windows `ClientStream` and `ServerStream` differ
-pub(crate) type ServerStream = UnixStream;
+pub type ServerStream = UnixStream;
 
 /// Connect to the IPC service
 #[allow(clippy::wildcard_enum_match_arm)]
@@ -47,8 +47,15 @@ pub async fn connect_to_service(id: ServiceId) -> Result<ClientStream, Error> {
 
 impl Server {
     /// Platform-specific setup
-    pub(crate) async fn new(id: ServiceId) -> Result<Self> {
+    pub async fn new(id: ServiceId) -> Result<Self> {
         let sock_path = ipc_path(id);
+        // Don't steal the socket from a server that's still running
+        if UnixStream::connect(&sock_path).await.is_ok() {
+            bail!(
+                "Another process is already listening on `{}`",
+                sock_path.display()
+            );
+        }
         // Remove the socket if a previous run left it there
         tokio::fs::remove_file(&sock_path).await.ok();
         // Create the dir if possible, needed for test paths under `/run/user`
@@ -60,14 +67,10 @@ impl Server {
             .with_context(|| format!("Couldn't bind UDS `{}`", sock_path.display()))?;
         let perms = std::fs::Permissions::from_mode(0o660);
         tokio::fs::set_permissions(&sock_path, perms).await?;
-
-        // TODO: Change this to `notify_service_controller` and put it in
-        // the same place in the IPC service's main loop as in the Headless Client.
-        sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
         Ok(Self { listener })
     }
 
-    pub(crate) async fn next_client(&mut self) -> Result<ServerStream> {
+    pub(crate) async fn next_client(&mut self) -> Result<(ServerStream, Peer)> {
         tracing::info!("Listening for GUI to connect over IPC...");
         let (stream, _) = self.listener.accept().await?;
         let cred = stream.peer_cred()?;
@@ -77,7 +80,11 @@ impl Server {
             pid = cred.pid(),
             "Accepted an IPC connection"
         );
-        Ok(stream)
+        let peer = Peer {
+            pid: cred.pid().and_then(|pid| u32::try_from(pid).ok()),
+            privileged: cred.uid() == 0,
+        };
+        Ok((stream, peer))
     }
 }
 
@@ -93,6 +100,7 @@ impl Server {
 fn ipc_path(id: ServiceId) -> PathBuf {
     match id {
         ServiceId::Prod => PathBuf::from("/run").join(BUNDLE_ID).join("ipc.sock"),
+        ServiceId::Headless => PathBuf::from("/run").join(BUNDLE_ID).join("headless.sock"),
         ServiceId::Test(id) => crate::known_dirs::runtime()
             .expect("`known_dirs::runtime()` should always work")
             .join(format!("ipc_test_{id}.sock")),
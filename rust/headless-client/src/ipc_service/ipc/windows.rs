@@ -1,4 +1,4 @@
-use super::{Error, ServiceId};
+use super::{Error, Peer, ServiceId};
 use anyhow::{bail, Context as _, Result};
 use firezone_bin_shared::BUNDLE_ID;
 use std::{ffi::c_void, io::ErrorKind, os::windows::io::AsRawHandle, time::Duration};
@@ -9,15 +9,20 @@ use windows::Win32::{
     System::Pipes::{GetNamedPipeClientProcessId, GetNamedPipeServerProcessId},
 };
 
-pub(crate) struct Server {
+pub struct Server {
     pipe_path: String,
+    /// True once we created the first instance of our named pipe
+    ///
+    /// Only the first instance is created with `first_pipe_instance`, so that nobody can squat
+    /// on our pipe name. The instances for concurrent clients come after.
+    created_first_instance: bool,
 }
 
 /// Alias for the client's half of a platform-specific IPC stream
 pub type ClientStream = named_pipe::NamedPipeClient;
 
 /// Alias for the server's half of a platform-specific IPC stream
-pub(crate) type ServerStream = named_pipe::NamedPipeServer;
+pub type ServerStream = named_pipe::NamedPipeServer;
 
 /// Connect to the IPC service
 ///
@@ -48,13 +53,16 @@ impl Server {
     ///
     /// This is async on Linux
     #[allow(clippy::unused_async)]
-    pub(crate) async fn new(id: ServiceId) -> Result<Self> {
+    pub async fn new(id: ServiceId) -> Result<Self> {
         let pipe_path = ipc_path(id);
-        Ok(Self { pipe_path })
+        Ok(Self {
+            pipe_path,
+            created_first_instance: false,
+        })
     }
 
     // `&mut self` needed to match the Linux signature
-    pub(crate) async fn next_client(&mut self) -> Result<ServerStream> {
+    pub(crate) async fn next_client(&mut self) -> Result<(ServerStream, Peer)> {
         // Fixes #5143. In the IPC service, if we close the pipe and immediately re-open
         // it, Tokio may not get a chance to clean up the pipe. Yielding seems to fix
         // this in tests, but `yield_now` doesn't make any such guarantees, so
@@ -81,17 +89,26 @@ impl Server {
         unsafe { GetNamedPipeClientProcessId(handle, &mut client_pid) }
             .context("Couldn't get PID of named pipe client")?;
         tracing::info!(?client_pid, "Accepted IPC connection");
-        Ok(server)
+        let peer = Peer {
+            pid: Some(client_pid),
+            // We don't check the elevation of the client process yet, so on Windows
+            // only the client that owns the session may control it.
+            privileged: false,
+        };
+        Ok((server, peer))
     }
 
-    async fn bind_to_pipe(&self) -> Result<ServerStream> {
+    async fn bind_to_pipe(&mut self) -> Result<ServerStream> {
         const NUM_ITERS: usize = 10;
         // This loop is defense-in-depth. The `yield_now` in `next_client` is enough
         // to fix #5143, but Tokio doesn't guarantee any behavior when yielding, so
         // the loop will catch it even if yielding doesn't.
         for i in 0..NUM_ITERS {
-            match create_pipe_server(&self.pipe_path) {
-                Ok(server) => return Ok(server),
+            match create_pipe_server(&self.pipe_path, !self.created_first_instance) {
+                Ok(server) => {
+                    self.created_first_instance = true;
+                    return Ok(server);
+                }
                 Err(PipeError::AccessDenied) => {
                     tracing::warn!("PipeError::AccessDenied, sleeping... (loop {i})");
                     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -111,9 +128,12 @@ enum PipeError {
     Other(#[from] anyhow::Error),
 }
 
-fn create_pipe_server(pipe_path: &str) -> Result<named_pipe::NamedPipeServer, PipeError> {
+fn create_pipe_server(
+    pipe_path: &str,
+    first_instance: bool,
+) -> Result<named_pipe::NamedPipeServer, PipeError> {
     let mut server_options = named_pipe::ServerOptions::new();
-    server_options.first_pipe_instance(true);
+    server_options.first_pipe_instance(first_instance);
 
     // This will allow non-admin clients to connect to us even though we're running with privilege
     let mut sd = WinSec::SECURITY_DESCRIPTOR::default();
@@ -158,6 +178,7 @@ fn create_pipe_server(pipe_path: &str) -> Result<named_pipe::NamedPipeServer, Pi
 fn ipc_path(id: ServiceId) -> String {
     let name = match id {
         ServiceId::Prod => format!("{BUNDLE_ID}.ipc_service"),
+        ServiceId::Headless => format!("{BUNDLE_ID}.headless_client"),
         ServiceId::Test(id) => format!("{BUNDLE_ID}_test_{id}.ipc_service"),
     };
     named_pipe_path(&name)
@@ -202,14 +223,14 @@ mod tests {
         let pipe_path = server_1.pipe_path.clone();
 
         tokio::spawn(async move {
-            let (mut rx, _tx) = server_1.next_client_split().await?;
+            let (mut rx, _tx, _peer) = server_1.next_client_split().await?;
             rx.next().await;
             Ok::<_, anyhow::Error>(())
         });
 
         let (_rx, _tx) = crate::ipc::connect_to_service(ID).await?;
 
-        match super::create_pipe_server(&pipe_path) {
+        match super::create_pipe_server(&pipe_path, true) {
             Err(super::PipeError::AccessDenied) => {}
             Err(error) => {
                 Err(error).context("Expected `PipeError::AccessDenied` but got another error")?
@@ -6,16 +6,25 @@ use anyhow::{bail, Result};
 ///
 /// Linux uses the CLI args from here, Windows does not
 pub(crate) fn run_ipc_service(cli: CliCommon) -> Result<()> {
-    let (_handle, log_filter_reloader) = super::setup_logging(cli.log_dir)?;
+    let retention = cli.log_retention();
+    // The OTLP exporters get spawned onto the runtime, so it must exist before we set up logging.
+    let rt = tokio::runtime::Runtime::new()?;
+    let _guard = rt.enter();
+    let (_handle, _ship_handle, log_filter_reloader) = super::setup_logging(
+        cli.log_dir,
+        cli.log_ship.as_ref(),
+        cli.otlp_grpc_endpoint.as_deref(),
+        retention,
+    )?;
     if !elevation_check()? {
         bail!("IPC service failed its elevation check, try running as admin / root");
     }
-    let rt = tokio::runtime::Runtime::new()?;
-    let _guard = rt.enter();
     let mut signals = signals::Terminate::new()?;
 
     rt.block_on(super::ipc_listen(
         cli.dns_control,
+        cli.kill_switch,
+        cli.mtu,
         &log_filter_reloader,
         &mut signals,
     ))
@@ -28,6 +37,10 @@ pub(crate) fn elevation_check() -> Result<bool> {
     Ok(nix::unistd::getuid().is_root())
 }
 
+pub(crate) fn notify_service_controller() -> Result<()> {
+    Ok(sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?)
+}
+
 pub(crate) fn install_ipc_service() -> Result<()> {
     bail!("`install_ipc_service` not implemented and not needed on Linux")
 }
@@ -1,5 +1,6 @@
 use crate::CliCommon;
 use anyhow::{bail, Context as _, Result};
+use connlib_shared::DEFAULT_MTU;
 use firezone_bin_shared::platform::DnsControlMethod;
 use futures::future::{self, Either};
 use std::{
@@ -85,6 +86,13 @@ impl Drop for ProcessToken {
     }
 }
 
+// Does nothing on Windows. On Linux this notifies systemd that we're ready.
+// The Windows service reports `Running` to the service controller in `fallible_service_run`.
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn notify_service_controller() -> Result<()> {
+    Ok(())
+}
+
 pub(crate) fn install_ipc_service() -> Result<()> {
     let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
     let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
@@ -129,8 +137,9 @@ windows_service::define_windows_service!(ffi_service_run, service_run);
 fn service_run(arguments: Vec<OsString>) {
     // `arguments` doesn't seem to work right when running as a Windows service
     // (even though it's meant for that) so just use the default log dir.
-    let (handle, log_filter_reloader) =
-        super::setup_logging(None).expect("Should be able to set up logging");
+    let (handle, _, log_filter_reloader) =
+        super::setup_logging(None, None, None, Default::default())
+            .expect("Should be able to set up logging");
     if let Err(error) = fallible_service_run(arguments, handle, log_filter_reloader) {
         tracing::error!(?error, "`fallible_windows_service_run` returned an error");
     }
@@ -255,6 +264,8 @@ async fn service_run_async(
     let mut signals = crate::signals::Terminate::new()?;
     let listen_fut = pin!(super::ipc_listen(
         DnsControlMethod::Nrpt,
+        false,
+        DEFAULT_MTU,
         log_filter_reloader,
         &mut signals
     ));
@@ -38,8 +38,13 @@ pub fn logs() -> Option<PathBuf> {
 /// e.g. `/run/user/1000/dev.firezone.client/data`
 ///
 /// Crash handler socket and other temp files go here
+///
+/// Services running as root usually don't have `$XDG_RUNTIME_DIR`, so they use
+/// `/run/dev.firezone.client/data`, inside the systemd `RuntimeDirectory=`.
+#[allow(clippy::unnecessary_wraps)] // Signature must match Windows
 pub fn runtime() -> Option<PathBuf> {
-    Some(dirs::runtime_dir()?.join(BUNDLE_ID).join("data"))
+    let dir = dirs::runtime_dir().unwrap_or_else(|| PathBuf::from("/run"));
+    Some(dir.join(BUNDLE_ID).join("data"))
 }
 
 /// e.g. `/home/alice/.local/share/dev.firezone.client/data`
@@ -10,8 +10,9 @@
 
 use anyhow::{Context as _, Result};
 use connlib_client_shared::{Callbacks, DisconnectError};
-use connlib_shared::callbacks;
+use connlib_shared::{callbacks, DEFAULT_MTU, MAX_MTU};
 use firezone_bin_shared::platform::DnsControlMethod;
+use firezone_logging::{file, ship, LogFormat};
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::PathBuf,
@@ -27,6 +28,9 @@ pub mod device_id;
 pub mod dns_control;
 mod ipc_service;
 pub mod known_dirs;
+/// Undoes our DNS and routing changes if the last run didn't exit cleanly
+#[cfg(target_os = "linux")]
+pub mod restore;
 // TODO: Move to `bin-shared`?
 pub mod signals;
 pub mod uptime;
@@ -35,7 +39,7 @@ pub use clear_logs::clear_logs;
 pub use dns_control::DnsController;
 pub use ipc_service::{
     ipc, run_only_ipc_service, ClientMsg as IpcClientMsg, Error as IpcServiceError,
-    ServerMsg as IpcServerMsg,
+    ServerMsg as IpcServerMsg, Status as IpcServiceStatus,
 };
 
 use ip_network::{Ipv4Network, Ipv6Network};
@@ -56,14 +60,63 @@ pub struct CliCommon {
     #[arg(long, env = "FIREZONE_DNS_CONTROL", default_value = "nrpt")]
     pub dns_control: DnsControlMethod,
 
+    /// Block all traffic outside the tunnel while signed in, including while reconnecting.
+    ///
+    /// Only traffic from Firezone itself, loopback, DHCP, IPv6 neighbor discovery and DNS to the system's resolvers may bypass the tunnel.
+    /// This applies with or without the Internet Resource: Without it, only Resources stay reachable.
+    /// Only implemented on Linux, where it needs `nft`.
+    #[arg(long, env = "FIREZONE_KILL_SWITCH")]
+    pub kill_switch: bool,
+
     /// File logging directory. Should be a path that's writeable by the current user.
     #[arg(short, long, env = "LOG_DIR")]
     pub log_dir: Option<PathBuf>,
 
+    /// How to format the logs on stdout, `human` or `json`.
+    #[arg(long, env = "FIREZONE_LOG_FORMAT", default_value_t = LogFormat::Human)]
+    pub log_format: LogFormat,
+
+    /// Also ship logs to `syslog://host[:port]`, `syslog:///dev/log` or to an OTLP collector at `otlp://host[:port]`.
+    #[arg(long, env = "FIREZONE_LOG_SHIP")]
+    pub log_ship: Option<ship::Destination>,
+
+    /// How many MiB of logs to keep in the log directory.
+    ///
+    /// Log files are rotated and gzipped once they get big or a day old, the oldest gzipped ones are deleted first.
+    #[arg(long, env = "FIREZONE_LOG_DIR_MAX_MIB", default_value_t = 100)]
+    pub log_dir_max_mib: u64,
+
+    /// Report connection metrics and traces to the OTLP collector at this `host:port`, via gRPC.
+    #[arg(long, env = "FIREZONE_OTLP_GRPC_ENDPOINT")]
+    pub otlp_grpc_endpoint: Option<String>,
+
     /// Maximum length of time to retry connecting to the portal if we're having internet issues or
     /// it's down. Accepts human times. e.g. "5m" or "1h" or "30d".
+    ///
+    /// After that, the control plane is "degraded": existing connections keep working and we
+    /// keep trying to reconnect in the background.
     #[arg(short, long, env = "MAX_PARTITION_TIME")]
     pub max_partition_time: Option<humantime::Duration>,
+
+    /// MTU of the tunnel interface, between 1280 and 9000 bytes.
+    ///
+    /// Packets larger than the path MTU to a Gateway are rejected with an ICMP error.
+    #[arg(
+        long,
+        env = "FIREZONE_MTU",
+        default_value_t = DEFAULT_MTU,
+        value_parser = clap::value_parser!(u16).range(DEFAULT_MTU as i64..=MAX_MTU as i64).map(usize::from)
+    )]
+    pub mtu: usize,
+}
+
+impl CliCommon {
+    pub fn log_retention(&self) -> file::Retention {
+        file::Retention {
+            max_dir_size: self.log_dir_max_mib * 1024 * 1024,
+            ..Default::default()
+        }
+    }
 }
 
 /// Messages that connlib can produce and send to the headless Client, IPC service, or GUI process.
@@ -72,6 +125,8 @@ pub struct CliCommon {
 // The names are CamelCase versions of the connlib callbacks.
 #[allow(clippy::enum_variant_names)]
 pub enum ConnlibMsg {
+    /// True while connlib can't reach the portal, but keeps the tunnel up
+    OnControlPlaneDegraded(bool),
     OnDisconnect {
         error_msg: String,
         is_authentication_error: bool,
@@ -95,6 +150,12 @@ pub struct CallbackHandler {
 }
 
 impl Callbacks for CallbackHandler {
+    fn on_control_plane_degraded(&self, degraded: bool) {
+        self.cb_tx
+            .try_send(ConnlibMsg::OnControlPlaneDegraded(degraded))
+            .expect("Should be able to send OnControlPlaneDegraded");
+    }
+
     fn on_disconnect(&self, error: &connlib_client_shared::DisconnectError) {
         tracing::error!(?error, "Got `on_disconnect` from connlib");
         let is_authentication_error = if let DisconnectError::PortalConnectionFailed(error) = error
@@ -179,6 +240,15 @@ mod tests {
             DnsControlMethod::SystemdResolved
         ));
 
+        let actual = CliCommon::parse_from([EXE_NAME, "--dns-control", "resolvconf"]);
+        assert!(matches!(actual.dns_control, DnsControlMethod::Resolvconf));
+
+        let actual = CliCommon::parse_from([EXE_NAME, "--dns-control", "network-manager"]);
+        assert!(matches!(
+            actual.dns_control,
+            DnsControlMethod::NetworkManager
+        ));
+
         assert!(CliCommon::try_parse_from([EXE_NAME, "--dns-control", "invalid"]).is_err());
     }
 
@@ -196,4 +266,17 @@ mod tests {
 
         assert!(CliCommon::try_parse_from([EXE_NAME, "--dns-control", "invalid"]).is_err());
     }
+
+    #[test]
+    fn mtu() {
+        let actual = CliCommon::parse_from([EXE_NAME]);
+        assert_eq!(actual.mtu, DEFAULT_MTU);
+
+        let actual = CliCommon::parse_from([EXE_NAME, "--mtu", "1420"]);
+        assert_eq!(actual.mtu, 1420);
+
+        assert!(CliCommon::try_parse_from([EXE_NAME, "--mtu", "big"]).is_err());
+        assert!(CliCommon::try_parse_from([EXE_NAME, "--mtu", "576"]).is_err());
+        assert!(CliCommon::try_parse_from([EXE_NAME, "--mtu", "65000"]).is_err());
+    }
 }
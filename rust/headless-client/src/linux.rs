@@ -1,8 +1,10 @@
 //! Implementation, Linux-specific
 
 use super::TOKEN_ENV_KEY;
-use anyhow::{bail, Result};
-use firezone_bin_shared::BUNDLE_ID;
+use anyhow::{bail, Context as _, Result};
+use connlib_shared::DEFAULT_MTU;
+use firezone_bin_shared::{TunDeviceManager, BUNDLE_ID};
+use firezone_headless_client::{restore, setup_stdout_logging};
 use std::path::{Path, PathBuf};
 
 // The Client currently must run as root to control DNS
@@ -14,6 +16,12 @@ pub(crate) fn default_token_path() -> PathBuf {
     PathBuf::from("/etc").join(BUNDLE_ID).join("token")
 }
 
+// Fallible on Windows
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn default_config_path() -> Result<PathBuf> {
+    Ok(PathBuf::from("/etc/firezone/client.toml"))
+}
+
 pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
     let Ok(stat) = nix::sys::stat::fstatat(None, path, nix::fcntl::AtFlags::empty()) else {
         // File doesn't exist or can't be read
@@ -48,3 +56,21 @@ pub(crate) fn check_token_permissions(path: &Path) -> Result<()> {
 pub(crate) fn notify_service_controller() -> Result<()> {
     Ok(sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?)
 }
+
+/// Undoes the DNS and routing changes that a killed Headless Client or IPC service left behind
+///
+/// Refuses to run while either of them is running, it would undo their changes too.
+pub(crate) fn repair() -> Result<()> {
+    let _reloader = setup_stdout_logging()?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let owner = restore::Owner::acquire()?
+            .context("A Firezone Client is running, stop it before repairing")?;
+        let mut tun_device = TunDeviceManager::new(DEFAULT_MTU)?;
+        restore::repair(&owner, &mut tun_device).await
+    })?;
+    tracing::info!("Repaired");
+    Ok(())
+}
@@ -2,27 +2,37 @@
 
 use anyhow::{anyhow, Context as _, Result};
 use backoff::ExponentialBackoffBuilder;
-use clap::Parser;
+use clap::{CommandFactory as _, FromArgMatches as _};
 use connlib_client_shared::{keypair, ConnectArgs, LoginUrl, Session};
-use connlib_shared::{get_user_agent, DEFAULT_MTU};
+use connlib_shared::get_user_agent;
 use firezone_bin_shared::{
-    new_dns_notifier, new_network_notifier,
-    platform::{tcp_socket_factory, udp_socket_factory},
-    TunDeviceManager, TOKEN_ENV_KEY,
+    new_dns_notifier, new_network_notifier, otlp,
+    platform::{tcp_socket_factory, udp_socket_factory, DnsControlMethod},
+    KillSwitch, TunDeviceManager, TOKEN_ENV_KEY,
 };
 use firezone_headless_client::{
-    device_id, signals, CallbackHandler, CliCommon, ConnlibMsg, DnsController,
+    device_id, ipc::ServiceId, signals, CallbackHandler, CliCommon, ConnlibMsg, DnsController,
+    IpcClientMsg, IpcServerMsg, IpcServiceStatus, LogFilterReloader,
 };
 use futures::{FutureExt as _, StreamExt as _};
+use ip_network::{Ipv4Network, Ipv6Network};
+use netstack::Netstack;
 use phoenix_channel::PhoenixChannel;
-use secrecy::{Secret, SecretString};
+use secrecy::{ExposeSecret as _, Secret, SecretString};
 use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     pin::pin,
     sync::Arc,
 };
-use tokio::{sync::mpsc, time::Instant};
+use tokio::{net::TcpListener, sync::mpsc, time::Instant};
 use tokio_stream::wrappers::ReceiverStream;
+use tun::Tun;
+
+mod config;
+mod control;
+mod proxy;
 
 #[cfg(target_os = "linux")]
 #[path = "linux.rs"]
@@ -34,14 +44,15 @@ mod platform;
 
 use platform::default_token_path;
 
+#[cfg(target_os = "linux")]
+use firezone_headless_client::restore;
+
 /// Command-line args for the headless Client
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    // Needed to preserve CLI arg compatibility
-    // TODO: Remove when we can break CLI compatibility for headless Clients
     #[command(subcommand)]
-    _command: Option<Cmd>,
+    command: Option<Cmd>,
 
     #[command(flatten)]
     common: CliCommon,
@@ -62,6 +73,12 @@ struct Cli {
     #[arg(long)]
     check: bool,
 
+    /// A TOML config file with settings for the other args, e.g. `/etc/firezone/client.toml`
+    ///
+    /// Args from the CLI or from env vars override it. SIGHUP reloads it.
+    #[arg(long, env = "FIREZONE_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Connect to the Firezone network and initialize, then exit
     ///
     /// Use this to check how fast you can connect.
@@ -72,6 +89,28 @@ struct Cli {
     #[arg(long, env = "FIREZONE_NAME")]
     firezone_name: Option<String>,
 
+    /// Don't create a TUN device, use a userspace network stack behind a SOCKS5 and HTTP proxy on this address instead, e.g. `127.0.0.1:1080`
+    ///
+    /// Resources are only reachable through the proxy then.
+    /// Doesn't need `CAP_NET_ADMIN` and leaves the system's DNS and routing alone.
+    /// Without root, there is no control socket either, so the `status`, `resources` and `dns` commands don't work.
+    /// Anything but a loopback address needs `--proxy-username` and `--proxy-password`.
+    #[arg(long, env = "FIREZONE_PROXY_LISTEN")]
+    proxy_listen: Option<SocketAddr>,
+
+    /// Username that proxy clients must authenticate with
+    #[arg(long, env = "FIREZONE_PROXY_USERNAME", requires = "proxy_password")]
+    proxy_username: Option<String>,
+
+    /// Password that proxy clients must authenticate with
+    #[arg(
+        long,
+        env = "FIREZONE_PROXY_PASSWORD",
+        hide_env_values = true,
+        requires = "proxy_username"
+    )]
+    proxy_password: Option<String>,
+
     /// Identifier used by the portal to identify and display the device.
 
     // AKA `device_id` in the Windows and Linux GUI clients
@@ -95,9 +134,18 @@ struct Cli {
     token_path: PathBuf,
 }
 
-#[derive(clap::Subcommand, Clone, Copy)]
+#[derive(clap::Subcommand)]
 enum Cmd {
+    // Needed to preserve CLI arg compatibility
+    // TODO: Remove when we can break CLI compatibility for headless Clients
+    #[command(hide = true)]
     Standalone,
+    /// Control a running Headless Client or IPC service, and print the result as JSON
+    #[command(flatten)]
+    Control(control::Cmd),
+    /// Undo DNS and routing changes left behind by a Headless Client or IPC service that was killed
+    #[cfg(target_os = "linux")]
+    Repair,
 }
 
 fn main() -> Result<()> {
@@ -105,7 +153,18 @@ fn main() -> Result<()> {
         .install_default()
         .expect("Calling `install_default` only once per process should always succeed");
 
-    let mut cli = Cli::try_parse()?;
+    let matches = Cli::command().try_get_matches()?;
+    let mut cli = Cli::from_arg_matches(&matches)?;
+
+    match cli.command.take() {
+        None | Some(Cmd::Standalone) => {}
+        Some(Cmd::Control(cmd)) => return control::run(cmd),
+        #[cfg(target_os = "linux")]
+        Some(Cmd::Repair) => return platform::repair(),
+    }
+
+    let mut config = config::Config::read(cli.config.as_deref())?;
+    config.apply_to_cli(&mut cli, &matches)?;
 
     // Modifying the environment of a running process is unsafe. If any other
     // thread is reading or writing the environment, something bad can happen.
@@ -113,6 +172,7 @@ fn main() -> Result<()> {
     // take the token env var before any other threads spawn.
 
     let token_env_var = cli.token.take().map(SecretString::from);
+    let token_from_env = token_env_var.is_some();
     let cli = cli;
 
     // Docs indicate that `remove_var` should actually be marked unsafe
@@ -128,23 +188,60 @@ fn main() -> Result<()> {
 
     // TODO: This might have the same issue with fatal errors not getting logged
     // as addressed for the IPC service in PR #5216
-    let (layer, _handle) = cli
+    let (file_layer, _handle) = cli
         .common
         .log_dir
         .as_deref()
-        .map(firezone_logging::file::layer)
+        .map(|log_dir| {
+            firezone_logging::file::layer_with_retention(log_dir, cli.common.log_retention())
+        })
         .unzip();
-    firezone_logging::setup_global_subscriber(layer);
+    let (ship_layer, _ship_handle) = cli
+        .common
+        .log_ship
+        .as_ref()
+        .map(|destination| {
+            firezone_logging::ship::layer(
+                destination,
+                "firezone-headless-client",
+                Arc::new(tcp_socket_factory),
+                Arc::new(udp_socket_factory),
+            )
+            .with_context(|| format!("Couldn't ship logs to `{destination}`"))
+        })
+        .transpose()?
+        .unzip();
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // The OTLP exporters get spawned onto the runtime, they run whenever we `block_on` it.
+    let otlp_layer = cli
+        .common
+        .otlp_grpc_endpoint
+        .as_deref()
+        .map(|endpoint| {
+            let _guard = rt.enter();
+            otlp::layer(endpoint, "firezone-headless-client")
+                .with_context(|| format!("Couldn't export to the OTLP collector at `{endpoint}`"))
+        })
+        .transpose()?;
+
+    let log_filter_reloader = firezone_logging::setup_reloadable_global_subscriber(
+        cli.common.log_format,
+        &log_filter(&config),
+        file_layer
+            .into_iter()
+            .chain(ship_layer)
+            .chain(otlp_layer)
+            .collect::<Vec<_>>(),
+    )?;
 
     tracing::info!(
         arch = std::env::consts::ARCH,
         git_version = firezone_bin_shared::git_version!("headless-client-*")
     );
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?;
-
     let token = get_token(token_env_var, &cli.token_path)?.with_context(|| {
         format!(
             "Can't find the Firezone token in ${TOKEN_ENV_KEY} or in `{}`",
@@ -161,19 +258,38 @@ fn main() -> Result<()> {
     };
 
     let (private_key, public_key) = keypair();
-    let url = LoginUrl::client(
-        cli.api_url,
-        &token,
+    let mut login = Login {
+        api_url: cli.api_url.clone(),
+        token_path: (!token_from_env).then(|| cli.token_path.clone()),
+        token,
         firezone_id,
-        cli.firezone_name,
-        public_key.to_bytes(),
-    )?;
+        firezone_name: cli.firezone_name.clone(),
+        public_key: public_key.to_bytes(),
+    };
+    let url = login.url()?;
 
     if cli.check {
         tracing::info!("Check passed");
         return Ok(());
     }
 
+    if cli.proxy_listen.is_some() && cli.common.kill_switch {
+        anyhow::bail!(
+            "The kill switch needs a TUN device, it can't be combined with `--proxy-listen`"
+        );
+    }
+    let proxy_credentials = cli
+        .proxy_username
+        .clone()
+        .zip(cli.proxy_password.clone())
+        .map(|(username, password)| proxy::Credentials {
+            username,
+            password: SecretString::from(password),
+        });
+    if let Some(addr) = cli.proxy_listen {
+        proxy::check_listen_addr(addr, proxy_credentials.as_ref())?;
+    }
+
     let (cb_tx, cb_rx) = mpsc::channel(1_000);
     let callbacks = CallbackHandler { cb_tx };
 
@@ -184,6 +300,7 @@ fn main() -> Result<()> {
         tcp_socket_factory: Arc::new(tcp_socket_factory),
         private_key,
         callbacks,
+        mtu: cli.common.mtu,
     };
     let _guard = rt.enter(); // Constructing `PhoenixChannel` requires a runtime context.
 
@@ -203,18 +320,62 @@ fn main() -> Result<()> {
         Arc::new(tcp_socket_factory),
     )?;
     let session = Session::connect(args, portal, rt.handle().clone());
+    if !config.known_hosts.is_empty() {
+        session.set_known_hosts(config.known_hosts.clone());
+    }
+    if !config.disabled_resources.is_empty() {
+        session.set_disabled_resources(config.disabled_resources.clone());
+    }
 
     let result = rt.block_on(async {
         let mut terminate = signals::Terminate::new()?;
         let mut hangup = signals::Hangup::new()?;
         let mut terminate = pin!(terminate.recv().fuse());
-        let mut hangup = pin!(hangup.recv().fuse());
-        let dns_control_method = cli.common.dns_control;
+        // Behind the proxy, only the proxy's clients use our DNS.
+        let dns_control_method = match cli.proxy_listen {
+            Some(_) => DnsControlMethod::Disabled,
+            None => cli.common.dns_control,
+        };
         let mut dns_controller = DnsController { dns_control_method };
         // Deactivate Firezone DNS control in case the system or IPC service crashed
         // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
         dns_controller.deactivate()?;
-        let mut tun_device = TunDeviceManager::new(DEFAULT_MTU)?;
+        let mut device = match cli.proxy_listen {
+            Some(addr) => {
+                let netstack = Netstack::new(cli.common.mtu);
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to listen for proxy clients on {addr}"))?;
+                tracing::info!(%addr, "Listening for SOCKS5 and HTTP proxy clients");
+                tokio::spawn(proxy::serve(
+                    listener,
+                    netstack.clone(),
+                    proxy_credentials,
+                ));
+
+                Device::Netstack(netstack)
+            }
+            None => Device::Tun(TunDeviceManager::new(cli.common.mtu)?),
+        };
+        // Undo whatever the last run couldn't, e.g. because it was killed.
+        // Held until we exit, so the next run knows we're gone.
+        #[cfg(target_os = "linux")]
+        let _restore_owner = match &mut device {
+            Device::Tun(tun_device) => restore::take_over(tun_device).await?,
+            Device::Netstack(_) => None,
+        };
+        // Disabled when dropped, on our way out.
+        let mut kill_switch = KillSwitch::default();
+        if cli.common.kill_switch {
+            #[cfg(target_os = "linux")]
+            restore::record(restore::Undo::KillSwitch);
+            kill_switch.enable(&dns_controller.system_resolvers())?;
+        }
+        // The control socket lives in a directory only root may write to, and the proxy doesn't need root.
+        let mut control = match device {
+            Device::Tun(_) => control::Server::new(ServiceId::Headless).await?,
+            Device::Netstack(_) => control::Server::disabled(),
+        };
         let mut cb_rx = ReceiverStream::new(cb_rx).fuse();
 
         let tokio_handle = tokio::runtime::Handle::current();
@@ -225,53 +386,178 @@ fn main() -> Result<()> {
             new_network_notifier(tokio_handle.clone(), dns_control_method).await?;
         drop(tokio_handle);
 
-        let tun = tun_device.make_tun()?;
-        session.set_tun(Box::new(tun));
-        session.set_dns(dns_controller.system_resolvers());
+        session.set_tun(device.make_tun()?);
+        set_dns(&session, &mut kill_switch, dns_controller.system_resolvers());
+
+        // Kept for `status` and `resources list` from the CLI
+        let mut resources = Vec::new();
+        let mut disabled_resources = config.disabled_resources.clone();
+        // Set by `dns set` from the CLI, and then used instead of the system resolvers
+        let mut dns_override = None;
+        let mut control_plane_degraded = false;
+        // Changes on SIGHUP
+        let mut mtu = cli.common.mtu;
 
         let result = loop {
-            let mut dns_changed = pin!(dns_notifier.notified().fuse());
-            let mut network_changed = pin!(network_notifier.notified().fuse());
-
-            let cb = futures::select! {
-                () = terminate => {
-                    tracing::info!("Caught SIGINT / SIGTERM / Ctrl+C");
-                    break Ok(());
-                },
-                () = hangup => {
-                    tracing::info!("Caught SIGHUP");
-                    session.reset();
-                    continue;
-                },
-                result = dns_changed => {
-                    result?;
-                    // If the DNS control method is not `systemd-resolved`
-                    // then we'll use polling here, so no point logging every 5 seconds that we're checking the DNS
-                    tracing::trace!("DNS change, notifying Session");
-                    session.set_dns(dns_controller.system_resolvers());
-                    continue;
-                },
-                result = network_changed => {
-                    result?;
-                    tracing::info!("Network change, resetting Session");
-                    session.reset();
+            let event = {
+                // Re-created every time, so we catch more than one SIGHUP
+                let mut hangup = pin!(hangup.recv().fuse());
+                let mut dns_changed = pin!(dns_notifier.notified().fuse());
+                let mut network_changed = pin!(network_notifier.notified().fuse());
+                let mut control_msg = pin!(control.next_msg().fuse());
+
+                futures::select! {
+                    () = terminate => {
+                        tracing::info!("Caught SIGINT / SIGTERM / Ctrl+C");
+                        break Ok(());
+                    },
+                    () = hangup => {
+                        tracing::info!("Caught SIGHUP");
+                        let new_mtu = reload_config(
+                            cli.config.as_deref(),
+                            &matches,
+                            &mut config,
+                            &mut login,
+                            &session,
+                            &mut disabled_resources,
+                            &log_filter_reloader,
+                        )
+                        .await;
+                        if let Some(new_mtu) = new_mtu.filter(|new_mtu| *new_mtu != mtu) {
+                            match device.set_mtu(new_mtu).await {
+                                Ok(()) => mtu = new_mtu,
+                                Err(error) => tracing::error!(?error, "Couldn't change the MTU"),
+                            }
+                        }
+                        // The user may have changed the system's resolvers along with the config file
+                        let resolvers = dns_override
+                            .clone()
+                            .unwrap_or_else(|| dns_controller.system_resolvers());
+                        set_dns(&session, &mut kill_switch, resolvers);
+                        continue;
+                    },
+                    result = dns_changed => {
+                        result?;
+                        // If the DNS control method is not `systemd-resolved`
+                        // then we'll use polling here, so no point logging every 5 seconds that we're checking the DNS
+                        tracing::trace!("DNS change, notifying Session");
+                        let resolvers = dns_override
+                            .clone()
+                            .unwrap_or_else(|| dns_controller.system_resolvers());
+                        set_dns(&session, &mut kill_switch, resolvers);
+                        continue;
+                    },
+                    result = network_changed => {
+                        result?;
+                        tracing::info!("Network change, resetting Session");
+                        session.reset();
+                        continue;
+                    },
+                    msg = control_msg => Event::Control(msg),
+                    cb = cb_rx.next() => {
+                        Event::Callback(cb.context("cb_rx unexpectedly ran empty")?)
+                    },
+                }
+            };
+
+            let cb = match event {
+                Event::Callback(cb) => cb,
+                Event::Control(msg) => {
+                    if !msg.is_read_only() && !control.may_control() {
+                        tracing::warn!("Only root may change the tunnel through control commands");
+                        control
+                            .send(&IpcServerMsg::Unauthorized(
+                                "Only root may change the tunnel".to_string(),
+                            ))
+                            .await;
+                        continue;
+                    }
+                    match msg {
+                        IpcClientMsg::ClearLogs => {
+                            let result = match cli.common.log_dir.as_deref() {
+                                Some(log_dir) => firezone_headless_client::clear_logs(log_dir)
+                                    .await
+                                    .map_err(|e| e.to_string()),
+                                None => {
+                                    Err("The Headless Client isn't logging to files".to_string())
+                                }
+                            };
+                            control.send(&IpcServerMsg::ClearedLogs(result)).await;
+                        }
+                        IpcClientMsg::Connect { .. } => {
+                            tracing::warn!(
+                                "Ignoring `Connect`, the Headless Client connects on its own"
+                            );
+                        }
+                        IpcClientMsg::Disconnect => {
+                            tracing::info!("Disconnecting due to a control command");
+                            control.send(&IpcServerMsg::TerminatingGracefully).await;
+                            break Ok(());
+                        }
+                        IpcClientMsg::GetResources => {
+                            control
+                                .send(&IpcServerMsg::OnUpdateResources(resources.clone()))
+                                .await;
+                        }
+                        IpcClientMsg::GetStatus => {
+                            let status = IpcServiceStatus {
+                                connected: true,
+                                tunnel_ready: last_connlib_start_instant.is_none(),
+                                control_plane_degraded,
+                                resources: resources.clone(),
+                                disabled_resources: disabled_resources.clone(),
+                                tunnel: session.stats().await,
+                            };
+                            control.send(&IpcServerMsg::Status(status)).await;
+                        }
+                        IpcClientMsg::ReloadLogFilter => {
+                            tracing::warn!(
+                                "Ignoring `ReloadLogFilter`, the Headless Client only reads `RUST_LOG` at startup"
+                            );
+                        }
+                        IpcClientMsg::Reset => session.reset(),
+                        IpcClientMsg::SetDns(resolvers) => {
+                            tracing::info!(?resolvers, "Overriding system resolvers");
+                            set_dns(&session, &mut kill_switch, resolvers.clone());
+                            dns_override = Some(resolvers);
+                        }
+                        IpcClientMsg::SetDisabledResources(ids) => {
+                            session.set_disabled_resources(ids.clone());
+                            disabled_resources = ids;
+                        }
+                        IpcClientMsg::Subscribe => {
+                            tracing::warn!(
+                                "Ignoring `Subscribe`, the Headless Client doesn't push events to control clients"
+                            );
+                        }
+                    }
                     continue;
-                },
-                cb = cb_rx.next() => cb.context("cb_rx unexpectedly ran empty")?,
+                }
             };
 
             match cb {
                 // TODO: Headless Client shouldn't be using messages labelled `Ipc`
+                ConnlibMsg::OnControlPlaneDegraded(degraded) => {
+                    if degraded {
+                        tracing::warn!("Can't reach the portal, existing connections keep working");
+                    }
+                    control_plane_degraded = degraded;
+                }
                 ConnlibMsg::OnDisconnect {
                     error_msg,
                     is_authentication_error: _,
                 } => break Err(anyhow!(error_msg).context("Firezone disconnected")),
-                ConnlibMsg::OnUpdateResources(_) => {
+                ConnlibMsg::OnUpdateResources(new_resources) => {
                     // On every Resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                     dns_controller.flush()?;
+                    // `set_dns` routed all queries to our sentinels, they keep reaching them without split DNS, so keep going.
+                    if let Err(error) = dns_controller.set_resources(&new_resources).await {
+                        tracing::error!(?error, "Failed to route DNS Resources to our sentinels");
+                    }
+                    resources = new_resources;
                 }
                 ConnlibMsg::OnSetInterfaceConfig { ipv4, ipv6, dns } => {
-                    tun_device.set_ips(ipv4, ipv6).await?;
+                    device.set_interface_config(ipv4, ipv6, &dns).await?;
                     dns_controller.set_dns(dns).await?;
                     // `on_set_interface_config` is guaranteed to be called when the tunnel is completely ready
                     // <https://github.com/firezone/firezone/pull/6026#discussion_r1692297438>
@@ -286,7 +572,7 @@ fn main() -> Result<()> {
                     }
                 }
                 ConnlibMsg::OnUpdateRoutes { ipv4, ipv6 } => {
-                    tun_device.set_routes(ipv4, ipv6).await?;
+                    device.set_routes(ipv4, ipv6).await?;
                 }
             }
         };
@@ -295,6 +581,16 @@ fn main() -> Result<()> {
             tracing::error!(?error, "network listener");
         }
 
+        // Undo our changes ourselves, so the next run doesn't have to.
+        #[cfg(target_os = "linux")]
+        let res = restore::remove_kill_switch(&mut kill_switch);
+        #[cfg(not(target_os = "linux"))]
+        let res = kill_switch.disable();
+        if let Err(error) = res {
+            tracing::error!(?error, "Failed to disable kill switch");
+        }
+        device.remove_routing().await;
+
         result
     });
 
@@ -303,6 +599,243 @@ fn main() -> Result<()> {
     result
 }
 
+/// Sends `resolvers` to connlib and lets DNS to them through the kill switch, if it's enabled
+fn set_dns(session: &Session, kill_switch: &mut KillSwitch, resolvers: Vec<IpAddr>) {
+    if let Err(error) = kill_switch.set_resolvers(&resolvers) {
+        tracing::error!(?error, "Failed to allow DNS to the new resolvers");
+    }
+    session.set_dns(resolvers);
+}
+
+enum Event {
+    Callback(ConnlibMsg),
+    Control(IpcClientMsg),
+}
+
+/// Where connlib's packets go
+enum Device {
+    /// A TUN device, which we route traffic for Resources to
+    Tun(TunDeviceManager),
+    /// Our userspace network stack, which apps reach through [`proxy`]
+    Netstack(Netstack),
+}
+
+impl Device {
+    fn make_tun(&mut self) -> Result<Box<dyn Tun>> {
+        Ok(match self {
+            Self::Tun(tun_device) => Box::new(tun_device.make_tun()?),
+            Self::Netstack(netstack) => Box::new(netstack.make_tun()),
+        })
+    }
+
+    async fn set_interface_config(
+        &mut self,
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        dns: &[IpAddr],
+    ) -> Result<()> {
+        match self {
+            Self::Tun(tun_device) => {
+                #[cfg(target_os = "linux")]
+                restore::record(restore::Undo::Routing);
+                tun_device.set_ips(ipv4, ipv6).await?;
+            }
+            Self::Netstack(netstack) => {
+                netstack.set_ips(ipv4, ipv6);
+                netstack.set_dns(dns.to_vec());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes our `ip rule`s on a clean exit, the kernel only deletes our routes along with the TUN device
+    #[allow(clippy::unused_async)] // Only async on Linux
+    async fn remove_routing(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Self::Tun(tun_device) = self {
+            match tun_device.remove_routing().await {
+                Ok(()) => restore::forget(restore::Undo::Routing),
+                Err(error) => tracing::error!(?error, "Failed to remove our routing"),
+            }
+        }
+    }
+
+    /// The userspace network stack only picks up a new MTU after a restart
+    async fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        match self {
+            Self::Tun(tun_device) => tun_device.set_mtu(mtu).await,
+            Self::Netstack(_) => {
+                tracing::warn!(%mtu, "The proxy only changes its MTU after restarting the Client");
+                Ok(())
+            }
+        }
+    }
+
+    /// The userspace network stack sends everything into the tunnel, so it doesn't need routes
+    async fn set_routes(&mut self, ipv4: Vec<Ipv4Network>, ipv6: Vec<Ipv6Network>) -> Result<()> {
+        match self {
+            Self::Tun(tun_device) => tun_device.set_routes(ipv4, ipv6).await,
+            Self::Netstack(_) => Ok(()),
+        }
+    }
+}
+
+/// `RUST_LOG` overrides the config file, the same as other env vars
+fn log_filter(config: &config::Config) -> String {
+    std::env::var("RUST_LOG")
+        .ok()
+        .or_else(|| config.log_filter.clone())
+        .unwrap_or_default()
+}
+
+/// Reads the config file again and applies the settings that can change without dropping the tunnel
+///
+/// If the file is broken, we keep running with the old settings.
+/// A new token or API URL reconnects us to the portal, which keeps our connections to Gateways.
+///
+/// Returns the MTU that the CLI and the new file ask for, so the caller can apply it to the device.
+async fn reload_config(
+    path: Option<&Path>,
+    matches: &clap::ArgMatches,
+    config: &mut config::Config,
+    login: &mut Login,
+    session: &Session,
+    disabled_resources: &mut BTreeSet<connlib_shared::messages::ResourceId>,
+    log_filter_reloader: &LogFilterReloader,
+) -> Option<usize> {
+    let new_config = match config::Config::read(path) {
+        Ok(x) => x,
+        Err(error) => {
+            tracing::error!(
+                ?error,
+                "Couldn't reload config file, keeping the old settings"
+            );
+            return None;
+        }
+    };
+
+    let needs_restart = config.needs_restart(&new_config);
+    if !needs_restart.is_empty() {
+        tracing::warn!(
+            settings = ?needs_restart,
+            "Some settings only take effect after restarting the Client"
+        );
+    }
+    if new_config.log_filter != config.log_filter {
+        let directives = log_filter(&new_config);
+        match firezone_logging::try_filter(&directives) {
+            Ok(filter) => {
+                if let Err(error) = log_filter_reloader.reload(filter) {
+                    tracing::error!(?error, "Couldn't reload log filter");
+                }
+            }
+            Err(error) => tracing::error!(?error, "Invalid log filter in config file"),
+        }
+    }
+    // Only touch these if the file changed them, so we don't undo changes from control commands
+    if new_config.disabled_resources != config.disabled_resources {
+        disabled_resources.clone_from(&new_config.disabled_resources);
+        session.set_disabled_resources(new_config.disabled_resources.clone());
+    }
+    if new_config.known_hosts != config.known_hosts {
+        session.set_known_hosts(new_config.known_hosts.clone());
+    }
+
+    // Only keep the new credentials once we reconnected with them, so the next SIGHUP retries
+    let mut new_login = login.clone();
+    match new_login.update(matches, &new_config) {
+        Ok(false) => {}
+        Ok(true) => match new_login.url() {
+            // Resolve it here, connlib's eventloop may be the one answering our DNS queries
+            Ok(url) => match phoenix_channel::lookup_host(&url).await {
+                Ok(resolved_addresses) => {
+                    tracing::info!("Token or API URL changed, reconnecting to the portal");
+                    session.set_login_url(url, resolved_addresses);
+                    *login = new_login;
+                }
+                Err(error) => {
+                    tracing::error!(
+                        ?error,
+                        "Couldn't resolve the new API URL, keeping the old one"
+                    )
+                }
+            },
+            Err(error) => tracing::error!(?error, "Couldn't build the new login URL"),
+        },
+        Err(error) => tracing::error!(?error, "Couldn't reload the token, keeping the old one"),
+    }
+    let mtu = match effective_mtu(matches, &new_config) {
+        Ok(mtu) => Some(mtu),
+        Err(error) => {
+            tracing::error!(?error, "Couldn't apply the MTU from the config file");
+            None
+        }
+    };
+
+    tracing::info!("Reloaded config file");
+    *config = new_config;
+    mtu
+}
+
+/// The MTU from the CLI or env var if there is one, otherwise from `config`
+fn effective_mtu(matches: &clap::ArgMatches, config: &config::Config) -> Result<usize> {
+    let mut cli = Cli::from_arg_matches(matches)?;
+    config.apply_to_cli(&mut cli, matches)?;
+    Ok(cli.common.mtu)
+}
+
+/// What we need to build a new `LoginUrl` on SIGHUP
+#[derive(Clone)]
+struct Login {
+    api_url: url::Url,
+    /// `None` if the token came from the env var, since then it can't change
+    token_path: Option<PathBuf>,
+    token: SecretString,
+    firezone_id: String,
+    firezone_name: Option<String>,
+    public_key: [u8; 32],
+}
+
+impl Login {
+    fn url(&self) -> Result<LoginUrl> {
+        Ok(LoginUrl::client(
+            self.api_url.clone(),
+            &self.token,
+            self.firezone_id.clone(),
+            self.firezone_name.clone(),
+            self.public_key,
+        )?)
+    }
+
+    /// Picks up a new API URL or token path from `config`, and re-reads the token file
+    ///
+    /// CLI args and env vars still take precedence over the config file.
+    ///
+    /// Returns true if anything changed.
+    fn update(&mut self, matches: &clap::ArgMatches, config: &config::Config) -> Result<bool> {
+        let mut cli = Cli::from_arg_matches(matches)?;
+        config.apply_to_cli(&mut cli, matches)?;
+
+        let mut changed = cli.api_url != self.api_url;
+        if let Some(token_path) = &mut self.token_path {
+            let token = read_token_file(&cli.token_path)?.with_context(|| {
+                format!(
+                    "Can't find the Firezone token in `{}`",
+                    cli.token_path.display()
+                )
+            })?;
+            changed |= cli.token_path != *token_path
+                || token.expose_secret() != self.token.expose_secret();
+            *token_path = cli.token_path;
+            self.token = token;
+        }
+        self.api_url = cli.api_url;
+
+        Ok(changed)
+    }
+}
+
 /// Read the token from disk if it was not in the environment
 ///
 /// # Returns
@@ -357,9 +890,12 @@ fn read_token_file(path: &Path) -> Result<Option<SecretString>> {
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{control, Cli, Cmd};
     use clap::Parser;
-    use std::path::PathBuf;
+    use std::{
+        net::{IpAddr, Ipv6Addr},
+        path::PathBuf,
+    };
     use url::Url;
 
     // Can't remember how Clap works sometimes
@@ -380,4 +916,51 @@ mod tests {
         assert!(actual.check);
         assert_eq!(actual.common.log_dir, Some(PathBuf::from("bogus_log_dir")));
     }
+
+    #[test]
+    fn control_cli() {
+        let exe_name = "firezone-headless-client";
+
+        let actual = Cli::try_parse_from([exe_name, "standalone"]).unwrap();
+        assert!(matches!(actual.command, Some(Cmd::Standalone)));
+
+        let actual = Cli::try_parse_from([exe_name, "status"]).unwrap();
+        assert!(matches!(
+            actual.command,
+            Some(Cmd::Control(control::Cmd::Status))
+        ));
+
+        let id = "73037362-715d-4a83-a749-f18eadd970e6";
+        let actual = Cli::try_parse_from([exe_name, "resources", "disable", id]).unwrap();
+        let Some(Cmd::Control(control::Cmd::Resources {
+            command: control::ResourcesCmd::Disable { id: actual },
+        })) = actual.command
+        else {
+            panic!("Expected `resources disable`");
+        };
+        assert_eq!(actual, id.parse().unwrap());
+        assert!(Cli::try_parse_from([exe_name, "resources", "disable", "bogus"]).is_err());
+
+        let actual = Cli::try_parse_from([exe_name, "dns", "set", "1.1.1.1", "::1"]).unwrap();
+        let Some(Cmd::Control(control::Cmd::Dns {
+            command: control::DnsCmd::Set { servers },
+        })) = actual.command
+        else {
+            panic!("Expected `dns set`");
+        };
+        assert_eq!(
+            servers,
+            vec![
+                IpAddr::from([1, 1, 1, 1]),
+                IpAddr::from(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert!(Cli::try_parse_from([exe_name, "dns", "set"]).is_err());
+
+        #[cfg(target_os = "linux")]
+        {
+            let actual = Cli::try_parse_from([exe_name, "repair"]).unwrap();
+            assert!(matches!(actual.command, Some(Cmd::Repair)));
+        }
+    }
 }
@@ -0,0 +1,272 @@
+//! A journal of the system changes we have to undo if we don't exit cleanly
+//!
+//! If we're killed, `Drop` impls don't run, so `/etc/resolv.conf`, our `resolvconf` record,
+//! our `ip rule`s and the kill switch stay behind and break DNS or routing until someone removes them.
+//!
+//! Before changing anything, we record how to undo it in a file under [`known_dirs::runtime`].
+//! On the next start, or with `firezone-headless-client repair`, we replay the journal.
+//! The kill switch is the exception: Only `repair` or a clean disconnect removes it, see [`Undo::KillSwitch`].
+//! Only whoever holds the [`Owner`] lock may do that, so we never undo the changes of a Client that's still running.
+
+use crate::{dns_control, known_dirs};
+use anyhow::{Context as _, Result};
+use firezone_bin_shared::{platform::DnsControlMethod, KillSwitch, TunDeviceManager};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::PathBuf,
+};
+
+const FILE_NAME: &str = "restore.json";
+const LOCK_FILE_NAME: &str = "restore.lock";
+
+/// Proves that no other Headless Client or IPC service is running, so the changes in the journal are ours
+///
+/// The kernel releases the lock when we exit, even if we're killed,
+/// so if we can take it, whoever wrote the journal is gone.
+pub struct Owner {
+    _lock: Flock<fs::File>,
+}
+
+impl Owner {
+    /// Returns `None` if another Headless Client or IPC service holds the lock
+    pub fn acquire() -> Result<Option<Self>> {
+        let dir = known_dirs::runtime().context("Can't compute the runtime dir")?;
+        fs::create_dir_all(&dir).context("Failed to create the runtime dir")?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE_NAME))
+            .context("Failed to open the restore lock")?;
+
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Some(Self { _lock: lock })),
+            Err((_, Errno::EWOULDBLOCK)) => Ok(None),
+            Err((_, errno)) => Err(errno).context("Failed to take the restore lock"),
+        }
+    }
+}
+
+/// Something we changed and have to change back
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Undo {
+    /// Revert the DNS control method, e.g. restore `/etc/resolv.conf` from its backup
+    Dns(DnsControlMethod),
+    /// Delete our `ip rule`s and the routes in our routing table
+    Routing,
+    /// Delete our nftables table
+    ///
+    /// Not undone on start: After a crash, traffic must stay blocked until the user disconnects or runs `repair`,
+    /// rather than leak before the restarted IPC service even has a session.
+    KillSwitch,
+}
+
+/// Records `undo` before we make the change it undoes
+///
+/// If we can't write the journal, we log it and carry on, like we did before there was one.
+pub fn record(undo: Undo) {
+    if let Err(error) = Journal::open().and_then(|journal| journal.insert(undo)) {
+        tracing::warn!(
+            ?error,
+            ?undo,
+            "Failed to record how to undo a system change"
+        );
+    }
+}
+
+/// Removes `undo` from the journal after we undid it ourselves
+pub fn forget(undo: Undo) {
+    // `record` already warned about this.
+    let Ok(journal) = Journal::open() else {
+        return;
+    };
+    if let Err(error) = journal.remove(undo) {
+        tracing::warn!(?error, ?undo, "Failed to update the restore journal");
+    }
+}
+
+/// Disables `kill_switch` on a clean disconnect, and removes the one a crashed run left behind, see [`Undo::KillSwitch`]
+pub fn remove_kill_switch(kill_switch: &mut KillSwitch) -> Result<()> {
+    kill_switch.disable()?;
+    let leftover = Journal::open()
+        .and_then(|journal| journal.read())
+        .is_ok_and(|entries| entries.contains(&Undo::KillSwitch));
+    if leftover {
+        KillSwitch::remove_leftover()?;
+    }
+    forget(Undo::KillSwitch);
+
+    Ok(())
+}
+
+/// Takes the [`Owner`] lock and undoes whatever the last run couldn't, e.g. because it was killed
+///
+/// Leaves the kill switch in place, see [`Undo::KillSwitch`].
+/// Fails if another Headless Client or IPC service is running.
+/// If we can't take the lock for other reasons, we log it and carry on without the journal's help, like [`record`].
+/// Hold on to the returned lock until we exit.
+pub async fn take_over(tun_device: &mut TunDeviceManager) -> Result<Option<Owner>> {
+    let owner = match Owner::acquire() {
+        Ok(Some(owner)) => owner,
+        Ok(None) => anyhow::bail!("Another Firezone Client is already running"),
+        Err(error) => {
+            tracing::warn!(?error, "Can't undo the system changes of the last run");
+            return Ok(None);
+        }
+    };
+    if let Err(error) = undo(tun_device, |undo| undo != Undo::KillSwitch).await {
+        tracing::warn!(?error, "Failed to undo the system changes of the last run");
+    }
+
+    Ok(Some(owner))
+}
+
+/// Undoes everything in the journal, e.g. after the last run was killed
+///
+/// Entries that fail stay in the journal, so a later `repair` can retry them.
+pub async fn repair(_owner: &Owner, tun_device: &mut TunDeviceManager) -> Result<()> {
+    undo(tun_device, |_| true).await
+}
+
+/// Undoes the entries in the journal that `filter` selects, and leaves the others in it
+async fn undo(tun_device: &mut TunDeviceManager, filter: impl Fn(Undo) -> bool) -> Result<()> {
+    let journal = Journal::open()?;
+    let entries = journal
+        .read()?
+        .into_iter()
+        .filter(|undo| filter(*undo))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    tracing::info!(
+        ?entries,
+        "The last run of Firezone didn't exit cleanly, undoing its system changes"
+    );
+
+    let mut result = Ok(());
+    for undo in entries {
+        let res = match undo {
+            Undo::Dns(method) => dns_control::revert(method),
+            Undo::Routing => tun_device.remove_routing().await,
+            Undo::KillSwitch => KillSwitch::remove_leftover(),
+        };
+        match res {
+            Ok(()) => journal.remove(undo)?,
+            Err(error) => {
+                tracing::error!(?error, ?undo, "Failed to undo system change");
+                result = Err(error);
+            }
+        }
+    }
+
+    result
+}
+
+struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    fn open() -> Result<Self> {
+        let dir = known_dirs::runtime().context("Can't compute the runtime dir")?;
+        Ok(Self {
+            path: dir.join(FILE_NAME),
+        })
+    }
+
+    fn read(&self) -> Result<Vec<Undo>> {
+        let text = match fs::read_to_string(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            res => res.context("Failed to read the restore journal")?,
+        };
+        serde_json::from_str(&text).context("Failed to parse the restore journal")
+    }
+
+    fn insert(&self, undo: Undo) -> Result<()> {
+        let mut entries = self.read()?;
+        if entries.contains(&undo) {
+            return Ok(());
+        }
+        entries.push(undo);
+        self.write(&entries)
+    }
+
+    fn remove(&self, undo: Undo) -> Result<()> {
+        let mut entries = self.read()?;
+        if !entries.contains(&undo) {
+            return Ok(());
+        }
+        entries.retain(|entry| *entry != undo);
+        self.write(&entries)
+    }
+
+    /// Uses `atomicwrites` so a crash mid-write can't leave a journal we can't parse
+    fn write(&self, entries: &[Undo]) -> Result<()> {
+        if entries.is_empty() {
+            return fs::remove_file(&self.path).context("Failed to delete the restore journal");
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("Failed to create the runtime dir")?;
+        }
+        let text = serde_json::to_string(entries)?;
+        atomicwrites::AtomicFile::new(&self.path, atomicwrites::OverwriteBehavior::AllowOverwrite)
+            .write(|f| f.write_all(text.as_bytes()))
+            .context("Failed to write the restore journal")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(dir: &tempfile::TempDir) -> Journal {
+        Journal {
+            path: dir.path().join(FILE_NAME),
+        }
+    }
+
+    #[test]
+    fn journal_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let journal = journal(&dir);
+        assert!(journal.read()?.is_empty());
+
+        journal.insert(Undo::Routing)?;
+        journal.insert(Undo::Dns(DnsControlMethod::EtcResolvConf))?;
+        journal.insert(Undo::Routing)?;
+
+        assert_eq!(
+            journal.read()?,
+            [Undo::Routing, Undo::Dns(DnsControlMethod::EtcResolvConf)]
+        );
+        assert_eq!(
+            fs::read_to_string(&journal.path)?,
+            r#"["routing",{"dns":"etc-resolv-conf"}]"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn empty_journal_is_deleted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let journal = journal(&dir);
+
+        journal.insert(Undo::Dns(DnsControlMethod::Resolvconf))?;
+        journal.remove(Undo::Routing)?;
+        assert!(journal.path.exists());
+
+        journal.remove(Undo::Dns(DnsControlMethod::Resolvconf))?;
+        assert!(!journal.path.exists());
+        assert!(journal.read()?.is_empty());
+        Ok(())
+    }
+}
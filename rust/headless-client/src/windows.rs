@@ -4,7 +4,7 @@
 //! service to be stopped even if its only process ends, for some reason.
 //! We must tell Windows explicitly when our service is stopping.
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use std::path::{Path, PathBuf};
 
 // The return value is useful on Linux
@@ -19,6 +19,15 @@ pub(crate) fn default_token_path() -> std::path::PathBuf {
     PathBuf::from("token.txt")
 }
 
+/// e.g. `C:\ProgramData\dev.firezone.client\config\client.toml`
+///
+/// Anchored in a known folder, so it doesn't depend on the working dir we were started in
+pub(crate) fn default_config_path() -> Result<PathBuf> {
+    Ok(firezone_headless_client::known_dirs::ipc_service_config()
+        .context("Can't find the ProgramData folder")?
+        .join("client.toml"))
+}
+
 // Does nothing on Windows. On Linux this notifies systemd that we're ready.
 // When we eventually have a system service for the Windows Headless Client,
 // this could notify the Windows service controller too.
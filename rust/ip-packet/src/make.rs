@@ -61,6 +61,63 @@ pub fn icmp_response_packet(packet: IpPacket<'static>) -> MutableIpPacket<'stati
     .expect("src and dst come from the same packet")
 }
 
+/// Makes an ICMP error that tells the sender of `packet` to not send packets larger than `mtu`.
+///
+/// For IPv4, this is a "fragmentation needed" message, for IPv6 a "packet too big" message.
+/// The error appears to come from the original destination and quotes as much of the original packet as fits into the minimum MTU of the respective IP version.
+pub fn icmp_packet_too_big(packet: IpPacket<'_>, mtu: u16) -> MutableIpPacket<'static> {
+    use crate::{
+        icmp::{IcmpCode, IcmpTypes, MutableIcmpPacket},
+        icmpv6::{Icmpv6Code, Icmpv6Types, MutableIcmpv6Packet},
+        ip::IpNextHeaderProtocols,
+        MutablePacket as _, Packet as _,
+    };
+
+    let original = packet.packet();
+
+    let buf = match (packet.source(), packet.destination()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let quoted = &original[..original.len().min(576 - 20 - 8)];
+
+            let mut buf = vec![0u8; 20 + 20 + 8 + quoted.len()];
+            ipv4_header(dst, src, IpNextHeaderProtocols::Icmp, 5, &mut buf[20..]);
+
+            let mut icmp = MutableIcmpPacket::new(&mut buf[40..]).unwrap();
+            icmp.set_icmp_type(IcmpTypes::DestinationUnreachable);
+            icmp.set_icmp_code(IcmpCode::new(4)); // Fragmentation needed and DF set.
+
+            let payload = icmp.payload_mut();
+            payload[2..4].copy_from_slice(&mtu.to_be_bytes()); // The first two bytes are unused.
+            payload[4..].copy_from_slice(quoted);
+
+            buf
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let quoted = &original[..original.len().min(1280 - 40 - 8)];
+
+            let mut buf = vec![0u8; 20 + 40 + 8 + quoted.len()];
+            ipv6_header(dst, src, IpNextHeaderProtocols::Icmpv6, &mut buf[20..]);
+
+            let mut icmp = MutableIcmpv6Packet::new(&mut buf[60..]).unwrap();
+            icmp.set_icmpv6_type(Icmpv6Types::PacketTooBig);
+            icmp.set_icmpv6_code(Icmpv6Code::new(0));
+
+            let payload = icmp.payload_mut();
+            payload[..4].copy_from_slice(&u32::from(mtu).to_be_bytes());
+            payload[4..].copy_from_slice(quoted);
+
+            buf
+        }
+        (IpAddr::V6(_), IpAddr::V4(_)) | (IpAddr::V4(_), IpAddr::V6(_)) => {
+            unreachable!("source and destination come from the same packet")
+        }
+    };
+
+    let mut result = MutableIpPacket::owned(buf).unwrap();
+    result.update_checksum();
+    result
+}
+
 #[cfg_attr(test, derive(Debug, test_strategy::Arbitrary))]
 pub(crate) enum IcmpKind {
     Request,
@@ -217,6 +274,55 @@ where
     }
 }
 
+/// Makes a TCP SYN packet, i.e. the first packet of a TCP handshake.
+pub fn tcp_syn_packet<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    seq: u32,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    tcp_packet_with_flags(saddr, daddr, sport, dport, seq, tcp::TcpFlags::SYN)
+}
+
+/// Makes a TCP RST packet, i.e. a packet that aborts a TCP connection.
+pub fn tcp_rst_packet<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    seq: u32,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    tcp_packet_with_flags(saddr, daddr, sport, dport, seq, tcp::TcpFlags::RST)
+}
+
+fn tcp_packet_with_flags<IP>(
+    saddr: IP,
+    daddr: IP,
+    sport: u16,
+    dport: u16,
+    seq: u32,
+    flags: u8,
+) -> Result<MutableIpPacket<'static>, IpVersionMismatch>
+where
+    IP: Into<IpAddr>,
+{
+    let mut packet = tcp_packet(saddr, daddr, sport, dport, Vec::new())?;
+
+    let mut tcp = packet.as_tcp().expect("we just built a TCP packet");
+    tcp.set_sequence(seq);
+    tcp.set_flags(flags);
+    packet.update_checksum();
+
+    Ok(packet)
+}
+
 pub fn udp_packet<IP>(
     saddr: IP,
     daddr: IP,
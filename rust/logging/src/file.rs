@@ -2,10 +2,15 @@
 //!
 //! This module implements a file-based logger for connlib using tracing-appender.
 //!
-//! The log files are never rotated for the duration of the process; this prevents
+//! By default, the log files are never rotated for the duration of the process; this prevents
 //! tracing_appender from trying to prune old log files which triggers privacy
 //! alerts in Apple app store submissions.
 //!
+//! The desktop Clients opt into a [`Retention`] policy instead. Then we start a new file once the
+//! current one gets too big or too old, gzip the old one and delete the oldest gzipped files
+//! once they expire or the directory grows beyond its cap.
+//! We tell the age of a file from the timestamp in its name, we never look at file times.
+//!
 //! Since these will be leaving the user's device, these logs should contain *only*
 //! the necessary debugging information, and **not** any sensitive information,
 //! including but not limited to:
@@ -17,27 +22,77 @@
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
-use time::OffsetDateTime;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use time::{OffsetDateTime, PrimitiveDateTime};
 use tracing::Subscriber;
 use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_subscriber::Layer;
 
 const LOG_FILE_BASE_NAME: &str = "connlib";
 pub const TIME_FORMAT: &str = "[year]-[month]-[day]-[hour]-[minute]-[second]";
+const COMPRESSED_EXTENSION: &str = "gz";
+
+/// When to rotate log files and when to delete them again.
+///
+/// Only gzipped files are ever deleted, the files we currently write to count towards
+/// `max_dir_size` but stay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    /// Start a new file once the current one reaches this many bytes.
+    pub max_file_size: u64,
+    /// Start a new file once we have been writing to the current one for this long.
+    pub max_file_age: Duration,
+    /// Delete rotated files that are older than this.
+    pub max_age: Duration,
+    /// Delete the oldest rotated files once all our logs in the directory take up more bytes than this.
+    pub max_dir_size: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_file_size: 10 * 1024 * 1024,
+            max_file_age: Duration::from_secs(24 * 60 * 60),
+            max_age: Duration::from_secs(14 * 24 * 60 * 60),
+            max_dir_size: 100 * 1024 * 1024,
+        }
+    }
+}
 
 /// Create a new file logger layer.
 pub fn layer<T>(log_dir: &Path) -> (Box<dyn Layer<T> + Send + Sync + 'static>, Handle)
 where
     T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
-    let (appender_json, handle_json) = new_appender(log_dir.to_path_buf(), "jsonl");
-    let layer_json = tracing_stackdriver::layer()
-        .with_writer(appender_json)
-        .boxed();
+    new_layer(log_dir, None)
+}
+
+/// Create a new file logger layer that rotates and deletes its files according to `retention`.
+pub fn layer_with_retention<T>(
+    log_dir: &Path,
+    retention: Retention,
+) -> (Box<dyn Layer<T> + Send + Sync + 'static>, Handle)
+where
+    T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    new_layer(log_dir, Some(retention))
+}
 
-    let (appender_fmt, handle_fmt) = new_appender(log_dir.to_path_buf(), "log");
+fn new_layer<T>(
+    log_dir: &Path,
+    retention: Option<Retention>,
+) -> (Box<dyn Layer<T> + Send + Sync + 'static>, Handle)
+where
+    T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let (appender_json, handle_json) = new_appender(log_dir.to_path_buf(), "jsonl", retention);
+    let layer_json = crate::json::layer(appender_json).boxed();
+
+    let (appender_fmt, handle_fmt) = new_appender(log_dir.to_path_buf(), "log", retention);
     let layer_fmt = tracing_subscriber::fmt::layer()
         .with_writer(appender_fmt)
         .with_ansi(false)
@@ -54,11 +109,16 @@ where
     (vec![layer_json, layer_fmt].boxed(), handle)
 }
 
-fn new_appender(directory: PathBuf, file_extension: &'static str) -> (NonBlocking, WorkerGuard) {
+fn new_appender(
+    directory: PathBuf,
+    file_extension: &'static str,
+    retention: Option<Retention>,
+) -> (NonBlocking, WorkerGuard) {
     let appender = Appender {
         directory,
         current: None,
         file_extension,
+        retention,
     };
 
     let (non_blocking, guard) = tracing_appender::non_blocking(appender);
@@ -81,9 +141,18 @@ pub struct Handle {
 struct Appender {
     directory: PathBuf,
     file_extension: &'static str,
+    retention: Option<Retention>,
     // Leaving this so that I/O errors come up through `write` instead of panicking
     // in `layer`
-    current: Option<(fs::File, String)>,
+    current: Option<CurrentFile>,
+}
+
+#[derive(Debug)]
+struct CurrentFile {
+    file: fs::File,
+    name: String,
+    opened_at: Instant,
+    len: u64,
 }
 
 impl Appender {
@@ -93,27 +162,62 @@ impl Appender {
     ) -> io::Result<R> {
         match self.current.as_mut() {
             None => {
+                if let Some(retention) = self.retention {
+                    // Compress whatever the previous file was, be it from this process or an earlier one.
+                    // If that fails, we rather keep logging to a full disk than not logging at all.
+                    let _ = clean_up(&self.directory, self.file_extension, &retention);
+                }
+
                 let (mut file, name) = self.create_new_writer()?;
 
                 let ret = cb(&mut file);
 
-                self.current = Some((file, name));
+                self.current = Some(CurrentFile {
+                    file,
+                    name,
+                    opened_at: Instant::now(),
+                    len: 0,
+                });
 
                 ret
             }
-            Some((file, _)) => cb(file),
+            Some(current) => cb(&mut current.file),
         }
     }
 
-    // Inspired from `tracing-appender/src/rolling.rs`.
-    fn create_new_writer(&self) -> io::Result<(fs::File, String)> {
+    /// Closes the current file if it is due for rotation, the next write then opens a new one.
+    fn rotate_if_due(&mut self) {
+        let (Some(retention), Some(current)) = (self.retention, self.current.as_ref()) else {
+            return;
+        };
+
+        if current.len < retention.max_file_size
+            && current.opened_at.elapsed() < retention.max_file_age
+        {
+            return;
+        }
+
+        // File names only have a resolution of one second, we must not re-open the file we just closed.
+        if self.new_file_name() == current.name {
+            return;
+        }
+
+        self.current = None;
+    }
+
+    fn new_file_name(&self) -> String {
         let format = time::format_description::parse(TIME_FORMAT)
             .expect("static format description should always be parsable");
         let date = OffsetDateTime::now_utc()
             .format(&format)
             .expect("formatting a timestamp should always be possible");
 
-        let filename = format!("{LOG_FILE_BASE_NAME}.{date}.{}", self.file_extension);
+        format!("{LOG_FILE_BASE_NAME}.{date}.{}", self.file_extension)
+    }
+
+    // Inspired from `tracing-appender/src/rolling.rs`.
+    fn create_new_writer(&self) -> io::Result<(fs::File, String)> {
+        let filename = self.new_file_name();
 
         let path = self.directory.join(&filename);
         let mut open_options = fs::OpenOptions::new();
@@ -130,37 +234,232 @@ impl Appender {
         }
 
         let file = new_file?;
-        Self::set_permissions(&file)?;
+        set_permissions(&file)?;
 
         Ok((file, filename))
     }
+}
 
-    /// Make the logs group-readable so that the GUI, running as a user in the `firezone`
-    /// group, can zip them up when exporting logs.
-    #[cfg(target_os = "linux")]
-    fn set_permissions(f: &fs::File) -> io::Result<()> {
-        // I would put this at the top of the file, but it only exists on Linux
-        use std::os::unix::fs::PermissionsExt;
-        // user read/write, group read-only, others nothing
-        let perms = fs::Permissions::from_mode(0o640);
-        f.set_permissions(perms)?;
-        Ok(())
+/// Make the logs group-readable so that the GUI, running as a user in the `firezone`
+/// group, can zip them up when exporting logs.
+#[cfg(target_os = "linux")]
+fn set_permissions(f: &fs::File) -> io::Result<()> {
+    // I would put this at the top of the file, but it only exists on Linux
+    use std::os::unix::fs::PermissionsExt;
+    // user read/write, group read-only, others nothing
+    let perms = fs::Permissions::from_mode(0o640);
+    f.set_permissions(perms)?;
+    Ok(())
+}
+
+/// Does nothing on non-Linux systems
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::unnecessary_wraps)]
+fn set_permissions(_f: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Gzips our closed files with `file_extension`, then deletes gzipped files as `retention` demands.
+///
+/// Must be called while this appender has no open file, or it would compress that one too.
+fn clean_up(directory: &Path, file_extension: &str, retention: &Retention) -> io::Result<()> {
+    for file in log_files(directory)? {
+        if file.extension == file_extension {
+            compress(&directory.join(&file.name))?;
+        }
+    }
+
+    let mut files = log_files(directory)?;
+    files.sort_by_key(|file| file.created_at);
+
+    let expired_before = OffsetDateTime::now_utc() - retention.max_age;
+    let mut total_size = files.iter().map(|file| file.len).sum::<u64>();
+
+    for file in files
+        .iter()
+        .filter(|file| file.extension == COMPRESSED_EXTENSION)
+    {
+        if file.created_at >= expired_before && total_size <= retention.max_dir_size {
+            break; // Sorted by age, all the following files are newer.
+        }
+
+        match fs::remove_file(directory.join(&file.name)) {
+            Ok(()) => {}
+            // The appender for the other extension got there first.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        total_size = total_size.saturating_sub(file.len);
+    }
+
+    Ok(())
+}
+
+/// Replaces `path` with a gzipped `path.gz`.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".");
+    compressed_path.push(COMPRESSED_EXTENSION);
+    let compressed_path = PathBuf::from(compressed_path);
+
+    let result = (|| {
+        let mut input = fs::File::open(path)?;
+        let output = fs::File::create(&compressed_path)?;
+        set_permissions(&output)?;
+
+        let mut encoder = GzEncoder::new(output, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()
+    })();
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&compressed_path);
+        return Err(e);
     }
 
-    /// Does nothing on non-Linux systems
-    #[cfg(not(target_os = "linux"))]
-    #[allow(clippy::unnecessary_wraps)]
-    fn set_permissions(_f: &fs::File) -> io::Result<()> {
-        Ok(())
+    fs::remove_file(path)
+}
+
+/// A file in a log directory that we wrote
+#[derive(Debug, PartialEq)]
+struct LogFile {
+    name: String,
+    /// `log`, `jsonl` or `gz`
+    extension: String,
+    created_at: OffsetDateTime,
+    len: u64,
+}
+
+fn log_files(directory: &Path) -> io::Result<Vec<LogFile>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Some((created_at, extension)) = parse_file_name(&name) else {
+            continue; // Not ours, e.g. a crash dump.
+        };
+        let len = entry.metadata()?.len();
+
+        files.push(LogFile {
+            extension: extension.to_owned(),
+            name,
+            created_at,
+            len,
+        });
     }
+
+    Ok(files)
+}
+
+/// Parses e.g. `connlib.2024-08-05-19-41-46.log.gz` into its timestamp and `gz`.
+fn parse_file_name(name: &str) -> Option<(OffsetDateTime, &str)> {
+    let rest = name.strip_prefix(LOG_FILE_BASE_NAME)?.strip_prefix('.')?;
+    let (date, extension) = rest.split_once('.')?;
+    let extension = extension.rsplit('.').next()?;
+
+    let format = time::format_description::parse(TIME_FORMAT)
+        .expect("static format description should always be parsable");
+    let created_at = PrimitiveDateTime::parse(date, &format).ok()?.assume_utc();
+
+    Some((created_at, extension))
 }
 
 impl io::Write for Appender {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.with_current_file(|f| f.write(buf))
+        self.rotate_if_due();
+
+        let written = self.with_current_file(|f| f.write(buf))?;
+        if let Some(current) = self.current.as_mut() {
+            current.len += written as u64;
+        }
+
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         self.with_current_file(|f| f.flush())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_our_file_names() {
+        let (created_at, extension) =
+            parse_file_name("connlib.2024-08-05-19-41-46.log.gz").unwrap();
+
+        assert_eq!(created_at.unix_timestamp(), 1_722_886_906);
+        assert_eq!(extension, "gz");
+        assert_eq!(
+            parse_file_name("connlib.2024-08-05-19-41-46.jsonl")
+                .unwrap()
+                .1,
+            "jsonl"
+        );
+        assert!(parse_file_name("crash.2024-07-22-21-16-20.dmp").is_none());
+        assert!(parse_file_name("connlib.not-a-date.log").is_none());
+    }
+
+    #[test]
+    fn compresses_and_prunes() {
+        let dir = std::env::temp_dir().join(format!(
+            "firezone-logging-compresses-and-prunes-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let now = OffsetDateTime::now_utc();
+        let name = |age: Duration, extension: &str| {
+            let format = time::format_description::parse(TIME_FORMAT).unwrap();
+            format!(
+                "{LOG_FILE_BASE_NAME}.{}.{extension}",
+                (now - age).format(&format).unwrap()
+            )
+        };
+        let hours = |n: u64| Duration::from_secs(n * 60 * 60);
+
+        let expired = name(Duration::from_secs(15 * 24 * 60 * 60), "log.gz");
+        let oldest = name(hours(3), "log.gz");
+        let older = name(hours(2), "log.gz");
+        let previous = name(hours(1), "log");
+        let other_appender = name(hours(1), "jsonl");
+        for file in [&expired, &oldest, &older, &previous, &other_appender] {
+            fs::write(dir.join(file), vec![b'a'; 1000]).unwrap();
+        }
+        fs::write(dir.join("last_crash.dmp"), vec![0; 10_000]).unwrap();
+
+        clean_up(
+            &dir,
+            "log",
+            &Retention {
+                max_dir_size: 2500,
+                ..Retention::default()
+            },
+        )
+        .unwrap();
+
+        let mut remaining = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+
+        let compressed = format!("{previous}.gz");
+        let mut expected = vec![
+            older.as_str(),
+            compressed.as_str(),
+            other_appender.as_str(),
+            "last_crash.dmp",
+        ];
+        expected.sort();
+        assert_eq!(remaining, expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
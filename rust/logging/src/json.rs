@@ -0,0 +1,249 @@
+//! The JSON log format that all our binaries share
+//!
+//! Each event becomes one JSON object per line, with the fields of the event and all of its spans flattened into it.
+//! We log the same IDs under different names in different places, e.g. `cid` in snownet and `gateway` or `gateway_id` in connlib.
+//! Here, they always get the names in [`canonical_name`], so log queries don't have to know all the aliases.
+
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Write as _;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Record};
+use tracing::{Event, Id, Subscriber};
+use tracing_log::NormalizeEvent as _;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+pub const CLIENT_ID: &str = "client_id";
+pub const GATEWAY_ID: &str = "gateway_id";
+pub const RESOURCE_ID: &str = "resource_id";
+/// The ID of the peer on the other side of a connection, i.e. a Gateway on Clients and a Client on Gateways
+pub const CONN_ID: &str = "conn_id";
+
+/// Writes events as JSON lines to `make_writer`.
+pub fn layer<W>(make_writer: W) -> JsonLayer<W>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    JsonLayer { make_writer }
+}
+
+pub struct JsonLayer<W> {
+    make_writer: W,
+}
+
+/// Maps the names we use for IDs across the code base to the ones we promise in our logs.
+pub fn canonical_name(name: &str) -> &str {
+    match name {
+        "cid" | "conn" | "conn_id" => CONN_ID,
+        "client" | "client_id" => CLIENT_ID,
+        "gateway" | "gateway_id" | "gid" => GATEWAY_ID,
+        "resource" | "resource_id" | "rid" => RESOURCE_ID,
+        other => other,
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        on_record(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = match serde_json::to_vec(&record(event, &ctx)) {
+            Ok(line) => line,
+            Err(_) => return, // Only maps with string keys, can't fail.
+        };
+        line.push(b'\n');
+
+        let _ = self
+            .make_writer
+            .make_writer_for(event.metadata())
+            .write_all(&line);
+    }
+}
+
+/// The fields of a span, as we will log them
+struct SpanFields(Map<String, Value>);
+
+/// Remembers the fields of a new span for [`record`].
+///
+/// Other layers that need the span fields, like the log shipping, share them through the span's extensions.
+pub(crate) fn on_new_span<S>(attrs: &Attributes<'_>, id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span) = ctx.span(id) else {
+        return;
+    };
+    let mut extensions = span.extensions_mut();
+    if extensions.get_mut::<SpanFields>().is_some() {
+        return; // Another of our layers got here first.
+    }
+
+    let mut fields = Visitor(Map::new());
+    attrs.record(&mut fields);
+    extensions.insert(SpanFields(fields.0));
+}
+
+pub(crate) fn on_record<S>(id: &Id, values: &Record<'_>, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span) = ctx.span(id) else {
+        return;
+    };
+    let mut extensions = span.extensions_mut();
+    let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() else {
+        return;
+    };
+
+    let mut visitor = Visitor(std::mem::take(fields));
+    values.record(&mut visitor);
+    *fields = visitor.0;
+}
+
+/// Turns `event` into the JSON object that we log.
+pub(crate) fn record<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Map<String, Value>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let normalized = event.normalized_metadata();
+    let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_owned(),
+        OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .map(Value::String)
+            .unwrap_or(Value::Null),
+    );
+    object.insert("level".to_owned(), metadata.level().as_str().into());
+    object.insert("target".to_owned(), metadata.target().into());
+
+    // Inner spans override the fields of outer spans, and the event overrides all spans.
+    if let Some(scope) = ctx.event_scope(event) {
+        for span in scope.from_root() {
+            if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                object.extend(fields.clone());
+            }
+            object.insert("span".to_owned(), span.name().into());
+        }
+    }
+
+    let mut visitor = Visitor(object);
+    event.record(&mut visitor);
+
+    visitor.0
+}
+
+struct Visitor(Map<String, Value>);
+
+impl Visitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // `log` records carry their metadata in fields, `normalized_metadata` already took care of them.
+        if field.name().starts_with("log.") {
+            return;
+        }
+
+        self.0
+            .insert(canonical_name(field.name()).to_owned(), value);
+    }
+}
+
+impl Visit for Visitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field, value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn flattens_spans_and_renames_ids() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(layer(buffer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("connection", cid = 5, resource = "r1").entered();
+            let _inner = tracing::info_span!("handshake", resource = "r2").entered();
+
+            tracing::info!(gateway = %"g1", attempt = 2, "Hello");
+        });
+
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let object: Map<String, Value> = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(object["message"], "Hello");
+        assert_eq!(object["level"], "INFO");
+        assert_eq!(object["span"], "handshake");
+        assert_eq!(object[CONN_ID], 5);
+        assert_eq!(object[RESOURCE_ID], "r2");
+        assert_eq!(object[GATEWAY_ID], "g1");
+        assert_eq!(object["attempt"], 2);
+        assert!(line.ends_with('\n'));
+    }
+}
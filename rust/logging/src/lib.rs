@@ -1,14 +1,34 @@
 pub mod file;
+pub mod json;
+pub mod ship;
 
-use tracing::subscriber::DefaultGuard;
+use std::str::FromStr;
+use tracing::{subscriber::DefaultGuard, Subscriber};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
-    filter::ParseError, fmt, layer::SubscriberExt as _, util::SubscriberInitExt, EnvFilter, Layer,
-    Registry,
+    filter::ParseError,
+    fmt,
+    layer::{Layered, SubscriberExt as _},
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
 };
 
+/// Changes the log filter of a subscriber set up with [`setup_reloadable_global_subscriber`]
+pub type FilterReloadHandle = reload::Handle<EnvFilter, Registry>;
+
+/// How we format the logs on stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// See [`json`]
+    Json,
+}
+
 /// Registers a global subscriber with stdout logging and `additional_layer`
-pub fn setup_global_subscriber<L>(additional_layer: L)
+pub fn setup_global_subscriber<L>(format: LogFormat, additional_layer: L)
 where
     L: Layer<Registry> + Send + Sync,
 {
@@ -16,12 +36,46 @@ where
 
     let subscriber = Registry::default()
         .with(additional_layer)
-        .with(fmt::layer())
+        .with(stdout_layer(format))
         .with(filter(&directives));
     tracing::subscriber::set_global_default(subscriber).expect("Could not set global default");
     LogTracer::init().unwrap();
 }
 
+/// Like [`setup_global_subscriber`], but with a log filter that can change while we're running
+///
+/// Uses `directives` instead of reading `RUST_LOG`, so callers can take the filter from a config file.
+pub fn setup_reloadable_global_subscriber<L>(
+    format: LogFormat,
+    directives: &str,
+    additional_layer: L,
+) -> Result<FilterReloadHandle, ParseError>
+where
+    L: Layer<Layered<reload::Layer<EnvFilter, Registry>, Registry>> + Send + Sync,
+{
+    let (filter, handle) = reload::Layer::new(try_filter(directives)?);
+
+    let subscriber = Registry::default()
+        .with(filter)
+        .with(additional_layer)
+        .with(stdout_layer(format));
+    tracing::subscriber::set_global_default(subscriber).expect("Could not set global default");
+    LogTracer::init().unwrap();
+
+    Ok(handle)
+}
+
+/// Logs to stdout in `format`
+pub fn stdout_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        LogFormat::Human => fmt::layer().boxed(),
+        LogFormat::Json => json::layer(std::io::stdout).boxed(),
+    }
+}
+
 /// Constructs an opinionated [`EnvFilter`] with some crates already silenced.
 pub fn filter(directives: &str) -> EnvFilter {
     try_filter(directives).unwrap()
@@ -42,6 +96,27 @@ pub fn try_filter(directives: &str) -> Result<EnvFilter, ParseError> {
     EnvFilter::try_new(format!("{IRRELEVANT_CRATES},{directives}"))
 }
 
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("`{s}` should be `human` or `json`")),
+        }
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Human => f.write_str("human"),
+            Self::Json => f.write_str("json"),
+        }
+    }
+}
+
 /// Initialises a logger to be used in tests.
 pub fn test(directives: &str) -> DefaultGuard {
     tracing_subscriber::fmt()
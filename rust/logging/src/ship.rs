@@ -0,0 +1,593 @@
+//! Ships logs to a syslog server or to a local OpenTelemetry collector
+//!
+//! Events are formatted like in [`crate::json`] and handed to a background thread,
+//! so a slow or unreachable destination never blocks the thread that logs.
+//! If the thread can't keep up, we drop events instead of buffering them without bounds.
+//!
+//! The thread runs its own Tokio runtime, for the OTLP exporter and for our sockets.
+//! Those come from the caller's socket factories, so e.g. on Linux they carry our mark:
+//! The kill switch lets them out and the Internet Resource doesn't route them back into the tunnel.
+
+use crate::json;
+use opentelemetry::logs::{AnyValue, LogRecord as _, Severity};
+use opentelemetry::{InstrumentationLibrary, Key, KeyValue};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::export::logs::{LogData, LogExporter as _};
+use opentelemetry_sdk::logs::LogRecord;
+use opentelemetry_sdk::Resource;
+use serde_json::{Map, Value};
+use socket_factory::{DatagramOut, SocketFactory, TcpSocket, UdpSocket};
+use std::borrow::Cow;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, ThreadId};
+use std::time::{Duration, SystemTime};
+use std::{fmt, thread};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::span::{Attributes, Record};
+use tracing::{Event, Id, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// How many events can wait for the background thread before we start dropping them.
+const QUEUE_LEN: usize = 10_000;
+/// The most events we send to the collector in one request.
+const OTLP_BATCH_SIZE: usize = 512;
+/// How long an event may wait for more events to fill up its batch.
+const OTLP_BATCH_DELAY: Duration = Duration::from_secs(1);
+const OTLP_DEFAULT_PORT: u16 = 4317;
+const SYSLOG_DEFAULT_PORT: u16 = 514;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to ship logs to, parsed from a URL-like string
+///
+/// - `syslog://host[:port]`: RFC 5424 messages over UDP, port 514 by default
+/// - `syslog:///dev/log`: RFC 5424 messages over a Unix datagram socket (Unix only)
+/// - `otlp://host[:port]`: OTLP over gRPC, port 4317 by default
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    SyslogUdp(String),
+    #[cfg(unix)]
+    SyslogUnix(PathBuf),
+    Otlp(String),
+}
+
+/// Creates a layer that ships all events to `destination`.
+///
+/// `service_name` identifies the binary, it becomes the syslog APP-NAME and the OTLP `service.name`.
+/// We connect to syslog servers and OTLP collectors with sockets from `tcp_socket_factory` and `udp_socket_factory`.
+pub fn layer<S>(
+    destination: &Destination,
+    service_name: &'static str,
+    tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
+) -> io::Result<(Box<dyn Layer<S> + Send + Sync + 'static>, Handle)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let sink = {
+        let _guard = runtime.enter();
+        Sink::connect(
+            destination,
+            service_name,
+            tcp_socket_factory,
+            udp_socket_factory,
+        )
+    };
+    let sink = match sink {
+        Ok(sink) => sink,
+        Err(error) => {
+            runtime.shutdown_background(); // We may be called from within another runtime, where we can't block.
+            return Err(error);
+        }
+    };
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let queue = Queue {
+        rx,
+        shutdown: shutdown_rx,
+        shutting_down: false,
+    };
+    let thread = thread::Builder::new()
+        .name("log shipper".to_owned())
+        .spawn(move || runtime.block_on(sink.run(queue)))?;
+    let shipper = thread.thread().id();
+
+    let handle = Handle {
+        _worker: Arc::new(Worker {
+            shutdown: Mutex::new(Some(shutdown_tx)),
+            thread: Mutex::new(Some(thread)),
+        }),
+    };
+
+    Ok((Box::new(ShipLayer { tx, shipper }), handle))
+}
+
+/// Keeps the background thread alive.
+///
+/// Once the last clone is dropped, we ship the events that are still queued and stop.
+#[must_use]
+#[derive(Clone, Debug)]
+pub struct Handle {
+    _worker: Arc<Worker>,
+}
+
+#[derive(Debug)]
+struct Worker {
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let shutdown = self
+            .shutdown
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(shutdown) = shutdown {
+            let _ = shutdown.send(());
+        }
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Message {
+    level: Level,
+    record: Map<String, Value>,
+}
+
+struct ShipLayer {
+    tx: mpsc::Sender<Message>,
+    /// The background thread, which logs about its own sockets and the OTLP client
+    shipper: ThreadId,
+}
+
+impl<S> Layer<S> for ShipLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        json::on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        json::on_record(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // Whatever the shipper logs about itself would end up in its own queue.
+        if thread::current().id() == self.shipper {
+            return;
+        }
+
+        let message = Message {
+            level: *event.metadata().level(),
+            record: json::record(event, &ctx),
+        };
+        // If the queue is full, dropping the event is better than blocking the app.
+        let _ = self.tx.try_send(message);
+    }
+}
+
+/// The receiving end of the events from [`ShipLayer`]
+struct Queue {
+    rx: mpsc::Receiver<Message>,
+    shutdown: oneshot::Receiver<()>,
+    shutting_down: bool,
+}
+
+impl Queue {
+    /// Waits for the next event, returns `None` once we should stop
+    ///
+    /// After [`Handle`] asked us to stop, it only returns the events that are already queued.
+    async fn next(&mut self) -> Option<Message> {
+        if !self.shutting_down {
+            tokio::select! {
+                message = self.rx.recv() => return message,
+                _ = &mut self.shutdown => self.shutting_down = true,
+            }
+        }
+
+        self.rx.try_recv().ok()
+    }
+}
+
+#[allow(clippy::large_enum_variant)] // There is only one per shipper.
+enum Sink {
+    Syslog {
+        socket: SyslogSocket,
+        service_name: &'static str,
+    },
+    Otlp(opentelemetry_otlp::LogExporter),
+}
+
+#[allow(clippy::large_enum_variant)]
+enum SyslogSocket {
+    Udp {
+        socket: UdpSocket,
+        dst: SocketAddr,
+    },
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Sink {
+    /// Must be called within the runtime of the background thread, our sockets register with it.
+    fn connect(
+        destination: &Destination,
+        service_name: &'static str,
+        tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
+    ) -> io::Result<Self> {
+        let sink = match destination {
+            Destination::SyslogUdp(host) => {
+                let dst = resolve(&with_default_port(host, SYSLOG_DEFAULT_PORT))?;
+                let socket = udp_socket_factory(&match dst {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                })?;
+
+                Self::Syslog {
+                    socket: SyslogSocket::Udp { socket, dst },
+                    service_name,
+                }
+            }
+            #[cfg(unix)]
+            Destination::SyslogUnix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+
+                Self::Syslog {
+                    socket: SyslogSocket::Unix(socket),
+                    service_name,
+                }
+            }
+            Destination::Otlp(host) => {
+                let host = with_default_port(host, OTLP_DEFAULT_PORT);
+                let channel = tonic::transport::Endpoint::from_shared(format!("http://{host}"))
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?
+                    .connect_timeout(TIMEOUT)
+                    .connect_with_connector_lazy(tower::service_fn(move |_| {
+                        let host = host.clone();
+                        let tcp_socket_factory = tcp_socket_factory.clone();
+
+                        async move {
+                            let addr = resolve(&host)?;
+                            let stream = tcp_socket_factory(&addr)?.connect(addr).await?;
+
+                            io::Result::Ok(hyper_util::rt::TokioIo::new(stream))
+                        }
+                    }));
+                let mut exporter = opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_channel(channel)
+                    .with_timeout(TIMEOUT)
+                    .build_log_exporter()
+                    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                exporter.set_resource(&Resource::new([KeyValue::new(
+                    "service.name",
+                    service_name,
+                )]));
+
+                Self::Otlp(exporter)
+            }
+        };
+
+        Ok(sink)
+    }
+
+    async fn run(self, mut queue: Queue) {
+        match self {
+            Self::Syslog {
+                mut socket,
+                service_name,
+            } => {
+                while let Some(Message { level, record }) = queue.next().await {
+                    let message = syslog_message(service_name, level, &record);
+                    let result = match &mut socket {
+                        SyslogSocket::Udp { socket, dst } => {
+                            send_datagram(socket, *dst, message.as_bytes()).await
+                        }
+                        #[cfg(unix)]
+                        SyslogSocket::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+                    };
+                    if let Err(error) = result {
+                        tracing::debug!(%error, "Failed to send log to syslog");
+                    }
+                }
+            }
+            Self::Otlp(mut exporter) => {
+                let mut batch = Vec::with_capacity(OTLP_BATCH_SIZE);
+                let mut deadline = None;
+                loop {
+                    let next = match deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline, queue.next()).await,
+                        None => Ok(queue.next().await),
+                    };
+                    let shutdown = match next {
+                        Ok(Some(Message { level, record })) => {
+                            batch.push(Cow::Owned(otlp_log_data(level, record)));
+                            deadline.get_or_insert_with(|| Instant::now() + OTLP_BATCH_DELAY);
+                            false
+                        }
+                        Ok(None) => true,
+                        Err(_) => false, // The deadline passed.
+                    };
+
+                    let deadline_passed = deadline.is_some_and(|d| Instant::now() >= d);
+                    if !batch.is_empty()
+                        && (shutdown || deadline_passed || batch.len() >= OTLP_BATCH_SIZE)
+                    {
+                        deadline = None;
+
+                        if let Err(error) = exporter.export(std::mem::take(&mut batch)).await {
+                            tracing::debug!(%error, "Failed to ship logs to OTLP collector");
+                        }
+                    }
+                    if shutdown {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Formats an RFC 5424 message, with our JSON record as the MSG.
+fn syslog_message(service_name: &str, level: Level, record: &Map<String, Value>) -> String {
+    const FACILITY_DAEMON: u8 = 3;
+
+    // `Level` is not an enum, so we can't match on it.
+    let severity = if level == Level::ERROR {
+        3
+    } else if level == Level::WARN {
+        4
+    } else if level == Level::INFO {
+        6
+    } else {
+        7
+    };
+    let timestamp = record
+        .get("timestamp")
+        .and_then(Value::as_str)
+        .unwrap_or("-");
+    let msg = serde_json::to_string(record).unwrap_or_default();
+
+    format!(
+        "<{}>1 {timestamp} - {service_name} {} - - {msg}",
+        FACILITY_DAEMON * 8 + severity,
+        std::process::id()
+    )
+}
+
+/// Converts our JSON record to an OTLP `LogRecord`.
+fn otlp_log_data(level: Level, mut record: Map<String, Value>) -> LogData {
+    let severity = if level == Level::ERROR {
+        Severity::Error
+    } else if level == Level::WARN {
+        Severity::Warn
+    } else if level == Level::INFO {
+        Severity::Info
+    } else if level == Level::DEBUG {
+        Severity::Debug
+    } else {
+        Severity::Trace
+    };
+    let body = record.remove("message").unwrap_or(Value::Null);
+    record.remove("timestamp");
+    record.remove("level");
+    let now = SystemTime::now();
+
+    let mut log_record = LogRecord::default();
+    log_record.set_timestamp(now);
+    log_record.set_observed_timestamp(now);
+    log_record.set_severity_text(level.as_str().into());
+    log_record.set_severity_number(severity);
+    log_record.set_body(any_value(body));
+    log_record.add_attributes(
+        record
+            .into_iter()
+            .map(|(key, value)| (Key::new(key), any_value(value))),
+    );
+
+    LogData {
+        record: log_record,
+        instrumentation: InstrumentationLibrary::builder("firezone").build(),
+    }
+}
+
+fn any_value(value: Value) -> AnyValue {
+    match value {
+        Value::Bool(b) => AnyValue::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => AnyValue::Int(i),
+            None => AnyValue::Double(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => AnyValue::from(s),
+        Value::Null | Value::Array(_) | Value::Object(_) => AnyValue::from(value.to_string()),
+    }
+}
+
+/// Sends one datagram, waiting if the socket is busy.
+async fn send_datagram(socket: &mut UdpSocket, dst: SocketAddr, packet: &[u8]) -> io::Result<()> {
+    socket.send(DatagramOut {
+        src: None,
+        dst,
+        packet: Cow::Borrowed(packet),
+        segment_size: None,
+    })?;
+
+    std::future::poll_fn(|cx| socket.poll_flush(cx)).await
+}
+
+fn resolve(host: &str) -> io::Result<SocketAddr> {
+    host.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Can't resolve `{host}`")))
+}
+
+fn with_default_port(host: &str, port: u16) -> String {
+    let has_port = match host.rsplit_once(':') {
+        Some((host, _)) => !host.contains(':') || host.ends_with(']'),
+        None => false,
+    };
+
+    if has_port {
+        host.to_owned()
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "`{s}` should be `syslog://host[:port]`, `syslog:///path` or `otlp://host[:port]`"
+            )
+        };
+
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        match scheme {
+            #[cfg(unix)]
+            "syslog" if rest.starts_with('/') => Ok(Self::SyslogUnix(PathBuf::from(rest))),
+            "syslog" if !rest.is_empty() => Ok(Self::SyslogUdp(rest.to_owned())),
+            "otlp" if !rest.is_empty() => Ok(Self::Otlp(rest.to_owned())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SyslogUdp(host) => write!(f, "syslog://{host}"),
+            #[cfg(unix)]
+            Self::SyslogUnix(path) => write!(f, "syslog://{}", path.display()),
+            Self::Otlp(host) => write!(f, "otlp://{host}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    #[test]
+    fn drops_events_when_queue_is_full() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let subscriber = tracing_subscriber::registry().with(ShipLayer {
+            tx,
+            shipper: thread::spawn(|| {}).thread().id(),
+        });
+
+        // Nobody drains the queue, so this would hang if we blocked.
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::info!(%i, "Event");
+            }
+        });
+
+        let shipped = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| message.record["i"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(shipped, ["0", "1"]);
+    }
+
+    #[test]
+    fn ships_to_syslog_through_socket_factory() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let destination = Destination::SyslogUdp(server.local_addr().unwrap().to_string());
+        let used_factory = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let udp_socket_factory = {
+            let used_factory = used_factory.clone();
+            move |addr: &SocketAddr| {
+                used_factory.store(true, std::sync::atomic::Ordering::Relaxed);
+                socket_factory::udp(addr)
+            }
+        };
+
+        let (layer, handle) = layer(
+            &destination,
+            "firezone-gateway",
+            Arc::new(socket_factory::tcp),
+            Arc::new(udp_socket_factory),
+        )
+        .unwrap();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::warn!("Hello");
+        });
+        drop(handle);
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("<28>1 "), "{message}");
+        assert!(message.contains(r#""message":"Hello""#), "{message}");
+        assert!(used_factory.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn parse_destination() {
+        assert_eq!(
+            "syslog://logs.corp".parse(),
+            Ok(Destination::SyslogUdp("logs.corp".to_owned()))
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "syslog:///dev/log".parse(),
+            Ok(Destination::SyslogUnix(PathBuf::from("/dev/log")))
+        );
+        assert_eq!(
+            "otlp://127.0.0.1:4317".parse(),
+            Ok(Destination::Otlp("127.0.0.1:4317".to_owned()))
+        );
+        assert!("otlp://".parse::<Destination>().is_err());
+        assert!("http://127.0.0.1".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn default_ports() {
+        assert_eq!(with_default_port("logs.corp", 514), "logs.corp:514");
+        assert_eq!(with_default_port("logs.corp:1514", 514), "logs.corp:1514");
+        assert_eq!(with_default_port("[::1]", 514), "[::1]:514");
+        assert_eq!(with_default_port("[::1]:1514", 514), "[::1]:1514");
+    }
+
+    #[test]
+    fn rfc_5424() {
+        let mut record = Map::new();
+        record.insert("timestamp".to_owned(), "2024-08-01T12:00:00Z".into());
+        record.insert("message".to_owned(), "Hello".into());
+
+        let message = syslog_message("firezone-gateway", Level::WARN, &record);
+
+        assert_eq!(
+            message,
+            format!(
+                r#"<28>1 2024-08-01T12:00:00Z - firezone-gateway {} - - {{"message":"Hello","timestamp":"2024-08-01T12:00:00Z"}}"#,
+                std::process::id()
+            )
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::collections::VecDeque;
+
+/// How many packets we queue for connlib before the stack has to wait for connlib to read them.
+const TX_QUEUE_LEN: usize = 1024;
+
+/// The [`phy::Device`] of our stack, backed by two queues of IP packets.
+pub(crate) struct VirtualDevice {
+    mtu: usize,
+    /// Packets connlib wrote to the [`NetstackTun`](crate::NetstackTun), for the stack to receive.
+    rx: VecDeque<Vec<u8>>,
+    /// Packets the stack sent, for connlib to read from the [`NetstackTun`](crate::NetstackTun).
+    tx: VecDeque<Vec<u8>>,
+}
+
+impl VirtualDevice {
+    pub(crate) fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn push_rx(&mut self, packet: Vec<u8>) {
+        self.rx.push_back(packet);
+    }
+
+    pub(crate) fn pop_tx(&mut self) -> Option<Vec<u8>> {
+        self.tx.pop_front()
+    }
+
+    pub(crate) fn has_tx(&self) -> bool {
+        !self.tx.is_empty()
+    }
+
+    pub(crate) fn is_tx_full(&self) -> bool {
+        self.tx.len() >= TX_QUEUE_LEN
+    }
+}
+
+impl phy::Device for VirtualDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<Self::TxToken<'_>> {
+        if self.is_tx_full() {
+            return None;
+        }
+
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+
+        caps
+    }
+}
+
+pub(crate) struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+pub(crate) struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+
+        result
+    }
+}
@@ -0,0 +1,230 @@
+//! Resolves names with the DNS servers connlib intercepts, so resources resolve to the IPs connlib routes to them
+
+use crate::Shared;
+use domain::base::{iana::Rcode, Message, MessageBuilder, Name, Question, Rtype};
+use domain::rdata::{Aaaa, A};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::udp;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use std::{fmt, io};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Without EDNS, responses over UDP are at most 512 bytes, but some servers send more anyway.
+const MAX_RESPONSE_LEN: usize = 4096;
+
+pub(crate) async fn lookup(shared: &Arc<Shared>, host: &str) -> io::Result<Vec<IpAddr>> {
+    let name = Name::vec_from_str(host).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid domain name `{host}`: {e:?}"),
+        )
+    })?;
+    let server = shared.lock().dns.first().copied().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotConnected,
+            "We don't have a DNS server yet",
+        )
+    })?;
+
+    let (ipv4, ipv6) = tokio::join!(
+        query(shared, server, &name, Rtype::A),
+        query(shared, server, &name, Rtype::AAAA)
+    );
+    let ips = ipv4
+        .iter()
+        .chain(ipv6.iter())
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+
+    if ips.is_empty() {
+        ipv4?;
+        ipv6?;
+
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("`{host}` has no IPs"),
+        ));
+    }
+
+    Ok(ips)
+}
+
+async fn query(
+    shared: &Arc<Shared>,
+    server: IpAddr,
+    name: &Name<Vec<u8>>,
+    rtype: Rtype,
+) -> io::Result<Vec<IpAddr>> {
+    let socket = DnsSocket::bind(shared.clone())?;
+    let query = make_query(name.clone(), rtype, socket.id);
+
+    shared
+        .with(|inner| {
+            inner
+                .sockets
+                .get_mut::<udp::Socket>(socket.handle)
+                .send_slice(&query, IpEndpoint::new(IpAddress::from(server), 53))
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:?}")))?;
+
+    let mut response = vec![0; MAX_RESPONSE_LEN];
+    let len = tokio::time::timeout(
+        TIMEOUT,
+        std::future::poll_fn(|cx| {
+            shared.with(|inner| {
+                let udp = inner.sockets.get_mut::<udp::Socket>(socket.handle);
+                if !udp.can_recv() {
+                    udp.register_recv_waker(cx.waker());
+                    return Poll::Pending;
+                }
+
+                Poll::Ready(udp.recv_slice(&mut response).map(|(len, _)| len))
+            })
+        }),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:?}")))?;
+
+    parse_response(&response[..len], socket.id)
+}
+
+fn make_query(name: Name<Vec<u8>>, rtype: Rtype, id: u16) -> Vec<u8> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_rd(true);
+    builder.header_mut().set_id(id);
+
+    let mut question = builder.question();
+    question
+        .push(Question::new_in(name, rtype))
+        .expect("a single question always fits");
+
+    question.finish()
+}
+
+fn parse_response(response: &[u8], id: u16) -> io::Result<Vec<IpAddr>> {
+    let message = Message::from_slice(response).map_err(invalid_response)?;
+    if message.header().id() != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DNS response doesn't match the query",
+        ));
+    }
+    let rcode = message.header().rcode();
+    if rcode != Rcode::NOERROR {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("DNS query failed: {rcode}"),
+        ));
+    }
+
+    let ipv4 = message
+        .answer()
+        .map_err(invalid_response)?
+        .limit_to::<A>()
+        .filter_map(Result::ok)
+        .map(|record| IpAddr::from(record.data().addr()));
+    let ipv6 = message
+        .answer()
+        .map_err(invalid_response)?
+        .limit_to::<Aaaa>()
+        .filter_map(Result::ok)
+        .map(|record| IpAddr::from(record.data().addr()));
+
+    Ok(ipv4.chain(ipv6).collect())
+}
+
+fn invalid_response(e: impl fmt::Debug) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid DNS response: {e:?}"),
+    )
+}
+
+/// A UDP socket for a single query, removed from the stack once dropped.
+struct DnsSocket {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    /// Our local port, which is unique among the in-flight queries, so it doubles as the query ID.
+    id: u16,
+}
+
+impl DnsSocket {
+    fn bind(shared: Arc<Shared>) -> io::Result<Self> {
+        let (handle, id) = shared.with(|inner| {
+            let mut socket = udp::Socket::new(buffer(), buffer());
+            let port = inner.next_port();
+            socket
+                .bind(port)
+                .map_err(|e| io::Error::new(io::ErrorKind::AddrInUse, format!("{e:?}")))?;
+
+            io::Result::Ok((inner.sockets.add(socket), port))
+        })?;
+
+        Ok(Self { shared, handle, id })
+    }
+}
+
+impl Drop for DnsSocket {
+    fn drop(&mut self) {
+        self.shared.with(|inner| {
+            inner.sockets.remove(self.handle);
+        });
+    }
+}
+
+fn buffer() -> udp::PacketBuffer<'static> {
+    udp::PacketBuffer::new(
+        vec![udp::PacketMetadata::EMPTY; 1],
+        vec![0; MAX_RESPONSE_LEN],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::{iana::Class, Record, Ttl};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn parses_a_and_aaaa_records() {
+        let name = Name::vec_from_str("httpbin.search.test").unwrap();
+        let query = make_query(name.clone(), Rtype::A, 1234);
+        let query = Message::from_slice(&query).unwrap();
+
+        let mut answers = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        answers
+            .push(Record::new(
+                name.clone(),
+                Class::IN,
+                Ttl::from_secs(60),
+                A::new(Ipv4Addr::new(100, 96, 0, 1)),
+            ))
+            .unwrap();
+        answers
+            .push(Record::new(
+                name,
+                Class::IN,
+                Ttl::from_secs(60),
+                Aaaa::new(Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 1)),
+            ))
+            .unwrap();
+        let response = answers.finish();
+
+        assert_eq!(
+            parse_response(&response, 1234).unwrap(),
+            [
+                IpAddr::from([100, 96, 0, 1]),
+                IpAddr::from(Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 1))
+            ]
+        );
+        assert!(parse_response(&response, 4321).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! A userspace TCP/IP stack, for systems where we can't create a TUN device
+//!
+//! [`Netstack::make_tun`] returns a [`NetstackTun`], which connlib uses in place of a TUN device.
+//! Packets that connlib writes to it are handled by [`smoltcp`] and the packets [`smoltcp`] sends are read back by connlib.
+//! Applications reach resources with [`Netstack::connect`] and resolve their names with [`Netstack::lookup`].
+//! [`Netstack::listen`] accepts connections to our tunnel IPs.
+
+mod device;
+mod dns;
+mod tcp_listener;
+mod tcp_stream;
+
+pub use tcp_listener::TcpListener;
+pub use tcp_stream::TcpStream;
+
+use device::VirtualDevice;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::Notify;
+
+/// The ports we pick local ports for TCP connections and DNS queries from, see RFC 6335.
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// A handle to the userspace TCP/IP stack.
+///
+/// Cheap to clone, all clones share the same stack.
+#[derive(Clone)]
+pub struct Netstack {
+    shared: Arc<Shared>,
+}
+
+/// The stack's end of the tunnel: connlib reads the packets the stack sends and writes the packets it receives.
+pub struct NetstackTun {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    /// Wakes the task handling timers, e.g. because a write changed when the next retransmit is due.
+    driver: Arc<Notify>,
+}
+
+struct Inner {
+    iface: Interface,
+    device: VirtualDevice,
+    sockets: SocketSet<'static>,
+    /// TCP sockets whose [`TcpStream`] is gone, removed once they are fully closed.
+    orphans: Vec<SocketHandle>,
+    /// The ports of all [`TcpListener`]s.
+    listening: HashSet<u16>,
+    /// The DNS servers connlib intercepts, i.e. the ones that can resolve resources.
+    dns: Vec<IpAddr>,
+    next_port: u16,
+    /// The task reading from [`NetstackTun`], waiting for packets.
+    reader: Option<Waker>,
+}
+
+impl Netstack {
+    /// Creates a new stack, with an MTU of `mtu` bytes.
+    ///
+    /// Must be called within a Tokio runtime context so we can spawn the task handling the stack's timers.
+    pub fn new(mtu: usize) -> Self {
+        let mut device = VirtualDevice::new(mtu);
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let iface = Interface::new(config, &mut device, Instant::now());
+
+        let driver = Arc::new(Notify::new());
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                iface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                orphans: Vec::new(),
+                listening: HashSet::new(),
+                dns: Vec::new(),
+                next_port: *EPHEMERAL_PORTS.start(),
+                reader: None,
+            }),
+            driver: driver.clone(),
+        });
+
+        tokio::spawn(drive(Arc::downgrade(&shared), driver));
+
+        Self { shared }
+    }
+
+    /// Returns the stack's end of the tunnel, to hand to connlib.
+    pub fn make_tun(&self) -> NetstackTun {
+        NetstackTun {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Assigns the tunnel IPs connlib got from the portal to the stack.
+    pub fn set_ips(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        self.shared.with(|inner| {
+            inner.iface.update_ip_addrs(|addrs| {
+                addrs.clear();
+                addrs
+                    .push(IpCidr::new(IpAddress::Ipv4(ipv4.into()), 32))
+                    .expect("two addresses always fit");
+                addrs
+                    .push(IpCidr::new(IpAddress::Ipv6(ipv6.into()), 128))
+                    .expect("two addresses always fit");
+            });
+
+            // Everything goes through the tunnel, connlib drops what it doesn't have a route for.
+            let routes = inner.iface.routes_mut();
+            routes
+                .add_default_ipv4_route(ipv4.into())
+                .expect("the routing table is empty except for the default routes");
+            routes
+                .add_default_ipv6_route(ipv6.into())
+                .expect("the routing table is empty except for the default routes");
+        });
+
+        tracing::info!(%ipv4, %ipv6, "Assigned IPs to the userspace network stack");
+    }
+
+    /// Sets the DNS servers [`Netstack::lookup`] queries, i.e. the sentinel DNS servers connlib intercepts.
+    pub fn set_dns(&self, servers: Vec<IpAddr>) {
+        self.shared.lock().dns = servers;
+    }
+
+    /// Opens a TCP connection through the tunnel.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(self.shared.clone(), addr).await
+    }
+
+    /// Listens for TCP connections to `port` on our tunnel IPs.
+    pub fn listen(&self, port: u16) -> io::Result<TcpListener> {
+        TcpListener::bind(self.shared.clone(), port)
+    }
+
+    /// Resolves `host` through the tunnel, so resources' names resolve to the IPs connlib routes to them.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        dns::lookup(&self.shared, host).await
+    }
+}
+
+impl tun::Tun for NetstackTun {
+    fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.lock();
+
+        let was_full = inner.device.is_tx_full();
+        let Some(packet) = inner.device.pop_tx() else {
+            inner.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+        drop(inner);
+
+        // The stack stopped sending while the queue was full, have it continue.
+        if was_full {
+            self.shared.driver.notify_one();
+        }
+
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn name(&self) -> &str {
+        "netstack"
+    }
+}
+
+impl NetstackTun {
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.with(|inner| inner.device.push_rx(buf.to_vec()));
+
+        Ok(buf.len())
+    }
+}
+
+impl Shared {
+    /// Runs `f` against the stack and lets the stack process whatever `f` did.
+    fn with<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        let mut inner = self.lock();
+        let result = f(&mut inner);
+        inner.poll();
+        drop(inner);
+
+        self.driver.notify_one();
+
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .expect("we never panic while holding the lock")
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.driver.notify_one(); // Let the driver notice that we are gone.
+    }
+}
+
+impl Inner {
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+        self.orphans.retain(|handle| {
+            let closed = self.sockets.get::<tcp::Socket>(*handle).state() == tcp::State::Closed;
+            if closed {
+                self.sockets.remove(*handle);
+            }
+
+            !closed
+        });
+
+        if self.device.has_tx() {
+            if let Some(reader) = self.reader.take() {
+                reader.wake();
+            }
+        }
+    }
+
+    fn next_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+
+        port
+    }
+}
+
+/// Polls the stack whenever a timer is due, e.g. to retransmit or to send a delayed ACK.
+async fn drive(shared: Weak<Shared>, notify: Arc<Notify>) {
+    loop {
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let delay = {
+            let mut guard = shared.lock();
+            guard.poll();
+
+            let inner = &mut *guard;
+            inner.iface.poll_delay(Instant::now(), &inner.sockets)
+        };
+        drop(shared);
+
+        match delay {
+            Some(delay) => {
+                tokio::select! {
+                    () = tokio::time::sleep(delay.into()) => {},
+                    () = notify.notified() => {},
+                }
+            }
+            None => notify.notified().await,
+        }
+    }
+}
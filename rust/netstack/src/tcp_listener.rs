@@ -0,0 +1,124 @@
+use crate::{Inner, Shared, TcpStream};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// How many connections can be mid-handshake or waiting for [`TcpListener::accept`] at once.
+const BACKLOG: usize = 8;
+
+/// Accepts TCP connections to one of our tunnel IPs.
+///
+/// Stops listening once it is dropped.
+pub struct TcpListener {
+    shared: Arc<Shared>,
+    port: u16,
+    /// Listening sockets, each of them turns into a connection once a peer connects to it.
+    backlog: Vec<SocketHandle>,
+}
+
+impl TcpListener {
+    pub(crate) fn bind(shared: Arc<Shared>, port: u16) -> io::Result<Self> {
+        if port == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't listen on port 0",
+            ));
+        }
+
+        let backlog = shared.with(|inner| {
+            if !inner.listening.insert(port) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Already listening on port {port}"),
+                ));
+            }
+
+            Ok((0..BACKLOG).map(|_| inner.listen(port)).collect())
+        })?;
+
+        tracing::debug!(%port, "Listening through the userspace network stack");
+
+        Ok(Self {
+            shared,
+            port,
+            backlog,
+        })
+    }
+
+    /// The port we are listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for the next connection, returning it together with the peer's address.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        std::future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        let port = self.port;
+        let accepted = self.shared.with(|inner| {
+            for slot in &mut self.backlog {
+                let socket = inner.tcp_socket(*slot);
+
+                if socket.may_send() {
+                    let remote = socket
+                        .remote_endpoint()
+                        .expect("connected sockets have a remote endpoint");
+                    let handle = std::mem::replace(slot, inner.listen(port));
+
+                    return Some((handle, SocketAddr::new(remote.addr.into(), remote.port)));
+                }
+                if socket.state() == tcp::State::Closed {
+                    // The handshake failed, e.g. because the peer reset it.
+                    socket
+                        .listen(port)
+                        .expect("a closed socket can always listen again");
+                }
+
+                // smoltcp wakes both wakers when the handshake completes.
+                socket.register_send_waker(cx.waker());
+            }
+
+            None
+        });
+
+        let Some((handle, remote)) = accepted else {
+            return Poll::Pending;
+        };
+
+        tracing::debug!(%remote, port = %self.port, "Accepted a connection through the userspace network stack");
+
+        Poll::Ready(Ok((
+            TcpStream::accepted(self.shared.clone(), handle),
+            remote,
+        )))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        self.shared.with(|inner| {
+            inner.listening.remove(&self.port);
+
+            for handle in self.backlog.drain(..) {
+                inner.tcp_socket(handle).abort();
+                inner.orphans.push(handle);
+            }
+        });
+    }
+}
+
+impl Inner {
+    fn listen(&mut self, port: u16) -> SocketHandle {
+        let mut socket = TcpStream::new_socket();
+        socket
+            .listen(port)
+            .expect("port is not 0 and the socket is new");
+
+        self.sockets.add(socket)
+    }
+}
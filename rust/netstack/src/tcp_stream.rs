@@ -0,0 +1,169 @@
+use crate::{Inner, Shared};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp;
+use smoltcp::time::Duration;
+use smoltcp::wire::IpAddress;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How long we wait for the peer, during the handshake or while it has to ACK our data.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How many bytes we buffer per direction of a connection.
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// A TCP connection through the tunnel.
+///
+/// Closing happens in the background once it is dropped.
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    pub(crate) async fn connect(shared: Arc<Shared>, addr: SocketAddr) -> io::Result<Self> {
+        let handle = shared.with(|inner| {
+            let mut socket = Self::new_socket();
+            let local_port = inner.next_port();
+            socket
+                .connect(
+                    inner.iface.context(),
+                    (IpAddress::from(addr.ip()), addr.port()),
+                    local_port,
+                )
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("Failed to connect to {addr}: {e:?}"),
+                    )
+                })?;
+
+            io::Result::Ok(inner.sockets.add(socket))
+        })?;
+        let stream = Self { shared, handle };
+
+        std::future::poll_fn(|cx| stream.poll_established(cx)).await?;
+
+        tracing::debug!(%addr, "Connected through the userspace network stack");
+
+        Ok(stream)
+    }
+
+    /// Wraps a socket that a [`TcpListener`](crate::TcpListener) accepted.
+    pub(crate) fn accepted(shared: Arc<Shared>, handle: SocketHandle) -> Self {
+        Self { shared, handle }
+    }
+
+    pub(crate) fn new_socket() -> tcp::Socket<'static> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+        );
+        socket.set_timeout(Some(TIMEOUT));
+
+        socket
+    }
+
+    fn poll_established(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.with(|inner| {
+            let socket = inner.tcp_socket(self.handle);
+            let state = socket.state();
+
+            if socket.may_send() {
+                return Poll::Ready(Ok(()));
+            }
+            if matches!(state, tcp::State::SynSent | tcp::State::SynReceived) {
+                socket.register_send_waker(cx.waker());
+                return Poll::Pending;
+            }
+
+            Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Connection refused or timed out, in state {state}"),
+            )))
+        })
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.shared.with(|inner| {
+            let socket = inner.tcp_socket(self.handle);
+
+            if socket.can_recv() {
+                let n = socket
+                    .recv_slice(buf.initialize_unfilled())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:?}")))?;
+                buf.advance(n);
+
+                return Poll::Ready(Ok(()));
+            }
+            if !socket.may_recv() {
+                return Poll::Ready(Ok(())); // EOF
+            }
+
+            socket.register_recv_waker(cx.waker());
+
+            Poll::Pending
+        })
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.shared.with(|inner| {
+            let socket = inner.tcp_socket(self.handle);
+
+            if !socket.may_send() {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if !socket.can_send() {
+                socket.register_send_waker(cx.waker());
+                return Poll::Pending;
+            }
+
+            Poll::Ready(
+                socket
+                    .send_slice(buf)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:?}"))),
+            )
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(())) // The stack sends as soon as the peer's window allows.
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared
+            .with(|inner| inner.tcp_socket(self.handle).close());
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.shared.with(|inner| {
+            inner.tcp_socket(self.handle).close();
+            inner.orphans.push(self.handle);
+        });
+    }
+}
+
+impl Inner {
+    pub(crate) fn tcp_socket(&mut self, handle: SocketHandle) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut(handle)
+    }
+}
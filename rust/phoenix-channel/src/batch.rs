@@ -0,0 +1,117 @@
+use futures::FutureExt;
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+/// How long we wait for more messages before writing the queued ones to the socket.
+pub const WINDOW: Duration = Duration::from_millis(10);
+/// After this many queued messages, we don't wait for the window to pass.
+pub const MAX_SIZE: usize = 100;
+
+/// Groups outbound messages so we write and flush them together.
+///
+/// Bursts such as ICE candidates for many connections then end up in a few TCP segments instead of one per message.
+/// Messages that can't wait, e.g. heartbeats, take the batch with them via [`Batch::expedite`].
+pub struct Batch {
+    window: Duration,
+    max_size: usize,
+
+    /// When to send what we have queued so far, started by the first queued message.
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Whether to send the queued messages without waiting for the window to pass.
+    urgent: bool,
+}
+
+impl Batch {
+    pub fn new(window: Duration, max_size: usize) -> Self {
+        Self {
+            window,
+            max_size,
+            deadline: None,
+            urgent: false,
+        }
+    }
+
+    /// Sends the current batch as soon as possible, to be called after queueing a message that can't wait.
+    pub fn expedite(&mut self) {
+        self.urgent = true;
+    }
+
+    /// Whether the `queued` messages should be sent now.
+    pub fn poll_ready(&mut self, queued: usize, cx: &mut Context) -> Poll<()> {
+        if queued == 0 {
+            return Poll::Pending;
+        }
+
+        if self.urgent || queued >= self.max_size {
+            return Poll::Ready(());
+        }
+
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(self.window)));
+        ready!(deadline.poll_unpin(cx));
+
+        Poll::Ready(())
+    }
+
+    /// Starts a new batch, to be called once the queue has been written out.
+    pub fn reset(&mut self) {
+        self.deadline = None;
+        self.urgent = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future::poll_fn, time::Instant};
+
+    const WINDOW: Duration = Duration::from_millis(30);
+
+    #[tokio::test]
+    async fn waits_for_window_before_sending() {
+        let mut batch = Batch::new(WINDOW, MAX_SIZE);
+
+        let start = Instant::now();
+        poll_fn(|cx| batch.poll_ready(1, cx)).await;
+
+        assert!(start.elapsed() >= WINDOW);
+    }
+
+    #[tokio::test]
+    async fn sends_full_batch_immediately() {
+        let mut batch = Batch::new(WINDOW, MAX_SIZE);
+
+        let start = Instant::now();
+        poll_fn(|cx| batch.poll_ready(MAX_SIZE, cx)).await;
+
+        assert!(start.elapsed() < WINDOW);
+    }
+
+    #[tokio::test]
+    async fn sends_expedited_batch_immediately() {
+        let mut batch = Batch::new(WINDOW, MAX_SIZE);
+        batch.expedite();
+
+        let start = Instant::now();
+        poll_fn(|cx| batch.poll_ready(2, cx)).await;
+
+        assert!(start.elapsed() < WINDOW);
+    }
+
+    #[tokio::test]
+    async fn reset_starts_new_window() {
+        let mut batch = Batch::new(WINDOW, MAX_SIZE);
+        poll_fn(|cx| batch.poll_ready(1, cx)).await;
+
+        batch.reset();
+
+        let start = Instant::now();
+        poll_fn(|cx| batch.poll_ready(1, cx)).await;
+
+        assert!(start.elapsed() >= WINDOW);
+    }
+}
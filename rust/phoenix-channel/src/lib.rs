@@ -1,7 +1,9 @@
+mod batch;
 mod heartbeat;
 mod login_url;
+mod topic;
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
@@ -11,6 +13,8 @@ use std::{io, mem};
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
 use base64::Engine;
+use batch::Batch;
+use futures::channel::mpsc;
 use futures::future::BoxFuture;
 use futures::{FutureExt, SinkExt, StreamExt};
 use heartbeat::{Heartbeat, MissedLastHeartbeat};
@@ -29,13 +33,17 @@ use tokio_tungstenite::{
 use url::{Host, Url};
 
 pub use login_url::{LoginUrl, LoginUrlError};
+pub use topic::{Topic, TopicEvent};
 
 pub struct PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes> {
     state: State,
     waker: Option<Waker>,
     pending_messages: VecDeque<String>,
+    batch: Batch,
     next_request_id: Arc<AtomicU64>,
     socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    /// Set by [`PhoenixChannel::reconnect`], so the next poll emits [`Event::Reconnecting`].
+    reconnect_started: bool,
 
     heartbeat: Heartbeat,
 
@@ -52,6 +60,15 @@ pub struct PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes> {
 
     login: &'static str,
     init_req: TInitReq,
+
+    /// The topics we joined via [`PhoenixChannel::join_topic`].
+    topics: HashMap<String, JoinedTopic>,
+}
+
+struct JoinedTopic {
+    /// Stored so we can join again after reconnecting.
+    payload: serde_json::Value,
+    events: mpsc::UnboundedSender<TopicEvent<serde_json::Value, serde_json::Value>>,
 }
 
 enum State {
@@ -190,6 +207,13 @@ impl fmt::Display for InternalError {
 pub struct OutboundRequestId(u64);
 
 impl OutboundRequestId {
+    /// For control planes other than [`PhoenixChannel`], which have to hand out their own IDs.
+    ///
+    /// They must still be strictly increasing.
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+
     // Should only be used for unit-testing.
     pub fn for_test(id: u64) -> Self {
         Self(id)
@@ -231,29 +255,48 @@ where
         reconnect_backoff: ExponentialBackoff,
         socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
     ) -> io::Result<Self> {
-        let next_request_id = Arc::new(AtomicU64::new(0));
-
         // Statically resolve the host in the URL to a set of addresses.
         // We don't use these directly because we need to connect to the domain via TLS which requires a hostname.
         // We expose them to other components that deal with DNS stuff to ensure our domain always resolves to these IPs.
-        let resolved_addresses = url
-            .expose_secret()
-            .inner()
-            .socket_addrs(|| None)?
-            .iter()
-            .map(|addr| addr.ip())
-            .collect();
+        let resolved_addresses = resolve(&url)?;
+
+        Ok(Self::connect_resolved(
+            url,
+            resolved_addresses,
+            user_agent,
+            login,
+            init_req,
+            reconnect_backoff,
+            socket_factory,
+        ))
+    }
+
+    /// Like [`PhoenixChannel::connect`], with the addresses of the URL's host already resolved, e.g. by [`lookup_host`].
+    ///
+    /// Doesn't block, so it can be called from within async code.
+    pub fn connect_resolved(
+        url: Secret<LoginUrl>,
+        resolved_addresses: Vec<IpAddr>,
+        user_agent: String,
+        login: &'static str,
+        init_req: TInitReq,
+        reconnect_backoff: ExponentialBackoff,
+        socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    ) -> Self {
+        let next_request_id = Arc::new(AtomicU64::new(0));
 
         tracing::debug!(host = %url.expose_secret().host(), %user_agent, "Connecting to portal");
 
-        Ok(Self {
+        Self {
             reconnect_backoff,
             url: url.clone(),
             user_agent: user_agent.clone(),
             state: State::connect(url, user_agent, socket_factory.clone()),
             socket_factory,
+            reconnect_started: false,
             waker: None,
             pending_messages: Default::default(),
+            batch: Batch::new(batch::WINDOW, batch::MAX_SIZE),
             _phantom: PhantomData,
             heartbeat: Heartbeat::new(
                 heartbeat::INTERVAL,
@@ -265,7 +308,8 @@ where
             login,
             init_req,
             resolved_addresses,
-        })
+            topics: HashMap::default(),
+        }
     }
 
     /// Returns the addresses that have been resolved for our server host.
@@ -273,6 +317,11 @@ where
         self.resolved_addresses.clone()
     }
 
+    /// The topic we join when logging in, and which we send our messages to.
+    pub fn login_topic(&self) -> &'static str {
+        self.login
+    }
+
     /// The host we are connecting / connected to.
     pub fn server_host(&self) -> &str {
         self.url.expose_secret().host()
@@ -284,10 +333,43 @@ where
     pub fn join(&mut self, topic: impl Into<String>, payload: impl Serialize) {
         let (request_id, msg) = self.make_message(topic, EgressControlMessage::PhxJoin(payload));
         self.pending_messages.push_front(msg); // Must send the join message before all others.
+        self.batch.expedite();
 
         self.pending_join_requests.insert(request_id);
     }
 
+    /// Joins another topic on the same websocket and returns a stream of its messages.
+    ///
+    /// The topic is joined again whenever we reconnect, the same as the login topic.
+    /// Joining a topic that we already joined replaces its previous [`Topic`].
+    pub fn join_topic<TTopicMsg, TTopicRes>(
+        &mut self,
+        topic: impl Into<String>,
+        payload: impl Serialize,
+    ) -> Topic<TTopicMsg, TTopicRes> {
+        let topic = topic.into();
+        debug_assert_ne!(topic, self.login, "The login topic is always joined");
+
+        let payload = serde_json::to_value(payload)
+            .expect("we should always be able to serialize a join payload");
+        let (tx, rx) = mpsc::unbounded();
+
+        // Otherwise, we join once connected.
+        if matches!(self.state, State::Connected(_)) {
+            self.join(topic.clone(), &payload);
+        }
+
+        self.topics.insert(
+            topic.clone(),
+            JoinedTopic {
+                payload,
+                events: tx,
+            },
+        );
+
+        Topic::new(topic, rx)
+    }
+
     /// Send a message to a topic.
     pub fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
         let (id, msg) = self.make_message(topic, message);
@@ -305,6 +387,7 @@ where
         let url = self.url.clone();
         let user_agent = self.user_agent.clone();
         self.state = State::connect(url, user_agent, self.socket_factory.clone());
+        self.reconnect_started = true;
 
         // 3. In case we were already re-connecting, we need to wake the suspended task.
         if let Some(waker) = self.waker.take() {
@@ -312,6 +395,19 @@ where
         }
     }
 
+    /// Reconnects to the portal using a new URL, e.g. to rotate the token.
+    ///
+    /// Unlike [`PhoenixChannel::connect`], this doesn't resolve the host itself.
+    /// It's called from within eventloops that may answer DNS queries themselves, so the caller must resolve it up-front, e.g. with [`lookup_host`].
+    /// Once connected, we re-join the login topic, so the portal sends us a fresh `init`.
+    pub fn set_url(&mut self, url: Secret<LoginUrl>, resolved_addresses: Vec<IpAddr>) {
+        tracing::debug!(host = %url.expose_secret().host(), "Switching portal URL");
+
+        self.resolved_addresses = resolved_addresses;
+        self.url = url;
+        self.reconnect();
+    }
+
     /// Initiate a graceful close of the connection.
     pub fn close(&mut self) -> Result<(), Connecting> {
         tracing::info!("Closing connection to portal");
@@ -331,6 +427,10 @@ where
         &mut self,
         cx: &mut Context,
     ) -> Poll<Result<Event<TInboundMsg, TOutboundRes>, Error>> {
+        if mem::take(&mut self.reconnect_started) {
+            return Poll::Ready(Ok(Event::Reconnecting));
+        }
+
         loop {
             // First, check if we are connected.
             let stream = match &mut self.state {
@@ -360,6 +460,8 @@ where
                         let host = self.url.expose_secret().host();
 
                         tracing::info!(%host, "Connected to portal");
+                        // `join` queues at the front, so this makes the login topic go first.
+                        self.rejoin_topics();
                         self.join(self.login, self.init_req.clone());
 
                         continue;
@@ -387,7 +489,7 @@ where
                                 .await
                         }));
 
-                        continue;
+                        return Poll::Ready(Ok(Event::Reconnecting));
                     }
                     Poll::Pending => {
                         // Save a waker in case we want to reset the `Connecting` state while we are waiting.
@@ -400,11 +502,26 @@ where
             // Priority 1: Keep local buffers small and send pending messages.
             match stream.poll_ready_unpin(cx) {
                 Poll::Ready(Ok(())) => {
-                    if let Some(message) = self.pending_messages.pop_front() {
+                    if self
+                        .batch
+                        .poll_ready(self.pending_messages.len(), cx)
+                        .is_ready()
+                    {
+                        let message = self
+                            .pending_messages
+                            .pop_front()
+                            .expect("batch is never ready without messages");
+
                         match stream.start_send_unpin(Message::Text(message.clone())) {
                             Ok(()) => {
                                 tracing::trace!(target: "wire::api::send", %message);
 
+                                // Only flush once we've written the entire batch.
+                                if !self.pending_messages.is_empty() {
+                                    continue;
+                                }
+                                self.batch.reset();
+
                                 match stream.poll_flush_unpin(cx) {
                                     Poll::Ready(Ok(())) => {
                                         tracing::trace!("Flushed websocket");
@@ -443,6 +560,13 @@ where
 
                     tracing::trace!(target: "wire::api::recv", %message);
 
+                    if let Some(topic) = self.joined_topic_of(&message) {
+                        match self.handle_topic_message(topic, &message) {
+                            Ok(()) => continue,
+                            Err(e) => return Poll::Ready(Err(e)),
+                        }
+                    }
+
                     let message = match serde_json::from_str::<
                         PhoenixMessage<TInboundMsg, TOutboundRes>,
                     >(&message)
@@ -548,6 +672,8 @@ where
                         EgressControlMessage::<()>::Heartbeat(Empty {}),
                         id.copy(),
                     ));
+                    // The portal must see our heartbeat within its timeout, so don't hold it back.
+                    self.batch.expedite();
 
                     return Poll::Ready(Ok(Event::HeartbeatSent));
                 }
@@ -562,6 +688,103 @@ where
         }
     }
 
+    fn rejoin_topics(&mut self) {
+        let topics = self
+            .topics
+            .iter()
+            .map(|(topic, joined)| (topic.clone(), joined.payload.clone()))
+            .collect::<Vec<_>>();
+
+        for (topic, payload) in topics {
+            self.join(topic, payload);
+        }
+    }
+
+    /// The topic of `message`, if it is one we joined via [`PhoenixChannel::join_topic`].
+    fn joined_topic_of(&self, message: &str) -> Option<String> {
+        #[derive(Deserialize)]
+        struct TopicOnly {
+            topic: String,
+        }
+
+        let TopicOnly { topic } = serde_json::from_str(message).ok()?;
+
+        self.topics.contains_key(&topic).then_some(topic)
+    }
+
+    fn handle_topic_message(&mut self, topic: String, message: &str) -> Result<(), Error> {
+        let message = match serde_json::from_str::<
+            PhoenixMessage<serde_json::Value, serde_json::Value>,
+        >(message)
+        {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::warn!(%topic, "Failed to deserialize message: {e}");
+                return Ok(());
+            }
+        };
+
+        let event = match (message.payload, message.reference) {
+            (Payload::Message(msg), _) => TopicEvent::InboundMessage(msg),
+            (Payload::Reply(_), None) => {
+                tracing::warn!(%topic, "Discarding reply because server omitted reference");
+                return Ok(());
+            }
+            (Payload::Reply(Reply::Error { reason }), Some(req_id)) => {
+                self.pending_join_requests.remove(&req_id);
+
+                TopicEvent::ErrorResponse {
+                    req_id,
+                    res: reason,
+                }
+            }
+            (Payload::Reply(Reply::Ok(reply)), Some(req_id)) => {
+                if self.pending_join_requests.remove(&req_id) {
+                    tracing::info!("Joined {topic} room on portal");
+
+                    TopicEvent::Joined
+                } else {
+                    let res = match reply {
+                        OkReply::Message(res) => res,
+                        OkReply::NoMessage(Empty {}) => serde_json::Value::Null,
+                    };
+
+                    TopicEvent::SuccessResponse { req_id, res }
+                }
+            }
+            (Payload::Error(Empty {}), _) => {
+                tracing::debug!(%topic, "Topic crashed on the server, joining again");
+
+                let payload = self.topics[&topic].payload.clone();
+                self.join(topic, payload);
+
+                return Ok(());
+            }
+            (Payload::Close(Empty {}), _) => {
+                if let Some(joined) = self.topics.remove(&topic) {
+                    let _ = joined.events.unbounded_send(TopicEvent::Closed);
+                }
+
+                return Ok(());
+            }
+            (
+                Payload::Disconnect {
+                    reason: DisconnectReason::TokenExpired,
+                },
+                _,
+            ) => return Err(Error::TokenExpired),
+        };
+
+        if self.topics[&topic].events.unbounded_send(event).is_err() {
+            tracing::debug!(%topic, "Leaving topic because its stream was dropped");
+
+            self.topics.remove(&topic);
+            self.send(topic, EgressControlMessage::<()>::PhxLeave(Empty {}));
+        }
+
+        Ok(())
+    }
+
     /// Sets the channels state to [`State::Connecting`] with the given error.
     ///
     /// The [`PhoenixChannel::poll`] function will handle the reconnect if appropriate for the given error.
@@ -613,6 +836,10 @@ pub enum Event<TInboundMsg, TOutboundRes> {
         topic: String,
         msg: TInboundMsg,
     },
+    /// We lost the connection to the portal, or dropped it to reconnect, and are connecting again.
+    ///
+    /// Once we're back, we emit [`Event::JoinedRoom`] for the login topic.
+    Reconnecting,
     /// The connection was closed successfully.
     Closed,
 }
@@ -755,10 +982,36 @@ fn make_request(url: Secret<LoginUrl>, user_agent: String) -> Request {
         .expect("building static request always works")
 }
 
+/// Resolves the host in the URL to a set of addresses without blocking, e.g. for [`PhoenixChannel::set_url`].
+pub async fn lookup_host(url: &LoginUrl) -> io::Result<Vec<IpAddr>> {
+    let url = url.inner();
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or_default();
+
+    Ok(tokio::net::lookup_host((host, port))
+        .await?
+        .map(|addr| addr.ip())
+        .collect())
+}
+
+/// Statically resolves the host in the URL to a set of addresses.
+fn resolve(url: &Secret<LoginUrl>) -> io::Result<Vec<IpAddr>> {
+    Ok(url
+        .expose_secret()
+        .inner()
+        .socket_addrs(|| None)?
+        .iter()
+        .map(|addr| addr.ip())
+        .collect())
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 enum EgressControlMessage<T> {
     PhxJoin(T),
+    PhxLeave(Empty),
     Heartbeat(Empty),
 }
 
@@ -785,6 +1038,184 @@ mod tests {
         Shout { hello: String },
     }
 
+    #[tokio::test]
+    async fn set_url_uses_addresses_from_caller() {
+        let url = |host: &str| {
+            Secret::new(
+                LoginUrl::client(
+                    format!("wss://{host}").as_str(),
+                    &secrecy::SecretString::from("token".to_owned()),
+                    "device".to_owned(),
+                    Some("name".to_owned()),
+                    [0; 32],
+                )
+                .unwrap(),
+            )
+        };
+        let mut channel = PhoenixChannel::<(), (), ()>::connect(
+            url("127.0.0.1"),
+            "test".to_owned(),
+            "client",
+            (),
+            ExponentialBackoff::default(),
+            Arc::new(socket_factory::tcp),
+        )
+        .unwrap();
+
+        let new_url = url("127.0.0.2");
+        let addresses = lookup_host(new_url.expose_secret()).await.unwrap();
+        channel.set_url(new_url, addresses);
+
+        assert_eq!(channel.server_host(), "127.0.0.2");
+        assert_eq!(
+            channel.resolved_addresses(),
+            vec![IpAddr::from([127, 0, 0, 2])]
+        );
+    }
+
+    #[tokio::test]
+    async fn can_reconnect_after_max_retries() {
+        let url = LoginUrl::client(
+            "ws://127.0.0.1:1",
+            &secrecy::SecretString::from("token".to_owned()),
+            "device".to_owned(),
+            Some("name".to_owned()),
+            [0; 32],
+        )
+        .unwrap();
+        let mut channel = PhoenixChannel::<(), (), ()>::connect(
+            Secret::new(url),
+            "test".to_owned(),
+            "client",
+            (),
+            ExponentialBackoff {
+                max_elapsed_time: Some(std::time::Duration::ZERO),
+                ..Default::default()
+            },
+            Arc::new(socket_factory::tcp),
+        )
+        .unwrap();
+
+        let error = future::poll_fn(|cx| channel.poll(cx)).await.unwrap_err();
+        assert!(matches!(error, Error::MaxRetriesReached));
+
+        // e.g. after a network change, this must not stay closed.
+        channel.reconnect();
+        let event = future::poll_fn(|cx| channel.poll(cx)).await.unwrap();
+        assert!(matches!(event, Event::Reconnecting));
+        let error = future::poll_fn(|cx| channel.poll(cx)).await.unwrap_err();
+        assert!(matches!(error, Error::MaxRetriesReached));
+    }
+
+    #[tokio::test]
+    async fn emits_reconnecting_as_soon_as_connecting_fails() {
+        let url = LoginUrl::client(
+            "ws://127.0.0.1:1",
+            &secrecy::SecretString::from("token".to_owned()),
+            "device".to_owned(),
+            Some("name".to_owned()),
+            [0; 32],
+        )
+        .unwrap();
+        let mut channel = PhoenixChannel::<(), (), ()>::connect(
+            Secret::new(url),
+            "test".to_owned(),
+            "client",
+            (),
+            ExponentialBackoff::default(),
+            Arc::new(socket_factory::tcp),
+        )
+        .unwrap();
+
+        // Long before the backoff expires
+        let event = future::poll_fn(|cx| channel.poll(cx)).await.unwrap();
+        assert!(matches!(event, Event::Reconnecting));
+    }
+
+    #[tokio::test]
+    async fn joined_topics_get_their_own_messages() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Acknowledges every join and greets each topic once it is joined.
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let msg = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                if msg["event"] != "phx_join" {
+                    continue;
+                }
+
+                let reply = serde_json::json!({
+                    "topic": msg["topic"],
+                    "event": "phx_reply",
+                    "payload": { "status": "ok", "response": {} },
+                    "ref": msg["ref"],
+                });
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+
+                let shout = serde_json::json!({
+                    "topic": msg["topic"],
+                    "event": "shout",
+                    "payload": { "hello": msg["topic"] },
+                    "ref": null,
+                });
+                ws.send(Message::Text(shout.to_string())).await.unwrap();
+            }
+        });
+
+        let url = LoginUrl::client(
+            format!("ws://127.0.0.1:{port}").as_str(),
+            &secrecy::SecretString::from("token".to_owned()),
+            "device".to_owned(),
+            Some("name".to_owned()),
+            [0; 32],
+        )
+        .unwrap();
+        let mut channel = PhoenixChannel::<(), Msg, ()>::connect(
+            Secret::new(url),
+            "test".to_owned(),
+            "client",
+            (),
+            ExponentialBackoff::default(),
+            Arc::new(socket_factory::tcp),
+        )
+        .unwrap();
+        let mut lobby = channel.join_topic::<Msg, ()>("room:lobby", ());
+
+        let mut login_msg = None;
+        let mut lobby_events = Vec::new();
+
+        while login_msg.is_none() || lobby_events.len() < 2 {
+            tokio::select! {
+                event = future::poll_fn(|cx| channel.poll(cx)) => {
+                    if let Event::InboundMessage { topic, msg } = event.unwrap() {
+                        assert_eq!(topic, "client");
+                        login_msg = Some(msg);
+                    }
+                }
+                Some(event) = lobby.next() => lobby_events.push(event),
+            }
+        }
+
+        assert_eq!(
+            login_msg,
+            Some(Msg::Shout {
+                hello: "client".to_owned()
+            })
+        );
+        assert!(matches!(lobby_events[0], TopicEvent::Joined));
+        assert!(matches!(
+            &lobby_events[1],
+            TopicEvent::InboundMessage(Msg::Shout { hello }) if hello == "room:lobby"
+        ));
+    }
+
     #[test]
     fn can_deserialize_inbound_message() {
         let msg = r#"{
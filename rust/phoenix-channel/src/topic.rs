@@ -0,0 +1,94 @@
+use crate::{ErrorReply, OutboundRequestId};
+use futures::{channel::mpsc, Stream, StreamExt as _};
+use serde::de::DeserializeOwned;
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// A topic joined via [`PhoenixChannel::join_topic`](crate::PhoenixChannel::join_topic), in addition to the login topic.
+///
+/// The [`PhoenixChannel`](crate::PhoenixChannel) must still be polled for this to make progress.
+/// Dropping it leaves the topic.
+pub struct Topic<TInboundMsg, TOutboundRes> {
+    name: String,
+    events: mpsc::UnboundedReceiver<TopicEvent<serde_json::Value, serde_json::Value>>,
+
+    _phantom: PhantomData<fn() -> (TInboundMsg, TOutboundRes)>,
+}
+
+#[derive(Debug)]
+pub enum TopicEvent<TInboundMsg, TOutboundRes> {
+    /// We (re-)joined the topic, e.g. after reconnecting.
+    Joined,
+    InboundMessage(TInboundMsg),
+    SuccessResponse {
+        req_id: OutboundRequestId,
+        res: TOutboundRes,
+    },
+    ErrorResponse {
+        req_id: OutboundRequestId,
+        res: ErrorReply,
+    },
+    /// The server closed the topic. The stream ends after this.
+    Closed,
+}
+
+impl<TInboundMsg, TOutboundRes> Topic<TInboundMsg, TOutboundRes> {
+    pub(crate) fn new(
+        name: String,
+        events: mpsc::UnboundedReceiver<TopicEvent<serde_json::Value, serde_json::Value>>,
+    ) -> Self {
+        Self {
+            name,
+            events,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The topic to pass to [`PhoenixChannel::send`](crate::PhoenixChannel::send).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<TInboundMsg, TOutboundRes> Stream for Topic<TInboundMsg, TOutboundRes>
+where
+    TInboundMsg: DeserializeOwned,
+    TOutboundRes: DeserializeOwned,
+{
+    type Item = TopicEvent<TInboundMsg, TOutboundRes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let Some(event) = ready!(self.events.poll_next_unpin(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            let event = match event {
+                TopicEvent::Joined => TopicEvent::Joined,
+                TopicEvent::InboundMessage(msg) => match serde_json::from_value(msg) {
+                    Ok(msg) => TopicEvent::InboundMessage(msg),
+                    Err(e) => {
+                        tracing::warn!(topic = %self.name, "Failed to deserialize message: {e}");
+                        continue;
+                    }
+                },
+                TopicEvent::SuccessResponse { req_id, res } => match serde_json::from_value(res) {
+                    Ok(res) => TopicEvent::SuccessResponse { req_id, res },
+                    Err(e) => {
+                        tracing::warn!(topic = %self.name, %req_id, "Failed to deserialize reply: {e}");
+                        continue;
+                    }
+                },
+                TopicEvent::ErrorResponse { req_id, res } => {
+                    TopicEvent::ErrorResponse { req_id, res }
+                }
+                TopicEvent::Closed => TopicEvent::Closed,
+            };
+
+            return Poll::Ready(Some(event));
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use firezone_bin_shared::http_health_check;
+use firezone_bin_shared::{http_health_check, otlp};
 use firezone_relay::sockets::Sockets;
 use firezone_relay::{
     sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack,
@@ -67,6 +67,8 @@ struct Args {
     rng_seed: Option<u64>,
 
     /// How to format the logs.
+    ///
+    /// `json` is the format that all our binaries share, see `firezone_logging::json`.
     #[arg(long, env, default_value = "human", hide = true)]
     log_format: LogFormat,
 
@@ -194,7 +196,7 @@ fn setup_tracing(args: &Args) -> Result<()> {
             .with(env_filter())
             .into(),
         Some(endpoint) => {
-            let metadata = make_otel_metadata();
+            let metadata = otlp::make_otel_metadata("relay");
             let grpc_endpoint = format!("http://{endpoint}");
 
             tracing::trace!(target: "relay", %grpc_endpoint, "Setting up OTLP exporter for collector");
@@ -249,7 +251,7 @@ fn setup_tracing(args: &Args) -> Result<()> {
 /// The user has a choice between:
 ///
 /// - human-centered formatting
-/// - JSON-formatting
+/// - JSON-formatting, the same as our other binaries
 /// - Google Cloud optimised formatting
 fn log_layer<T>(args: &Args) -> Box<dyn Layer<T> + Send + Sync>
 where
@@ -257,7 +259,7 @@ where
 {
     match (args.log_format, args.google_cloud_project_id.clone()) {
         (LogFormat::Human, _) => tracing_subscriber::fmt::layer().boxed(),
-        (LogFormat::Json, _) => tracing_subscriber::fmt::layer().json().boxed(),
+        (LogFormat::Json, _) => firezone_logging::json::layer(std::io::stdout).boxed(),
         (LogFormat::GoogleCloud, None) => {
             tracing::warn!(target: "relay", "Emitting logs in Google Cloud format but without the project ID set. Spans will be emitted without IDs!");
 
@@ -568,6 +570,9 @@ where
             Event::ErrorResponse { topic, req_id, res } => {
                 tracing::warn!(target: "relay", "Request with ID {req_id} on topic {topic} failed: {res:?}");
             }
+            Event::Reconnecting => {
+                tracing::debug!(target: "relay", "Reconnecting to portal");
+            }
             Event::HeartbeatSent => {
                 tracing::debug!(target: "relay", "Heartbeat sent to portal");
                 *self.last_heartbeat_sent.lock().unwrap() = Some(Instant::now());
@@ -614,29 +619,6 @@ fn is_healthy(last_heartbeat_sent: Arc<Mutex<Option<Instant>>>) -> bool {
     last_hearbeat_sent.elapsed() < MAX_PARTITION_TIME
 }
 
-fn make_otel_metadata() -> opentelemetry_sdk::Resource {
-    use opentelemetry::{Key, KeyValue};
-    use opentelemetry_sdk::resource::{EnvResourceDetector, TelemetryResourceDetector};
-    use opentelemetry_sdk::Resource;
-
-    const SERVICE_NAME: Key = Key::from_static_str("service.name");
-    const SERVICE_NAMESPACE: Key = Key::from_static_str("service.namespace");
-
-    let default_metadata = Resource::new([
-        KeyValue::new(SERVICE_NAMESPACE, "firezone"),
-        KeyValue::new(SERVICE_NAME, "relay"),
-    ]);
-    let detected_metadata = Resource::from_detectors(
-        Duration::ZERO,
-        vec![
-            Box::new(TelemetryResourceDetector),
-            Box::new(EnvResourceDetector::new()), // Allow overriding metadata using `OTEL_RESOURCE_ATTRIBUTES` env var.
-        ],
-    );
-
-    default_metadata.merge(&detected_metadata)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,6 +144,19 @@ pub struct DatagramOut<'a> {
     pub src: Option<SocketAddr>,
     pub dst: SocketAddr,
     pub packet: Cow<'a, [u8]>,
+    /// If set, `packet` contains several datagrams of this size that are sent using GSO (generic segmentation offload).
+    ///
+    /// Only the last datagram may be shorter.
+    pub segment_size: Option<usize>,
+}
+
+/// A datagram within the buffer passed to [`UdpSocket::send_batch`].
+#[derive(Debug, Clone, Copy)]
+pub struct BatchedDatagram {
+    pub src: Option<SocketAddr>,
+    pub dst: SocketAddr,
+    /// The length of the datagram, it starts right after the previous one.
+    pub len: usize,
 }
 
 impl<'a> DatagramOut<'a> {
@@ -152,6 +165,7 @@ impl<'a> DatagramOut<'a> {
             src: self.src,
             dst: self.dst,
             packet: Cow::Owned(self.packet.into_owned()),
+            segment_size: self.segment_size,
         }
     }
 }
@@ -233,7 +247,7 @@ impl UdpSocket {
     }
 
     pub fn send(&mut self, datagram: DatagramOut) -> io::Result<()> {
-        tracing::trace!(target: "wire::net::send", src = ?datagram.src, dst = %datagram.dst, num_bytes = %datagram.packet.len());
+        tracing::trace!(target: "wire::net::send", src = ?datagram.src, dst = %datagram.dst, num_bytes = %datagram.packet.len(), segment_size = ?datagram.segment_size);
 
         debug_assert!(
             self.buffered_datagrams.len() < 10_000,
@@ -252,6 +266,52 @@ impl UdpSocket {
         }
     }
 
+    /// Sends a batch of datagrams that are laid out back-to-back in `buffer`.
+    ///
+    /// Consecutive datagrams with the same source and destination are sent as a single GSO batch if the platform supports it.
+    /// The datagrams are sent straight out of `buffer`, thus callers can encrypt packets right into it without copying them again.
+    pub fn send_batch(
+        &mut self,
+        buffer: &[u8],
+        datagrams: impl IntoIterator<Item = BatchedDatagram>,
+    ) -> io::Result<()> {
+        let max_segments = self.state.max_gso_segments();
+        let mut datagrams = datagrams.into_iter().peekable();
+        let mut start = 0;
+
+        while let Some(first) = datagrams.next() {
+            let segment_size = first.len;
+            let mut end = start + first.len;
+            let mut num_segments = 1;
+
+            while num_segments < max_segments {
+                let Some(next) = datagrams
+                    .next_if(|d| d.src == first.src && d.dst == first.dst && d.len <= segment_size)
+                else {
+                    break;
+                };
+
+                end += next.len;
+                num_segments += 1;
+
+                if next.len < segment_size {
+                    break; // Only the last segment may be shorter.
+                }
+            }
+
+            self.send(DatagramOut {
+                src: first.src,
+                dst: first.dst,
+                packet: Cow::Borrowed(&buffer[start..end]),
+                segment_size: (num_segments > 1).then_some(segment_size),
+            })?;
+
+            start = end;
+        }
+
+        Ok(())
+    }
+
     pub fn try_send(&mut self, transmit: &DatagramOut) -> io::Result<()> {
         let destination = transmit.dst;
         let src_ip = transmit.src.map(|s| s.ip());
@@ -274,7 +334,7 @@ impl UdpSocket {
             destination,
             ecn: None,
             contents: &transmit.packet,
-            segment_size: None,
+            segment_size: transmit.segment_size,
             src_ip,
         };
 
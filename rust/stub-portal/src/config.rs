@@ -0,0 +1,238 @@
+//! The deployment that the stub portal pretends to manage
+//!
+//! e.g.
+//!
+//! ```yaml
+//! relay_token: relay
+//! upstream_dns: ["1.1.1.1:53"]
+//!
+//! sites:
+//!   - id: bf56f32d-7b2c-4f5d-a784-788977d014a4
+//!     name: Lab
+//!     token: gateway-lab
+//!
+//! clients:
+//!   - id: 3a25ff38-f8d7-47de-9b30-c7c40c206083
+//!     token: client-laptop
+//!
+//! resources:
+//!   - type: cidr
+//!     id: 73037362-715d-4a83-a749-f18eadd970e6
+//!     name: Lab network
+//!     address: 172.20.0.0/16
+//!     sites: [bf56f32d-7b2c-4f5d-a784-788977d014a4]
+//!   - type: dns
+//!     id: 03000143-e25e-45c7-aafb-144990e57dcd
+//!     name: Lab DNS
+//!     address: "*.lab.internal"
+//!     sites: [bf56f32d-7b2c-4f5d-a784-788977d014a4]
+//!     filters:
+//!       - protocol: tcp
+//!         port_range_start: 80
+//!         port_range_end: 443
+//!
+//! policies:
+//!   - resource: 73037362-715d-4a83-a749-f18eadd970e6
+//!     clients: [3a25ff38-f8d7-47de-9b30-c7c40c206083]
+//! ```
+//!
+//! Gateways log in with the token of their Site, Clients with their own token and Relays with `relay_token`.
+//! A Client only sees the Resources that a policy grants it.
+
+use crate::messages::Filter;
+use anyhow::{bail, Context as _, Result};
+use connlib_shared::messages::{client::SiteId, ClientId, ResourceId};
+use ip_network::IpNetwork;
+use serde::Deserialize;
+use std::{collections::BTreeSet, net::SocketAddr, path::Path};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub relay_token: String,
+    #[serde(default)]
+    pub upstream_dns: Vec<SocketAddr>,
+    #[serde(default)]
+    pub sites: Vec<Site>,
+    #[serde(default)]
+    pub clients: Vec<Client>,
+    #[serde(default)]
+    pub resources: Vec<Resource>,
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    pub id: SiteId,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub id: ClientId,
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Resource {
+    Cidr {
+        id: ResourceId,
+        name: String,
+        address: IpNetwork,
+        #[serde(default)]
+        address_description: Option<String>,
+        sites: Vec<SiteId>,
+        #[serde(default)]
+        filters: Vec<Filter>,
+    },
+    Dns {
+        id: ResourceId,
+        name: String,
+        address: String,
+        #[serde(default)]
+        address_description: Option<String>,
+        sites: Vec<SiteId>,
+        #[serde(default)]
+        filters: Vec<Filter>,
+    },
+    Internet {
+        id: ResourceId,
+        sites: Vec<SiteId>,
+    },
+}
+
+impl Resource {
+    pub fn id(&self) -> ResourceId {
+        match self {
+            Resource::Cidr { id, .. }
+            | Resource::Dns { id, .. }
+            | Resource::Internet { id, .. } => *id,
+        }
+    }
+
+    pub fn sites(&self) -> &[SiteId] {
+        match self {
+            Resource::Cidr { sites, .. }
+            | Resource::Dns { sites, .. }
+            | Resource::Internet { sites, .. } => sites,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub resource: ResourceId,
+    pub clients: Vec<ClientId>,
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read `{}`", path.display()))?;
+        let config = serde_yaml::from_str::<Self>(&s)
+            .with_context(|| format!("Couldn't parse `{}`", path.display()))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Catches typos in IDs, which would otherwise just look like a Resource going missing
+    fn validate(&self) -> Result<()> {
+        let site_ids = self.sites.iter().map(|s| s.id).collect::<BTreeSet<_>>();
+        let client_ids = self.clients.iter().map(|c| c.id).collect::<BTreeSet<_>>();
+        let resource_ids = self
+            .resources
+            .iter()
+            .map(|r| r.id())
+            .collect::<BTreeSet<_>>();
+
+        if site_ids.len() != self.sites.len()
+            || client_ids.len() != self.clients.len()
+            || resource_ids.len() != self.resources.len()
+        {
+            bail!("Sites, Clients and Resources must have unique IDs");
+        }
+
+        let mut tokens = BTreeSet::from([self.relay_token.as_str()]);
+        for token in self
+            .sites
+            .iter()
+            .map(|s| s.token.as_str())
+            .chain(self.clients.iter().map(|c| c.token.as_str()))
+        {
+            if !tokens.insert(token) {
+                bail!("Token `{token}` is used more than once");
+            }
+        }
+
+        for resource in &self.resources {
+            if let Some(site) = resource.sites().iter().find(|s| !site_ids.contains(s)) {
+                bail!("Resource {} is in unknown Site {site}", resource.id());
+            }
+        }
+
+        for policy in &self.policies {
+            if !resource_ids.contains(&policy.resource) {
+                bail!("Policy for unknown Resource {}", policy.resource);
+            }
+            if let Some(client) = policy.clients.iter().find(|c| !client_ids.contains(c)) {
+                bail!(
+                    "Policy for Resource {} names unknown Client {client}",
+                    policy.resource
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_is_valid() {
+        let example = include_str!("config.rs")
+            .lines()
+            .skip_while(|line| *line != "//! ```yaml")
+            .skip(1)
+            .take_while(|line| *line != "//! ```")
+            .map(|line| {
+                line.strip_prefix("//!")
+                    .unwrap()
+                    .strip_prefix(' ')
+                    .unwrap_or("")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let config = serde_yaml::from_str::<Config>(&example).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.resources.len(), 2);
+        assert_eq!(config.policies.len(), 1);
+    }
+
+    #[test]
+    fn unknown_site_is_an_error() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+relay_token: relay
+resources:
+  - type: internet
+    id: 73037362-715d-4a83-a749-f18eadd970e6
+    sites: [bf56f32d-7b2c-4f5d-a784-788977d014a4]
+"#,
+        )
+        .unwrap();
+
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! A stand-in for the portal, so Clients, Gateways and Relays can run without internet access
+//!
+//! It speaks the same websocket protocol as the portal and serves the deployment from a YAML file,
+//! see [`config`] for the format.
+//! Point the other components at it with e.g. `FIREZONE_API_URL=ws://127.0.0.1:8081`.
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use firezone_logging::LogFormat;
+use futures::{SinkExt as _, StreamExt as _};
+use portal::{ConnectionId, LoginError, Portal};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+use tracing_subscriber::layer;
+use url::Url;
+
+mod config;
+mod messages;
+mod portal;
+
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The YAML file with the Sites, Clients, Resources and policies to serve.
+    #[arg(long, env = "FIREZONE_STUB_PORTAL_CONFIG")]
+    config: PathBuf,
+    /// Where to listen for websocket connections.
+    #[arg(
+        long,
+        env = "FIREZONE_STUB_PORTAL_LISTEN_ADDR",
+        default_value = "127.0.0.1:8081"
+    )]
+    listen_addr: SocketAddr,
+}
+
+/// The portal and how to reach each of its connections.
+struct Shared {
+    portal: Portal,
+    senders: HashMap<ConnectionId, mpsc::UnboundedSender<String>>,
+}
+
+impl Shared {
+    fn flush(&mut self) {
+        while let Some((conn, msg)) = self.portal.poll_transmit() {
+            let Some(sender) = self.senders.get(&conn) else {
+                tracing::debug!(%conn, "Dropping message for closed connection");
+                continue;
+            };
+
+            let _ = sender.send(msg);
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    firezone_logging::setup_global_subscriber(LogFormat::Human, layer::Identity::new());
+
+    let cli = Cli::parse();
+    let config = config::Config::read(&cli.config)?;
+
+    let listener = tokio::net::TcpListener::bind(cli.listen_addr)
+        .await
+        .with_context(|| format!("Couldn't listen on {}", cli.listen_addr))?;
+    tracing::info!(addr = %cli.listen_addr, "Listening for websocket connections");
+
+    let shared = Arc::new(Mutex::new(Shared {
+        portal: Portal::new(config),
+        senders: HashMap::default(),
+    }));
+    let mut next_conn = 0;
+
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+
+        let conn = ConnectionId(next_conn);
+        next_conn += 1;
+
+        tokio::spawn({
+            let shared = shared.clone();
+
+            async move {
+                if let Err(e) = handle_connection(&shared, conn, stream, remote).await {
+                    tracing::debug!(%conn, %remote, "Connection failed: {e:#}");
+                }
+
+                let mut shared = shared.lock().expect("never poisoned");
+                shared.senders.remove(&conn);
+                shared.portal.handle_disconnect(conn);
+                shared.flush();
+            }
+        });
+    }
+}
+
+#[allow(clippy::result_large_err)] // The handshake callback has to return tungstenite's `ErrorResponse`.
+async fn handle_connection(
+    shared: &Mutex<Shared>,
+    conn: ConnectionId,
+    stream: TcpStream,
+    remote: SocketAddr,
+) -> Result<()> {
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res: Response| {
+        let url = Url::parse(&format!("ws://stub-portal{}", req.uri()))
+            .map_err(|e| error_response(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+        shared
+            .lock()
+            .expect("never poisoned")
+            .portal
+            .login(conn, &url, remote.ip())
+            .map_err(|e| {
+                tracing::info!(%conn, %remote, "Rejecting login: {e}");
+
+                let status = match e {
+                    LoginError::UnknownPath(_) => StatusCode::NOT_FOUND,
+                    LoginError::InvalidToken => StatusCode::UNAUTHORIZED,
+                    LoginError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+                };
+
+                error_response(status, e.to_string())
+            })?;
+
+        Ok(res)
+    })
+    .await
+    .context("Websocket handshake failed")?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    shared
+        .lock()
+        .expect("never poisoned")
+        .senders
+        .insert(conn, tx);
+
+    let (mut sink, mut stream) = websocket.split();
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let mut shared = shared.lock().expect("never poisoned");
+                    shared.portal.handle_message(conn, &text);
+                    shared.flush();
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            Some(msg) = rx.recv() => sink.send(Message::Text(msg)).await?,
+        }
+    }
+}
+
+fn error_response(status: StatusCode, body: String) -> ErrorResponse {
+    let mut res = ErrorResponse::new(Some(body));
+    *res.status_mut() = status;
+
+    res
+}
@@ -0,0 +1,253 @@
+//! The portal's side of the websocket protocol.
+//!
+//! The types in `connlib` and the Gateway only (de)serialize the direction they need,
+//! so we mirror the ones we have to send here.
+
+use chrono::{serde::ts_seconds, DateTime, Utc};
+use connlib_shared::messages::{
+    client::{self, SiteId},
+    ClientId, ClientPayload, GatewayId, GatewayResponse, Interface, Key, Peer, RelayId,
+    RequestConnection, ResolveRequest, ResourceId, ReuseConnection,
+};
+use ip_network::IpNetwork;
+use phoenix_channel::ErrorReply;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// A message from one of our websockets.
+#[derive(Debug, Deserialize)]
+pub struct Inbound<T> {
+    pub topic: String,
+    #[serde(flatten)]
+    pub payload: InboundPayload<T>,
+    /// We echo this back in our reply, whatever it is.
+    #[serde(rename = "ref")]
+    pub reference: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum InboundPayload<T> {
+    PhxJoin(serde_json::Value),
+    Heartbeat {},
+    #[serde(untagged)]
+    Message(T),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum ClientMessage {
+    PrepareConnection {
+        resource_id: ResourceId,
+        connected_gateway_ids: BTreeSet<GatewayId>,
+    },
+    RequestConnection(RequestConnection),
+    ReuseConnection(ReuseConnection),
+    BroadcastIceCandidates(GatewaysIceCandidates),
+    BroadcastInvalidatedIceCandidates(GatewaysIceCandidates),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GatewaysIceCandidates {
+    pub gateway_ids: Vec<GatewayId>,
+    pub candidates: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum GatewayMessage {
+    ConnectionReady {
+        #[serde(rename = "ref")]
+        reference: String,
+        gateway_payload: GatewayResponse,
+    },
+    BroadcastIceCandidates(ClientsIceCandidates),
+    BroadcastInvalidatedIceCandidates(ClientsIceCandidates),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientsIceCandidates {
+    pub client_ids: Vec<ClientId>,
+    pub candidates: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelayJoin {
+    pub stamp_secret: String,
+}
+
+/// A message to one of our websockets.
+#[derive(Debug, Serialize)]
+pub struct Outbound<'a> {
+    pub topic: &'a str,
+    #[serde(flatten)]
+    pub payload: Egress,
+    #[serde(rename = "ref")]
+    pub reference: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "event", content = "payload")]
+pub enum Egress {
+    PhxReply(Reply),
+    Init(Init),
+    RelaysPresence(RelaysPresence),
+    IceCandidates(IceCandidates),
+    InvalidateIceCandidates(IceCandidates),
+    RequestConnection(GatewayRequestConnection),
+    AllowAccess(AllowAccess),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "response")]
+pub enum Reply {
+    Ok(OkReply),
+    Error { reason: ErrorReply },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum OkReply {
+    Empty {},
+    ConnectionDetails {
+        resource_id: ResourceId,
+        gateway_id: GatewayId,
+        gateway_remote_ip: IpAddr,
+        gateway_group_id: SiteId,
+    },
+    Connect {
+        gateway_payload: GatewayResponse,
+        resource_id: ResourceId,
+        gateway_public_key: Key,
+        persistent_keepalive: u64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Init {
+    Client {
+        interface: Interface,
+        resources: Vec<client::ResourceDescription>,
+        relays: Vec<Relay>,
+    },
+    Gateway {
+        interface: Interface,
+        config: GatewayConfig,
+        relays: Vec<Relay>,
+    },
+    Relay {},
+}
+
+#[derive(Debug, Serialize)]
+pub struct GatewayConfig {
+    pub ipv4_masquerade_enabled: bool,
+    pub ipv6_masquerade_enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Relay {
+    Turn {
+        id: RelayId,
+        #[serde(with = "ts_seconds")]
+        expires_at: DateTime<Utc>,
+        addr: SocketAddr,
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelaysPresence {
+    pub disconnected_ids: Vec<RelayId>,
+    pub connected: Vec<Relay>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IceCandidates {
+    #[serde(flatten)]
+    pub sender: Sender,
+    pub candidates: Vec<String>,
+}
+
+/// Clients learn which Gateway sent the candidates and vice versa.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sender {
+    GatewayId(GatewayId),
+    ClientId(ClientId),
+}
+
+#[derive(Debug, Serialize)]
+pub struct GatewayRequestConnection {
+    pub resource: GatewayResource,
+    pub client: GatewayClient,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GatewayClient {
+    pub id: ClientId,
+    pub payload: ClientPayload,
+    pub peer: Peer,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllowAccess {
+    pub client_id: ClientId,
+    pub resource: GatewayResource,
+    pub expires_at: Option<i64>,
+    pub payload: Option<ResolveRequest>,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub client_ipv4: Ipv4Addr,
+    pub client_ipv6: Ipv6Addr,
+}
+
+/// A Resource the way Gateways see it.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GatewayResource {
+    Dns {
+        id: ResourceId,
+        address: String,
+        name: String,
+        filters: Vec<Filter>,
+    },
+    Cidr {
+        id: ResourceId,
+        address: IpNetwork,
+        name: String,
+        filters: Vec<Filter>,
+    },
+    Internet {
+        id: ResourceId,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum Filter {
+    Udp(PortRange),
+    Tcp(PortRange),
+    Icmp,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    #[serde(default)]
+    pub port_range_start: u16,
+    #[serde(default = "max_port")]
+    pub port_range_end: u16,
+}
+
+fn max_port() -> u16 {
+    u16::MAX
+}
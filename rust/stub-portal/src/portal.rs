@@ -0,0 +1,1147 @@
+//! What the portal does for Clients, Gateways and Relays, without any IO.
+//!
+//! Connections log in during the websocket handshake and are online once they joined their topic.
+
+use crate::{
+    config::{Config, Resource},
+    messages::{
+        AllowAccess, ClientMessage, ClientsIceCandidates, Egress, GatewayClient, GatewayConfig,
+        GatewayMessage, GatewayRequestConnection, GatewayResource, GatewaysIceCandidates,
+        IceCandidates, Inbound, InboundPayload, Init, OkReply, Relay, RelayJoin, RelaysPresence,
+        Reply, Sender,
+    },
+};
+use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{
+    client::{self, SiteId},
+    ClientId, DnsServer, GatewayId, Interface, Key, Peer, RelayId, RequestConnection, ResourceId,
+    ReuseConnection,
+};
+use phoenix_channel::ErrorReply;
+use rand::{distributions::Alphanumeric, Rng as _};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Digest as _;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use url::Url;
+use uuid::Uuid;
+
+/// How often the Gateways expect WireGuard keepalives, the same as the portal tells us.
+const PERSISTENT_KEEPALIVE: u64 = 25;
+
+/// How long the TURN credentials that we hand out are valid.
+const RELAY_CREDENTIALS_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+const IPV4_TUNNEL: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 0);
+const IPV6_TUNNEL: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Conn-{}", self.0)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoginError {
+    #[error("unknown path `{0}`")]
+    UnknownPath(String),
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("missing or invalid query parameter `{0}`")]
+    InvalidParameter(&'static str),
+}
+
+pub struct Portal {
+    config: Config,
+
+    /// Everyone who passed the websocket handshake.
+    logins: HashMap<ConnectionId, Login>,
+
+    /// Everyone who joined their topic.
+    clients: BTreeMap<ClientId, ConnectionId>,
+    gateways: BTreeMap<GatewayId, OnlineGateway>,
+    relays: BTreeMap<ConnectionId, OnlineRelay>,
+
+    /// Tunnel addresses stay the same across reconnects, like with the real portal.
+    tunnel_addresses: BTreeMap<TunnelPeer, (Ipv4Addr, Ipv6Addr)>,
+
+    /// Connection requests we forwarded to a Gateway, by the `ref` we gave it.
+    pending_connections: HashMap<String, PendingConnection>,
+
+    transmits: VecDeque<(ConnectionId, String)>,
+}
+
+#[derive(Debug, Clone)]
+enum Login {
+    Client {
+        id: ClientId,
+        public_key: Key,
+    },
+    Gateway {
+        id: GatewayId,
+        site: SiteId,
+        public_key: Key,
+        remote_ip: IpAddr,
+    },
+    Relay {
+        addrs: Vec<SocketAddr>,
+    },
+}
+
+struct OnlineGateway {
+    conn: ConnectionId,
+    site: SiteId,
+    public_key: Key,
+    remote_ip: IpAddr,
+}
+
+struct OnlineRelay {
+    id: RelayId,
+    addrs: Vec<SocketAddr>,
+    stamp_secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TunnelPeer {
+    Client(ClientId),
+    Gateway(GatewayId),
+}
+
+struct PendingConnection {
+    client: ClientId,
+    /// The `ref` of the Client's request, which we reply to once the Gateway is ready.
+    client_ref: Option<serde_json::Value>,
+    resource_id: ResourceId,
+    gateway_id: GatewayId,
+}
+
+impl Portal {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            logins: HashMap::default(),
+            clients: BTreeMap::default(),
+            gateways: BTreeMap::default(),
+            relays: BTreeMap::default(),
+            tunnel_addresses: BTreeMap::default(),
+            pending_connections: HashMap::default(),
+            transmits: VecDeque::default(),
+        }
+    }
+
+    /// Authenticates a websocket from the URL it connected to, e.g. `/client/websocket?token=...`.
+    pub fn login(
+        &mut self,
+        conn: ConnectionId,
+        url: &Url,
+        remote_ip: IpAddr,
+    ) -> Result<(), LoginError> {
+        let query = url.query_pairs().collect::<HashMap<_, _>>();
+        let param = |name: &'static str| {
+            query
+                .get(name)
+                .map(|v| v.as_ref())
+                .ok_or(LoginError::InvalidParameter(name))
+        };
+        let token = param("token")?;
+
+        let login = match url.path() {
+            "/client/websocket" => {
+                let client = self
+                    .config
+                    .clients
+                    .iter()
+                    .find(|c| c.token == token)
+                    .ok_or(LoginError::InvalidToken)?;
+
+                Login::Client {
+                    id: client.id,
+                    public_key: public_key(param("public_key")?)?,
+                }
+            }
+            "/gateway/websocket" => {
+                let site = self
+                    .config
+                    .sites
+                    .iter()
+                    .find(|s| s.token == token)
+                    .ok_or(LoginError::InvalidToken)?;
+
+                Login::Gateway {
+                    id: gateway_id(param("external_id")?)?,
+                    site: site.id,
+                    public_key: public_key(param("public_key")?)?,
+                    remote_ip,
+                }
+            }
+            "/relay/websocket" => {
+                if token != self.config.relay_token {
+                    return Err(LoginError::InvalidToken);
+                }
+
+                let port = param("port")?
+                    .parse::<u16>()
+                    .map_err(|_| LoginError::InvalidParameter("port"))?;
+                let ipv4 = param("ipv4")
+                    .ok()
+                    .map(|ip| ip.parse::<Ipv4Addr>().map(IpAddr::from))
+                    .transpose()
+                    .map_err(|_| LoginError::InvalidParameter("ipv4"))?;
+                let ipv6 = param("ipv6")
+                    .ok()
+                    .map(|ip| ip.parse::<Ipv6Addr>().map(IpAddr::from))
+                    .transpose()
+                    .map_err(|_| LoginError::InvalidParameter("ipv6"))?;
+                let addrs = ipv4
+                    .into_iter()
+                    .chain(ipv6)
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect::<Vec<_>>();
+
+                if addrs.is_empty() {
+                    return Err(LoginError::InvalidParameter("ipv4"));
+                }
+
+                Login::Relay { addrs }
+            }
+            other => return Err(LoginError::UnknownPath(other.to_owned())),
+        };
+
+        tracing::info!(%conn, ?login, "Logged in");
+        self.logins.insert(conn, login);
+
+        Ok(())
+    }
+
+    pub fn handle_message(&mut self, conn: ConnectionId, text: &str) {
+        let Some(login) = self.logins.get(&conn).cloned() else {
+            tracing::warn!(%conn, "Message from a connection that isn't logged in");
+            return;
+        };
+
+        tracing::trace!(target: "wire::api::recv", %conn, %text);
+
+        match login {
+            Login::Client { id, public_key } => {
+                let Some(msg) = self.parse(conn, "client", text) else {
+                    return;
+                };
+                self.handle_client_message(conn, id, public_key, msg);
+            }
+            Login::Gateway {
+                id,
+                site,
+                public_key,
+                remote_ip,
+            } => {
+                let Some(msg) = self.parse(conn, "gateway", text) else {
+                    return;
+                };
+                self.handle_gateway_message(
+                    conn,
+                    id,
+                    OnlineGateway {
+                        conn,
+                        site,
+                        public_key,
+                        remote_ip,
+                    },
+                    msg,
+                );
+            }
+            Login::Relay { addrs } => {
+                let Some(msg) = self.parse::<serde_json::Value>(conn, "relay", text) else {
+                    return;
+                };
+                self.handle_relay_message(conn, addrs, msg);
+            }
+        }
+    }
+
+    pub fn handle_disconnect(&mut self, conn: ConnectionId) {
+        let Some(login) = self.logins.remove(&conn) else {
+            return;
+        };
+
+        tracing::info!(%conn, ?login, "Disconnected");
+
+        match login {
+            Login::Client { id, .. } => {
+                if self.clients.get(&id) == Some(&conn) {
+                    self.clients.remove(&id);
+                }
+                self.pending_connections.retain(|_, p| p.client != id);
+            }
+            Login::Gateway { id, .. } => {
+                if self.gateways.get(&id).map(|g| g.conn) != Some(conn) {
+                    return;
+                }
+                self.gateways.remove(&id);
+
+                let failed = self
+                    .pending_connections
+                    .iter()
+                    .filter(|(_, p)| p.gateway_id == id)
+                    .map(|(reference, _)| reference.clone())
+                    .collect::<Vec<_>>();
+                for reference in failed {
+                    let Some(pending) = self.pending_connections.remove(&reference) else {
+                        continue;
+                    };
+                    if let Some(&client_conn) = self.clients.get(&pending.client) {
+                        self.reply(
+                            client_conn,
+                            "client",
+                            pending.client_ref,
+                            Err(ErrorReply::Offline),
+                        );
+                    }
+                }
+            }
+            Login::Relay { .. } => {
+                let Some(relay) = self.relays.remove(&conn) else {
+                    return;
+                };
+
+                self.broadcast_relays_presence(RelaysPresence {
+                    disconnected_ids: vec![relay.id],
+                    connected: Vec::new(),
+                });
+            }
+        }
+    }
+
+    /// The next message to send and the connection to send it on.
+    pub fn poll_transmit(&mut self) -> Option<(ConnectionId, String)> {
+        self.transmits.pop_front()
+    }
+
+    fn handle_client_message(
+        &mut self,
+        conn: ConnectionId,
+        client: ClientId,
+        public_key: Key,
+        msg: Inbound<ClientMessage>,
+    ) {
+        match msg.payload {
+            InboundPayload::PhxJoin(_) => {
+                self.reply(conn, "client", msg.reference, Ok(OkReply::Empty {}));
+                self.clients.insert(client, conn);
+
+                let (ipv4, ipv6) = self.tunnel_addresses(TunnelPeer::Client(client));
+                let init = Init::Client {
+                    interface: Interface {
+                        ipv4,
+                        ipv6,
+                        upstream_dns: self
+                            .config
+                            .upstream_dns
+                            .iter()
+                            .copied()
+                            .map(DnsServer::from)
+                            .collect(),
+                    },
+                    resources: self
+                        .config
+                        .resources
+                        .iter()
+                        .filter(|r| self.is_allowed(client, r.id()))
+                        .map(|r| self.client_resource(r))
+                        .collect(),
+                    relays: self.relays.values().flat_map(turn_credentials).collect(),
+                };
+                self.push(conn, "client", Egress::Init(init), None);
+            }
+            InboundPayload::Heartbeat {} => {
+                self.reply(conn, "phoenix", msg.reference, Ok(OkReply::Empty {}));
+            }
+            InboundPayload::Message(ClientMessage::PrepareConnection {
+                resource_id,
+                connected_gateway_ids,
+            }) => {
+                let res = self.connection_details(client, resource_id, &connected_gateway_ids);
+                self.reply(conn, "client", msg.reference, res);
+            }
+            InboundPayload::Message(ClientMessage::RequestConnection(RequestConnection {
+                gateway_id,
+                resource_id,
+                client_preshared_key,
+                client_payload,
+            })) => {
+                let gateway = match self.authorized_gateway(client, resource_id, gateway_id) {
+                    Ok(gateway) => gateway,
+                    Err(e) => {
+                        self.reply(conn, "client", msg.reference, Err(e));
+                        return;
+                    }
+                };
+                let (ipv4, ipv6) = self.tunnel_addresses(TunnelPeer::Client(client));
+                let reference = self.add_pending_connection(PendingConnection {
+                    client,
+                    client_ref: msg.reference,
+                    resource_id,
+                    gateway_id,
+                });
+
+                let request = GatewayRequestConnection {
+                    resource: self.gateway_resource(resource_id),
+                    client: GatewayClient {
+                        id: client,
+                        payload: client_payload,
+                        peer: Peer {
+                            persistent_keepalive: Some(PERSISTENT_KEEPALIVE as u16),
+                            public_key,
+                            ipv4,
+                            ipv6,
+                            preshared_key: client_preshared_key,
+                        },
+                    },
+                    reference,
+                    expires_at: None,
+                };
+                self.push(gateway, "gateway", Egress::RequestConnection(request), None);
+            }
+            InboundPayload::Message(ClientMessage::ReuseConnection(ReuseConnection {
+                resource_id,
+                gateway_id,
+                payload,
+            })) => {
+                let gateway = match self.authorized_gateway(client, resource_id, gateway_id) {
+                    Ok(gateway) => gateway,
+                    Err(e) => {
+                        self.reply(conn, "client", msg.reference, Err(e));
+                        return;
+                    }
+                };
+                let (client_ipv4, client_ipv6) = self.tunnel_addresses(TunnelPeer::Client(client));
+
+                // Gateways only confirm access to DNS resources.
+                let reference = if payload.is_some() {
+                    self.add_pending_connection(PendingConnection {
+                        client,
+                        client_ref: msg.reference,
+                        resource_id,
+                        gateway_id,
+                    })
+                } else {
+                    self.reply(conn, "client", msg.reference, Ok(OkReply::Empty {}));
+
+                    Uuid::new_v4().to_string()
+                };
+
+                let allow = AllowAccess {
+                    client_id: client,
+                    resource: self.gateway_resource(resource_id),
+                    expires_at: None,
+                    payload,
+                    reference,
+                    client_ipv4,
+                    client_ipv6,
+                };
+                self.push(gateway, "gateway", Egress::AllowAccess(allow), None);
+            }
+            InboundPayload::Message(ClientMessage::BroadcastIceCandidates(
+                GatewaysIceCandidates {
+                    gateway_ids,
+                    candidates,
+                },
+            )) => {
+                for gateway in gateway_ids {
+                    let Some(gateway) = self.gateways.get(&gateway) else {
+                        continue;
+                    };
+
+                    self.push(
+                        gateway.conn,
+                        "gateway",
+                        Egress::IceCandidates(IceCandidates {
+                            sender: Sender::ClientId(client),
+                            candidates: candidates.clone(),
+                        }),
+                        None,
+                    );
+                }
+            }
+            InboundPayload::Message(ClientMessage::BroadcastInvalidatedIceCandidates(
+                GatewaysIceCandidates {
+                    gateway_ids,
+                    candidates,
+                },
+            )) => {
+                for gateway in gateway_ids {
+                    let Some(gateway) = self.gateways.get(&gateway) else {
+                        continue;
+                    };
+
+                    self.push(
+                        gateway.conn,
+                        "gateway",
+                        Egress::InvalidateIceCandidates(IceCandidates {
+                            sender: Sender::ClientId(client),
+                            candidates: candidates.clone(),
+                        }),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    fn handle_gateway_message(
+        &mut self,
+        conn: ConnectionId,
+        gateway: GatewayId,
+        online: OnlineGateway,
+        msg: Inbound<GatewayMessage>,
+    ) {
+        match msg.payload {
+            InboundPayload::PhxJoin(_) => {
+                self.reply(conn, "gateway", msg.reference, Ok(OkReply::Empty {}));
+                self.gateways.insert(gateway, online);
+
+                let (ipv4, ipv6) = self.tunnel_addresses(TunnelPeer::Gateway(gateway));
+                let init = Init::Gateway {
+                    interface: Interface {
+                        ipv4,
+                        ipv6,
+                        upstream_dns: Vec::new(),
+                    },
+                    config: GatewayConfig {
+                        ipv4_masquerade_enabled: true,
+                        ipv6_masquerade_enabled: true,
+                    },
+                    relays: self.relays.values().flat_map(turn_credentials).collect(),
+                };
+                self.push(conn, "gateway", Egress::Init(init), None);
+            }
+            InboundPayload::Heartbeat {} => {
+                self.reply(conn, "phoenix", msg.reference, Ok(OkReply::Empty {}));
+            }
+            InboundPayload::Message(GatewayMessage::ConnectionReady {
+                reference,
+                gateway_payload,
+            }) => {
+                self.reply(conn, "gateway", msg.reference, Ok(OkReply::Empty {}));
+
+                let Some(pending) = self.pending_connections.remove(&reference) else {
+                    tracing::debug!(%gateway, %reference, "Unknown or expired connection request");
+                    return;
+                };
+                let Some(&client_conn) = self.clients.get(&pending.client) else {
+                    return;
+                };
+
+                self.reply(
+                    client_conn,
+                    "client",
+                    pending.client_ref,
+                    Ok(OkReply::Connect {
+                        gateway_payload,
+                        resource_id: pending.resource_id,
+                        gateway_public_key: online.public_key,
+                        persistent_keepalive: PERSISTENT_KEEPALIVE,
+                    }),
+                );
+            }
+            InboundPayload::Message(GatewayMessage::BroadcastIceCandidates(
+                ClientsIceCandidates {
+                    client_ids,
+                    candidates,
+                },
+            )) => {
+                for client in client_ids {
+                    let Some(&client_conn) = self.clients.get(&client) else {
+                        continue;
+                    };
+
+                    self.push(
+                        client_conn,
+                        "client",
+                        Egress::IceCandidates(IceCandidates {
+                            sender: Sender::GatewayId(gateway),
+                            candidates: candidates.clone(),
+                        }),
+                        None,
+                    );
+                }
+            }
+            InboundPayload::Message(GatewayMessage::BroadcastInvalidatedIceCandidates(
+                ClientsIceCandidates {
+                    client_ids,
+                    candidates,
+                },
+            )) => {
+                for client in client_ids {
+                    let Some(&client_conn) = self.clients.get(&client) else {
+                        continue;
+                    };
+
+                    self.push(
+                        client_conn,
+                        "client",
+                        Egress::InvalidateIceCandidates(IceCandidates {
+                            sender: Sender::GatewayId(gateway),
+                            candidates: candidates.clone(),
+                        }),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    fn handle_relay_message(
+        &mut self,
+        conn: ConnectionId,
+        addrs: Vec<SocketAddr>,
+        msg: Inbound<serde_json::Value>,
+    ) {
+        match msg.payload {
+            InboundPayload::PhxJoin(payload) => {
+                let RelayJoin { stamp_secret } = match serde_json::from_value(payload) {
+                    Ok(join) => join,
+                    Err(e) => {
+                        tracing::warn!(%conn, "Invalid join from relay: {e}");
+                        self.reply(conn, "relay", msg.reference, Err(ErrorReply::Other));
+                        return;
+                    }
+                };
+
+                self.reply(conn, "relay", msg.reference, Ok(OkReply::Empty {}));
+                self.push(conn, "relay", Egress::Init(Init::Relay {}), None);
+
+                let relay = OnlineRelay {
+                    id: RelayId::from_u128(rand::random()),
+                    addrs,
+                    stamp_secret,
+                };
+                self.broadcast_relays_presence(RelaysPresence {
+                    disconnected_ids: Vec::new(),
+                    connected: turn_credentials(&relay),
+                });
+                self.relays.insert(conn, relay);
+            }
+            InboundPayload::Heartbeat {} => {
+                self.reply(conn, "phoenix", msg.reference, Ok(OkReply::Empty {}));
+            }
+            InboundPayload::Message(msg) => {
+                tracing::debug!(%conn, %msg, "Ignoring message from relay");
+            }
+        }
+    }
+
+    fn parse<T>(&mut self, conn: ConnectionId, topic: &str, text: &str) -> Option<Inbound<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let msg = match serde_json::from_str::<Inbound<T>>(text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::warn!(%conn, "Failed to deserialize message: {e}");
+                return None;
+            }
+        };
+
+        let expected = match msg.payload {
+            InboundPayload::Heartbeat {} => "phoenix",
+            InboundPayload::PhxJoin(_) | InboundPayload::Message(_) => topic,
+        };
+        if msg.topic != expected {
+            self.reply(
+                conn,
+                &msg.topic,
+                msg.reference,
+                Err(ErrorReply::UnmatchedTopic),
+            );
+            return None;
+        }
+
+        Some(msg)
+    }
+
+    /// Picks a Gateway in one of the Resource's Sites, preferring the ones the Client is already connected to.
+    fn connection_details(
+        &self,
+        client: ClientId,
+        resource_id: ResourceId,
+        connected_gateway_ids: &BTreeSet<GatewayId>,
+    ) -> Result<OkReply, ErrorReply> {
+        let resource = self
+            .config
+            .resources
+            .iter()
+            .find(|r| r.id() == resource_id && self.is_allowed(client, resource_id))
+            .ok_or(ErrorReply::NotFound)?;
+        let mut candidates = self
+            .gateways
+            .iter()
+            .filter(|(_, g)| resource.sites().contains(&g.site));
+        let (gateway_id, gateway) = candidates
+            .clone()
+            .find(|(id, _)| connected_gateway_ids.contains(id))
+            .or_else(|| candidates.next())
+            .ok_or(ErrorReply::Offline)?;
+
+        Ok(OkReply::ConnectionDetails {
+            resource_id,
+            gateway_id: *gateway_id,
+            gateway_remote_ip: gateway.remote_ip,
+            gateway_group_id: gateway.site,
+        })
+    }
+
+    /// The connection of a Gateway that may serve `resource` to `client`.
+    fn authorized_gateway(
+        &self,
+        client: ClientId,
+        resource: ResourceId,
+        gateway: GatewayId,
+    ) -> Result<ConnectionId, ErrorReply> {
+        let resource = self
+            .config
+            .resources
+            .iter()
+            .find(|r| r.id() == resource && self.is_allowed(client, resource))
+            .ok_or(ErrorReply::NotFound)?;
+        let gateway = self
+            .gateways
+            .get(&gateway)
+            .filter(|g| resource.sites().contains(&g.site))
+            .ok_or(ErrorReply::Offline)?;
+
+        Ok(gateway.conn)
+    }
+
+    fn is_allowed(&self, client: ClientId, resource: ResourceId) -> bool {
+        self.config
+            .policies
+            .iter()
+            .any(|p| p.resource == resource && p.clients.contains(&client))
+    }
+
+    fn client_resource(&self, resource: &Resource) -> client::ResourceDescription {
+        let sites = resource
+            .sites()
+            .iter()
+            .filter_map(|id| self.config.sites.iter().find(|s| s.id == *id))
+            .map(|s| client::Site {
+                id: s.id,
+                name: s.name.clone(),
+            })
+            .collect();
+
+        match resource {
+            Resource::Cidr {
+                id,
+                name,
+                address,
+                address_description,
+                ..
+            } => client::ResourceDescription::Cidr(client::ResourceDescriptionCidr {
+                id: *id,
+                address: *address,
+                name: name.clone(),
+                address_description: address_description.clone(),
+                sites,
+                health_check: None,
+            }),
+            Resource::Dns {
+                id,
+                name,
+                address,
+                address_description,
+                ..
+            } => client::ResourceDescription::Dns(client::ResourceDescriptionDns {
+                id: *id,
+                address: address.clone(),
+                name: name.clone(),
+                address_description: address_description.clone(),
+                sites,
+                health_check: None,
+            }),
+            Resource::Internet { id, .. } => {
+                client::ResourceDescription::Internet(client::ResourceDescriptionInternet {
+                    name: "Internet Resource".to_owned(),
+                    id: *id,
+                    sites,
+                    can_be_disabled: true,
+                })
+            }
+        }
+    }
+
+    fn gateway_resource(&self, id: ResourceId) -> GatewayResource {
+        let resource = self
+            .config
+            .resources
+            .iter()
+            .find(|r| r.id() == id)
+            .expect("only called for authorized resources");
+
+        match resource {
+            Resource::Cidr {
+                id,
+                name,
+                address,
+                filters,
+                ..
+            } => GatewayResource::Cidr {
+                id: *id,
+                address: *address,
+                name: name.clone(),
+                filters: filters.clone(),
+            },
+            Resource::Dns {
+                id,
+                name,
+                address,
+                filters,
+                ..
+            } => GatewayResource::Dns {
+                id: *id,
+                address: address.clone(),
+                name: name.clone(),
+                filters: filters.clone(),
+            },
+            Resource::Internet { id, .. } => GatewayResource::Internet { id: *id },
+        }
+    }
+
+    fn tunnel_addresses(&mut self, peer: TunnelPeer) -> (Ipv4Addr, Ipv6Addr) {
+        let next = self.tunnel_addresses.len() as u32 + 1;
+
+        *self.tunnel_addresses.entry(peer).or_insert_with(|| {
+            (
+                Ipv4Addr::from(u32::from(IPV4_TUNNEL) + next),
+                Ipv6Addr::from(u128::from(IPV6_TUNNEL) + u128::from(next)),
+            )
+        })
+    }
+
+    fn add_pending_connection(&mut self, pending: PendingConnection) -> String {
+        let reference = Uuid::new_v4().to_string();
+        self.pending_connections.insert(reference.clone(), pending);
+
+        reference
+    }
+
+    fn broadcast_relays_presence(&mut self, presence: RelaysPresence) {
+        let clients = self.clients.values().map(|conn| (*conn, "client"));
+        let gateways = self.gateways.values().map(|g| (g.conn, "gateway"));
+
+        for (conn, topic) in clients.chain(gateways).collect::<Vec<_>>() {
+            self.push(
+                conn,
+                topic,
+                Egress::RelaysPresence(RelaysPresence {
+                    disconnected_ids: presence.disconnected_ids.clone(),
+                    connected: presence.connected.clone(),
+                }),
+                None,
+            );
+        }
+    }
+
+    fn reply(
+        &mut self,
+        conn: ConnectionId,
+        topic: &str,
+        reference: Option<serde_json::Value>,
+        res: Result<OkReply, ErrorReply>,
+    ) {
+        let reply = match res {
+            Ok(res) => Reply::Ok(res),
+            Err(reason) => Reply::Error { reason },
+        };
+
+        self.push(conn, topic, Egress::PhxReply(reply), reference);
+    }
+
+    fn push(
+        &mut self,
+        conn: ConnectionId,
+        topic: &str,
+        payload: Egress,
+        reference: Option<serde_json::Value>,
+    ) {
+        let msg = serialize(&crate::messages::Outbound {
+            topic,
+            payload,
+            reference,
+        });
+
+        tracing::trace!(target: "wire::api::send", %conn, %msg);
+
+        self.transmits.push_back((conn, msg));
+    }
+}
+
+fn serialize(msg: &impl Serialize) -> String {
+    serde_json::to_string(msg).expect("we should always be able to serialize our messages")
+}
+
+fn public_key(s: &str) -> Result<Key, LoginError> {
+    s.parse()
+        .map_err(|_| LoginError::InvalidParameter("public_key"))
+}
+
+/// Gateways identify themselves by the hex-encoded SHA256 of their ID, we use the first half of it.
+fn gateway_id(external_id: &str) -> Result<GatewayId, LoginError> {
+    external_id
+        .get(..32)
+        .and_then(|s| s.parse().ok())
+        .ok_or(LoginError::InvalidParameter("external_id"))
+}
+
+/// Creates TURN credentials for each of the relay's addresses, the way the relay verifies them.
+fn turn_credentials(relay: &OnlineRelay) -> Vec<Relay> {
+    let expires_at =
+        DateTime::<Utc>::from(std::time::SystemTime::now() + RELAY_CREDENTIALS_LIFETIME);
+    let salt = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+    let password = turn_password(&relay.stamp_secret, expires_at.timestamp(), &salt);
+
+    relay
+        .addrs
+        .iter()
+        .map(|addr| Relay::Turn {
+            id: relay.id,
+            expires_at,
+            addr: *addr,
+            username: format!("{}:{salt}", expires_at.timestamp()),
+            password: password.clone(),
+        })
+        .collect()
+}
+
+fn turn_password(stamp_secret: &str, expiry_secs: i64, salt: &str) -> String {
+    let mut hasher = sha2::Sha256::default();
+    hasher.update(format!("{expiry_secs}"));
+    hasher.update(":");
+    hasher.update(stamp_secret);
+    hasher.update(":");
+    hasher.update(salt);
+
+    BASE64_STANDARD_NO_PAD.encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const CLIENT: &str = "3a25ff38-f8d7-47de-9b30-c7c40c206083";
+    const SITE: &str = "bf56f32d-7b2c-4f5d-a784-788977d014a4";
+    const RESOURCE: &str = "73037362-715d-4a83-a749-f18eadd970e6";
+    const PUBLIC_KEY: &str = "OR2dYCLwMEtwqtjOxSm4SU7BbHJDfM8ZCqK7HKXXxDw=";
+    const EXTERNAL_ID: &str = "0a7f2e0b7d2a4f3cb6b7c1f5a1e2d3c4b5a6978877665544332211ffeeddccbb";
+
+    const CLIENT_CONN: ConnectionId = ConnectionId(0);
+    const GATEWAY_CONN: ConnectionId = ConnectionId(1);
+
+    #[test]
+    fn turn_password_matches_relay_test_vector() {
+        let password = turn_password(
+            "4c98bf59c99b3e467ecd7cf9d6b3e5279645fca59be67bc5bb4af3cf653761ab",
+            60 * 60 * 24 * 365 * 60,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        assert_eq!(password, "00hqldgk5xLeKKOB+xls9mHMVtgqzie9DulfgQwMv68")
+    }
+
+    #[test]
+    fn rejects_unknown_token() {
+        let mut portal = portal();
+
+        let result = portal.login(
+            CLIENT_CONN,
+            &client_url("wrong"),
+            IpAddr::from([127, 0, 0, 1]),
+        );
+
+        assert!(matches!(result, Err(LoginError::InvalidToken)));
+    }
+
+    #[test]
+    fn client_only_sees_allowed_resources() {
+        let mut portal = portal();
+        join_client(&mut portal);
+
+        let init = transmits(&mut portal)
+            .into_iter()
+            .find(|(_, m)| m["event"] == "init")
+            .unwrap()
+            .1;
+
+        assert_eq!(init["payload"]["resources"].as_array().unwrap().len(), 1);
+        assert_eq!(init["payload"]["resources"][0]["id"], RESOURCE);
+        assert_eq!(init["payload"]["interface"]["ipv4"], "100.64.0.1");
+    }
+
+    #[test]
+    fn prepare_connection_without_gateway_is_offline() {
+        let mut portal = portal();
+        join_client(&mut portal);
+        transmits(&mut portal);
+
+        portal.handle_message(
+            CLIENT_CONN,
+            &json!({"topic": "client", "event": "prepare_connection", "ref": 3, "payload": {"resource_id": RESOURCE, "connected_gateway_ids": []}}).to_string(),
+        );
+
+        let (_, reply) = transmits(&mut portal).pop().unwrap();
+        assert_eq!(reply["ref"], 3);
+        assert_eq!(reply["payload"]["status"], "error");
+        assert_eq!(reply["payload"]["response"]["reason"], "offline");
+    }
+
+    #[test]
+    fn connection_request_is_forwarded_and_answered() {
+        let mut portal = portal();
+        join_client(&mut portal);
+        join_gateway(&mut portal);
+        transmits(&mut portal);
+
+        portal.handle_message(
+            CLIENT_CONN,
+            &json!({"topic": "client", "event": "prepare_connection", "ref": 3, "payload": {"resource_id": RESOURCE, "connected_gateway_ids": []}}).to_string(),
+        );
+        let (_, details) = transmits(&mut portal).pop().unwrap();
+        assert_eq!(details["payload"]["response"]["gateway_group_id"], SITE);
+        let gateway_id = details["payload"]["response"]["gateway_id"].clone();
+
+        portal.handle_message(
+            CLIENT_CONN,
+            &json!({"topic": "client", "event": "request_connection", "ref": 4, "payload": {
+                "gateway_id": gateway_id,
+                "resource_id": RESOURCE,
+                "client_preshared_key": PUBLIC_KEY,
+                "client_payload": {"ice_parameters": {"username": "u", "password": "p"}, "domain": null},
+            }})
+            .to_string(),
+        );
+        let (conn, request) = transmits(&mut portal).pop().unwrap();
+        assert_eq!(conn, GATEWAY_CONN);
+        assert_eq!(request["event"], "request_connection");
+        assert_eq!(request["payload"]["client"]["id"], CLIENT);
+        assert_eq!(request["payload"]["resource"]["type"], "cidr");
+
+        portal.handle_message(
+            GATEWAY_CONN,
+            &json!({"topic": "gateway", "event": "connection_ready", "ref": 1, "payload": {
+                "ref": request["payload"]["ref"],
+                "gateway_payload": {"ConnectionAccepted": {"ice_parameters": {"username": "u2", "password": "p2"}, "domain_response": null}},
+            }})
+            .to_string(),
+        );
+        let (conn, connect) = transmits(&mut portal).pop().unwrap();
+        assert_eq!(conn, CLIENT_CONN);
+        assert_eq!(connect["ref"], 4);
+        assert_eq!(
+            connect["payload"]["response"]["gateway_public_key"],
+            PUBLIC_KEY
+        );
+    }
+
+    #[test]
+    fn replies_to_heartbeats_on_phoenix_topic() {
+        let mut portal = portal();
+        join_client(&mut portal);
+        transmits(&mut portal);
+
+        portal.handle_message(
+            CLIENT_CONN,
+            &json!({"topic": "phoenix", "event": "heartbeat", "ref": 7, "payload": {}}).to_string(),
+        );
+
+        let (_, reply) = transmits(&mut portal).pop().unwrap();
+        assert_eq!(reply["topic"], "phoenix");
+        assert_eq!(reply["payload"], json!({"status": "ok", "response": {}}));
+    }
+
+    fn portal() -> Portal {
+        let config = serde_yaml::from_str(&format!(
+            r#"
+relay_token: relay
+sites:
+  - id: {SITE}
+    name: Lab
+    token: gateway
+clients:
+  - id: {CLIENT}
+    token: client
+resources:
+  - type: cidr
+    id: {RESOURCE}
+    name: Lab
+    address: 10.0.0.0/24
+    sites: [{SITE}]
+  - type: internet
+    id: 03000143-e25e-45c7-aafb-144990e57dcd
+    sites: [{SITE}]
+policies:
+  - resource: {RESOURCE}
+    clients: [{CLIENT}]
+"#
+        ))
+        .unwrap();
+
+        Portal::new(config)
+    }
+
+    fn client_url(token: &str) -> Url {
+        Url::parse(&format!(
+            "ws://localhost/client/websocket?token={token}&public_key={}",
+            url::form_urlencoded::byte_serialize(PUBLIC_KEY.as_bytes()).collect::<String>()
+        ))
+        .unwrap()
+    }
+
+    fn join_client(portal: &mut Portal) {
+        portal
+            .login(
+                CLIENT_CONN,
+                &client_url("client"),
+                IpAddr::from([127, 0, 0, 1]),
+            )
+            .unwrap();
+        portal.handle_message(
+            CLIENT_CONN,
+            &json!({"topic": "client", "event": "phx_join", "ref": 0, "payload": {}}).to_string(),
+        );
+    }
+
+    fn join_gateway(portal: &mut Portal) {
+        let url = Url::parse(&format!(
+            "ws://localhost/gateway/websocket?token=gateway&external_id={EXTERNAL_ID}&public_key={}",
+            url::form_urlencoded::byte_serialize(PUBLIC_KEY.as_bytes()).collect::<String>()
+        ))
+        .unwrap();
+
+        portal
+            .login(GATEWAY_CONN, &url, IpAddr::from([127, 0, 0, 2]))
+            .unwrap();
+        portal.handle_message(
+            GATEWAY_CONN,
+            &json!({"topic": "gateway", "event": "phx_join", "ref": 0, "payload": {}}).to_string(),
+        );
+    }
+
+    fn transmits(portal: &mut Portal) -> Vec<(ConnectionId, Value)> {
+        std::iter::from_fn(|| portal.poll_transmit())
+            .map(|(conn, msg)| (conn, serde_json::from_str(&msg).unwrap()))
+            .collect()
+    }
+}
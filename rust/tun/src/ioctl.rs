@@ -40,6 +40,12 @@ impl Request<SetTunFlagsPayload> {
             },
         }
     }
+
+    /// Adds flags such as `IFF_MULTI_QUEUE` or `IFF_VNET_HDR` to the default `IFF_TUN | IFF_NO_PI`.
+    pub fn with_flags(mut self, flags: std::ffi::c_int) -> Self {
+        self.payload.flags |= flags as std::ffi::c_short;
+        self
+    }
 }
 
 impl Request<GetInterfaceNamePayload> {
@@ -13,4 +13,64 @@ pub trait Tun: Send + Sync + 'static {
     fn write6(&self, buf: &[u8]) -> io::Result<usize>;
     fn poll_read(&mut self, buf: &mut [u8], cx: &mut Context<'_>) -> Poll<io::Result<usize>>;
     fn name(&self) -> &str;
+
+    /// Reads up to one packet into each of `bufs` and stores their lengths in `lens`.
+    ///
+    /// Returns how many packets were read, which is 0 only if the device is closed.
+    /// Only returns [`Poll::Pending`] if no packet was ready at all.
+    ///
+    /// The default implementation calls [`Tun::poll_read`] until it returns [`Poll::Pending`].
+    /// Devices that can read several packets per syscall should override it.
+    fn poll_read_many(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+        lens: &mut [usize],
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        let mut num_packets = 0;
+
+        for (buf, len) in bufs.iter_mut().zip(lens.iter_mut()) {
+            let n = match self.poll_read(buf, cx) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) if num_packets == 0 => return Poll::Ready(Err(e)),
+                Poll::Pending if num_packets == 0 => return Poll::Pending,
+                // Hand out what we have first. If the error persists, the next call returns it.
+                Poll::Ready(Err(_)) | Poll::Pending => break,
+            };
+            if n == 0 {
+                break; // Closed, the next call returns 0 right away.
+            }
+
+            *len = n;
+            num_packets += 1;
+        }
+
+        Poll::Ready(Ok(num_packets))
+    }
+
+    /// Writes all `packets`, picking [`Tun::write4`] or [`Tun::write6`] from each packet's IP version.
+    ///
+    /// Returns how many packets were written before the first error, if any.
+    /// Devices that can write several packets per syscall, e.g. by coalescing them, should override it.
+    fn write_many(&self, packets: &[&[u8]]) -> io::Result<usize> {
+        for (num_written, packet) in packets.iter().enumerate() {
+            let res = match packet.first().map(|b| b >> 4) {
+                Some(4) => self.write4(packet),
+                Some(6) => self.write6(packet),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not an IP packet",
+                )),
+            };
+
+            if let Err(e) = res {
+                if num_written == 0 {
+                    return Err(e);
+                }
+                return Ok(num_written);
+            }
+        }
+
+        Ok(packets.len())
+    }
 }
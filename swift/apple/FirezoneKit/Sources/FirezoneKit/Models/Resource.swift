@@ -52,6 +52,7 @@ public enum ResourceStatus: String, Decodable {
   case offline = "Offline"
   case online = "Online"
   case unknown = "Unknown"
+  case degraded = "Degraded"
 
   public func toSiteStatus() -> String {
     switch self {
@@ -61,6 +62,8 @@ public enum ResourceStatus: String, Decodable {
       return "Gateway connected"
     case .unknown:
       return "No activity"
+    case .degraded:
+      return "Gateway connected, Resource degraded"
     }
   }
 
@@ -73,6 +76,8 @@ public enum ResourceStatus: String, Decodable {
       return "You're connected to a healthy Gateway in this Site."
     case .unknown:
       return "No connection has been attempted to Resources in this Site. Access a Resource to establish a Gateway connection."
+    case .degraded:
+      return "You're connected to a Gateway in this Site but it's failing to reach this Resource."
     }
   }
 }
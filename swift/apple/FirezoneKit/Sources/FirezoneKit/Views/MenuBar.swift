@@ -714,6 +714,8 @@ public final class MenuBar: NSObject, ObservableObject {
       return .on
     case .unknown:
       return .mixed
+    case .degraded:
+      return .mixed
     }
   }
 }
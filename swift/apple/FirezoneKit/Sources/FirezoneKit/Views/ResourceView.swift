@@ -87,6 +87,8 @@ struct ResourceView: View {
       return .red
     case .unknown:
       return .gray
+    case .degraded:
+      return .yellow
     }
   }
 }